@@ -0,0 +1,10 @@
+//! Parse arbitrary bytes as a release index; errors are fine, panics are not.
+
+#![no_main]
+
+use fakeup::metadata::ReleasesJSON;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ReleasesJSON::from_slice(data);
+});
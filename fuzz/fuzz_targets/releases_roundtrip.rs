@@ -0,0 +1,18 @@
+//! Serialize an arbitrary release index and parse it back.
+
+#![no_main]
+
+use fakeup::metadata::ReleasesJSON;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|index: ReleasesJSON| {
+    let body = serde_json::to_vec(&index).expect("serializable release index");
+    let blank_version = index.releases.iter().any(|r| r.version.trim().is_empty());
+    match ReleasesJSON::from_slice(&body) {
+        Ok(parsed) => {
+            assert!(!blank_version);
+            assert_eq!(parsed.releases.len(), index.releases.len());
+        }
+        Err(_) => assert!(blank_version),
+    }
+});
@@ -0,0 +1,142 @@
+//! Administrative endpoints.
+
+use crate::clients;
+use crate::fixture;
+use crate::scraper;
+use crate::AppState;
+use actix_web::{http::Method, HttpRequest, HttpResponse, Scope};
+use failure::Error;
+use futures::prelude::*;
+use serde_derive::Serialize;
+
+/// Register administrative routes.
+pub(crate) fn register(scope: Scope<AppState>) -> Scope<AppState> {
+    scope
+        .route("/status", Method::GET, status)
+        .route("/fixture", Method::GET, fixture)
+        .route("/changelog", Method::GET, changelog)
+        .route("/adoption", Method::GET, adoption)
+        .route("/quotas/reset", Method::POST, reset_quotas)
+        .route("/approve/{version}", Method::POST, approve)
+        .route("/freeze", Method::POST, freeze)
+        .route("/unfreeze", Method::POST, unfreeze)
+}
+
+/// Latch the currently served graphs, ignoring further scrapes.
+pub(crate) fn freeze(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    set_frozen(&req, true)
+}
+
+/// Resume applying scrapes to the served graphs.
+pub(crate) fn unfreeze(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    set_frozen(&req, false)
+}
+
+fn set_frozen(
+    req: &HttpRequest<AppState>,
+    frozen: bool,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let msg = scraper::SetFrozen { frozen };
+    let resp = scraper::request(&req.state().scraper_addr, msg)
+        .map(|_| HttpResponse::NoContent().finish());
+    Box::new(resp)
+}
+
+/// Approve a release withheld by the release gate.
+pub(crate) fn approve(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let version = req.match_info().get("version").unwrap_or_default();
+    let msg = scraper::ApproveRelease {
+        version: version.to_string(),
+    };
+    let resp = scraper::request(&req.state().scraper_addr, msg).map(|found| {
+        if found {
+            HttpResponse::NoContent().finish()
+        } else {
+            HttpResponse::NotFound().finish()
+        }
+    });
+    Box::new(resp)
+}
+
+/// Reset node quotas, for a single `node_uuid` or for all nodes.
+pub(crate) fn reset_quotas(req: HttpRequest<AppState>) -> HttpResponse {
+    let quota = match req.state().node_quota {
+        Some(ref q) => q,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let query = req.query();
+    let reset = quota.reset(query.get("node_uuid").map(String::as_str));
+    HttpResponse::Ok().json(serde_json::json!({ "reset": reset }))
+}
+
+/// Report release adoption, from checksums reported by nodes.
+pub(crate) fn adoption(req: HttpRequest<AppState>) -> HttpResponse {
+    let clients = &req.state().clients;
+    if !clients.tracks_adoption() {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok().json(clients.adoption())
+}
+
+/// Report changes detected between scrapes, optionally for a single `stream`.
+pub(crate) fn changelog(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let msg = scraper::GetChangelog {
+        stream: req.query().get("stream").cloned(),
+    };
+    let resp = scraper::request(&req.state().scraper_addr, msg)
+        .map(|entries| HttpResponse::Ok().json(entries));
+    Box::new(resp)
+}
+
+/// Report server status.
+pub(crate) fn status(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let clients = req.state().clients.clone();
+    let msg = scraper::GetStatus {};
+    let resp = scraper::request(&req.state().scraper_addr, msg).map(move |scraper| {
+        let status = AdminStatus {
+            scraper,
+            bandwidth: clients.bandwidth(),
+        };
+        HttpResponse::Ok().json(status)
+    });
+    Box::new(resp)
+}
+
+/// Export server state as a fixture.
+pub(crate) fn fixture(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let client_version = req.state().client_version;
+    let msg = scraper::GetSnapshot {};
+    let resp = scraper::request(&req.state().scraper_addr, msg).map(move |snapshot| {
+        let fixture = fixture::Fixture {
+            config: fixture::FixtureConfig {
+                streams: snapshot.streams,
+                priority_streams: snapshot.priority_streams,
+                client_version,
+                frozen: snapshot.frozen,
+            },
+            releases: snapshot.releases,
+        };
+        HttpResponse::Ok().json(fixture)
+    });
+    Box::new(resp)
+}
+
+/// Server status, as reported by the admin API.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct AdminStatus {
+    #[serde(flatten)]
+    pub(crate) scraper: scraper::ScraperStatus,
+    pub(crate) bandwidth: clients::BandwidthReport,
+}
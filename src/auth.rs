@@ -0,0 +1,123 @@
+//! Bearer token authentication, on the graph endpoint.
+
+use crate::errors::GraphError;
+use actix_web::http::{header, HeaderMap};
+use failure::{bail, format_err, Fallible};
+use prometheus::IntCounter;
+use std::path::Path;
+
+lazy_static::lazy_static! {
+    static ref UNAUTHORIZED: IntCounter = register_int_counter!(opts!(
+        "fakeup_graph_unauthorized_requests_total",
+        "Total number of graph requests rejected due to a missing or wrong bearer token"
+    ))
+    .unwrap();
+}
+
+/// Static token which clients must send as `Authorization: Bearer <token>`.
+#[derive(Clone)]
+pub(crate) struct BearerToken(String);
+
+impl std::fmt::Debug for BearerToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BearerToken(<redacted>)")
+    }
+}
+
+impl BearerToken {
+    pub(crate) fn new(token: &str) -> Fallible<Self> {
+        let token = token.trim();
+        if token.is_empty() {
+            bail!("empty bearer token");
+        }
+        Ok(Self(token.to_string()))
+    }
+
+    /// Read the token from a file, ignoring surrounding whitespace.
+    pub(crate) fn from_file(path: &Path) -> Fallible<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("failed to read token file '{}': {}", path.display(), e))?;
+        Self::new(&content).map_err(|e| format_err!("{} in '{}'", e, path.display()))
+    }
+
+    /// Check request credentials, failing as unauthorized if they do not match.
+    pub(crate) fn check(&self, headers: &HeaderMap) -> Result<(), GraphError> {
+        let value = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        let reason = match value.map(|v| v.trim().split_once(' ')) {
+            None => "missing bearer token",
+            Some(Some((scheme, token)))
+                if scheme.eq_ignore_ascii_case("bearer") && self.matches(token.trim()) =>
+            {
+                return Ok(())
+            }
+            Some(Some((scheme, _))) if scheme.eq_ignore_ascii_case("bearer") => {
+                "invalid bearer token"
+            }
+            Some(_) => "unsupported authorization scheme",
+        };
+        UNAUTHORIZED.inc();
+        Err(GraphError::Unauthorized(reason))
+    }
+
+    /// Compare tokens, in time independent of where they differ.
+    fn matches(&self, candidate: &str) -> bool {
+        let (expected, candidate) = (self.0.as_bytes(), candidate.as_bytes());
+        expected.len() == candidate.len()
+            && expected
+                .iter()
+                .zip(candidate)
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+    use actix_web::http::StatusCode;
+
+    fn check(token: &BearerToken, authorization: Option<&str>) -> Option<StatusCode> {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = authorization {
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        }
+        token.check(&headers).err().map(|e| e.response().status())
+    }
+
+    #[test]
+    fn bearer_tokens_are_checked() {
+        let token = BearerToken::new(" s3cret\n").unwrap();
+        assert_eq!(check(&token, Some("Bearer s3cret")), None);
+        assert_eq!(check(&token, Some("bearer  s3cret ")), None);
+
+        let unauthorized = Some(StatusCode::UNAUTHORIZED);
+        assert_eq!(check(&token, None), unauthorized);
+        assert_eq!(check(&token, Some("Bearer s3cre")), unauthorized);
+        assert_eq!(check(&token, Some("Bearer s3cret2")), unauthorized);
+        assert_eq!(check(&token, Some("Basic s3cret")), unauthorized);
+        assert_eq!(check(&token, Some("s3cret")), unauthorized);
+
+        let mut headers = HeaderMap::new();
+        let resp = token.check(&headers).unwrap_err().response();
+        assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer x"));
+        assert!(token.check(&headers).is_err());
+    }
+
+    #[test]
+    fn tokens_are_read_from_files() {
+        let path = std::env::temp_dir().join(format!("fakeup-token-{}", std::process::id()));
+        std::fs::write(&path, "s3cret\n").unwrap();
+        let token = BearerToken::from_file(&path).unwrap();
+        assert!(token.matches("s3cret"));
+        assert!(!format!("{:?}", token).contains("s3cret"));
+
+        std::fs::write(&path, " \n").unwrap();
+        assert!(BearerToken::from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(BearerToken::from_file(&path).is_err());
+    }
+}
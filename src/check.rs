@@ -0,0 +1,208 @@
+//! Dry-run validation of the server configuration.
+
+use crate::config::ConfigFile;
+use crate::{fetcher, fixture, https, scraper, tls, ServeOptions};
+use failure::{bail, Fallible};
+use futures::future;
+use futures::prelude::*;
+use std::fmt::Display;
+
+/// Outcome of configuration checks, printed as a report.
+#[derive(Debug, Default)]
+struct CheckReport {
+    failures: usize,
+}
+
+impl CheckReport {
+    /// Record and print the outcome of a single check.
+    fn record<T: Display, E: Display>(&mut self, subject: &str, res: Result<T, E>) {
+        match res {
+            Ok(detail) => println!("ok    {}: {}", subject, detail),
+            Err(e) => {
+                self.failures += 1;
+                println!("FAIL  {}: {}", subject, e);
+            }
+        }
+    }
+}
+
+/// Validate settings as `serve` would use them, and scrape each stream once.
+///
+/// It fails if any check failed.
+pub(crate) fn check_config(
+    opts: &ServeOptions,
+    file_config: &ConfigFile,
+    releases_template: Option<String>,
+) -> Fallible<()> {
+    let mut report = CheckReport::default();
+    let mut sys = actix::System::new("fakeup-check");
+
+    let mut streams = crate::scraped_streams(&opts.streams, file_config);
+    streams.extend(opts.priority_streams.iter().cloned());
+    if !opts.pins.is_empty() {
+        streams.clear();
+        for pin in &opts.pins {
+            report.record::<_, String>(
+                &format!("pin '{}'", pin.stream),
+                Ok(format!("{} ({})", pin.version, pin.checksum)),
+            );
+        }
+    }
+    for stream in &streams {
+        let res = if opts.stream_pattern.is_match(stream) {
+            Ok("valid name")
+        } else {
+            Err(format!(
+                "does not match stream pattern '{}'",
+                opts.stream_pattern
+            ))
+        };
+        report.record(&format!("stream '{}'", stream), res);
+    }
+
+    let refresh_pause = crate::refresh_interval(opts.refresh_interval, file_config);
+    report.record(
+        "refresh interval",
+        refresh_pause
+            .as_ref()
+            .map(|d| humantime::format_duration(*d).to_string())
+            .map_err(|e| e.to_string()),
+    );
+    if let Some(ref template) = releases_template {
+        // Already validated on startup.
+        report.record::<_, String>("releases URL template", Ok(template));
+    }
+    if let Some(ref path) = opts.import_fixture {
+        let res = fixture::Fixture::read_bundle(path)
+            .map(|f| format!("{} cached streams", f.releases.len()));
+        report.record("imported fixture", res);
+    }
+    if let Some(ref dir) = opts.fixtures_dir {
+        let res = if dir.is_dir() {
+            Ok(dir.display())
+        } else {
+            Err(format!("'{}' is not a directory", dir.display()))
+        };
+        report.record("fixtures directory", res);
+    }
+    if let (Some(cert), Some(key)) = (&opts.tls_cert, &opts.tls_key) {
+        let client_ca = opts.tls_client_ca.as_deref();
+        let res = https::ServerTls::load(cert, key, client_ca).map(|_| match client_ca {
+            Some(ca) => format!(
+                "certificate '{}', client CAs '{}'",
+                cert.display(),
+                ca.display()
+            ),
+            None => format!("certificate '{}'", cert.display()),
+        });
+        report.record("serving TLS", res);
+    }
+
+    let upstream_tls = tls::UpstreamTls {
+        ca_bundle: opts.upstream_ca.clone(),
+        insecure: opts.upstream_insecure,
+    };
+    let tls_res = fetcher::new_fetcher(&upstream_tls).map(|_| match upstream_tls.ca_bundle {
+        Some(ref path) => format!("trusting CA bundle '{}'", path.display()),
+        None => "system trust store".to_string(),
+    });
+    let tls_ok = tls_res.is_ok();
+    report.record("upstream TLS", tls_res);
+
+    // Trial scrapes only make sense with a usable client setup.
+    let scrapable = opts.import_fixture.is_none() && opts.synthetic_releases.is_none();
+    if let (true, true, Ok(refresh_pause)) = (scrapable, tls_ok, refresh_pause) {
+        let retry_policy = crate::retry::RetryPolicy::new(opts.upstream_retry.clone());
+        let scraper = scraper::Scraper::new(streams.clone(), refresh_pause, retry_policy)?
+            .with_releases_template(releases_template)
+            .with_fixtures_dir(opts.fixtures_dir.clone())
+            .with_upstream_tls(&upstream_tls)?;
+        let scrapes: Vec<_> = streams
+            .iter()
+            .map(|stream| {
+                let stream = stream.clone();
+                scraper
+                    .fetch_releases(&stream, None)
+                    .then(move |res| Ok::<_, ()>((stream, res)))
+            })
+            .collect();
+        let results = sys.block_on(future::join_all(scrapes)).unwrap_or_default();
+        for (stream, res) in results {
+            let res = res.map(|releases| format!("{} releases", releases.len()));
+            report.record(&format!("scrape '{}'", stream), res);
+        }
+    }
+
+    if report.failures > 0 {
+        bail!("{} configuration checks failed", report.failures);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CliOptions, Command};
+    use structopt::StructOpt;
+
+    fn serve_options(args: &[&str]) -> ServeOptions {
+        let args = ["fakeup", "check-config"].iter().chain(args);
+        match CliOptions::from_iter_safe(args).unwrap().command().unwrap() {
+            Command::CheckConfig(opts) => opts,
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn streams_are_scraped_once() {
+        let dir = std::env::temp_dir().join(format!("fakeup-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("releases-stable.json"), r#"{"releases": []}"#).unwrap();
+        let fixtures = dir.to_str().unwrap();
+        let file_config = ConfigFile::default();
+
+        let opts = serve_options(&["--fixtures-dir", fixtures, "--stream", "stable"]);
+        assert!(check_config(&opts, &file_config, None).is_ok());
+
+        let opts = serve_options(&[
+            "--fixtures-dir",
+            fixtures,
+            "--stream",
+            "stable",
+            "--stream",
+            "next",
+        ]);
+        let err = check_config(&opts, &file_config, None).unwrap_err();
+        assert_eq!(err.to_string(), "1 configuration checks failed");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_settings_fail() {
+        let file_config = ConfigFile::default();
+        let opts = serve_options(&["--pin", "bad/stream=1:c1", "--stream-pattern", "^[a-z]+$"]);
+        assert!(check_config(&opts, &file_config, None).is_ok());
+
+        let opts = serve_options(&["--fixtures-dir", "/nonexistent", "--stream", "Bad/Stream"]);
+        let err = check_config(&opts, &file_config, None).unwrap_err();
+        // Invalid stream name, missing fixtures directory, failed scrape.
+        assert_eq!(err.to_string(), "3 configuration checks failed");
+    }
+
+    #[test]
+    fn serving_tls_is_loaded() {
+        let file_config = ConfigFile::default();
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+        let pin = ["--pin", "stable=1:c1"];
+
+        let opts = serve_options(&[&pin[..], &["--tls-cert", cert, "--tls-key", key]].concat());
+        let res = check_config(&opts, &file_config, None);
+        assert_eq!(res.is_ok(), cfg!(feature = "https"));
+        // A certificate is not a key.
+        let opts = serve_options(&[&pin[..], &["--tls-cert", cert, "--tls-key", cert]].concat());
+        let err = check_config(&opts, &file_config, None).unwrap_err();
+        assert_eq!(err.to_string(), "1 configuration checks failed");
+    }
+}
@@ -0,0 +1,631 @@
+//! Client-side accounting, shared across server workers.
+
+use crate::CincinnatiPayload;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+lazy_static::lazy_static! {
+    static ref RESPONSE_BYTES: IntCounterVec = register_int_counter_vec!(
+        "fakeup_graph_response_bytes_total",
+        "Total number of graph response bytes served",
+        &["stream"]
+    )
+    .unwrap();
+    static ref DUPLICATE_NODES: IntGauge = register_int_gauge!(opts!(
+        "fakeup_clients_duplicate_node_uuids",
+        "Number of node UUIDs seen from multiple source addresses"
+    ))
+    .unwrap();
+    static ref SHARED_ADDRESSES: IntGauge = register_int_gauge!(opts!(
+        "fakeup_clients_shared_addresses",
+        "Number of source addresses seen with multiple node UUIDs"
+    ))
+    .unwrap();
+    static ref RELEASE_NODES: IntGaugeVec = register_int_gauge_vec!(
+        "fakeup_clients_release_nodes",
+        "Number of nodes last seen running a release",
+        &["stream", "version"]
+    )
+    .unwrap();
+    static ref RELEASE_NODES_REPORTED: IntCounterVec = register_int_counter_vec!(
+        "fakeup_clients_release_nodes_reported_total",
+        "Number of distinct nodes which have reported running a release",
+        &["stream", "version"]
+    )
+    .unwrap();
+    static ref STICKY_TARGETS: IntCounter = register_int_counter!(opts!(
+        "fakeup_clients_sticky_targets_served_total",
+        "Number of graph responses pinned to a previously offered target"
+    ))
+    .unwrap();
+}
+
+/// Default maximum number of nodes (and addresses) tracked at once.
+pub static DEFAULT_MAX_NODES: usize = 100_000;
+
+/// Table of client state.
+#[derive(Clone, Debug)]
+pub struct ClientsTable {
+    /// Whether to account traffic per node UUID.
+    track_nodes: bool,
+    /// Number of distinct addresses (or UUIDs) flagged as duplicate.
+    duplicates_threshold: usize,
+    /// Whether to keep offering the same target to a node until it updates.
+    sticky_targets: bool,
+    /// Whether to track release adoption per node UUID.
+    track_adoption: bool,
+    state: Arc<Mutex<TableState>>,
+}
+
+/// Per-node state is bounded, keeping the most recently seen nodes (and
+/// addresses): forgotten nodes are tracked afresh when seen again.
+#[derive(Debug)]
+struct TableState {
+    /// Graph responses, per HTTP status.
+    responses: BTreeMap<u16, u64>,
+    stream_bytes: BTreeMap<String, u64>,
+    node_bytes: Recent<String, u64>,
+    node_addrs: Recent<String, BTreeSet<IpAddr>>,
+    addr_nodes: Recent<IpAddr, BTreeSet<String>>,
+    /// Target offered to a node, per `(node_uuid, stream)`.
+    node_targets: Recent<(String, String), CincinnatiPayload>,
+    /// Release adoption, per `(stream, checksum)`.
+    adoption: BTreeMap<(String, String), AdoptionEntry>,
+    /// Release last reported by a node, as `(stream, checksum)`.
+    node_releases: Recent<String, (String, String)>,
+}
+
+impl TableState {
+    fn new(max_nodes: usize) -> Self {
+        Self {
+            responses: BTreeMap::new(),
+            stream_bytes: BTreeMap::new(),
+            node_bytes: Recent::new(max_nodes),
+            node_addrs: Recent::new(max_nodes),
+            addr_nodes: Recent::new(max_nodes),
+            node_targets: Recent::new(max_nodes),
+            adoption: BTreeMap::new(),
+            node_releases: Recent::new(max_nodes),
+        }
+    }
+
+    /// Stop counting a node as running its last reported release.
+    fn forget_release(&mut self, release: &(String, String)) {
+        if let Some(entry) = self.adoption.get_mut(release) {
+            entry.current_nodes = entry.current_nodes.saturating_sub(1);
+            RELEASE_NODES
+                .with_label_values(&[&release.0, entry.metric_version()])
+                .dec();
+        }
+    }
+}
+
+/// Map bounded to its most recently used entries.
+#[derive(Debug)]
+struct Recent<K, V> {
+    capacity: usize,
+    /// Use counter, increasing on each access.
+    tick: u64,
+    entries: HashMap<K, (u64, V)>,
+    /// Keys by last use, oldest first.
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash, V> Recent<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(_, v)| v)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let (tick, value) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        Some(value)
+    }
+
+    /// Mark a key as most recently used, making room for it if new.
+    ///
+    /// It returns evicted entries, least recently used first.
+    fn touch(&mut self, key: &K) -> Vec<(K, V)> {
+        let mut evicted = vec![];
+        match self.entries.get(key) {
+            Some((tick, _)) => {
+                self.order.remove(tick);
+            }
+            None => {
+                while self.entries.len() >= self.capacity {
+                    let oldest = match self.order.pop_first() {
+                        Some((_, oldest)) => oldest,
+                        None => break,
+                    };
+                    if let Some((_, value)) = self.entries.remove(&oldest) {
+                        evicted.push((oldest, value));
+                    }
+                }
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        evicted
+    }
+
+    /// Entry for a key, as most recently used.
+    fn entry(&mut self, key: K, evicted: &mut Vec<(K, V)>) -> &mut V
+    where
+        V: Default,
+    {
+        evicted.extend(self.touch(&key));
+        let tick = self.tick;
+        let slot = self
+            .entries
+            .entry(key)
+            .or_insert_with(|| (tick, V::default()));
+        slot.0 = tick;
+        &mut slot.1
+    }
+
+    /// Insert a value as most recently used, returning the previous one.
+    fn insert(&mut self, key: K, value: V, evicted: &mut Vec<(K, V)>) -> Option<V> {
+        evicted.extend(self.touch(&key));
+        self.entries
+            .insert(key, (self.tick, value))
+            .map(|(_, previous)| previous)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(k, (_, v))| (k, v))
+    }
+}
+
+#[derive(Debug, Default)]
+struct AdoptionEntry {
+    version: Option<String>,
+    current_nodes: u64,
+    reported_nodes: BTreeSet<String>,
+}
+
+impl AdoptionEntry {
+    fn metric_version(&self) -> &str {
+        self.version.as_deref().unwrap_or("unknown")
+    }
+}
+
+/// Adoption of a release, from checksums reported by clients.
+#[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+#[derive(Clone, Debug, Serialize)]
+pub struct ReleaseAdoption {
+    /// Release version, if the payload is in the release index.
+    pub version: Option<String>,
+    /// Nodes whose last report was this payload.
+    pub current_nodes: u64,
+    /// Distinct nodes which ever reported this payload.
+    pub reported_nodes: u64,
+}
+
+/// Release adoption per stream, by payload checksum.
+#[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+#[derive(Clone, Debug, Serialize)]
+pub struct AdoptionReport {
+    pub streams: BTreeMap<String, BTreeMap<String, ReleaseAdoption>>,
+}
+
+/// Node UUIDs seen from multiple addresses, and vice versa.
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicatesReport {
+    pub nodes: BTreeMap<String, BTreeSet<IpAddr>>,
+    pub addresses: BTreeMap<IpAddr, BTreeSet<String>>,
+}
+
+/// Bytes served, per stream and per node UUID.
+#[derive(Clone, Debug, Serialize)]
+pub struct BandwidthReport {
+    pub streams: BTreeMap<String, u64>,
+    pub nodes: BTreeMap<String, u64>,
+}
+
+impl ClientsTable {
+    pub fn new(track_nodes: bool, duplicates_threshold: usize) -> Self {
+        Self {
+            track_nodes,
+            duplicates_threshold,
+            sticky_targets: false,
+            track_adoption: false,
+            state: Arc::new(Mutex::new(TableState::new(DEFAULT_MAX_NODES))),
+        }
+    }
+
+    /// Bound per-node state to the most recently seen nodes.
+    pub fn with_max_nodes(mut self, max_nodes: usize) -> Self {
+        self.state = Arc::new(Mutex::new(TableState::new(max_nodes)));
+        self
+    }
+
+    /// Keep offering the same target to a node, until it reports it.
+    pub fn with_sticky_targets(mut self, sticky: bool) -> Self {
+        self.sticky_targets = sticky;
+        self
+    }
+
+    /// Track which releases nodes report running.
+    pub fn with_adoption(mut self, track: bool) -> Self {
+        self.track_adoption = track;
+        self
+    }
+
+    /// Whether release adoption is tracked.
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub fn tracks_adoption(&self) -> bool {
+        self.track_adoption
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TableState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Account a graph response served to a client.
+    pub fn record_response(&self, stream: &str, node_uuid: Option<&str>, bytes: u64) {
+        RESPONSE_BYTES
+            .with_label_values(&[stream])
+            .inc_by(bytes as i64);
+
+        let mut state = self.lock();
+        *state.stream_bytes.entry(stream.to_string()).or_default() += bytes;
+        if let (true, Some(uuid)) = (self.track_nodes, node_uuid) {
+            *state.node_bytes.entry(uuid.to_string(), &mut vec![]) += bytes;
+        }
+    }
+
+    /// Account the status of a graph response, whether successful or not.
+    pub fn record_status(&self, status: u16) {
+        *self.lock().responses.entry(status).or_default() += 1;
+    }
+
+    /// Record the source address of a node, flagging duplicates.
+    pub fn record_node_address(&self, node_uuid: &str, addr: IpAddr) {
+        let threshold = self.duplicates_threshold;
+        let mut state = self.lock();
+
+        let mut evicted = vec![];
+        let addrs = state.node_addrs.entry(node_uuid.to_string(), &mut evicted);
+        if addrs.insert(addr) && addrs.len() == threshold {
+            let logged_uuid = crate::redact::param("node_uuid", node_uuid);
+            warn!(
+                "node UUID {} seen from {} addresses",
+                logged_uuid, threshold
+            );
+            DUPLICATE_NODES.inc();
+        }
+        for (_, addrs) in evicted {
+            if addrs.len() >= threshold {
+                DUPLICATE_NODES.dec();
+            }
+        }
+
+        let mut evicted = vec![];
+        let nodes = state.addr_nodes.entry(addr, &mut evicted);
+        if nodes.insert(node_uuid.to_string()) && nodes.len() == threshold {
+            warn!("address {} seen with {} node UUIDs", addr, threshold);
+            SHARED_ADDRESSES.inc();
+        }
+        for (_, nodes) in evicted {
+            if nodes.len() >= threshold {
+                SHARED_ADDRESSES.dec();
+            }
+        }
+    }
+
+    /// Record the payload a node reports running, with its version if known.
+    pub fn record_release(
+        &self,
+        stream: &str,
+        node_uuid: Option<&str>,
+        checksum: &str,
+        version: Option<&str>,
+    ) {
+        let uuid = match (self.track_adoption, node_uuid) {
+            (true, Some(uuid)) => uuid,
+            _ => return,
+        };
+        let release = (stream.to_string(), checksum.to_string());
+        let mut state = self.lock();
+
+        let mut evicted = vec![];
+        let previous = state
+            .node_releases
+            .insert(uuid.to_string(), release.clone(), &mut evicted);
+        for (_, forgotten) in evicted {
+            state.forget_release(&forgotten);
+        }
+        if previous.as_ref() == Some(&release) {
+            return;
+        }
+        if let Some(previous) = previous {
+            state.forget_release(&previous);
+        }
+
+        let entry = state.adoption.entry(release).or_default();
+        if entry.version.is_none() {
+            entry.version = version.map(String::from);
+        }
+        entry.current_nodes += 1;
+        RELEASE_NODES
+            .with_label_values(&[stream, entry.metric_version()])
+            .inc();
+        if entry.reported_nodes.insert(uuid.to_string()) {
+            RELEASE_NODES_REPORTED
+                .with_label_values(&[stream, entry.metric_version()])
+                .inc();
+        }
+    }
+
+    /// Return the target to offer to a node, given the current latest.
+    ///
+    /// With sticky targets, a node keeps being offered its first target
+    /// until it reports running that payload.
+    pub(crate) fn sticky_target(
+        &self,
+        node_uuid: Option<&str>,
+        stream: &str,
+        current: &CincinnatiPayload,
+        latest: Option<CincinnatiPayload>,
+    ) -> Option<CincinnatiPayload> {
+        let uuid = match (self.sticky_targets, node_uuid) {
+            (true, Some(uuid)) => uuid,
+            _ => return latest,
+        };
+        let key = (uuid.to_string(), stream.to_string());
+        let mut state = self.lock();
+
+        match state.node_targets.get(&key) {
+            // Node reached (or went past) its target, release it.
+            Some(target)
+                if target.payload == current.payload || age_index(target) <= age_index(current) =>
+            {
+                state.node_targets.remove(&key);
+            }
+            Some(target) => {
+                STICKY_TARGETS.inc();
+                return Some(target.clone());
+            }
+            None => {}
+        }
+        if let Some(ref latest) = latest {
+            if latest.payload != current.payload {
+                state.node_targets.insert(key, latest.clone(), &mut vec![]);
+            }
+        }
+        latest
+    }
+
+    /// Return node UUIDs and addresses over the duplicates threshold.
+    pub fn duplicates(&self) -> DuplicatesReport {
+        let threshold = self.duplicates_threshold;
+        let state = self.lock();
+        DuplicatesReport {
+            nodes: state
+                .node_addrs
+                .iter()
+                .filter(|(_, addrs)| addrs.len() >= threshold)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            addresses: state
+                .addr_nodes
+                .iter()
+                .filter(|(_, nodes)| nodes.len() >= threshold)
+                .map(|(k, v)| (*k, v.clone()))
+                .collect(),
+        }
+    }
+
+    /// Return the number of graph responses, per HTTP status.
+    pub fn responses(&self) -> BTreeMap<u16, u64> {
+        self.lock().responses.clone()
+    }
+
+    /// Return a snapshot of release adoption.
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub fn adoption(&self) -> AdoptionReport {
+        let state = self.lock();
+        let mut streams: BTreeMap<String, BTreeMap<String, ReleaseAdoption>> = BTreeMap::new();
+        for ((stream, checksum), entry) in &state.adoption {
+            let adoption = ReleaseAdoption {
+                version: entry.version.clone(),
+                current_nodes: entry.current_nodes,
+                reported_nodes: entry.reported_nodes.len() as u64,
+            };
+            streams
+                .entry(stream.clone())
+                .or_default()
+                .insert(checksum.clone(), adoption);
+        }
+        AdoptionReport { streams }
+    }
+
+    /// Return a snapshot of bandwidth accounting.
+    pub fn bandwidth(&self) -> BandwidthReport {
+        let state = self.lock();
+        BandwidthReport {
+            streams: state.stream_bytes.clone(),
+            nodes: state
+                .node_bytes
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+        }
+    }
+}
+
+/// Parse the age index of a node, defaulting to the oldest.
+fn age_index(node: &CincinnatiPayload) -> u64 {
+    node.metadata
+        .get(crate::metadata::AGE_INDEX)
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(version: &str, age_index: u64) -> CincinnatiPayload {
+        let mut metadata = std::collections::HashMap::new();
+        metadata.insert(
+            crate::metadata::AGE_INDEX.to_string(),
+            age_index.to_string(),
+        );
+        CincinnatiPayload {
+            version: version.to_string(),
+            metadata,
+            payload: format!("payload-{}", version),
+        }
+    }
+
+    #[test]
+    fn sticky_targets_are_kept_until_reached() {
+        let clients = ClientsTable::new(false, 2).with_sticky_targets(true);
+        let (v1, v2, v3) = (node("1", 0), node("2", 1), node("3", 2));
+
+        let target = clients.sticky_target(Some("n1"), "sticky", &v1, Some(v2.clone()));
+        assert_eq!(target.unwrap().version, "2");
+        let target = clients.sticky_target(Some("n1"), "sticky", &v1, Some(v3.clone()));
+        assert_eq!(target.unwrap().version, "2");
+        // Other nodes, and anonymous ones, get the latest.
+        let target = clients.sticky_target(Some("n2"), "sticky", &v1, Some(v3.clone()));
+        assert_eq!(target.unwrap().version, "3");
+        let target = clients.sticky_target(None, "sticky", &v1, Some(v3.clone()));
+        assert_eq!(target.unwrap().version, "3");
+
+        // Once updated, the node moves on.
+        let target = clients.sticky_target(Some("n1"), "sticky", &v2, Some(v3.clone()));
+        assert_eq!(target.unwrap().version, "3");
+    }
+
+    #[test]
+    fn targets_are_not_sticky_by_default() {
+        let clients = ClientsTable::new(false, 2);
+        let (v1, v2, v3) = (node("1", 0), node("2", 1), node("3", 2));
+        clients.sticky_target(Some("n1"), "plain", &v1, Some(v2));
+        let target = clients.sticky_target(Some("n1"), "plain", &v1, Some(v3));
+        assert_eq!(target.unwrap().version, "3");
+    }
+
+    #[test]
+    fn bandwidth_is_accounted_per_stream() {
+        let clients = ClientsTable::new(false, 2);
+        clients.record_response("bw-stable", Some("node-a"), 10);
+        clients.record_response("bw-stable", None, 5);
+        clients.record_response("bw-testing", Some("node-a"), 3);
+
+        let report = clients.bandwidth();
+        assert_eq!(report.streams["bw-stable"], 15);
+        assert_eq!(report.streams["bw-testing"], 3);
+        assert!(report.nodes.is_empty());
+        assert_eq!(RESPONSE_BYTES.with_label_values(&["bw-stable"]).get(), 15);
+    }
+
+    #[test]
+    fn bandwidth_is_accounted_per_node_if_tracked() {
+        let clients = ClientsTable::new(true, 2);
+        clients.record_response("bw-nodes", Some("node-a"), 10);
+        clients.record_response("bw-nodes", Some("node-a"), 2);
+        clients.record_response("bw-nodes", None, 5);
+
+        let report = clients.bandwidth();
+        assert_eq!(report.nodes.len(), 1);
+        assert_eq!(report.nodes["node-a"], 12);
+    }
+
+    #[test]
+    fn shared_node_uuids_are_flagged() {
+        let clients = ClientsTable::new(false, 2);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        clients.record_node_address("node-a", a);
+        clients.record_node_address("node-a", a);
+        assert!(clients.duplicates().nodes.is_empty());
+
+        clients.record_node_address("node-a", b);
+        clients.record_node_address("node-b", b);
+        let report = clients.duplicates();
+        assert_eq!(report.nodes.len(), 1);
+        assert_eq!(report.nodes["node-a"], btreeset![a, b]);
+        assert_eq!(report.addresses.len(), 1);
+        assert_eq!(
+            report.addresses[&b],
+            btreeset!["node-a".to_string(), "node-b".to_string()]
+        );
+    }
+
+    #[test]
+    fn release_adoption_follows_node_reports() {
+        let clients = ClientsTable::new(false, 2).with_adoption(true);
+        clients.record_release("adopt", Some("n1"), "c1", Some("1"));
+        clients.record_release("adopt", Some("n2"), "c1", Some("1"));
+        clients.record_release("adopt", Some("n1"), "c1", Some("1"));
+        clients.record_release("adopt", Some("n1"), "c2", None);
+        clients.record_release("adopt", None, "c3", None);
+
+        let report = clients.adoption();
+        let stream = &report.streams["adopt"];
+        assert_eq!(stream.len(), 2);
+        let c1 = &stream["c1"];
+        assert_eq!(c1.version.as_deref(), Some("1"));
+        assert_eq!((c1.current_nodes, c1.reported_nodes), (1, 2));
+        let c2 = &stream["c2"];
+        assert_eq!(c2.version, None);
+        assert_eq!((c2.current_nodes, c2.reported_nodes), (1, 1));
+    }
+
+    #[test]
+    fn adoption_is_not_tracked_by_default() {
+        let clients = ClientsTable::new(false, 2);
+        clients.record_release("untracked", Some("n1"), "c1", Some("1"));
+        assert!(!clients.tracks_adoption());
+        assert!(clients.adoption().streams.is_empty());
+    }
+
+    #[test]
+    fn recent_evicts_least_recently_used() {
+        let mut recent = Recent::new(2);
+        let mut evicted = vec![];
+        recent.insert("a", 1, &mut evicted);
+        recent.insert("b", 2, &mut evicted);
+        *recent.entry("a", &mut evicted) += 10;
+        recent.insert("c", 3, &mut evicted);
+
+        assert_eq!(evicted, vec![("b", 2)]);
+        assert_eq!(recent.get(&"a"), Some(&11));
+        assert_eq!(recent.get(&"b"), None);
+        assert_eq!(recent.remove(&"c"), Some(3));
+        assert_eq!(recent.iter().count(), 1);
+    }
+
+    #[test]
+    fn node_state_is_bounded() {
+        let clients = ClientsTable::new(true, 2).with_max_nodes(2);
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        for uuid in &["n1", "n2", "n3"] {
+            clients.record_node_address(uuid, addr);
+            clients.record_response("stable", Some(uuid), 10);
+        }
+
+        let bandwidth = clients.bandwidth();
+        let nodes: Vec<&String> = bandwidth.nodes.keys().collect();
+        assert_eq!(nodes, vec!["n2", "n3"]);
+        let duplicates = clients.duplicates();
+        assert_eq!(duplicates.addresses[&addr].len(), 3);
+        assert!(duplicates.nodes.is_empty());
+    }
+}
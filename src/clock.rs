@@ -0,0 +1,55 @@
+//! Server clock, optionally frozen or offset for deterministic testing.
+
+use chrono::{DateTime, Duration, Utc};
+use std::sync::RwLock;
+
+lazy_static::lazy_static! {
+    static ref CLOCK: RwLock<Clock> = RwLock::new(Clock::Real);
+}
+
+/// Source of time for all emitted timestamps.
+#[derive(Clone, Copy, Debug)]
+pub enum Clock {
+    /// Wall-clock time.
+    Real,
+    /// Fixed point in time.
+    Frozen(DateTime<Utc>),
+    /// Wall-clock time, shifted by a fixed offset.
+    Offset(Duration),
+}
+
+/// Set the global clock.
+pub fn set(clock: Clock) {
+    let mut global = CLOCK.write().unwrap_or_else(|e| e.into_inner());
+    *global = clock;
+}
+
+/// Return current time, according to the global clock.
+pub fn now() -> DateTime<Utc> {
+    let clock = *CLOCK.read().unwrap_or_else(|e| e.into_inner());
+    match clock {
+        Clock::Real => Utc::now(),
+        Clock::Frozen(t) => t,
+        Clock::Offset(offset) => Utc::now() + offset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn frozen_and_offset_clocks() {
+        let frozen = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        set(Clock::Frozen(frozen));
+        assert_eq!(now(), frozen);
+
+        set(Clock::Offset(Duration::days(-365)));
+        let shifted = now();
+        set(Clock::Real);
+        let real = now();
+        assert!(real - shifted >= Duration::days(365));
+        assert!(real - shifted < Duration::days(366));
+    }
+}
@@ -0,0 +1,240 @@
+//! Conditional graph requests, for polling clients and caches.
+
+use crate::{clock, Graph};
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::http::header::{self, HeaderValue, HttpDate};
+use actix_web::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use failure::{format_err, Fallible};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Caching directives for graph responses, as set by a CDN in front of
+/// Cincinnati.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CachePolicy {
+    control: Option<HeaderValue>,
+    expires: Option<chrono::Duration>,
+}
+
+impl CachePolicy {
+    pub(crate) fn new(control: Option<&str>, expires: Option<Duration>) -> Fallible<Self> {
+        let control = control
+            .map(|value| {
+                HeaderValue::from_str(value.trim())
+                    .map_err(|e| format_err!("invalid Cache-Control value '{}': {}", value, e))
+            })
+            .transpose()?;
+        let expires = expires
+            .map(|lifetime| {
+                chrono::Duration::from_std(lifetime)
+                    .map_err(|e| format_err!("cache lifetime out of range: {}", e))
+            })
+            .transpose()?;
+        Ok(Self { control, expires })
+    }
+
+    /// Add `Cache-Control` and `Expires` headers, if configured.
+    pub(crate) fn apply(&self, resp: &mut HttpResponseBuilder) {
+        if let Some(ref control) = self.control {
+            resp.header(header::CACHE_CONTROL, control.clone());
+        }
+        if let Some(lifetime) = self.expires {
+            let expires = SystemTime::from(clock::now() + lifetime);
+            resp.header(header::EXPIRES, HttpDate::from(expires));
+        }
+    }
+}
+
+/// Strong entity tag for a graph response.
+///
+/// It changes whenever the stream is refreshed, and also covers the graph,
+/// as responses depend on more than cached releases (e.g. quiet hours).
+/// The graph is hashed in canonical form, as node metadata has no stable
+/// order in rendered bodies.
+pub(crate) fn etag(
+    stream: &str,
+    refreshed: Option<DateTime<Utc>>,
+    graph: &Graph,
+) -> Fallible<String> {
+    let canonical = serde_json::to_string(&serde_json::to_value(graph)?)?;
+    let mut hasher = DefaultHasher::new();
+    (
+        stream,
+        refreshed.map(|t| t.timestamp_nanos_opt()),
+        canonical,
+    )
+        .hash(&mut hasher);
+    Ok(format!("\"{:016x}\"", hasher.finish()))
+}
+
+/// Check whether the client copy is still fresh, so that a 304 can be returned.
+///
+/// As in RFC 7232, `If-Modified-Since` is only evaluated when the request
+/// has no `If-None-Match`.
+pub(crate) fn is_fresh(
+    headers: &HeaderMap,
+    etag: &str,
+    last_modified: Option<DateTime<Utc>>,
+) -> bool {
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        return none_match(headers, etag);
+    }
+    match last_modified {
+        Some(modified) => not_modified_since(headers, modified),
+        None => false,
+    }
+}
+
+/// `Last-Modified` value for a refresh time.
+pub(crate) fn last_modified(refreshed: DateTime<Utc>) -> HttpDate {
+    HttpDate::from(SystemTime::from(refreshed))
+}
+
+/// Check whether `If-None-Match` matches an entity tag.
+///
+/// Comparison is weak, as recommended for `If-None-Match`.
+fn none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Check whether nothing changed since the `If-Modified-Since` date.
+///
+/// HTTP dates have a one second resolution, so sub-second parts are ignored.
+fn not_modified_since(headers: &HeaderMap, modified: DateTime<Utc>) -> bool {
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<HttpDate>().ok())
+        .map(SystemTime::from)
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+    match since {
+        Some(since) => modified.timestamp() <= since.as_secs() as i64,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+    use chrono::TimeZone;
+
+    fn headers(pairs: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn refreshed() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 1, 12, 0, 0).unwrap() + chrono::Duration::milliseconds(500)
+    }
+
+    #[test]
+    fn etags_cover_stream_refresh_and_graph() {
+        let graph = Graph {
+            nodes: vec![],
+            edges: vec![],
+        };
+        let etag = etag("stable", Some(refreshed()), &graph).unwrap();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(
+            etag,
+            super::etag("stable", Some(refreshed()), &graph).unwrap()
+        );
+        assert_ne!(
+            etag,
+            super::etag("next", Some(refreshed()), &graph).unwrap()
+        );
+        assert_ne!(etag, super::etag("stable", None, &graph).unwrap());
+        let later = refreshed() + chrono::Duration::milliseconds(1);
+        assert_ne!(etag, super::etag("stable", Some(later), &graph).unwrap());
+        let edged = Graph {
+            nodes: vec![],
+            edges: vec![(0, 1)],
+        };
+        assert_ne!(
+            etag,
+            super::etag("stable", Some(refreshed()), &edged).unwrap()
+        );
+    }
+
+    #[test]
+    fn if_none_match_matches_etags() {
+        let etag = "\"abc\"";
+        let fresh = |value| is_fresh(&headers(&[(header::IF_NONE_MATCH, value)]), etag, None);
+        assert!(fresh("\"abc\""));
+        assert!(fresh("W/\"abc\""));
+        assert!(fresh("\"xyz\", \"abc\""));
+        assert!(fresh("*"));
+        assert!(!fresh("\"xyz\""));
+        assert!(!fresh("abc"));
+        assert!(!is_fresh(&HeaderMap::new(), etag, Some(refreshed())));
+    }
+
+    #[test]
+    fn if_modified_since_ignores_subseconds() {
+        let fresh = |value| {
+            let headers = headers(&[(header::IF_MODIFIED_SINCE, value)]);
+            is_fresh(&headers, "\"abc\"", Some(refreshed()))
+        };
+        assert!(fresh("Thu, 01 Oct 2026 12:00:00 GMT"));
+        assert!(fresh("Thu, 01 Oct 2026 13:00:00 GMT"));
+        assert!(!fresh("Thu, 01 Oct 2026 11:59:59 GMT"));
+        assert!(!fresh("yesterday"));
+
+        let no_refresh = headers(&[(header::IF_MODIFIED_SINCE, "Thu, 01 Oct 2026 12:00:00 GMT")]);
+        assert!(!is_fresh(&no_refresh, "\"abc\"", None));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let headers = headers(&[
+            (header::IF_NONE_MATCH, "\"xyz\""),
+            (header::IF_MODIFIED_SINCE, "Thu, 01 Oct 2026 13:00:00 GMT"),
+        ]);
+        assert!(!is_fresh(&headers, "\"abc\"", Some(refreshed())));
+    }
+
+    #[test]
+    fn cache_policies_set_headers() {
+        let headers = |policy: &CachePolicy| {
+            let mut resp = actix_web::HttpResponse::Ok();
+            policy.apply(&mut resp);
+            resp.finish().headers().clone()
+        };
+        let none = headers(&CachePolicy::default());
+        assert!(!none.contains_key(header::CACHE_CONTROL));
+        assert!(!none.contains_key(header::EXPIRES));
+
+        let policy = CachePolicy::new(
+            Some(" public, max-age=300 "),
+            Some(Duration::from_secs(300)),
+        )
+        .unwrap();
+        let set = headers(&policy);
+        assert_eq!(set[header::CACHE_CONTROL], "public, max-age=300");
+        let expires: HttpDate = set[header::EXPIRES].to_str().unwrap().parse().unwrap();
+        let lifetime = SystemTime::from(expires)
+            .duration_since(SystemTime::from(clock::now()))
+            .unwrap();
+        assert!(lifetime > Duration::from_secs(290) && lifetime <= Duration::from_secs(300));
+
+        assert!(CachePolicy::new(Some("no-\nstore"), None).is_err());
+    }
+
+    #[test]
+    fn last_modified_is_an_http_date() {
+        let date = last_modified(refreshed()).to_string();
+        assert_eq!(date, "Thu, 01 Oct 2026 12:00:00 GMT");
+    }
+}
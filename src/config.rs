@@ -0,0 +1,470 @@
+//! Configuration helpers.
+
+use crate::engine::HopPolicy;
+use crate::{conditional, scraper};
+use failure::{format_err, Fallible};
+use serde_derive::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::time::Duration;
+use toml::Value;
+
+/// Parse a human-readable duration (e.g. `90s`, `15m`, `2h`).
+///
+/// Plain numbers are interpreted as seconds.
+pub fn parse_duration(input: &str) -> Fallible<Duration> {
+    let input = input.trim();
+    if let Ok(secs) = input.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    humantime::parse_duration(input).map_err(|e| format_err!("invalid duration '{}': {}", input, e))
+}
+
+/// Parse a signed human-readable duration (e.g. `-15m`, `+2h`).
+pub fn parse_signed_duration(input: &str) -> Fallible<chrono::Duration> {
+    let input = input.trim();
+    let (negative, abs) = match input.chars().next() {
+        Some('-') => (true, &input[1..]),
+        Some('+') => (false, &input[1..]),
+        _ => (false, input),
+    };
+    let abs = parse_duration(abs)?;
+    let abs = chrono::Duration::from_std(abs)
+        .map_err(|e| format_err!("duration out of range '{}': {}", input, e))?;
+    if negative {
+        Ok(-abs)
+    } else {
+        Ok(abs)
+    }
+}
+
+/// Parse a per-platform delay, as `<platform>=<duration>` (e.g. `metal=48h`).
+pub fn parse_platform_delay(input: &str) -> Fallible<(String, Duration)> {
+    let (platform, delay) = input
+        .split_once('=')
+        .ok_or_else(|| format_err!("invalid platform delay '{}', missing '='", input))?;
+    if platform.is_empty() {
+        return Err(format_err!(
+            "invalid platform delay '{}', empty platform",
+            input
+        ));
+    }
+    Ok((platform.to_string(), parse_duration(delay)?))
+}
+
+/// Parse a per-stream refresh interval, as `<stream>=<duration>` (e.g. `stable=10m`).
+pub fn parse_stream_interval(input: &str) -> Fallible<(String, Duration)> {
+    let (stream, interval) = input
+        .split_once('=')
+        .ok_or_else(|| format_err!("invalid stream interval '{}', missing '='", input))?;
+    if stream.is_empty() {
+        return Err(format_err!(
+            "invalid stream interval '{}', empty stream",
+            input
+        ));
+    }
+    Ok((stream.to_string(), parse_duration(interval)?))
+}
+
+/// Parse a per-stream hop policy, as `<stream>=<policy>` (e.g. `stable=stepwise:2`).
+pub fn parse_stream_hop_policy(input: &str) -> Fallible<(String, HopPolicy)> {
+    let (stream, policy) = input
+        .split_once('=')
+        .ok_or_else(|| format_err!("invalid stream hop policy '{}', missing '='", input))?;
+    if stream.is_empty() {
+        return Err(format_err!(
+            "invalid stream hop policy '{}', empty stream",
+            input
+        ));
+    }
+    Ok((stream.to_string(), policy.parse()?))
+}
+
+/// Settings from a TOML configuration file.
+///
+/// Command-line flags take precedence over file values.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// Streams to scrape, replacing the built-in set.
+    pub streams: Option<BTreeSet<String>>,
+    /// Pause between upstream refreshes (e.g. `30s`, `5m`).
+    pub refresh_interval: Option<String>,
+    /// Port to which the server will bind.
+    pub port: Option<u16>,
+    /// Templated URL for release indexes, with a `${stream}` variable.
+    pub releases_url_template: Option<String>,
+    /// Client platforms to serve (all if unset).
+    pub allowed_platforms: Option<Vec<String>>,
+    /// Per-stream refresh intervals, overriding `refresh-interval`.
+    pub stream_refresh_intervals: Option<BTreeMap<String, String>>,
+    /// `Cache-Control` value for graph responses (e.g. `public, max-age=300` or `no-store`).
+    pub cache_control: Option<String>,
+    /// Lifetime of graph responses, advertised with `Expires` (e.g. `5m`).
+    pub cache_expires: Option<String>,
+}
+
+impl ConfigFile {
+    /// Load configuration from a TOML file, rejecting unknown keys and bad values.
+    pub fn from_path(path: &Path) -> Fallible<Self> {
+        let content = read_config(path)?;
+        let issues = lint(&content)
+            .map_err(|e| format_err!("invalid config file '{}': {}", path.display(), e))?;
+        if !issues.is_empty() {
+            let issues: Vec<String> = issues.iter().map(|i| format!("\n  {}", i)).collect();
+            return Err(format_err!(
+                "invalid config file '{}':{}",
+                path.display(),
+                issues.concat()
+            ));
+        }
+        let config = toml::from_str(&content)
+            .map_err(|e| format_err!("invalid config file '{}': {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Check a TOML file against the configuration schema, reporting all issues.
+    pub fn lint_path(path: &Path) -> Fallible<Vec<ConfigIssue>> {
+        let content = read_config(path)?;
+        lint(&content).map_err(|e| format_err!("invalid config file '{}': {}", path.display(), e))
+    }
+
+    /// Parsed refresh interval, if set.
+    pub fn refresh_interval(&self) -> Fallible<Option<Duration>> {
+        self.refresh_interval
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+    }
+
+    /// Parsed graph response lifetime, if set.
+    pub fn cache_expires(&self) -> Fallible<Option<Duration>> {
+        self.cache_expires
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+    }
+
+    /// Parsed per-stream refresh intervals.
+    pub fn stream_refresh_intervals(&self) -> Fallible<BTreeMap<String, Duration>> {
+        let mut intervals = BTreeMap::new();
+        for (stream, interval) in self.stream_refresh_intervals.iter().flatten() {
+            intervals.insert(stream.clone(), parse_duration(interval)?);
+        }
+        Ok(intervals)
+    }
+}
+
+fn read_config(path: &Path) -> Fallible<String> {
+    std::fs::read_to_string(path)
+        .map_err(|e| format_err!("failed to read config file '{}': {}", path.display(), e))
+}
+
+/// Problem with a configuration value, at its key path (e.g. `streams[1]`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub path: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Expected shape of a configuration value.
+#[derive(Clone, Copy, Debug)]
+enum Schema {
+    Duration,
+    Port,
+    UrlTemplate,
+    CacheControl,
+    /// Array of non-empty strings, optionally without duplicates.
+    Strings {
+        unique: bool,
+    },
+    /// Table of durations, keyed by stream.
+    Durations,
+}
+
+/// Known top-level keys, matching `ConfigFile` fields.
+static SCHEMA: [(&str, Schema); 8] = [
+    ("streams", Schema::Strings { unique: true }),
+    ("refresh-interval", Schema::Duration),
+    ("port", Schema::Port),
+    ("releases-url-template", Schema::UrlTemplate),
+    ("allowed-platforms", Schema::Strings { unique: false }),
+    ("stream-refresh-intervals", Schema::Durations),
+    ("cache-control", Schema::CacheControl),
+    ("cache-expires", Schema::Duration),
+];
+
+/// Check TOML configuration against the schema.
+///
+/// It fails on TOML syntax errors, and otherwise returns every unknown key
+/// and bad value found.
+pub fn lint(content: &str) -> Fallible<Vec<ConfigIssue>> {
+    let root: toml::value::Table = toml::from_str(content)?;
+    let mut issues = vec![];
+    for (key, value) in &root {
+        match SCHEMA.iter().find(|(name, _)| name == key) {
+            Some((_, schema)) => check_value(key, value, *schema, &mut issues),
+            None => issues.push(ConfigIssue {
+                path: key.clone(),
+                message: "unknown key".to_string(),
+            }),
+        }
+    }
+    Ok(issues)
+}
+
+fn check_value(path: &str, value: &Value, schema: Schema, issues: &mut Vec<ConfigIssue>) {
+    fn issue(issues: &mut Vec<ConfigIssue>, path: &str, message: String) {
+        issues.push(ConfigIssue {
+            path: path.to_string(),
+            message,
+        })
+    }
+    let expected = match (schema, value) {
+        (Schema::Duration, Value::String(s)) => {
+            if let Err(e) = parse_duration(s) {
+                issue(issues, path, e.to_string());
+            }
+            return;
+        }
+        (Schema::Port, Value::Integer(port)) => {
+            if u16::try_from(*port).is_err() {
+                issue(issues, path, format!("port {} out of range", port));
+            }
+            return;
+        }
+        (Schema::UrlTemplate, Value::String(template)) => {
+            if let Err(e) = scraper::releases_url(Some(template), "stable".to_string()) {
+                issue(
+                    issues,
+                    path,
+                    format!("invalid releases URL template '{}': {}", template, e),
+                );
+            }
+            return;
+        }
+        (Schema::CacheControl, Value::String(control)) => {
+            if let Err(e) = conditional::CachePolicy::new(Some(control), None) {
+                issue(issues, path, e.to_string());
+            }
+            return;
+        }
+        (Schema::Strings { unique }, Value::Array(items)) => {
+            let mut seen = BTreeSet::new();
+            for (index, item) in items.iter().enumerate() {
+                let item_path = format!("{}[{}]", path, index);
+                match item.as_str() {
+                    Some("") => issue(issues, &item_path, "empty string".to_string()),
+                    Some(s) if unique && !seen.insert(s) => {
+                        issue(issues, &item_path, format!("duplicate value '{}'", s))
+                    }
+                    Some(_) => {}
+                    None => issue(
+                        issues,
+                        &item_path,
+                        format!("expected a string, found {}", item.type_str()),
+                    ),
+                }
+            }
+            return;
+        }
+        (Schema::Durations, Value::Table(table)) => {
+            for (stream, interval) in table {
+                let item_path = format!("{}.{}", path, stream);
+                check_value(&item_path, interval, Schema::Duration, issues);
+            }
+            return;
+        }
+        (Schema::Duration, _) | (Schema::UrlTemplate, _) | (Schema::CacheControl, _) => "a string",
+        (Schema::Port, _) => "an integer",
+        (Schema::Strings { .. }, _) => "an array of strings",
+        (Schema::Durations, _) => "a table of durations",
+    };
+    issue(
+        issues,
+        path,
+        format!("expected {}, found {}", expected, value.type_str()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_parse() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration(" 90s ").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("1h 30m").unwrap(), Duration::from_secs(5400));
+        for invalid in &["", "-5", "5x", "soon"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+
+        assert_eq!(
+            parse_signed_duration("-15m").unwrap(),
+            chrono::Duration::minutes(-15)
+        );
+        assert_eq!(
+            parse_signed_duration("+2h").unwrap(),
+            chrono::Duration::hours(2)
+        );
+        assert_eq!(
+            parse_signed_duration("30s").unwrap(),
+            chrono::Duration::seconds(30)
+        );
+        assert!(parse_signed_duration("--1h").is_err());
+    }
+
+    #[test]
+    fn platform_delays_parse() {
+        let (platform, delay) = parse_platform_delay("metal=48h").unwrap();
+        assert_eq!(platform, "metal");
+        assert_eq!(delay, Duration::from_secs(48 * 3600));
+        assert!(parse_platform_delay("metal").is_err());
+        assert!(parse_platform_delay("=1h").is_err());
+        assert!(parse_platform_delay("metal=soon").is_err());
+    }
+
+    #[test]
+    fn stream_settings_parse() {
+        assert_eq!(
+            parse_stream_interval("stable=10m").unwrap(),
+            ("stable".to_string(), Duration::from_secs(600))
+        );
+        assert!(parse_stream_interval("stable").is_err());
+        assert!(parse_stream_interval("=10m").is_err());
+        assert!(parse_stream_interval("stable=soon").is_err());
+
+        let config: ConfigFile = toml::from_str(
+            r#"
+            [stream-refresh-intervals]
+            stable = "10m"
+            next = "90"
+            "#,
+        )
+        .unwrap();
+        let intervals = config.stream_refresh_intervals().unwrap();
+        assert_eq!(intervals["stable"], Duration::from_secs(600));
+        assert_eq!(intervals["next"], Duration::from_secs(90));
+    }
+
+    #[test]
+    fn hop_policies_parse() {
+        assert_eq!(
+            parse_stream_hop_policy("stable=latest").unwrap(),
+            ("stable".to_string(), HopPolicy::Latest)
+        );
+        assert_eq!(
+            parse_stream_hop_policy("next=stepwise:2").unwrap(),
+            ("next".to_string(), HopPolicy::Stepwise(2))
+        );
+        for invalid in &[
+            "stable",
+            "=latest",
+            "stable=newest",
+            "stable=stepwise:",
+            "stable=stepwise:-1",
+        ] {
+            assert!(parse_stream_hop_policy(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn cache_settings_parse() {
+        let config: ConfigFile =
+            toml::from_str("cache-control = \"public, max-age=300\"\ncache-expires = \"5m\"\n")
+                .unwrap();
+        assert_eq!(config.cache_control.as_deref(), Some("public, max-age=300"));
+        assert_eq!(
+            config.cache_expires().unwrap(),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(ConfigFile::default().cache_expires().unwrap(), None);
+
+        let config: ConfigFile = toml::from_str("cache-expires = \"soon\"\n").unwrap();
+        assert!(config.cache_expires().is_err());
+    }
+
+    #[test]
+    fn config_files_parse() {
+        let path = std::env::temp_dir().join(format!("fakeup-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "streams = [\"next\"]\nrefresh-interval = \"5m\"\nport = 8080\n",
+        )
+        .unwrap();
+        let config = ConfigFile::from_path(&path).unwrap();
+        assert_eq!(config.streams, Some(maplit::btreeset!["next".to_string()]));
+        assert_eq!(config.port, Some(8080));
+        assert_eq!(
+            config.refresh_interval().unwrap(),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(config.releases_url_template, None);
+
+        std::fs::write(&path, "streams = [\"next\"]\nrefresh = \"5m\"\n").unwrap();
+        let err = ConfigFile::from_path(&path).unwrap_err();
+        assert!(err.to_string().contains("refresh"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn paths(content: &str) -> Vec<String> {
+        lint(content)
+            .unwrap()
+            .into_iter()
+            .map(|issue| issue.path)
+            .collect()
+    }
+
+    #[test]
+    fn lint_accepts_valid_config() {
+        let content = r#"
+            streams = ["stable", "next"]
+            refresh-interval = "90s"
+            port = 9876
+            cache-control = "public, max-age=300"
+
+            [stream-refresh-intervals]
+            stable = "10m"
+        "#;
+        assert!(lint(content).unwrap().is_empty());
+        assert!(lint("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn lint_reports_issue_paths() {
+        let content = r#"
+            streams = ["stable", "", "stable", 3]
+            refresh-intervall = "30s"
+            refresh-interval = "30x"
+            port = 70000
+            cache-expires = 5
+
+            [stream-refresh-intervals]
+            stable = "10m"
+            next = "soon"
+        "#;
+        assert_eq!(
+            paths(content),
+            vec![
+                "cache-expires",
+                "port",
+                "refresh-interval",
+                "refresh-intervall",
+                "stream-refresh-intervals.next",
+                "streams[1]",
+                "streams[2]",
+                "streams[3]",
+            ]
+        );
+        assert!(lint("port = [").is_err());
+    }
+}
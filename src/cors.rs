@@ -0,0 +1,118 @@
+//! Cross-origin requests, for browser-based dashboards and test tools.
+
+use actix_web::http::{header, Method};
+use actix_web::middleware::cors::Cors;
+use failure::{bail, format_err, Fallible};
+
+/// Wildcard origin, allowing any site.
+pub(crate) static ANY_ORIGIN: &str = "*";
+
+/// Preflight cache lifetime, in seconds.
+static MAX_AGE_SECS: usize = 3600;
+
+/// Parse an allowed origin, as `<scheme>://<host>[:<port>]` or `*`.
+pub(crate) fn parse_origin(input: &str) -> Fallible<String> {
+    let input = input.trim();
+    if input == ANY_ORIGIN {
+        return Ok(input.to_string());
+    }
+    let url =
+        url::Url::parse(input).map_err(|e| format_err!("invalid origin '{}': {}", input, e))?;
+    let bare = url.path() == "/" && url.query().is_none() && url.fragment().is_none();
+    if !bare || !url.origin().is_tuple() {
+        bail!(
+            "invalid origin '{}', expected '<scheme>://<host>[:<port>]'",
+            input
+        );
+    }
+    Ok(url.origin().ascii_serialization())
+}
+
+/// Build the CORS middleware, for read-only access from these origins.
+///
+/// Requests from other origins are rejected.
+pub(crate) fn middleware(origins: &[String]) -> Cors {
+    let mut cors = Cors::build();
+    cors.allowed_methods(vec![Method::GET])
+        .allowed_headers(vec![header::ACCEPT, header::AUTHORIZATION])
+        .allowed_header(crate::query::STREAM_OVERRIDE_HEADER)
+        .expose_headers(vec!["ETag", "X-Fakeup-Stale", "X-Fakeup-Quiet-Hours"])
+        .max_age(MAX_AGE_SECS);
+    if origins.iter().any(|o| o == ANY_ORIGIN) {
+        cors.send_wildcard();
+    } else {
+        for origin in origins {
+            cors.allowed_origin(origin);
+        }
+    }
+    cors.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_state;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestServer;
+    use actix_web::{App, HttpMessage};
+
+    #[test]
+    fn origins_parse() {
+        assert_eq!(parse_origin(" * ").unwrap(), "*");
+        assert_eq!(
+            parse_origin("https://dash.example.com").unwrap(),
+            "https://dash.example.com"
+        );
+        assert_eq!(
+            parse_origin("HTTP://Example.com:8080/").unwrap(),
+            "http://example.com:8080"
+        );
+        for invalid in &[
+            "",
+            "example.com",
+            "https://example.com/path",
+            "https://example.com/?q=1",
+            "data:text/plain,hi",
+        ] {
+            assert!(parse_origin(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    /// Status and allowed origin of a version request from `origin`.
+    fn request(origins: &[&str], origin: &str) -> (StatusCode, Option<String>) {
+        let origins: Vec<String> = origins.iter().map(|o| o.to_string()).collect();
+        let mut srv = TestServer::with_factory(move || {
+            App::with_state(test_state())
+                .middleware(middleware(&origins))
+                .configure(crate::router::register)
+        });
+        let req = srv
+            .get()
+            .uri(srv.url("/fakeup/v1/version"))
+            .header(header::ORIGIN, origin)
+            .finish()
+            .unwrap();
+        let resp = srv.execute(req.send()).unwrap();
+        let allowed = resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|v| v.to_str().unwrap().to_string());
+        (resp.status(), allowed)
+    }
+
+    #[test]
+    fn only_allowed_origins_are_served() {
+        let dash = "https://dash.example.com";
+        let (status, allowed) = request(&[dash], dash);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(allowed.as_deref(), Some(dash));
+
+        let (status, allowed) = request(&[dash], "https://evil.example.com");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(allowed, None);
+
+        let (status, allowed) = request(&[ANY_ORIGIN], "https://evil.example.com");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(allowed.as_deref(), Some("*"));
+    }
+}
@@ -0,0 +1,122 @@
+//! Classic daemon support: detaching, output redirection and pidfiles.
+
+use failure::{bail, format_err, Fallible};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Detach from the controlling terminal, continuing in a child process.
+///
+/// Standard input is read from `/dev/null`, and standard output and error
+/// are discarded to it too, unless kept (as already redirected to files).
+///
+/// This must run before any thread is spawned.
+pub fn daemonize(keep_stdout: bool, keep_stderr: bool) -> Fallible<()> {
+    match unsafe { libc::fork() } {
+        -1 => bail!("fork failed: {}", std::io::Error::last_os_error()),
+        0 => {}
+        _ => std::process::exit(0),
+    }
+    if unsafe { libc::setsid() } == -1 {
+        bail!("setsid failed: {}", std::io::Error::last_os_error());
+    }
+
+    let devnull = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    dup_onto(&devnull, libc::STDIN_FILENO)?;
+    if !keep_stdout {
+        dup_onto(&devnull, libc::STDOUT_FILENO)?;
+    }
+    if !keep_stderr {
+        dup_onto(&devnull, libc::STDERR_FILENO)?;
+    }
+    Ok(())
+}
+
+/// Redirect standard output and error to files (appending).
+pub fn redirect_output(stdout: Option<&Path>, stderr: Option<&Path>) -> Fallible<()> {
+    for (path, fd) in [(stdout, libc::STDOUT_FILENO), (stderr, libc::STDERR_FILENO)] {
+        let path = match path {
+            Some(p) => p,
+            None => continue,
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format_err!("failed to open '{}': {}", path.display(), e))?;
+        dup_onto(&file, fd)?;
+    }
+    Ok(())
+}
+
+fn dup_onto(file: &std::fs::File, fd: libc::c_int) -> Fallible<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+        bail!("dup2 failed: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Pidfile for the current process, removed on drop.
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Write the current PID, refusing to clobber a pidfile of a live process.
+    pub fn create(path: &Path) -> Fallible<Self> {
+        if let Ok(content) = std::fs::read_to_string(path) {
+            if let Ok(pid) = content.trim().parse::<libc::pid_t>() {
+                if pid > 0 && unsafe { libc::kill(pid, 0) } == 0 {
+                    bail!(
+                        "pidfile '{}' belongs to running process {}",
+                        path.display(),
+                        pid
+                    );
+                }
+            }
+        }
+
+        let mut file = std::fs::File::create(path)
+            .map_err(|e| format_err!("failed to create pidfile '{}': {}", path.display(), e))?;
+        writeln!(file, "{}", std::process::id())?;
+        let pidfile = Self {
+            path: path.to_path_buf(),
+        };
+        Ok(pidfile)
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("failed to remove pidfile '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pidfile_lifecycle() {
+        let path = std::env::temp_dir().join(format!("fakeup-pidfile-{}", std::process::id()));
+
+        // Stale pidfiles are overwritten.
+        std::fs::write(&path, "999999999\n").unwrap();
+        let pidfile = Pidfile::create(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.trim(), std::process::id().to_string());
+
+        // Live ones are not.
+        assert!(Pidfile::create(&path).is_err());
+
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,87 @@
+//! Debugging endpoints.
+
+use crate::clients::DuplicatesReport;
+use crate::query::GraphQuery;
+use crate::{redact, AppState};
+use actix_web::{http::Method, HttpRequest, HttpResponse, Scope};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+
+/// Register debugging routes.
+pub(crate) fn register(scope: Scope<AppState>) -> Scope<AppState> {
+    scope.route("/echo", Method::GET, serve_echo).route(
+        "/duplicates",
+        Method::GET,
+        serve_duplicates,
+    )
+}
+
+/// Echo back how a graph request would be interpreted.
+pub(crate) fn serve_echo(req: HttpRequest<AppState>) -> HttpResponse {
+    match GraphQuery::from_request(&req) {
+        Ok(gq) => HttpResponse::Ok().json(gq),
+        Err(e) => e.response(),
+    }
+}
+
+/// List node UUIDs seen from multiple addresses, and vice versa.
+///
+/// Node UUIDs are redacted as in logs; masked UUIDs are merged together.
+pub(crate) fn serve_duplicates(req: HttpRequest<AppState>) -> HttpResponse {
+    let report = req.state().clients.duplicates();
+    let mut nodes: BTreeMap<String, BTreeSet<IpAddr>> = BTreeMap::new();
+    for (uuid, addrs) in report.nodes {
+        nodes
+            .entry(redact::param("node_uuid", &uuid))
+            .or_default()
+            .extend(addrs);
+    }
+    let addresses = report
+        .addresses
+        .into_iter()
+        .map(|(addr, uuids)| {
+            let uuids = uuids
+                .iter()
+                .map(|uuid| redact::param("node_uuid", uuid))
+                .collect();
+            (addr, uuids)
+        })
+        .collect();
+    HttpResponse::Ok().json(DuplicatesReport { nodes, addresses })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_state;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn duplicates_are_redacted() {
+        let redactor = redact::Redactor::new(vec!["node_uuid".to_string()], Default::default());
+        redact::set(redactor);
+        let state = test_state();
+        for addr in &["192.0.2.1", "192.0.2.2"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            state.clients.record_node_address("dup-node", addr);
+        }
+        let shared: IpAddr = "192.0.2.1".parse().unwrap();
+        state.clients.record_node_address("dup-other", shared);
+
+        let req = TestRequest::with_state(state).finish();
+        let resp = serve_duplicates(req);
+        let body = match resp.body() {
+            actix_web::Body::Binary(body) => body.as_ref().to_vec(),
+            body => panic!("unexpected body: {:?}", body),
+        };
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let hashed = redact::param("node_uuid", "dup-node");
+        assert_ne!(hashed, "dup-node");
+        assert_eq!(report["nodes"][&hashed].as_array().unwrap().len(), 2);
+        assert_eq!(
+            report["addresses"]["192.0.2.1"].as_array().unwrap().len(),
+            2
+        );
+        assert!(!String::from_utf8(body).unwrap().contains("dup-"));
+    }
+}
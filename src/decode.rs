@@ -0,0 +1,78 @@
+//! Decoding of upstream documents, possibly compressed.
+//!
+//! Compression is detected from magic bytes, so that compressed documents
+//! are handled even when served without a `Content-Encoding` (e.g. static
+//! `.json.gz` files).
+
+use failure::{bail, Fallible};
+use std::borrow::Cow;
+use std::io::Read;
+
+static GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+static ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Upper bound on the size of a decompressed document.
+static MAX_DECODED_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Decompress a document body, if compressed.
+pub(crate) fn decompress(body: &[u8]) -> Fallible<Cow<'_, [u8]>> {
+    let reader: Box<dyn Read + '_> = if body.starts_with(GZIP_MAGIC) {
+        Box::new(flate2::read::GzDecoder::new(body))
+    } else if body.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::stream::read::Decoder::new(body)?)
+    } else {
+        return Ok(Cow::Borrowed(body));
+    };
+
+    let mut decoded = Vec::new();
+    reader
+        .take(MAX_DECODED_SIZE + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() as u64 > MAX_DECODED_SIZE {
+        bail!("decompressed document exceeds {} bytes", MAX_DECODED_SIZE);
+    }
+    Ok(Cow::Owned(decoded))
+}
+
+/// Parse a JSON document body, decompressing it if needed.
+pub(crate) fn json<T: serde::de::DeserializeOwned>(body: &[u8]) -> Fallible<T> {
+    let body = decompress(body)?;
+    let value = serde_json::from_slice(&body)?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    static DOC: &[u8] = br#"{"releases": []}"#;
+
+    #[test]
+    fn plain_documents_are_borrowed() {
+        match decompress(DOC).unwrap() {
+            Cow::Borrowed(body) => assert_eq!(body, DOC),
+            Cow::Owned(_) => panic!("plain document was copied"),
+        }
+    }
+
+    #[test]
+    fn compressed_documents_are_decoded() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(DOC).unwrap();
+        let gz = gz.finish().unwrap();
+        assert_eq!(&*decompress(&gz).unwrap(), DOC);
+
+        let zst = zstd::stream::encode_all(DOC, 0).unwrap();
+        assert_eq!(&*decompress(&zst).unwrap(), DOC);
+
+        let value: serde_json::Value = json(&zst).unwrap();
+        assert!(value["releases"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn corrupt_documents_are_rejected() {
+        assert!(decompress(&[0x1f, 0x8b, 0x00, 0x01]).is_err());
+        assert!(decompress(&[0x28, 0xb5, 0x2f, 0xfd, 0xff]).is_err());
+    }
+}
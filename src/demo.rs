@@ -0,0 +1,228 @@
+//! Self-contained demo, with bundled data and a scripted scenario.
+//!
+//! The server reads synthetic release indexes from a scratch fixtures
+//! directory, which a background thread rewrites along the scenario:
+//! a new release after two minutes, then another one which pushes the
+//! oldest release past the dead-end limit. Nothing goes over the network.
+
+use crate::{metadata, synthetic, ServeOptions};
+use failure::{format_err, Fallible};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Stream served by the demo.
+static DEMO_STREAM: &str = "stable";
+
+/// Architecture served by the demo.
+static DEMO_ARCH: &str = "x86_64";
+
+/// Releases initially in the demo stream.
+static INITIAL_RELEASES: usize = 2;
+
+/// Delay between scenario steps.
+static STEP_DELAY: Duration = Duration::from_secs(120);
+
+/// Refresh interval, short enough for scenario steps to show up quickly.
+static DEMO_REFRESH: &str = "10s";
+
+/// Releases behind latest past which a release is dead-ended.
+static DEADEND_BEHIND: usize = 2;
+
+/// Demo setup: serve options and scratch fixtures directory.
+#[derive(Debug)]
+pub(crate) struct Demo {
+    dir: PathBuf,
+    port: u16,
+}
+
+impl Demo {
+    /// Prepare the fixtures directory, with the initial releases.
+    pub(crate) fn new(port: u16) -> Fallible<Self> {
+        let dir = std::env::temp_dir().join(format!("fakeup-demo-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format_err!("failed to create '{}': {}", dir.display(), e))?;
+        let demo = Self { dir, port };
+        write_releases(&demo.dir, INITIAL_RELEASES)?;
+        Ok(demo)
+    }
+
+    /// Options to serve the demo fixtures.
+    pub(crate) fn serve_options(&self) -> Fallible<ServeOptions> {
+        let args = vec![
+            "serve".to_string(),
+            format!("--port={}", self.port),
+            format!("--stream={}", DEMO_STREAM),
+            format!("--fixtures-dir={}", self.dir.display()),
+            format!("--refresh-interval={}", DEMO_REFRESH),
+            format!("--auto-deadend-behind={}={}", DEMO_STREAM, DEADEND_BEHIND),
+        ];
+        let opts = ServeOptions::from_iter_safe(args).map_err(|e| format_err!("{}", e))?;
+        Ok(opts)
+    }
+
+    /// Explain the setup, and run the scenario in the background.
+    pub(crate) fn start_scenario(&self) -> Fallible<()> {
+        let releases = demo_releases(INITIAL_RELEASES);
+        log::info!(
+            "demo: serving stream '{}' with releases {}, refreshed every {}",
+            DEMO_STREAM,
+            versions(&releases),
+            DEMO_REFRESH
+        );
+        log::info!(
+            "demo: a client on the oldest release is offered the latest one: curl '{}'",
+            self.graph_url(&releases[0])
+        );
+        log::info!(
+            "demo: a client on the latest release gets no update edge: curl '{}'",
+            self.graph_url(&releases[releases.len() - 1])
+        );
+
+        let dir = self.dir.clone();
+        let base = self.graph_base();
+        std::thread::Builder::new()
+            .name("fakeup-demo".to_string())
+            .spawn(move || {
+                if let Err(e) = run_scenario(&dir, &base) {
+                    log::error!("demo: scenario failed: {}", e);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Remove the fixtures directory.
+    pub(crate) fn cleanup(&self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            log::warn!("failed to remove '{}': {}", self.dir.display(), e);
+        }
+    }
+
+    fn graph_base(&self) -> String {
+        format!(
+            "http://localhost:{}/v1/graph?stream={}&basearch={}",
+            self.port, DEMO_STREAM, DEMO_ARCH
+        )
+    }
+
+    fn graph_url(&self, release: &metadata::Release) -> String {
+        format!("{}&os_checksum={}", self.graph_base(), checksum(release))
+    }
+}
+
+/// Publish scenario releases, one step at a time.
+fn run_scenario(dir: &Path, base: &str) -> Fallible<()> {
+    log::info!(
+        "demo: a new release will be published in {}s",
+        STEP_DELAY.as_secs()
+    );
+    std::thread::sleep(STEP_DELAY);
+    write_releases(dir, INITIAL_RELEASES + 1)?;
+    let releases = demo_releases(INITIAL_RELEASES + 1);
+    let newest = &releases[releases.len() - 1];
+    log::info!(
+        "demo: release {} published; clients pick it up on the next refresh (within {}), \
+         previous ones are now offered it: curl '{}&os_checksum={}'",
+        newest.version,
+        DEMO_REFRESH,
+        base,
+        checksum(&releases[releases.len() - 2])
+    );
+
+    log::info!(
+        "demo: another release will be published in {}s, dead-ending the oldest one",
+        STEP_DELAY.as_secs()
+    );
+    std::thread::sleep(STEP_DELAY);
+    write_releases(dir, INITIAL_RELEASES + 2)?;
+    let releases = demo_releases(INITIAL_RELEASES + 2);
+    log::info!(
+        "demo: release {} published; release {} is more than {} releases behind and \
+         now marked as a dead-end in its node metadata: curl '{}&os_checksum={}'",
+        releases[releases.len() - 1].version,
+        releases[0].version,
+        DEADEND_BEHIND,
+        base,
+        checksum(&releases[0])
+    );
+    log::info!("demo: scenario complete, the server keeps running until interrupted");
+    Ok(())
+}
+
+/// First `count` synthetic releases of the demo stream, oldest first.
+fn demo_releases(count: usize) -> Vec<metadata::Release> {
+    let streams: BTreeSet<String> = std::iter::once(DEMO_STREAM.to_string()).collect();
+    synthetic::generate(&streams, count, &[DEMO_ARCH.to_string()])
+        .remove(DEMO_STREAM)
+        .unwrap_or_default()
+}
+
+/// Write the release index with the first `count` releases, atomically.
+fn write_releases(dir: &Path, count: usize) -> Fallible<()> {
+    let index = metadata::ReleasesJSON {
+        releases: demo_releases(count),
+    };
+    let path = dir.join(format!("releases-{}.json", DEMO_STREAM));
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&index)?)?;
+    std::fs::rename(&tmp, &path)
+        .map_err(|e| format_err!("failed to write '{}': {}", path.display(), e))?;
+    Ok(())
+}
+
+fn versions(releases: &[metadata::Release]) -> String {
+    let versions: Vec<&str> = releases.iter().map(|r| r.version.as_str()).collect();
+    versions.join(", ")
+}
+
+fn checksum(release: &metadata::Release) -> &str {
+    release
+        .commits
+        .first()
+        .map(|c| c.checksum.as_str())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scenario_steps_extend_the_stream() {
+        let initial = demo_releases(INITIAL_RELEASES);
+        let next = demo_releases(INITIAL_RELEASES + 1);
+        assert_eq!(initial.len(), INITIAL_RELEASES);
+        assert_eq!(next.len(), INITIAL_RELEASES + 1);
+        for (old, new) in initial.iter().zip(&next) {
+            assert_eq!(old.version, new.version);
+            assert_eq!(checksum(old), checksum(new));
+        }
+        assert!(!checksum(&next[INITIAL_RELEASES]).is_empty());
+    }
+
+    #[test]
+    fn demo_serves_its_fixtures() {
+        let demo = Demo::new(9999).unwrap();
+        let path = demo.dir.join(format!("releases-{}.json", DEMO_STREAM));
+        let index = metadata::ReleasesJSON::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(versions(&index.releases), versions(&demo_releases(2)));
+
+        write_releases(&demo.dir, INITIAL_RELEASES + 1).unwrap();
+        let files: Vec<_> = std::fs::read_dir(&demo.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec![path.file_name().unwrap().to_owned()]);
+
+        let opts = demo.serve_options().unwrap();
+        assert_eq!(opts.port, Some(9999));
+        assert_eq!(opts.fixtures_dir.as_ref(), Some(&demo.dir));
+        assert!(demo.graph_url(&index.releases[0]).starts_with(
+            "http://localhost:9999/v1/graph?stream=stable&basearch=x86_64&os_checksum="
+        ));
+
+        demo.cleanup();
+        assert!(!demo.dir.exists());
+    }
+}
@@ -0,0 +1,199 @@
+//! Semantic diff between scraped release indexes.
+
+use crate::metadata::Release;
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+/// Single change between two release indexes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub(crate) enum ReleaseChange {
+    /// New release.
+    Added { version: String },
+    /// Release no longer listed.
+    Removed { version: String },
+    /// New architecture for an existing release.
+    ArchAdded {
+        version: String,
+        architecture: String,
+    },
+    /// Architecture no longer listed for an existing release.
+    ArchRemoved {
+        version: String,
+        architecture: String,
+    },
+    /// Different payload checksum for an existing release and architecture.
+    ChecksumChanged {
+        version: String,
+        architecture: String,
+        old: String,
+        new: String,
+    },
+}
+
+impl std::fmt::Display for ReleaseChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReleaseChange::Added { version } => write!(f, "release '{}' added", version),
+            ReleaseChange::Removed { version } => write!(f, "release '{}' removed", version),
+            ReleaseChange::ArchAdded {
+                version,
+                architecture,
+            } => write!(f, "release '{}' gained arch '{}'", version, architecture),
+            ReleaseChange::ArchRemoved {
+                version,
+                architecture,
+            } => write!(f, "release '{}' lost arch '{}'", version, architecture),
+            ReleaseChange::ChecksumChanged {
+                version,
+                architecture,
+                old,
+                new,
+            } => write!(
+                f,
+                "release '{}' ({}) checksum changed from '{}' to '{}'",
+                version, architecture, old, new
+            ),
+        }
+    }
+}
+
+/// Change recorded in the scrape changelog.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ChangelogEntry {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) stream: String,
+    #[serde(flatten)]
+    pub(crate) change: ReleaseChange,
+}
+
+/// Compute changes from an old release index to a new one.
+pub(crate) fn diff_releases(old: &[Release], new: &[Release]) -> Vec<ReleaseChange> {
+    let mut changes = Vec::new();
+    let old_by_version: BTreeMap<&str, &Release> =
+        old.iter().map(|r| (r.version.as_str(), r)).collect();
+    let new_by_version: BTreeMap<&str, &Release> =
+        new.iter().map(|r| (r.version.as_str(), r)).collect();
+
+    for release in new {
+        let version = release.version.clone();
+        let previous = match old_by_version.get(release.version.as_str()) {
+            Some(p) => p,
+            None => {
+                changes.push(ReleaseChange::Added { version });
+                continue;
+            }
+        };
+
+        let old_commits = commits_by_arch(previous);
+        let new_commits = commits_by_arch(release);
+        for (arch, checksum) in &new_commits {
+            match old_commits.get(arch) {
+                None => changes.push(ReleaseChange::ArchAdded {
+                    version: version.clone(),
+                    architecture: arch.to_string(),
+                }),
+                Some(old) if old != checksum => changes.push(ReleaseChange::ChecksumChanged {
+                    version: version.clone(),
+                    architecture: arch.to_string(),
+                    old: old.to_string(),
+                    new: checksum.to_string(),
+                }),
+                Some(_) => {}
+            }
+        }
+        for arch in old_commits.keys() {
+            if !new_commits.contains_key(arch) {
+                changes.push(ReleaseChange::ArchRemoved {
+                    version: version.clone(),
+                    architecture: arch.to_string(),
+                });
+            }
+        }
+    }
+
+    for release in old {
+        if !new_by_version.contains_key(release.version.as_str()) {
+            changes.push(ReleaseChange::Removed {
+                version: release.version.clone(),
+            });
+        }
+    }
+    changes
+}
+
+/// Map architectures to payload checksums for a release.
+fn commits_by_arch(release: &Release) -> BTreeMap<&str, &str> {
+    release
+        .commits
+        .iter()
+        .map(|c| (c.architecture.as_str(), c.checksum.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::ReleaseCommit;
+
+    fn release(version: &str, commits: &[(&str, &str)]) -> Release {
+        Release {
+            commits: commits
+                .iter()
+                .map(|(arch, checksum)| ReleaseCommit {
+                    architecture: arch.to_string(),
+                    checksum: checksum.to_string(),
+                    size: None,
+                })
+                .collect(),
+            version: version.to_string(),
+            metadata: String::new(),
+        }
+    }
+
+    #[test]
+    fn identical_indexes_have_no_changes() {
+        let index = vec![release("30.1", &[("x86_64", "x1")])];
+        assert!(diff_releases(&index, &index).is_empty());
+    }
+
+    #[test]
+    fn release_and_arch_changes_are_detected() {
+        let old = vec![
+            release("30.1", &[("x86_64", "x1"), ("s390x", "s1")]),
+            release("30.2", &[("x86_64", "x2")]),
+        ];
+        let new = vec![
+            release("30.1", &[("x86_64", "x1-respin"), ("aarch64", "a1")]),
+            release("30.3", &[("x86_64", "x3")]),
+        ];
+        let changes = diff_releases(&old, &new);
+        assert_eq!(
+            changes,
+            vec![
+                ReleaseChange::ArchAdded {
+                    version: "30.1".to_string(),
+                    architecture: "aarch64".to_string(),
+                },
+                ReleaseChange::ChecksumChanged {
+                    version: "30.1".to_string(),
+                    architecture: "x86_64".to_string(),
+                    old: "x1".to_string(),
+                    new: "x1-respin".to_string(),
+                },
+                ReleaseChange::ArchRemoved {
+                    version: "30.1".to_string(),
+                    architecture: "s390x".to_string(),
+                },
+                ReleaseChange::Added {
+                    version: "30.3".to_string(),
+                },
+                ReleaseChange::Removed {
+                    version: "30.2".to_string(),
+                },
+            ]
+        );
+        assert_eq!(changes[3].to_string(), "release '30.3' added");
+    }
+}
@@ -0,0 +1,132 @@
+//! One-shot scrapes, without starting a server.
+
+use crate::decode;
+use crate::engine;
+use crate::fetcher;
+use crate::metadata;
+use crate::scraper;
+use crate::Graph;
+use failure::{bail, format_err, Fallible};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Fetch the release index of a stream.
+fn fetch_releases(template: Option<&str>, stream: &str) -> Fallible<Vec<metadata::Release>> {
+    let url = scraper::releases_url(template, stream.to_string())?;
+    let body = fetcher::get_blocking(&url)?;
+    let index = metadata::ReleasesJSON::from_slice(&decode::decompress(&body)?)?;
+    Ok(index.releases)
+}
+
+/// Scrape streams once and return their latest release (`None` if empty).
+///
+/// Failing streams are logged and skipped; it fails if all streams failed.
+pub(crate) fn fetch_latest(
+    template: Option<&str>,
+    streams: &BTreeSet<String>,
+) -> Fallible<BTreeMap<String, Option<metadata::Release>>> {
+    let mut latest = BTreeMap::new();
+    for stream in streams {
+        match fetch_releases(template, stream) {
+            Ok(mut releases) => {
+                latest.insert(stream.clone(), releases.pop());
+            }
+            Err(e) => log::error!("failed to scrape stream '{}': {}", stream, e),
+        }
+    }
+    if latest.is_empty() && !streams.is_empty() {
+        bail!("failed to scrape all streams");
+    }
+    Ok(latest)
+}
+
+/// Scrape a stream once and return the graph for its latest payload.
+pub(crate) fn dump_graph(template: Option<&str>, stream: &str, basearch: &str) -> Fallible<Graph> {
+    let releases = fetch_releases(template, stream)?;
+
+    // As when serving, skip releases which lack a payload for `basearch`.
+    let node = releases
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(age_index, rel)| engine::release_node(age_index, rel, basearch))
+        .ok_or_else(|| {
+            format_err!(
+                "stream '{}' has no releases for basearch '{}'",
+                stream,
+                basearch
+            )
+        })?;
+
+    let graph = Graph {
+        nodes: vec![node],
+        edges: vec![],
+    };
+    Ok(graph)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// Serve a single HTTP response with the given body.
+    fn serve_once(body: &'static str) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = conn.read(&mut buf).unwrap();
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            conn.write_all(resp.as_bytes()).unwrap();
+        });
+        format!("http://{}/${{stream}}.json", addr)
+    }
+
+    #[test]
+    fn latest_release_is_dumped() {
+        let template = serve_once(
+            r#"{"releases": [
+                {"version": "1", "metadata": "", "commits": [{"architecture": "x86_64", "checksum": "x1"}]},
+                {"version": "2", "metadata": "", "commits": [{"architecture": "x86_64", "checksum": "x2"}]}
+            ]}"#,
+        );
+        let graph = dump_graph(Some(&template), "dumped", "x86_64").unwrap();
+        assert_eq!(graph.nodes.len(), 1);
+        assert_eq!(graph.nodes[0].version, "2");
+        assert_eq!(graph.nodes[0].payload, "x2");
+        assert!(graph.edges.is_empty());
+    }
+
+    #[test]
+    fn render_skips_releases_lacking_basearch() {
+        let template = serve_once(
+            r#"{"releases": [
+                {"version": "1", "metadata": "", "commits": [{"architecture": "aarch64", "checksum": "a1"}]},
+                {"version": "2", "metadata": "", "commits": [{"architecture": "x86_64", "checksum": "x2"}]}
+            ]}"#,
+        );
+        let graph = dump_graph(Some(&template), "dumped", "aarch64").unwrap();
+        assert_eq!(graph.nodes[0].payload, "a1");
+    }
+
+    #[test]
+    fn failing_streams_are_skipped() {
+        let template = serve_once(
+            r#"{"releases": [
+                {"version": "1", "metadata": "", "commits": [{"architecture": "x86_64", "checksum": "x1"}]}
+            ]}"#,
+        );
+        let streams = maplit::btreeset!["a-up".to_string(), "b-down".to_string()];
+        let latest = fetch_latest(Some(&template), &streams).unwrap();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest["a-up"].as_ref().unwrap().version, "1");
+
+        let down = maplit::btreeset!["down".to_string()];
+        assert!(fetch_latest(Some(&template), &down).is_err());
+    }
+}
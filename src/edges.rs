@@ -0,0 +1,156 @@
+//! Validation of emitted graph edges.
+//!
+//! Edges are `(from, to)` node indexes, and should always point from an
+//! older release to a newer one (by age index).
+
+use crate::metadata;
+use crate::Graph;
+use failure::{bail, format_err, Error, Fallible};
+use prometheus::IntCounter;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+lazy_static::lazy_static! {
+    static ref INVALID_EDGES: IntCounter = register_int_counter!(opts!(
+        "fakeup_graph_invalid_edges_total",
+        "Total number of emitted edges failing direction validation"
+    ))
+    .unwrap();
+}
+
+/// Edge explicitly allowed to point backwards, as `<from-version>=<to-version>`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RollbackEdge {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for RollbackEdge {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        let (from, to) = input
+            .split_once('=')
+            .ok_or_else(|| format_err!("invalid rollback edge '{}', missing '='", input))?;
+        if from.is_empty() || to.is_empty() {
+            bail!("invalid rollback edge '{}', empty version", input);
+        }
+        let edge = Self {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        Ok(edge)
+    }
+}
+
+/// Checker for edge direction.
+#[derive(Clone, Debug, Default)]
+pub struct EdgeValidator {
+    rollbacks: BTreeSet<RollbackEdge>,
+}
+
+impl EdgeValidator {
+    pub fn new(rollbacks: impl IntoIterator<Item = RollbackEdge>) -> Self {
+        Self {
+            rollbacks: rollbacks.into_iter().collect(),
+        }
+    }
+
+    /// Check that all edges in a graph point from older to newer nodes.
+    pub(crate) fn check(&self, graph: &Graph) -> Fallible<()> {
+        for &(from, to) in &graph.edges {
+            if let Err(e) = self.check_edge(graph, from, to) {
+                INVALID_EDGES.inc();
+                log::error!("invalid edge ({}, {}): {}", from, to, e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_edge(&self, graph: &Graph, from: u64, to: u64) -> Fallible<()> {
+        let node = |index: u64| {
+            graph
+                .nodes
+                .get(index as usize)
+                .ok_or_else(|| format_err!("node index {} out of bounds", index))
+        };
+        let (from_node, to_node) = (node(from)?, node(to)?);
+
+        let age_index = |node: &crate::CincinnatiPayload| -> Fallible<u64> {
+            let value = node
+                .metadata
+                .get(metadata::AGE_INDEX)
+                .ok_or_else(|| format_err!("node '{}' has no age index", node.payload))?;
+            value
+                .parse()
+                .map_err(|e| format_err!("node '{}' has invalid age index: {}", node.payload, e))
+        };
+        if age_index(from_node)? < age_index(to_node)? {
+            return Ok(());
+        }
+
+        let rollback = RollbackEdge {
+            from: from_node.version.clone(),
+            to: to_node.version.clone(),
+        };
+        if self.rollbacks.contains(&rollback) {
+            return Ok(());
+        }
+        bail!(
+            "edge points backwards, from '{}' to '{}'",
+            from_node.version,
+            to_node.version
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CincinnatiPayload;
+
+    fn node(version: &str, age_index: u64) -> CincinnatiPayload {
+        CincinnatiPayload {
+            version: version.to_string(),
+            payload: format!("{}-payload", version),
+            metadata: maplit::hashmap! {
+                metadata::AGE_INDEX.to_string() => age_index.to_string(),
+            },
+        }
+    }
+
+    fn graph(edges: Vec<(u64, u64)>) -> Graph {
+        Graph {
+            nodes: vec![node("30.1", 0), node("30.2", 1)],
+            edges,
+        }
+    }
+
+    #[test]
+    fn rollback_edges_parse() {
+        let edge: RollbackEdge = "30.2=30.1".parse().unwrap();
+        assert_eq!(edge.from, "30.2");
+        assert_eq!(edge.to, "30.1");
+        for invalid in &["30.2", "=30.1", "30.2="] {
+            assert!(invalid.parse::<RollbackEdge>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn edges_must_point_forward() {
+        let validator = EdgeValidator::default();
+        validator.check(&graph(vec![(0, 1)])).unwrap();
+        assert!(validator.check(&graph(vec![(1, 0)])).is_err());
+        assert!(validator.check(&graph(vec![(0, 0)])).is_err());
+        assert!(validator.check(&graph(vec![(0, 2)])).is_err());
+    }
+
+    #[test]
+    fn rollback_edges_are_allowed() {
+        let validator = EdgeValidator::new(vec!["30.2=30.1".parse().unwrap()]);
+        validator.check(&graph(vec![(1, 0)])).unwrap();
+        let other = EdgeValidator::new(vec!["30.3=30.1".parse().unwrap()]);
+        assert!(other.check(&graph(vec![(1, 0)])).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Response body encoding.
+
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::http::{header, ContentEncoding};
+use bytes::{Bytes, BytesMut};
+use failure::{format_err, Error, Fallible};
+use std::cell::RefCell;
+
+/// Capacity reserved whenever the per-thread encoding buffer runs low.
+static BUFFER_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    /// Per-thread encoding buffer, whose spare capacity is reused
+    /// across responses until exhausted.
+    static BUFFER: RefCell<BytesMut> = RefCell::new(BytesMut::with_capacity(BUFFER_CAPACITY));
+}
+
+/// Serialize a value as JSON, compact unless `pretty` is set.
+pub(crate) fn to_json<T: serde::Serialize>(value: &T, pretty: bool) -> Fallible<Bytes> {
+    BUFFER.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        if buf.capacity() - buf.len() < BUFFER_CAPACITY / 4 {
+            buf.reserve(BUFFER_CAPACITY);
+        }
+        let mut writer = BytesWriter(&mut buf);
+        if pretty {
+            serde_json::to_writer_pretty(&mut writer, value)?;
+        } else {
+            serde_json::to_writer(&mut writer, value)?;
+        }
+        Ok(buf.take().freeze())
+    })
+}
+
+/// Content encoding of graph responses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum GraphCompression {
+    /// Negotiated from the client `Accept-Encoding`.
+    Auto,
+    /// Never compressed.
+    Off,
+    /// Always gzip, even if the client did not ask for it.
+    Gzip,
+    /// Always brotli, even if the client did not ask for it.
+    Brotli,
+}
+
+impl std::str::FromStr for GraphCompression {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        match input {
+            "auto" => Ok(GraphCompression::Auto),
+            "off" => Ok(GraphCompression::Off),
+            "gzip" => Ok(GraphCompression::Gzip),
+            "br" => Ok(GraphCompression::Brotli),
+            _ => Err(format_err!("unknown graph compression mode '{}'", input)),
+        }
+    }
+}
+
+impl GraphCompression {
+    /// Set the content encoding of a response.
+    pub(crate) fn apply(self, resp: &mut HttpResponseBuilder) {
+        let encoding = match self {
+            GraphCompression::Auto => {
+                resp.header(header::VARY, "Accept-Encoding");
+                ContentEncoding::Auto
+            }
+            GraphCompression::Off => ContentEncoding::Identity,
+            GraphCompression::Gzip => ContentEncoding::Gzip,
+            GraphCompression::Brotli => ContentEncoding::Br,
+        };
+        resp.content_encoding(encoding);
+    }
+}
+
+/// `io::Write` adapter appending to a `BytesMut`, growing it as needed.
+struct BytesWriter<'a>(&'a mut BytesMut);
+
+impl std::io::Write for BytesWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_is_compact_unless_pretty() {
+        let value = serde_json::json!({ "nodes": [1, 2] });
+        let compact = to_json(&value, false).unwrap();
+        assert_eq!(&compact[..], br#"{"nodes":[1,2]}"#);
+        let pretty = to_json(&value, true).unwrap();
+        assert!(std::str::from_utf8(&pretty)
+            .unwrap()
+            .contains("\n  \"nodes\""));
+    }
+
+    #[test]
+    fn buffers_are_not_shared_across_responses() {
+        let first = to_json(&"first", false).unwrap();
+        let large = "x".repeat(BUFFER_CAPACITY * 2);
+        let second = to_json(&large, false).unwrap();
+        assert_eq!(&first[..], b"\"first\"");
+        assert_eq!(second.len(), large.len() + 2);
+    }
+
+    #[test]
+    fn compression_modes_parse() {
+        let cases = [
+            ("auto", GraphCompression::Auto),
+            ("off", GraphCompression::Off),
+            ("gzip", GraphCompression::Gzip),
+            ("br", GraphCompression::Brotli),
+        ];
+        for (input, expected) in &cases {
+            assert_eq!(input.parse::<GraphCompression>().unwrap(), *expected);
+        }
+        for invalid in &["", "brotli", "GZIP", "zstd"] {
+            assert!(invalid.parse::<GraphCompression>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn compression_modes_set_encoding() {
+        let encoding = |mode: GraphCompression| {
+            let mut resp = actix_web::HttpResponse::Ok();
+            mode.apply(&mut resp);
+            let resp = resp.finish();
+            let vary = resp.headers().contains_key(header::VARY);
+            (resp.content_encoding(), vary)
+        };
+        assert_eq!(
+            encoding(GraphCompression::Auto),
+            (Some(ContentEncoding::Auto), true)
+        );
+        assert_eq!(
+            encoding(GraphCompression::Off),
+            (Some(ContentEncoding::Identity), false)
+        );
+        assert_eq!(
+            encoding(GraphCompression::Gzip),
+            (Some(ContentEncoding::Gzip), false)
+        );
+        assert_eq!(
+            encoding(GraphCompression::Brotli),
+            (Some(ContentEncoding::Br), false)
+        );
+    }
+}
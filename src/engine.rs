@@ -0,0 +1,1896 @@
+//! Runtime-agnostic scraping and caching core.
+//!
+//! The `fakeup` server drives this from its actix `Scraper` actor, but
+//! nothing here depends on actix: refreshes are plain futures, and timers
+//! go through the `Scheduler` trait. Embedders can run it on bare tokio,
+//! or step it by hand in synchronous tests with `ManualScheduler`.
+
+use crate::metadata;
+use chrono::{DateTime, Utc};
+use failure::{bail, format_err, Error, Fallible};
+use futures::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Failed priority streams are retried this many times more often.
+static PRIORITY_RETRY_FACTOR: u32 = 4;
+
+/// Interval between summaries of a repeated stream error.
+static ERROR_SUMMARY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Graph node for a release payload.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CincinnatiPayload {
+    pub version: String,
+    pub metadata: HashMap<String, String>,
+    pub payload: String,
+}
+
+/// Set of streams to refresh.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RefreshScope {
+    /// All streams (or all active ones, in lazy mode).
+    All,
+    /// Priority streams only.
+    Priority,
+    /// A single stream, refreshed out of the regular schedule.
+    Single(String),
+    /// A single stream, refreshed on its own schedule.
+    Scheduled(String),
+}
+
+/// Update target selection, for clients far behind the latest release.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum HopPolicy {
+    /// Always offer the latest release.
+    #[default]
+    Latest,
+    /// Offer clients more than this many releases behind only the oldest
+    /// release within that distance from latest, forcing stepwise updates.
+    Stepwise(usize),
+}
+
+impl FromStr for HopPolicy {
+    type Err = Error;
+
+    /// Parse `latest`, or `stepwise:<count>`.
+    fn from_str(input: &str) -> Fallible<Self> {
+        match input.trim().split_once(':') {
+            None if input.trim() == "latest" => Ok(HopPolicy::Latest),
+            Some(("stepwise", count)) => {
+                let count = count
+                    .parse()
+                    .map_err(|e| format_err!("invalid release count '{}': {}", count, e))?;
+                Ok(HopPolicy::Stepwise(count))
+            }
+            _ => bail!(
+                "invalid hop policy '{}', expected 'latest' or 'stepwise:<count>'",
+                input
+            ),
+        }
+    }
+}
+
+/// Timer facility for delayed refreshes, provided by the driving runtime.
+pub trait Scheduler {
+    /// Handle to a pending refresh, to cancel it.
+    type Handle;
+
+    /// Schedule a refresh of `scope` after a delay.
+    fn schedule(&mut self, scope: RefreshScope, after: Duration) -> Self::Handle;
+
+    /// Cancel a pending refresh, if it did not run yet.
+    fn cancel(&mut self, handle: Self::Handle);
+}
+
+/// Scheduler for synchronous contexts, where time only moves on request.
+#[derive(Debug)]
+pub struct ManualScheduler {
+    start: Instant,
+    elapsed: Duration,
+    next_id: u64,
+    pending: BTreeMap<(Duration, u64), RefreshScope>,
+}
+
+impl Default for ManualScheduler {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Duration::from_secs(0),
+            next_id: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+impl ManualScheduler {
+    /// Current instant, on the scheduler clock.
+    pub fn now(&self) -> Instant {
+        self.start + self.elapsed
+    }
+
+    /// Move time forward, returning refreshes which became due, in order.
+    pub fn advance(&mut self, by: Duration) -> Vec<RefreshScope> {
+        self.elapsed += by;
+        let later = self.pending.split_off(&(self.elapsed, u64::MAX));
+        std::mem::replace(&mut self.pending, later)
+            .into_values()
+            .collect()
+    }
+
+    /// Number of refreshes not due yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl Scheduler for ManualScheduler {
+    type Handle = (Duration, u64);
+
+    fn schedule(&mut self, scope: RefreshScope, after: Duration) -> Self::Handle {
+        let handle = (self.elapsed + after, self.next_id);
+        self.next_id += 1;
+        self.pending.insert(handle, scope);
+        handle
+    }
+
+    fn cancel(&mut self, handle: Self::Handle) {
+        self.pending.remove(&handle);
+    }
+}
+
+/// Retry delays for streams which failed to refresh.
+pub trait Backoff: std::fmt::Debug {
+    /// Delay before retrying a stream, or `None` to retry it with its next
+    /// regular refresh.
+    ///
+    /// `failures` is the number of consecutive failures (including this one).
+    fn retry_delay(&self, err: &Error, failures: u32, refresh_pause: Duration) -> Option<Duration>;
+}
+
+/// Gating of new releases, withheld until approved.
+#[derive(Clone, Debug, Default)]
+pub struct ReleaseGate {
+    /// Approve pending releases automatically after this delay.
+    pub auto_approve: Option<Duration>,
+    /// Pending release versions, with the instant they were first scraped.
+    pending: HashMap<String, Instant>,
+}
+
+impl ReleaseGate {
+    pub fn new(auto_approve: Option<Duration>) -> Self {
+        Self {
+            auto_approve,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Check whether a release version can be served.
+    pub fn is_approved(&self, version: &str, now: Instant) -> bool {
+        match (self.pending.get(version), self.auto_approve) {
+            (None, _) => true,
+            (Some(since), Some(delay)) => now.saturating_duration_since(*since) >= delay,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Mark releases which were not in the previous index as pending.
+    ///
+    /// The first index scraped for a stream is approved as a whole.
+    pub fn track(
+        &mut self,
+        previous: Option<&Vec<metadata::Release>>,
+        current: &[metadata::Release],
+        now: Instant,
+    ) {
+        let previous = match previous {
+            Some(p) => p,
+            None => return,
+        };
+        for release in current {
+            if previous.iter().any(|r| r.version == release.version) {
+                continue;
+            }
+            if !self.pending.contains_key(&release.version) {
+                log::info!("release '{}' pending approval", release.version);
+                self.pending.insert(release.version.clone(), now);
+            }
+        }
+    }
+
+    /// Approve a pending release, returning whether it was pending.
+    pub fn approve(&mut self, version: &str) -> bool {
+        self.pending.remove(version).is_some()
+    }
+
+    /// Versions currently withheld.
+    pub fn withheld(&self, now: Instant) -> BTreeSet<String> {
+        self.pending
+            .keys()
+            .filter(|v| !self.is_approved(v, now))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Retry state of a failing stream.
+#[derive(Clone, Debug, Default)]
+struct StreamRetry {
+    /// Consecutive failures.
+    failures: u32,
+    /// Do not scrape the stream again before this instant.
+    not_before: Option<Instant>,
+    /// Last error message, for coalescing repeated errors in logs.
+    last_error: String,
+    /// Last time the error state was logged, and failures at that time.
+    last_logged: Option<(Instant, u32)>,
+}
+
+impl StreamRetry {
+    /// Record a failure, returning whether it should be logged.
+    ///
+    /// The first error and any different error are always logged; an
+    /// identical error is only summarized once per `ERROR_SUMMARY_INTERVAL`.
+    fn record_failure(&mut self, error: String, now: Instant) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        let changed = self.last_error != error;
+        self.last_error = error;
+        let due = self
+            .last_logged
+            .is_none_or(|(at, _)| now.saturating_duration_since(at) >= ERROR_SUMMARY_INTERVAL);
+        changed || due
+    }
+}
+
+/// Cache miss for a graph query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheMiss {
+    /// Stream not scraped.
+    UnknownStream(String),
+    /// Stream scraped, but without releases cached yet.
+    EmptyCache(String),
+    /// Stream has releases, but none for this architecture.
+    UnavailableBasearch(String),
+}
+
+impl std::fmt::Display for CacheMiss {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheMiss::UnknownStream(stream) => write!(f, "unknown stream '{}'", stream),
+            CacheMiss::EmptyCache(stream) => {
+                write!(f, "no releases cached yet for stream '{}'", stream)
+            }
+            CacheMiss::UnavailableBasearch(basearch) => {
+                write!(f, "no releases for basearch '{}'", basearch)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CacheMiss {}
+
+/// Refresh in progress, from `Core::begin_refresh` to `Core::finish_refresh`.
+#[derive(Clone, Debug)]
+pub struct Refresh {
+    pub scope: RefreshScope,
+    /// Sequence number, increasing with each started refresh.
+    pub generation: u64,
+    /// Streams to fetch, in order.
+    pub streams: Vec<String>,
+}
+
+/// Outcome of a refresh for a single stream.
+#[derive(Debug)]
+pub enum StreamUpdate {
+    /// Stream refreshed, replacing its previous release index (if any).
+    Refreshed {
+        stream: String,
+        previous: Option<Arc<Vec<metadata::Release>>>,
+        current: Arc<Vec<metadata::Release>>,
+        /// Time spent building the stream graph.
+        build_time: Duration,
+        /// Whether the stream was previously served empty.
+        resumed: bool,
+    },
+    /// Stream refreshed with an empty release index, and keeps its previous
+    /// one (if any), while only serving client nodes.
+    Empty {
+        stream: String,
+        /// Whether the stream just became empty.
+        emptied: bool,
+    },
+    /// Stream failed to refresh, and keeps its previous release index.
+    Failed { stream: String, error: Error },
+}
+
+/// Graph nodes of a stream, precomputed per architecture.
+///
+/// Nodes are built once when a release index is cached, instead of on
+/// every graph request.
+#[derive(Clone, Debug, Default)]
+pub struct StreamGraph {
+    /// Whether the release index has any release.
+    populated: bool,
+    /// Nodes per architecture, from the newest release to the oldest one.
+    arches: HashMap<String, Vec<CincinnatiPayload>>,
+}
+
+impl StreamGraph {
+    /// Build the nodes of all architectures in a release index.
+    pub fn build(releases: &[metadata::Release]) -> Self {
+        let mut arches: HashMap<String, Vec<CincinnatiPayload>> = HashMap::new();
+        for (age_index, rel) in releases.iter().enumerate().rev() {
+            let basearches: BTreeSet<&str> = rel
+                .commits
+                .iter()
+                .map(|c| c.architecture.as_str())
+                .collect();
+            for basearch in basearches {
+                if let Some(node) = release_node(age_index, rel, basearch) {
+                    arches.entry(basearch.to_string()).or_default().push(node);
+                }
+            }
+        }
+        Self {
+            populated: !releases.is_empty(),
+            arches,
+        }
+    }
+
+    /// Nodes for `basearch`, from the newest release to the oldest one.
+    pub fn nodes(&self, basearch: &str) -> &[CincinnatiPayload] {
+        self.arches.get(basearch).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Build the graphs of several release indexes, timing each of them.
+///
+/// Indexes are spread over scoped threads, so that refresh latency stays
+/// flat as the number of streams grows.
+fn build_graphs(indexes: &[&[metadata::Release]]) -> Vec<(StreamGraph, Duration)> {
+    fn timed_build(releases: &[metadata::Release]) -> (StreamGraph, Duration) {
+        let start = Instant::now();
+        let graph = StreamGraph::build(releases);
+        (graph, start.elapsed())
+    }
+
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(indexes.len());
+    if workers <= 1 {
+        return indexes
+            .iter()
+            .map(|releases| timed_build(releases))
+            .collect();
+    }
+    let chunk_size = indexes.len().div_ceil(workers);
+    thread::scope(|scope| {
+        let workers: Vec<_> = indexes
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|releases| timed_build(releases))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    })
+}
+
+/// Release cache, with its refresh state machine.
+///
+/// This owns everything about which streams to refresh and when: retries
+/// of failing streams, warm-up of priority streams, per-stream schedules,
+/// release gating and first-seen tracking. Fetching and side effects
+/// (metrics, persistence, notifications) are left to the embedder, which
+/// reports fetched indexes back with `apply_refresh`.
+///
+/// `H` is the handle type of the driving `Scheduler`.
+#[derive(Debug)]
+pub struct Core<H> {
+    streams: BTreeSet<String>,
+    /// Streams which are scraped first, and retried more aggressively.
+    priority_streams: BTreeSet<String>,
+    /// Only scrape streams after they have been requested once.
+    lazy: bool,
+    /// Streams requested at least once (in lazy mode).
+    active_streams: BTreeSet<String>,
+    refresh_pause: Duration,
+    /// Streams refreshed on their own schedule, instead of with all the others.
+    stream_refresh: BTreeMap<String, Duration>,
+    /// Release index per stream, in upstream order (oldest first).
+    releases: HashMap<String, Arc<Vec<metadata::Release>>>,
+    /// Graph per stream, built from its cached release index.
+    graphs: HashMap<String, StreamGraph>,
+    /// Time of the last successful refresh, per stream.
+    refreshed_at: HashMap<String, DateTime<Utc>>,
+    /// Whether refresh results are currently ignored.
+    frozen: bool,
+    /// Whether refreshes are stopped, before shutdown.
+    stopping: bool,
+    /// Whether the initial full refresh has been started.
+    warmed_up: bool,
+    /// Retry state for streams which failed to refresh.
+    retries: HashMap<String, StreamRetry>,
+    backoff: Option<Box<dyn Backoff>>,
+    /// Manual approval of newly scraped releases.
+    gate: Option<ReleaseGate>,
+    /// Instant at which releases were first scraped, for releases which
+    /// were not part of the initial index of their stream.
+    first_seen: HashMap<String, Instant>,
+    /// Streams not found upstream anymore, served from their last known releases.
+    stale_streams: BTreeSet<String>,
+    /// Streams with an empty upstream index, keeping their last known releases.
+    empty_streams: BTreeSet<String>,
+    /// Update target selection per stream, for clients far behind (latest if unset).
+    hop_policies: HashMap<String, HopPolicy>,
+    /// Pending aggressive refresh for failed priority streams.
+    priority_retry: Option<H>,
+    /// Pending scheduled refresh, per stream with its own schedule.
+    scheduled: HashMap<String, H>,
+    /// Generation of the last started refresh.
+    generation: u64,
+    /// Generation of the last refresh applied, per stream.
+    applied: HashMap<String, u64>,
+}
+
+impl<H> Core<H> {
+    pub fn new(streams: BTreeSet<String>, refresh_pause: Duration) -> Self {
+        Self {
+            streams,
+            priority_streams: BTreeSet::new(),
+            lazy: false,
+            active_streams: BTreeSet::new(),
+            refresh_pause,
+            stream_refresh: BTreeMap::new(),
+            releases: HashMap::new(),
+            graphs: HashMap::new(),
+            refreshed_at: HashMap::new(),
+            frozen: false,
+            stopping: false,
+            warmed_up: false,
+            retries: HashMap::new(),
+            backoff: None,
+            gate: None,
+            first_seen: HashMap::new(),
+            stale_streams: BTreeSet::new(),
+            empty_streams: BTreeSet::new(),
+            hop_policies: HashMap::new(),
+            priority_retry: None,
+            scheduled: HashMap::new(),
+            generation: 0,
+            applied: HashMap::new(),
+        }
+    }
+
+    /// Mark streams as high priority.
+    ///
+    /// Priority streams are also added to the set of scraped streams.
+    pub fn with_priority_streams(mut self, priority_streams: BTreeSet<String>) -> Self {
+        self.streams.extend(priority_streams.iter().cloned());
+        self.priority_streams = priority_streams;
+        self
+    }
+
+    /// Only scrape streams once they are requested by a client.
+    ///
+    /// Priority streams are always scraped.
+    pub fn with_lazy_streams(mut self, lazy: bool) -> Self {
+        self.lazy = lazy;
+        self
+    }
+
+    /// Refresh some streams on their own schedule, with a distinct interval.
+    pub fn with_stream_refresh_intervals(mut self, intervals: BTreeMap<String, Duration>) -> Self {
+        self.stream_refresh = intervals;
+        self
+    }
+
+    /// Delay retries of failing streams (retried with the next refresh otherwise).
+    pub fn with_backoff(mut self, backoff: Box<dyn Backoff>) -> Self {
+        self.backoff = Some(backoff);
+        self
+    }
+
+    /// Withhold newly scraped releases until they are approved.
+    pub fn with_release_gate(mut self, gate: ReleaseGate) -> Self {
+        self.gate = Some(gate);
+        self
+    }
+
+    /// Select update targets per stream, for clients far behind latest.
+    pub fn with_hop_policies(mut self, policies: HashMap<String, HopPolicy>) -> Self {
+        self.hop_policies = policies;
+        self
+    }
+
+    /// Preload the cache with imported releases, and freeze it.
+    ///
+    /// Imported streams are also added to the set of scraped streams.
+    pub fn with_imported(mut self, releases: BTreeMap<String, Vec<metadata::Release>>) -> Self {
+        for (stream, index) in releases {
+            self.streams.insert(stream.clone());
+            let graph = StreamGraph::build(&index);
+            self.cache(stream, Arc::new(index), graph);
+        }
+        self.frozen = true;
+        self
+    }
+
+    /// Scraped streams.
+    pub fn streams(&self) -> &BTreeSet<String> {
+        &self.streams
+    }
+
+    /// Priority streams.
+    pub fn priority_streams(&self) -> &BTreeSet<String> {
+        &self.priority_streams
+    }
+
+    /// Pause between full refreshes.
+    pub fn refresh_pause(&self) -> Duration {
+        self.refresh_pause
+    }
+
+    /// Pause between refreshes of a stream.
+    pub fn stream_refresh_pause(&self, stream: &str) -> Duration {
+        self.stream_refresh
+            .get(stream)
+            .copied()
+            .unwrap_or(self.refresh_pause)
+    }
+
+    /// Cached release index of a stream.
+    pub fn releases(&self, stream: &str) -> Option<&Arc<Vec<metadata::Release>>> {
+        self.releases.get(stream)
+    }
+
+    /// All cached release indexes, by stream.
+    pub fn cached(&self) -> impl Iterator<Item = (&String, &Arc<Vec<metadata::Release>>)> {
+        self.releases.iter()
+    }
+
+    /// Time of the last successful refresh of a stream.
+    pub fn refreshed_at(&self, stream: &str) -> Option<DateTime<Utc>> {
+        self.refreshed_at.get(stream).cloned()
+    }
+
+    /// Instant at which a release was first scraped, if after its stream
+    /// was first cached.
+    pub fn first_seen(&self, version: &str) -> Option<Instant> {
+        self.first_seen.get(version).cloned()
+    }
+
+    /// Streams served from their last known releases.
+    pub fn stale_streams(&self) -> &BTreeSet<String> {
+        &self.stale_streams
+    }
+
+    /// Streams with an empty upstream index, served without update targets.
+    pub fn empty_streams(&self) -> &BTreeSet<String> {
+        &self.empty_streams
+    }
+
+    /// Whether refresh results are ignored.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Freeze or unfreeze the cache, returning whether this changed.
+    pub fn set_frozen(&mut self, frozen: bool) -> bool {
+        let changed = self.frozen != frozen;
+        self.frozen = frozen;
+        changed
+    }
+
+    /// Whether refreshes are stopped.
+    pub fn is_stopping(&self) -> bool {
+        self.stopping
+    }
+
+    /// Releases withheld pending approval.
+    pub fn withheld(&self, now: Instant) -> BTreeSet<String> {
+        self.gate
+            .as_ref()
+            .map(|g| g.withheld(now))
+            .unwrap_or_default()
+    }
+
+    /// Approve a pending release, returning whether it was pending.
+    pub fn approve(&mut self, version: &str) -> bool {
+        let found = self.gate.as_mut().is_some_and(|g| g.approve(version));
+        if found {
+            log::info!("release '{}' approved", version);
+        }
+        found
+    }
+
+    /// Mark a stream as requested, returning whether it needs a first scrape.
+    pub fn activate_stream(&mut self, stream: &str) -> bool {
+        if !self.lazy || !self.streams.contains(stream) || self.active_streams.contains(stream) {
+            return false;
+        }
+        log::debug!("activating lazy stream '{}'", stream);
+        self.active_streams.insert(stream.to_string());
+        true
+    }
+
+    /// Start scraping a new stream, returning whether it was not scraped yet.
+    pub fn add_stream(&mut self, stream: String) -> bool {
+        self.streams.insert(stream)
+    }
+
+    /// Streams which were never populated, neither by a successful refresh
+    /// nor by imported releases.
+    ///
+    /// Inactive lazy streams are only scraped on demand, so they are never
+    /// waited for. While frozen, streams without imported releases stay
+    /// pending, as scrapes are ignored.
+    pub fn pending_streams(&self) -> Vec<String> {
+        self.streams
+            .iter()
+            .filter(|s| !self.lazy || self.active_streams.contains(*s))
+            .filter(|s| !self.refreshed_at.contains_key(*s) && !self.releases.contains_key(*s))
+            .cloned()
+            .collect()
+    }
+
+    /// Return whether any priority stream is currently failing.
+    fn priority_failing(&self) -> bool {
+        self.priority_streams
+            .iter()
+            .any(|s| self.retries.contains_key(s))
+    }
+
+    /// Streams to refresh in a scope, skipping those with a pending retry.
+    pub fn scope_streams(&self, scope: &RefreshScope, now: Instant) -> Vec<String> {
+        let is_active =
+            |s: &String| self.active_streams.contains(s) || self.priority_streams.contains(s);
+        let streams: Vec<&String> = match scope {
+            RefreshScope::All => self
+                .streams
+                .iter()
+                .filter(|s| !self.stream_refresh.contains_key(*s))
+                .filter(|s| !self.lazy || is_active(s))
+                .collect(),
+            RefreshScope::Priority => self.priority_streams.iter().collect(),
+            RefreshScope::Single(stream) => self.streams.get(stream).into_iter().collect(),
+            RefreshScope::Scheduled(stream) => self
+                .streams
+                .get(stream)
+                .filter(|s| !self.lazy || is_active(s))
+                .into_iter()
+                .collect(),
+        };
+        streams
+            .into_iter()
+            .filter(
+                |stream| match self.retries.get(*stream).and_then(|r| r.not_before) {
+                    Some(not_before) if not_before > now => {
+                        log::debug!("skipping stream '{}', retry pending", stream);
+                        false
+                    }
+                    _ => true,
+                },
+            )
+            .cloned()
+            .collect()
+    }
+
+    /// Start a refresh of `scope`, returning the streams to fetch.
+    ///
+    /// Nothing is fetched once stopping. Refreshes may overlap, see
+    /// `apply_refresh`.
+    pub fn begin_refresh(&mut self, scope: RefreshScope, now: Instant) -> Refresh {
+        if scope == RefreshScope::Priority {
+            self.priority_retry = None;
+        }
+        let streams = if self.stopping {
+            vec![]
+        } else {
+            self.scope_streams(&scope, now)
+        };
+        self.generation += 1;
+        Refresh {
+            scope,
+            generation: self.generation,
+            streams,
+        }
+    }
+
+    /// Merge fetched release indexes into the cache.
+    ///
+    /// Streams which failed to refresh keep their previous cache entry, and
+    /// are retried according to the backoff policy. Streams with an empty
+    /// index keep it too, but offer no update target. Nothing is merged
+    /// while frozen. `at` is the wall-clock time of the refresh.
+    ///
+    /// A slow refresh can complete after a later one: results for streams
+    /// already updated by a later refresh are dropped, rather than rolling
+    /// the cache back.
+    pub fn apply_refresh(
+        &mut self,
+        refresh: &Refresh,
+        refreshed: Vec<(String, Fallible<Vec<metadata::Release>>)>,
+        now: Instant,
+        at: DateTime<Utc>,
+    ) -> Vec<StreamUpdate> {
+        if self.frozen {
+            log::debug!("cache frozen, ignoring scraped releases");
+            return vec![];
+        }
+
+        let indexes: Vec<&[metadata::Release]> = refreshed
+            .iter()
+            .filter_map(|(_, res)| res.as_ref().ok().map(Vec::as_slice))
+            .collect();
+        let mut graphs = build_graphs(&indexes).into_iter();
+
+        let mut updates = Vec::with_capacity(refreshed.len());
+        for (stream, res) in refreshed {
+            let applied = self.applied.entry(stream.clone()).or_default();
+            if *applied > refresh.generation {
+                log::debug!(
+                    "dropping stale refresh #{} of stream '{}', #{} already applied",
+                    refresh.generation,
+                    stream,
+                    applied
+                );
+                continue;
+            }
+            *applied = refresh.generation;
+            let update = match res {
+                Ok(releases) => {
+                    let (graph, build_time) = graphs.next().unwrap_or_default();
+                    self.refreshed(stream, releases, graph, build_time, now, at)
+                }
+                Err(error) => {
+                    self.failed(&stream, &error, now);
+                    StreamUpdate::Failed { stream, error }
+                }
+            };
+            updates.push(update);
+        }
+        updates
+    }
+
+    /// Merge a successfully refreshed stream.
+    fn refreshed(
+        &mut self,
+        stream: String,
+        releases: Vec<metadata::Release>,
+        graph: StreamGraph,
+        build_time: Duration,
+        now: Instant,
+        at: DateTime<Utc>,
+    ) -> StreamUpdate {
+        self.refreshed_at.insert(stream.clone(), at);
+        if let Some(retry) = self.retries.remove(&stream) {
+            log::info!(
+                "stream '{}' exited error state after {} failures",
+                stream,
+                retry.failures
+            );
+        }
+        if self.stale_streams.remove(&stream) {
+            log::info!("stream '{}' is served upstream again", stream);
+        }
+        if releases.is_empty() {
+            if !self.releases.contains_key(&stream) {
+                self.cache(stream.clone(), Arc::new(releases), graph);
+            }
+            let emptied = self.empty_streams.insert(stream.clone());
+            if emptied {
+                log::warn!(
+                    "stream '{}' has an empty release index, keeping last known releases",
+                    stream
+                );
+            }
+            return StreamUpdate::Empty { stream, emptied };
+        }
+        let resumed = self.empty_streams.remove(&stream);
+        let previous = self.releases.get(&stream).cloned();
+        if let Some(ref previous) = previous {
+            for rel in &releases {
+                if !previous.iter().any(|r| r.version == rel.version) {
+                    self.first_seen.entry(rel.version.clone()).or_insert(now);
+                }
+            }
+        }
+        if let Some(ref mut gate) = self.gate {
+            gate.track(previous.as_deref(), &releases, now);
+        }
+        let current = Arc::new(releases);
+        self.cache(stream.clone(), Arc::clone(&current), graph);
+        StreamUpdate::Refreshed {
+            stream,
+            previous,
+            current,
+            build_time,
+            resumed,
+        }
+    }
+
+    /// Cache the release index of a stream, along with its graph.
+    fn cache(&mut self, stream: String, releases: Arc<Vec<metadata::Release>>, graph: StreamGraph) {
+        self.graphs.insert(stream.clone(), graph);
+        self.releases.insert(stream, releases);
+    }
+
+    /// Forget the cached release index of a stream, returning whether it was cached.
+    fn uncache(&mut self, stream: &str) -> bool {
+        self.graphs.remove(stream);
+        self.releases.remove(stream).is_some()
+    }
+
+    /// Record a failed refresh, and when to retry it.
+    fn failed(&mut self, stream: &str, error: &Error, now: Instant) {
+        let refresh_pause = self.stream_refresh_pause(stream);
+        let retry = self.retries.entry(stream.to_string()).or_default();
+        let log_now = retry.record_failure(error.to_string(), now);
+        let delay = self
+            .backoff
+            .as_ref()
+            .and_then(|b| b.retry_delay(error, retry.failures, refresh_pause));
+        retry.not_before = delay.map(|d| now + d);
+        if !log_now {
+            return;
+        }
+
+        match retry.last_logged {
+            None => log::error!(
+                "stream '{}' entered error state: {}",
+                stream,
+                retry.last_error
+            ),
+            Some((_, logged_failures)) => log::error!(
+                "stream '{}' still failing ({} failures, {} since last report): {}",
+                stream,
+                retry.failures,
+                retry.failures - logged_failures,
+                retry.last_error
+            ),
+        }
+        retry.last_logged = Some((now, retry.failures));
+        if let Some(d) = delay {
+            log::warn!("retrying stream '{}' in {}s", stream, d.as_secs());
+        }
+    }
+
+    /// Drop the cache of a stream not served upstream anymore, returning
+    /// whether it was cached.
+    pub fn drop_stream(&mut self, stream: &str) -> bool {
+        self.empty_streams.remove(stream);
+        let dropped = self.uncache(stream);
+        if dropped {
+            log::warn!("stream '{}' not found upstream, dropped", stream);
+        }
+        dropped
+    }
+
+    /// Keep serving a stream not served upstream anymore, from its last
+    /// known releases (or from `fallback`, if not cached).
+    ///
+    /// It returns whether the stream just became stale.
+    pub fn mark_stale<F>(&mut self, stream: &str, fallback: F) -> bool
+    where
+        F: FnOnce() -> Option<Vec<metadata::Release>>,
+    {
+        if !self.releases.contains_key(stream) {
+            match fallback() {
+                Some(releases) => {
+                    let graph = StreamGraph::build(&releases);
+                    self.cache(stream.to_string(), Arc::new(releases), graph);
+                }
+                None => return false,
+            }
+        }
+        let stale = self.stale_streams.insert(stream.to_string());
+        if stale {
+            log::warn!(
+                "stream '{}' not found upstream, serving last known releases as stale",
+                stream
+            );
+        }
+        stale
+    }
+
+    /// Check whether a release has been offered, given its gating and a
+    /// minimum availability `delay`.
+    fn is_offered(&self, version: &str, delay: Option<Duration>, now: Instant) -> bool {
+        let approved = self
+            .gate
+            .as_ref()
+            .is_none_or(|g| g.is_approved(version, now));
+        let available = match (delay, self.first_seen.get(version)) {
+            (Some(delay), Some(seen)) => now.saturating_duration_since(*seen) >= delay,
+            _ => true,
+        };
+        approved && available
+    }
+
+    /// Update target for a client of `stream` on `basearch`, if any.
+    ///
+    /// Clients running `current` get a target according to the stream hop
+    /// policy, others get the latest release. Releases first scraped less
+    /// than `delay` ago are skipped.
+    pub fn latest(
+        &self,
+        stream: &str,
+        basearch: &str,
+        current: Option<&str>,
+        delay: Option<Duration>,
+        now: Instant,
+    ) -> Result<Option<CincinnatiPayload>, CacheMiss> {
+        let graph = match self.graphs.get(stream) {
+            Some(graph) => graph,
+            None if self.streams.contains(stream) => {
+                return Err(CacheMiss::EmptyCache(stream.to_string()))
+            }
+            None => return Err(CacheMiss::UnknownStream(stream.to_string())),
+        };
+        if self.empty_streams.contains(stream) {
+            return Ok(None);
+        }
+        let offered = |node: &CincinnatiPayload| self.is_offered(&node.version, delay, now);
+        let latest = match (self.hop_policies.get(stream), current) {
+            (Some(policy), Some(current)) => {
+                target_node(graph, basearch, current, *policy, offered)
+            }
+            _ => latest_node(graph, basearch, offered),
+        };
+        let mut node = match latest {
+            Err(e) => {
+                log::trace!("stream '{}': {}", stream, e);
+                return Err(CacheMiss::UnavailableBasearch(basearch.to_string()));
+            }
+            Ok(node) => node,
+        };
+        if let (Some(ref mut node), true) = (&mut node, self.stale_streams.contains(stream)) {
+            node.metadata
+                .insert(metadata::STALE.to_string(), "true".to_string());
+        }
+        Ok(node)
+    }
+
+    /// Node for a specific payload in a stream, if it is cached.
+    pub fn lookup(
+        &self,
+        stream: &str,
+        basearch: &str,
+        checksum: &str,
+    ) -> Option<CincinnatiPayload> {
+        let graph = self.graphs.get(stream)?;
+        lookup_node(graph, basearch, checksum)
+    }
+
+    /// Check whether a payload is in any cached release index.
+    pub fn has_payload(&self, checksum: &str) -> bool {
+        self.releases
+            .values()
+            .any(|releases| has_payload(releases, checksum))
+    }
+
+    /// Forget pending timers, dropped along with a restarted runtime.
+    pub fn restarted(&mut self) {
+        self.priority_retry = None;
+        self.scheduled.clear();
+        self.warmed_up = false;
+    }
+
+    /// Kick-start the state machine, warming up priority streams first.
+    ///
+    /// Streams with their own schedule start right away.
+    pub fn start<S>(&mut self, scheduler: &mut S)
+    where
+        S: Scheduler<Handle = H>,
+    {
+        let scheduled: Vec<String> = self.stream_refresh.keys().cloned().collect();
+        for stream in scheduled {
+            self.schedule_stream(scheduler, stream, Duration::from_secs(0));
+        }
+        if self.priority_streams.is_empty() {
+            self.warmed_up = true;
+            scheduler.schedule(RefreshScope::All, Duration::from_secs(0));
+        } else {
+            scheduler.schedule(RefreshScope::Priority, Duration::from_secs(0));
+        }
+    }
+
+    /// Schedule what comes after a completed refresh.
+    pub fn finish_refresh<S>(&mut self, refresh: &Refresh, scheduler: &mut S)
+    where
+        S: Scheduler<Handle = H>,
+    {
+        if self.stopping {
+            return;
+        }
+        if let RefreshScope::Scheduled(ref stream) = refresh.scope {
+            let pause = self.stream_refresh_pause(stream);
+            self.schedule_stream(scheduler, stream.clone(), pause);
+        }
+        if refresh.scope == RefreshScope::All {
+            scheduler.schedule(RefreshScope::All, self.refresh_pause);
+        } else if !self.warmed_up {
+            // Priority streams are warm, go on with all the others.
+            self.warmed_up = true;
+            scheduler.schedule(RefreshScope::All, Duration::from_secs(0));
+        }
+        self.maybe_retry_priority(scheduler);
+    }
+
+    /// Replace the set of scraped streams and the refresh intervals.
+    ///
+    /// Priority streams are always kept. A new global refresh interval applies
+    /// from the next scheduled refresh, while streams whose own interval changed
+    /// are refreshed right away. Added streams are refreshed right away too,
+    /// unless lazy.
+    pub fn reconfigure<S>(
+        &mut self,
+        mut streams: BTreeSet<String>,
+        refresh_pause: Duration,
+        stream_refresh: BTreeMap<String, Duration>,
+        scheduler: &mut S,
+    ) where
+        S: Scheduler<Handle = H>,
+    {
+        streams.extend(self.priority_streams.iter().cloned());
+
+        let removed: Vec<String> = self.streams.difference(&streams).cloned().collect();
+        for stream in &removed {
+            self.uncache(stream);
+            self.retries.remove(stream);
+            self.active_streams.remove(stream);
+            self.refreshed_at.remove(stream);
+            self.stale_streams.remove(stream);
+            self.applied.remove(stream);
+        }
+        let added: Vec<String> = streams.difference(&self.streams).cloned().collect();
+        self.streams = streams;
+        if self.refresh_pause != refresh_pause {
+            log::info!(
+                "refresh interval changed from {}s to {}s",
+                self.refresh_pause.as_secs(),
+                refresh_pause.as_secs()
+            );
+            self.refresh_pause = refresh_pause;
+        }
+        let previous_refresh = std::mem::replace(&mut self.stream_refresh, stream_refresh);
+
+        // Stop schedules which do not apply anymore or changed interval,
+        // and start new ones.
+        let unscheduled: Vec<String> = self
+            .scheduled
+            .keys()
+            .filter(|s| {
+                !self.streams.contains(*s)
+                    || self.stream_refresh.get(*s) != previous_refresh.get(*s)
+            })
+            .cloned()
+            .collect();
+        for stream in unscheduled {
+            if let Some(handle) = self.scheduled.remove(&stream) {
+                scheduler.cancel(handle);
+            }
+        }
+        let to_schedule: Vec<String> = self
+            .stream_refresh
+            .keys()
+            .filter(|s| self.streams.contains(*s) && !self.scheduled.contains_key(*s))
+            .cloned()
+            .collect();
+        for stream in to_schedule {
+            self.schedule_stream(scheduler, stream, Duration::from_secs(0));
+        }
+
+        log::info!(
+            "reconfigured streams: {} added ({}), {} removed ({})",
+            added.len(),
+            added.join(", "),
+            removed.len(),
+            removed.join(", ")
+        );
+
+        if !self.lazy {
+            for stream in added {
+                scheduler.schedule(RefreshScope::Single(stream), Duration::from_secs(0));
+            }
+        }
+    }
+
+    /// Stop refreshing, cancelling pending timers.
+    pub fn shutdown<S>(&mut self, scheduler: &mut S)
+    where
+        S: Scheduler<Handle = H>,
+    {
+        self.stopping = true;
+        if let Some(handle) = self.priority_retry.take() {
+            scheduler.cancel(handle);
+        }
+        for (_, handle) in self.scheduled.drain() {
+            scheduler.cancel(handle);
+        }
+    }
+
+    /// Schedule the next refresh of a stream with its own schedule.
+    ///
+    /// It stops once the stream is removed, or loses its own schedule.
+    fn schedule_stream<S>(&mut self, scheduler: &mut S, stream: String, after: Duration)
+    where
+        S: Scheduler<Handle = H>,
+    {
+        if !self.streams.contains(&stream) || !self.stream_refresh.contains_key(&stream) {
+            self.scheduled.remove(&stream);
+            return;
+        }
+        let handle = scheduler.schedule(RefreshScope::Scheduled(stream.clone()), after);
+        if let Some(previous) = self.scheduled.insert(stream, handle) {
+            scheduler.cancel(previous);
+        }
+    }
+
+    /// Schedule an aggressive refresh of failing priority streams, if needed.
+    fn maybe_retry_priority<S>(&mut self, scheduler: &mut S)
+    where
+        S: Scheduler<Handle = H>,
+    {
+        if self.priority_retry.is_some() || !self.priority_failing() {
+            return;
+        }
+        let after = self.refresh_pause / PRIORITY_RETRY_FACTOR;
+        self.priority_retry = Some(scheduler.schedule(RefreshScope::Priority, after));
+    }
+}
+
+/// Fetch the release index of several streams, from per-stream fetches.
+///
+/// See `buffered` for concurrency. This never fails as a whole; each
+/// stream carries its own result.
+pub fn refresh_streams<R>(
+    fetches: Vec<(String, R)>,
+    concurrency: usize,
+) -> impl Future<Item = Vec<(String, Fallible<Vec<metadata::Release>>)>, Error = Error>
+where
+    R: Future<Item = Vec<metadata::Release>, Error = Error>,
+{
+    let fetches = fetches
+        .into_iter()
+        .map(|(stream, fetch)| fetch.then(|res| Ok((stream, res))))
+        .collect();
+    buffered(fetches, concurrency)
+}
+
+/// Run fetches concurrently, up to `concurrency` at once (`0` for no
+/// limit), keeping results in order.
+pub fn buffered<F: Future>(
+    fetches: Vec<F>,
+    concurrency: usize,
+) -> impl Future<Item = Vec<F::Item>, Error = F::Error> {
+    let limit = match concurrency {
+        0 => fetches.len().max(1),
+        n => n,
+    };
+    futures::stream::iter_ok(fetches).buffered(limit).collect()
+}
+
+/// Drop payloads for architectures other than `arches` (none if empty).
+pub fn filter_arches(releases: &mut [metadata::Release], arches: &BTreeSet<String>) {
+    if arches.is_empty() {
+        return;
+    }
+    for release in releases {
+        release.commits.retain(|c| arches.contains(&c.architecture));
+    }
+}
+
+/// Build the node for a release, if it has a payload for `basearch`.
+///
+/// `age_index` is the release position in the upstream index, without
+/// compacting gaps for releases which lack this architecture.
+pub fn release_node(
+    age_index: usize,
+    release: &metadata::Release,
+    basearch: &str,
+) -> Option<CincinnatiPayload> {
+    let commit = release
+        .commits
+        .iter()
+        .rev()
+        .find(|c| c.architecture == basearch)?;
+
+    let mut node = CincinnatiPayload {
+        version: release.version.clone(),
+        payload: commit.checksum.clone(),
+        metadata: maplit::hashmap! {
+            metadata::SCHEME.to_string() => "checksum".to_string(),
+            metadata::AGE_INDEX.to_string() => age_index.to_string(),
+        },
+    };
+    if let Some(size) = commit.size {
+        node.metadata
+            .insert(metadata::DOWNLOAD_SIZE.to_string(), size.to_string());
+    }
+    Some(node)
+}
+
+/// Newest node for `basearch` which passes the `offered` policy check.
+///
+/// Architectures are independent: releases which lack a payload for
+/// `basearch` are skipped, rather than hiding older ones. It fails if
+/// there are releases, but none for `basearch`.
+pub fn latest_node<P>(
+    graph: &StreamGraph,
+    basearch: &str,
+    mut offered: P,
+) -> Fallible<Option<CincinnatiPayload>>
+where
+    P: FnMut(&CincinnatiPayload) -> bool,
+{
+    let nodes = graph.nodes(basearch);
+    if graph.populated && nodes.is_empty() {
+        return Err(format_err!("basearch unavailable"));
+    }
+    Ok(nodes.iter().find(|node| offered(node)).cloned())
+}
+
+/// Update target for a client running `current`, under a hop policy.
+///
+/// This is `latest_node`, except that clients too far behind get the
+/// oldest release within the policy distance from latest. Distances only
+/// count releases which pass the `offered` check.
+pub fn target_node<P>(
+    graph: &StreamGraph,
+    basearch: &str,
+    current: &str,
+    policy: HopPolicy,
+    mut offered: P,
+) -> Fallible<Option<CincinnatiPayload>>
+where
+    P: FnMut(&CincinnatiPayload) -> bool,
+{
+    let max_behind = match policy {
+        HopPolicy::Latest => return latest_node(graph, basearch, offered),
+        HopPolicy::Stepwise(count) => count,
+    };
+    let nodes = graph.nodes(basearch);
+    if graph.populated && nodes.is_empty() {
+        return Err(format_err!("basearch unavailable"));
+    }
+    let nodes: Vec<_> = nodes.iter().filter(|node| offered(node)).collect();
+    let target = match nodes.iter().position(|node| node.payload == current) {
+        Some(behind) if behind > max_behind => nodes.get(max_behind),
+        _ => nodes.first(),
+    };
+    Ok(target.map(|node| (*node).clone()))
+}
+
+/// Node for a specific payload, if it is in the release index.
+pub fn lookup_node(
+    graph: &StreamGraph,
+    basearch: &str,
+    checksum: &str,
+) -> Option<CincinnatiPayload> {
+    graph
+        .nodes(basearch)
+        .iter()
+        .find(|node| node.payload == checksum)
+        .cloned()
+}
+
+/// Check whether a payload is in a release index, for any architecture.
+pub fn has_payload(releases: &[metadata::Release], checksum: &str) -> bool {
+    releases
+        .iter()
+        .flat_map(|rel| rel.commits.iter())
+        .any(|commit| commit.checksum == checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    fn release(version: &str, commits: &[(&str, &str)]) -> metadata::Release {
+        metadata::Release {
+            commits: commits
+                .iter()
+                .map(|(arch, checksum)| metadata::ReleaseCommit {
+                    architecture: arch.to_string(),
+                    checksum: checksum.to_string(),
+                    size: None,
+                })
+                .collect(),
+            version: version.to_string(),
+            metadata: String::new(),
+        }
+    }
+
+    #[test]
+    fn stream_graph_has_a_node_per_arch() {
+        let rel = release(
+            "30.1",
+            &[("x86_64", "x1"), ("aarch64", "a1"), ("x86_64", "x2")],
+        );
+        let graph = StreamGraph::build(&[rel]);
+        let node = graph.nodes("x86_64").first().unwrap();
+        assert_eq!(node.version, "30.1");
+        assert_eq!(node.payload, "x2");
+        assert_eq!(graph.nodes("aarch64").first().unwrap().payload, "a1");
+        assert!(graph.nodes("s390x").is_empty());
+    }
+
+    #[test]
+    fn nodes_carry_upstream_age_index() {
+        let releases = vec![
+            release("30.1", &[("x86_64", "x1"), ("aarch64", "a1")]),
+            release("30.2", &[("aarch64", "a2")]),
+            release("30.3", &[("x86_64", "x3")]),
+        ];
+        let graph = StreamGraph::build(&releases);
+        let age_indexes: Vec<_> = graph
+            .nodes("x86_64")
+            .iter()
+            .map(|node| node.metadata[metadata::AGE_INDEX].as_str())
+            .collect();
+        assert_eq!(age_indexes, vec!["2", "0"]);
+        assert_eq!(graph.nodes("x86_64")[0].payload, "x3");
+        assert_eq!(graph.nodes("aarch64")[0].payload, "a2");
+    }
+
+    #[test]
+    fn nodes_carry_download_size() {
+        let mut rel = release("30.1", &[("x86_64", "x1"), ("aarch64", "a1")]);
+        rel.commits[0].size = Some(1024);
+        let graph = StreamGraph::build(&[rel]);
+        let node = graph.nodes("x86_64").first().unwrap();
+        assert_eq!(node.metadata[metadata::DOWNLOAD_SIZE], "1024");
+        let node = graph.nodes("aarch64").first().unwrap();
+        assert!(!node.metadata.contains_key(metadata::DOWNLOAD_SIZE));
+    }
+
+    #[test]
+    fn graphs_are_built_in_order() {
+        let indexes: Vec<Vec<metadata::Release>> = (0..16)
+            .map(|i| vec![release("30.1", &[("x86_64", &format!("c{}", i))])])
+            .collect();
+        let slices: Vec<&[metadata::Release]> = indexes.iter().map(Vec::as_slice).collect();
+        let graphs = build_graphs(&slices);
+        assert_eq!(graphs.len(), indexes.len());
+        for (i, (graph, _)) in graphs.iter().enumerate() {
+            assert_eq!(graph.nodes("x86_64")[0].payload, format!("c{}", i));
+        }
+        assert!(build_graphs(&[]).is_empty());
+    }
+
+    #[test]
+    fn latest_node_skips_withheld_releases() {
+        let releases = vec![
+            release("1", &[("x86_64", "c1")]),
+            release("2", &[("x86_64", "c2"), ("aarch64", "a2")]),
+            release("3", &[("x86_64", "c3")]),
+        ];
+        let graph = StreamGraph::build(&releases);
+        let latest = |basearch: &str, withheld: &[&str]| {
+            latest_node(&graph, basearch, |node| {
+                !withheld.contains(&node.payload.as_str())
+            })
+            .map(|node| node.map(|node| node.payload))
+        };
+        assert_eq!(latest("x86_64", &[]).unwrap().as_deref(), Some("c3"));
+        assert_eq!(latest("x86_64", &["c3"]).unwrap().as_deref(), Some("c2"));
+        assert_eq!(latest("x86_64", &["c1", "c2", "c3"]).unwrap(), None);
+        assert_eq!(latest("aarch64", &[]).unwrap().as_deref(), Some("a2"));
+        assert!(latest("s390x", &[]).is_err());
+
+        let empty = StreamGraph::build(&[]);
+        assert_eq!(latest_node(&empty, "s390x", |_| true).unwrap(), None);
+    }
+
+    #[test]
+    fn target_node_follows_hop_policy() {
+        let releases: Vec<_> = (1..=5)
+            .map(|i| release(&i.to_string(), &[("x86_64", &format!("c{}", i))]))
+            .collect();
+        let graph = StreamGraph::build(&releases);
+        let target = |current: &str, policy: HopPolicy, withheld: &[&str]| {
+            target_node(&graph, "x86_64", current, policy, |node| {
+                !withheld.contains(&node.payload.as_str())
+            })
+            .unwrap()
+            .map(|node| node.payload)
+        };
+        let stepwise = HopPolicy::Stepwise(2);
+        // Within distance, and unknown clients: latest.
+        assert_eq!(target("c3", stepwise, &[]).as_deref(), Some("c5"));
+        assert_eq!(target("x", stepwise, &[]).as_deref(), Some("c5"));
+        // Too far behind: oldest release within distance.
+        assert_eq!(target("c1", stepwise, &[]).as_deref(), Some("c3"));
+        // Distances only count offered releases.
+        assert_eq!(target("c1", stepwise, &["c4"]).as_deref(), Some("c2"));
+        assert_eq!(target("c1", HopPolicy::Latest, &[]).as_deref(), Some("c5"));
+        assert!(target_node(&graph, "s390x", "c1", stepwise, |_| true).is_err());
+    }
+
+    #[test]
+    fn hop_policies_parse() {
+        assert_eq!("latest".parse::<HopPolicy>().unwrap(), HopPolicy::Latest);
+        assert_eq!(
+            " stepwise:3".parse::<HopPolicy>().unwrap(),
+            HopPolicy::Stepwise(3)
+        );
+        for invalid in &["", "newest", "stepwise", "stepwise:x", "latest:1"] {
+            assert!(invalid.parse::<HopPolicy>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn payloads_are_looked_up_per_arch() {
+        let releases = vec![release("1", &[("x86_64", "c1"), ("aarch64", "a1")])];
+        let graph = StreamGraph::build(&releases);
+        assert_eq!(lookup_node(&graph, "x86_64", "c1").unwrap().version, "1");
+        assert_eq!(lookup_node(&graph, "x86_64", "a1"), None);
+        assert!(has_payload(&releases, "a1"));
+        assert!(!has_payload(&releases, "c2"));
+    }
+
+    #[test]
+    fn fetches_are_buffered_in_order() {
+        let fetches = vec![
+            (
+                "a".to_string(),
+                future::ok(vec![release("1", &[("x86_64", "c1")])]),
+            ),
+            ("b".to_string(), future::err(format_err!("boom"))),
+        ];
+        let refreshed = refresh_streams(fetches, 1).wait().unwrap();
+        assert_eq!(refreshed[0].0, "a");
+        assert_eq!(refreshed[0].1.as_ref().unwrap().len(), 1);
+        assert_eq!(refreshed[1].0, "b");
+        assert!(refreshed[1].1.is_err());
+
+        let mut releases = vec![release("1", &[("x86_64", "c1"), ("aarch64", "a1")])];
+        filter_arches(&mut releases, &BTreeSet::new());
+        assert_eq!(releases[0].commits.len(), 2);
+        filter_arches(&mut releases, &maplit::btreeset!["aarch64".to_string()]);
+        assert_eq!(releases[0].commits[0].checksum, "a1");
+        assert_eq!(releases[0].commits.len(), 1);
+    }
+
+    #[test]
+    fn manual_scheduler_runs_due_refreshes() {
+        let mut sched = ManualScheduler::default();
+        sched.schedule(RefreshScope::All, Duration::from_secs(30));
+        let single = sched.schedule(
+            RefreshScope::Single("s".to_string()),
+            Duration::from_secs(10),
+        );
+        sched.schedule(RefreshScope::Priority, Duration::from_secs(10));
+        assert_eq!(sched.pending(), 3);
+
+        sched.cancel(single);
+        assert_eq!(
+            sched.advance(Duration::from_secs(10)),
+            vec![RefreshScope::Priority]
+        );
+        assert!(sched.advance(Duration::from_secs(5)).is_empty());
+        assert_eq!(
+            sched.advance(Duration::from_secs(15)),
+            vec![RefreshScope::All]
+        );
+        assert_eq!(sched.pending(), 0);
+    }
+
+    type TestCore = Core<<ManualScheduler as Scheduler>::Handle>;
+
+    /// Retry failed streams after a fixed delay.
+    #[derive(Debug)]
+    struct FixedBackoff(Duration);
+
+    impl Backoff for FixedBackoff {
+        fn retry_delay(&self, _: &Error, _: u32, _: Duration) -> Option<Duration> {
+            Some(self.0)
+        }
+    }
+
+    fn streams(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// Run a refresh of `scope`, with a fixed result for every stream.
+    fn refresh(
+        core: &mut TestCore,
+        sched: &mut ManualScheduler,
+        scope: RefreshScope,
+        result: impl Fn() -> Fallible<Vec<metadata::Release>>,
+    ) -> Vec<String> {
+        let refresh = core.begin_refresh(scope, sched.now());
+        let results = refresh
+            .streams
+            .iter()
+            .map(|s| (s.clone(), result()))
+            .collect();
+        core.apply_refresh(&refresh, results, sched.now(), Utc::now());
+        core.finish_refresh(&refresh, sched);
+        refresh.streams
+    }
+
+    #[test]
+    fn manual_scheduler_fires_in_order() {
+        let mut sched = ManualScheduler::default();
+        sched.schedule(RefreshScope::All, secs(20));
+        let cancelled = sched.schedule(RefreshScope::Priority, secs(5));
+        sched.schedule(RefreshScope::Single("a".to_string()), secs(10));
+        sched.cancel(cancelled);
+
+        assert_eq!(sched.advance(secs(9)), vec![]);
+        assert_eq!(
+            sched.advance(secs(1)),
+            vec![RefreshScope::Single("a".to_string())]
+        );
+        assert_eq!(sched.pending(), 1);
+        assert_eq!(sched.advance(secs(60)), vec![RefreshScope::All]);
+        assert_eq!(sched.pending(), 0);
+    }
+
+    #[test]
+    fn core_warms_up_priority_streams_first() {
+        let mut sched = ManualScheduler::default();
+        let mut core =
+            TestCore::new(streams(&["a", "b"]), secs(60)).with_priority_streams(streams(&["p"]));
+        core.start(&mut sched);
+
+        let due = sched.advance(secs(0));
+        assert_eq!(due, vec![RefreshScope::Priority]);
+        let fetched = refresh(&mut core, &mut sched, RefreshScope::Priority, || Ok(vec![]));
+        assert_eq!(fetched, vec!["p"]);
+        assert_eq!(core.pending_streams(), vec!["a", "b"]);
+
+        let due = sched.advance(secs(0));
+        assert_eq!(due, vec![RefreshScope::All]);
+        let fetched = refresh(&mut core, &mut sched, RefreshScope::All, || Ok(vec![]));
+        assert_eq!(fetched, vec!["a", "b", "p"]);
+        assert!(core.pending_streams().is_empty());
+
+        assert_eq!(sched.advance(secs(59)), vec![]);
+        assert_eq!(sched.advance(secs(1)), vec![RefreshScope::All]);
+    }
+
+    #[test]
+    fn core_backs_off_failing_streams() {
+        let mut sched = ManualScheduler::default();
+        let mut core = TestCore::new(streams(&["a"]), secs(60))
+            .with_priority_streams(streams(&["p"]))
+            .with_backoff(Box::new(FixedBackoff(secs(300))));
+        core.start(&mut sched);
+        sched.advance(secs(0));
+
+        let fetched = refresh(&mut core, &mut sched, RefreshScope::Priority, || {
+            Err(format_err!("upstream down"))
+        });
+        assert_eq!(fetched, vec!["p"]);
+        // Warm-up goes on, and failed priority streams are retried early.
+        assert_eq!(sched.advance(secs(0)), vec![RefreshScope::All]);
+        assert_eq!(sched.advance(secs(15)), vec![RefreshScope::Priority]);
+
+        // The backoff delay applies to every scope.
+        let now = sched.now();
+        assert_eq!(core.scope_streams(&RefreshScope::All, now), vec!["a"]);
+        assert!(core.scope_streams(&RefreshScope::Priority, now).is_empty());
+        let later = now + secs(300);
+        assert_eq!(
+            core.scope_streams(&RefreshScope::All, later),
+            vec!["a", "p"]
+        );
+    }
+
+    #[test]
+    fn repeated_errors_are_coalesced() {
+        let now = Instant::now();
+        let mut retry = StreamRetry::default();
+        assert!(retry.record_failure("timeout".to_string(), now));
+        retry.last_logged = Some((now, retry.failures));
+        assert!(!retry.record_failure("timeout".to_string(), now));
+        assert!(retry.record_failure("forbidden".to_string(), now));
+        let later = now + ERROR_SUMMARY_INTERVAL;
+        assert!(retry.record_failure("forbidden".to_string(), later));
+        assert_eq!(retry.failures, 4);
+    }
+
+    #[test]
+    fn core_tracks_failing_priority_streams() {
+        let mut core =
+            TestCore::new(streams(&["other"]), secs(60)).with_priority_streams(streams(&["main"]));
+        assert_eq!(core.streams(), &streams(&["main", "other"]));
+
+        let now = Instant::now();
+        let mut apply = |stream: &str, res| {
+            let refresh = core.begin_refresh(RefreshScope::All, now);
+            core.apply_refresh(&refresh, vec![(stream.to_string(), res)], now, Utc::now());
+            core.priority_failing()
+        };
+        assert!(!apply("other", Err(format_err!("boom"))));
+        assert!(apply("main", Err(format_err!("boom"))));
+        assert!(!apply("main", Ok(vec![])));
+    }
+
+    #[test]
+    fn gated_releases_are_approved_automatically() {
+        let now = Instant::now();
+        let mut gate = ReleaseGate::new(Some(Duration::from_secs(0)));
+        let r1 = release("1", &[("x86_64", "a1")]);
+        let r2 = release("2", &[("x86_64", "a2")]);
+        gate.track(None, std::slice::from_ref(&r1), now);
+        assert!(gate.pending.is_empty());
+        gate.track(Some(&vec![r1.clone()]), &[r1, r2], now);
+        assert!(gate.pending.contains_key("2"));
+        assert!(gate.is_approved("2", now));
+        assert!(gate.withheld(now).is_empty());
+    }
+
+    #[test]
+    fn core_keeps_cache_of_failing_streams() {
+        let mut sched = ManualScheduler::default();
+        let mut core = TestCore::new(streams(&["a"]), secs(60));
+        refresh(&mut core, &mut sched, RefreshScope::All, || {
+            Ok(vec![release("1", &[("x86_64", "c1")])])
+        });
+        refresh(&mut core, &mut sched, RefreshScope::All, || {
+            Err(format_err!("upstream down"))
+        });
+
+        let latest = core.latest("a", "x86_64", None, None, sched.now());
+        assert_eq!(latest.unwrap().unwrap().version, "1");
+    }
+
+    #[test]
+    fn core_drops_overtaken_refreshes() {
+        let sched = ManualScheduler::default();
+        let mut core = TestCore::new(streams(&["a", "b"]), secs(60));
+        let slow = core.begin_refresh(RefreshScope::All, sched.now());
+        let fast = core.begin_refresh(RefreshScope::Single("a".to_string()), sched.now());
+        assert!(slow.generation < fast.generation);
+
+        let fresh = vec![("a".to_string(), Ok(vec![release("2", &[("x86_64", "c2")])]))];
+        core.apply_refresh(&fast, fresh, sched.now(), Utc::now());
+        let stale = vec![
+            ("a".to_string(), Ok(vec![release("1", &[("x86_64", "c1")])])),
+            ("b".to_string(), Ok(vec![release("1", &[("x86_64", "c1")])])),
+        ];
+        let updates = core.apply_refresh(&slow, stale, sched.now(), Utc::now());
+
+        assert_eq!(updates.len(), 1);
+        let latest = core.latest("a", "x86_64", None, None, sched.now());
+        assert_eq!(latest.unwrap().unwrap().version, "2");
+        let latest = core.latest("b", "x86_64", None, None, sched.now());
+        assert_eq!(latest.unwrap().unwrap().version, "1");
+    }
+
+    #[test]
+    fn core_gates_new_releases() {
+        let mut sched = ManualScheduler::default();
+        let gate = ReleaseGate::new(Some(secs(600)));
+        let mut core = TestCore::new(streams(&["a"]), secs(60)).with_release_gate(gate);
+
+        // The first index is approved as a whole.
+        refresh(&mut core, &mut sched, RefreshScope::All, || {
+            Ok(vec![release("1", &[("x86_64", "c1")])])
+        });
+        refresh(&mut core, &mut sched, RefreshScope::All, || {
+            Ok(vec![
+                release("1", &[("x86_64", "c1")]),
+                release("2", &[("x86_64", "c2")]),
+            ])
+        });
+        let latest = core.latest("a", "x86_64", None, None, sched.now());
+        assert_eq!(latest.unwrap().unwrap().version, "1");
+        assert_eq!(core.withheld(sched.now()), streams(&["2"]));
+
+        sched.advance(secs(600));
+        let latest = core.latest("a", "x86_64", None, None, sched.now());
+        assert_eq!(latest.unwrap().unwrap().version, "2");
+        assert!(core.withheld(sched.now()).is_empty());
+    }
+
+    #[test]
+    fn core_approves_gated_releases() {
+        let mut sched = ManualScheduler::default();
+        let mut core =
+            TestCore::new(streams(&["a"]), secs(60)).with_release_gate(ReleaseGate::new(None));
+        refresh(&mut core, &mut sched, RefreshScope::All, || Ok(vec![]));
+        refresh(&mut core, &mut sched, RefreshScope::All, || {
+            Ok(vec![release("1", &[("x86_64", "c1")])])
+        });
+
+        assert_eq!(
+            core.latest("a", "x86_64", None, None, sched.now()),
+            Ok(None)
+        );
+        assert!(core.approve("1"));
+        assert!(!core.approve("1"));
+        let latest = core.latest("a", "x86_64", None, None, sched.now());
+        assert_eq!(latest.unwrap().unwrap().version, "1");
+    }
+
+    #[test]
+    fn core_delays_recently_seen_releases() {
+        let mut sched = ManualScheduler::default();
+        let mut core = TestCore::new(streams(&["a"]), secs(60));
+        refresh(&mut core, &mut sched, RefreshScope::All, || {
+            Ok(vec![release("1", &[("x86_64", "c1")])])
+        });
+        sched.advance(secs(10));
+        refresh(&mut core, &mut sched, RefreshScope::All, || {
+            Ok(vec![
+                release("1", &[("x86_64", "c1")]),
+                release("2", &[("x86_64", "c2")]),
+            ])
+        });
+        assert!(core.first_seen("1").is_none());
+        assert_eq!(core.first_seen("2"), Some(sched.now()));
+
+        let delay = Some(secs(3600));
+        let latest = core.latest("a", "x86_64", None, delay, sched.now());
+        assert_eq!(latest.unwrap().unwrap().version, "1");
+        sched.advance(secs(3600));
+        let latest = core.latest("a", "x86_64", None, delay, sched.now());
+        assert_eq!(latest.unwrap().unwrap().version, "2");
+    }
+
+    #[test]
+    fn core_ignores_refreshes_while_frozen() {
+        let mut sched = ManualScheduler::default();
+        let imported =
+            maplit::btreemap! { "a".to_string() => vec![release("1", &[("x86_64", "c1")])] };
+        let mut core = TestCore::new(streams(&["b"]), secs(60)).with_imported(imported);
+        assert!(core.is_frozen());
+        assert_eq!(core.pending_streams(), vec!["b"]);
+
+        refresh(&mut core, &mut sched, RefreshScope::All, || {
+            Ok(vec![release("2", &[("x86_64", "c2")])])
+        });
+        let latest = core.latest("a", "x86_64", None, None, sched.now());
+        assert_eq!(latest.unwrap().unwrap().version, "1");
+        assert_eq!(core.pending_streams(), vec!["b"]);
+    }
+
+    #[test]
+    fn core_reports_cache_misses() {
+        let core = TestCore::new(streams(&["a"]), secs(60));
+        let now = Instant::now();
+        assert_eq!(
+            core.latest("a", "x86_64", None, None, now),
+            Err(CacheMiss::EmptyCache("a".to_string()))
+        );
+        assert_eq!(
+            core.latest("b", "x86_64", None, None, now),
+            Err(CacheMiss::UnknownStream("b".to_string()))
+        );
+    }
+
+    #[test]
+    fn core_builds_graphs_of_refreshed_streams() {
+        let names: Vec<String> = (0..16).map(|i| format!("s{}", i)).collect();
+        let mut sched = ManualScheduler::default();
+        let mut core = TestCore::new(names.iter().cloned().collect(), secs(60));
+        let refresh = core.begin_refresh(RefreshScope::All, sched.now());
+        let results = refresh
+            .streams
+            .iter()
+            .map(|s| {
+                let res = match s.as_str() {
+                    "s3" => Err(format_err!("boom")),
+                    s => Ok(vec![release("1", &[("x86_64", &format!("{}-1", s))])]),
+                };
+                (s.clone(), res)
+            })
+            .collect();
+        let updates = core.apply_refresh(&refresh, results, sched.now(), Utc::now());
+        core.finish_refresh(&refresh, &mut sched);
+        assert_eq!(updates.len(), names.len());
+
+        let now = sched.now();
+        for name in &names {
+            let latest = core.latest(name, "x86_64", None, None, now);
+            match name.as_str() {
+                "s3" => assert_eq!(latest, Err(CacheMiss::EmptyCache(name.clone()))),
+                _ => assert_eq!(latest.unwrap().unwrap().payload, format!("{}-1", name)),
+            }
+            assert_eq!(
+                core.lookup(name, "x86_64", &format!("{}-1", name))
+                    .is_some(),
+                name != "s3"
+            );
+        }
+        assert_eq!(
+            core.latest("s0", "aarch64", None, None, now),
+            Err(CacheMiss::UnavailableBasearch("aarch64".to_string()))
+        );
+    }
+
+    #[test]
+    fn core_keeps_releases_of_emptied_streams() {
+        let mut sched = ManualScheduler::default();
+        let mut core = TestCore::new(streams(&["e", "new"]), secs(60));
+        let index = || Ok(vec![release("1", &[("x86_64", "e1")])]);
+        let refreshed = refresh(&mut core, &mut sched, RefreshScope::All, index);
+        assert_eq!(refreshed.len(), 2);
+
+        let now = sched.now();
+        let empty = core.begin_refresh(RefreshScope::All, now);
+        let results = vec![("e".to_string(), Ok(vec![]))];
+        let updates = core.apply_refresh(&empty, results, now, Utc::now());
+        match updates.as_slice() {
+            [StreamUpdate::Empty { stream, emptied }] => {
+                assert_eq!(stream, "e");
+                assert!(emptied);
+            }
+            _ => panic!("unexpected updates: {:?}", updates),
+        }
+        assert_eq!(core.releases("e").unwrap().len(), 1);
+        assert_eq!(core.empty_streams(), &streams(&["e"]));
+        assert_eq!(core.latest("e", "x86_64", None, None, now), Ok(None));
+        assert!(core.lookup("e", "x86_64", "e1").is_some());
+        assert!(core
+            .latest("new", "x86_64", None, None, now)
+            .unwrap()
+            .is_some());
+
+        // Only the first empty refresh is reported as a change.
+        let updates =
+            core.apply_refresh(&empty, vec![("e".to_string(), Ok(vec![]))], now, Utc::now());
+        match updates.as_slice() {
+            [StreamUpdate::Empty { emptied, .. }] => assert!(!emptied),
+            _ => panic!("unexpected updates: {:?}", updates),
+        }
+
+        refresh(&mut core, &mut sched, RefreshScope::All, index);
+        assert!(core.empty_streams().is_empty());
+        let latest = core.latest("e", "x86_64", None, None, now).unwrap();
+        assert_eq!(latest.unwrap().payload, "e1");
+    }
+
+    #[test]
+    fn core_caches_streams_empty_from_the_start() {
+        let mut sched = ManualScheduler::default();
+        let mut core = TestCore::new(streams(&["e"]), secs(60));
+        refresh(&mut core, &mut sched, RefreshScope::All, || Ok(vec![]));
+        assert!(core.pending_streams().is_empty());
+        assert!(core.releases("e").unwrap().is_empty());
+        let now = sched.now();
+        assert_eq!(core.latest("e", "x86_64", None, None, now), Ok(None));
+    }
+
+    #[test]
+    fn core_stops_scheduling_on_shutdown() {
+        let mut sched = ManualScheduler::default();
+        let mut core = TestCore::new(streams(&["a", "s"]), secs(60))
+            .with_stream_refresh_intervals(maplit::btreemap! { "s".to_string() => secs(10) });
+        core.start(&mut sched);
+        assert_eq!(sched.pending(), 2);
+
+        core.shutdown(&mut sched);
+        assert_eq!(sched.pending(), 1);
+        let due = sched.advance(secs(0));
+        assert_eq!(due, vec![RefreshScope::All]);
+        assert!(refresh(&mut core, &mut sched, RefreshScope::All, || Ok(vec![])).is_empty());
+        assert_eq!(sched.pending(), 0);
+    }
+}
@@ -0,0 +1,247 @@
+//! Error responses, in Cincinnati JSON format.
+
+use crate::engine;
+use actix::MailboxError;
+use actix_web::http::{header, StatusCode};
+use actix_web::HttpResponse;
+use failure::Error;
+use prometheus::{IntCounter, IntCounterVec};
+use serde_derive::Serialize;
+use std::time::Duration;
+
+lazy_static::lazy_static! {
+    static ref BACKEND_UNAVAILABLE: IntCounter = register_int_counter!(opts!(
+        "fakeup_backend_unavailable_total",
+        "Total number of requests failed due to an unreachable scraper actor"
+    ))
+    .unwrap();
+    static ref GRAPH_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "fakeup_graph_errors_total",
+        "Total number of graph requests failed with a client-visible error, by kind",
+        &["kind"]
+    )
+    .unwrap();
+}
+
+/// Error body, as `{"kind": ..., "value": ...}`.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ErrorBody {
+    pub(crate) kind: String,
+    pub(crate) value: String,
+}
+
+impl ErrorBody {
+    pub(crate) fn new(kind: &str, value: impl ToString) -> Self {
+        Self {
+            kind: kind.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+/// Graph request failure, reported with its own error kind.
+#[derive(Debug)]
+pub(crate) enum GraphError {
+    /// Missing or wrong bearer token.
+    Unauthorized(&'static str),
+    /// Non-GET request.
+    MethodNotAllowed,
+    /// Shed beyond the in-flight limit.
+    Overloaded,
+    /// Node over its request quota, until this delay elapses.
+    OverQuota(Duration),
+    /// No `stream` parameter.
+    MissingStream,
+    /// No `os_checksum` (or `current_os`) parameter.
+    MissingChecksum,
+    /// Stream name rejected by the stream pattern.
+    InvalidStream(String),
+    /// Architecture not served.
+    InvalidBasearch(String),
+    /// Malformed client OS checksum, in strict mode.
+    InvalidChecksum(String),
+    /// Client platform not served (or missing).
+    UnservedPlatform(Option<String>),
+    /// Stream not served by this instance.
+    UnknownStream(String),
+    /// Stream has releases, but none for the client architecture.
+    UnavailableBasearch(String),
+    /// Stream served, but without releases cached yet.
+    EmptyCache(String),
+    /// Scraper actor unreachable.
+    BackendUnavailable(String),
+    /// Graph rejected by the edge validator.
+    InvalidGraph(String),
+    /// Response template failed to render.
+    FailedTemplate(String),
+    /// Any other server-side failure.
+    Internal(String),
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::Unauthorized(reason) => write!(f, "{}", reason),
+            GraphError::MethodNotAllowed => write!(f, "only GET is allowed"),
+            GraphError::Overloaded => write!(f, "too many in-flight requests"),
+            GraphError::OverQuota(_) => write!(f, "node over request quota"),
+            GraphError::MissingStream => write!(f, "missing client stream"),
+            GraphError::MissingChecksum => write!(f, "missing client OS checksum"),
+            GraphError::InvalidStream(reason)
+            | GraphError::InvalidBasearch(reason)
+            | GraphError::InvalidChecksum(reason) => write!(f, "{}", reason),
+            GraphError::UnservedPlatform(Some(platform)) => {
+                write!(f, "platform '{}' is not served", platform)
+            }
+            GraphError::UnservedPlatform(None) => write!(f, "missing platform"),
+            GraphError::UnknownStream(stream) => write!(f, "unknown stream '{}'", stream),
+            GraphError::UnavailableBasearch(basearch) => {
+                write!(f, "no releases for basearch '{}'", basearch)
+            }
+            GraphError::EmptyCache(stream) => {
+                write!(f, "no releases cached yet for stream '{}'", stream)
+            }
+            GraphError::BackendUnavailable(reason) => write!(f, "scraper unavailable: {}", reason),
+            GraphError::InvalidGraph(reason) => write!(f, "invalid graph: {}", reason),
+            GraphError::FailedTemplate(reason) => {
+                write!(f, "failed to render response template: {}", reason)
+            }
+            GraphError::Internal(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+impl From<engine::CacheMiss> for GraphError {
+    fn from(miss: engine::CacheMiss) -> Self {
+        match miss {
+            engine::CacheMiss::UnknownStream(stream) => GraphError::UnknownStream(stream),
+            engine::CacheMiss::EmptyCache(stream) => GraphError::EmptyCache(stream),
+            engine::CacheMiss::UnavailableBasearch(basearch) => {
+                GraphError::UnavailableBasearch(basearch)
+            }
+        }
+    }
+}
+
+impl GraphError {
+    fn kind(&self) -> &'static str {
+        match self {
+            GraphError::Unauthorized(_) => "unauthorized",
+            GraphError::MethodNotAllowed => "method_not_allowed",
+            GraphError::Overloaded => "overloaded",
+            GraphError::OverQuota(_) => "over_quota",
+            GraphError::MissingStream => "missing_stream",
+            GraphError::MissingChecksum => "missing_checksum",
+            GraphError::InvalidStream(_) => "invalid_stream",
+            GraphError::InvalidBasearch(_) => "invalid_basearch",
+            GraphError::InvalidChecksum(_) => "invalid_checksum",
+            GraphError::UnservedPlatform(_) => "unserved_platform",
+            GraphError::UnknownStream(_) => "unknown_stream",
+            GraphError::UnavailableBasearch(_) => "unavailable_basearch",
+            GraphError::EmptyCache(_) => "empty_cache",
+            GraphError::BackendUnavailable(_) => "backend_unavailable",
+            GraphError::InvalidGraph(_) => "invalid_graph",
+            GraphError::FailedTemplate(_) => "failed_template",
+            GraphError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            GraphError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            GraphError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            GraphError::OverQuota(_) => StatusCode::TOO_MANY_REQUESTS,
+            GraphError::Overloaded
+            | GraphError::EmptyCache(_)
+            | GraphError::BackendUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            GraphError::InvalidGraph(_)
+            | GraphError::FailedTemplate(_)
+            | GraphError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Error reply, with its JSON body and protocol headers.
+    pub(crate) fn response(&self) -> HttpResponse {
+        let kind = self.kind();
+        GRAPH_ERRORS.with_label_values(&[kind]).inc();
+        log::trace!("graph request failed ({}): {}", kind, self);
+        let mut resp = HttpResponse::build(self.status());
+        match self {
+            GraphError::Unauthorized(_) => {
+                resp.header(header::WWW_AUTHENTICATE, "Bearer");
+            }
+            GraphError::MethodNotAllowed => {
+                resp.header(header::ALLOW, "GET");
+            }
+            GraphError::Overloaded => {
+                let secs = crate::inflight::RETRY_AFTER_SECS;
+                resp.header(header::RETRY_AFTER, secs.to_string());
+            }
+            GraphError::OverQuota(retry_after) => {
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                resp.header(header::RETRY_AFTER, secs.to_string());
+            }
+            _ => {}
+        }
+        resp.json(ErrorBody::new(kind, self))
+    }
+}
+
+/// Turn any graph request failure into an error reply.
+///
+/// Failures without a specific kind are reported as `internal_error`.
+pub(crate) fn graph_error(err: Error) -> HttpResponse {
+    if let Some(e) = err.downcast_ref::<GraphError>() {
+        return e.response();
+    }
+    if let Some(e) = err.downcast_ref::<MailboxError>() {
+        BACKEND_UNAVAILABLE.inc();
+        log::error!("scraper unavailable: {}", e);
+        return GraphError::BackendUnavailable(e.to_string()).response();
+    }
+    log::error!("graph request failed: {}", err);
+    GraphError::Internal(err.to_string()).response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn mailbox_errors_are_backend_unavailable() {
+        let resp = graph_error(MailboxError::Closed.into());
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let resp = graph_error(failure::format_err!("stream unavailable"));
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn graph_errors_carry_their_kind() {
+        let resp = graph_error(GraphError::UnknownStream("foo".to_string()).into());
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = graph_error(GraphError::EmptyCache("stable".to_string()).into());
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(GraphError::MissingChecksum.kind(), "missing_checksum");
+
+        let before = GRAPH_ERRORS.with_label_values(&["internal_error"]).get();
+        graph_error(failure::format_err!("scraper failure"));
+        let after = GRAPH_ERRORS.with_label_values(&["internal_error"]).get();
+        assert!(after > before);
+    }
+
+    #[test]
+    fn protocol_headers_follow_the_kind() {
+        let resp = GraphError::MethodNotAllowed.response();
+        assert_eq!(resp.headers()[header::ALLOW], "GET");
+        let resp = GraphError::OverQuota(Duration::from_millis(1500)).response();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "2");
+        let resp = GraphError::Unauthorized("missing token").response();
+        assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+}
@@ -0,0 +1,144 @@
+//! Atom feeds of newly cached releases, per stream.
+
+use crate::metadata;
+use crate::scraper;
+use crate::AppState;
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use failure::Error;
+use futures::future;
+use futures::prelude::*;
+use std::fmt::Write;
+
+/// Release announced in a stream feed.
+#[derive(Clone, Debug)]
+pub(crate) struct FeedEntry {
+    pub(crate) version: String,
+    /// When the release was first scraped.
+    pub(crate) timestamp: DateTime<Utc>,
+    /// Payloads of the release, if still in the release index.
+    pub(crate) commits: Vec<metadata::ReleaseCommit>,
+}
+
+/// Serve the Atom feed of releases added to a stream.
+pub(crate) fn serve_feed(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let stream = match req.match_info().get("stream") {
+        Some(s) if req.state().stream_pattern.is_match(s) => s.to_string(),
+        _ => return Box::new(future::ok(HttpResponse::NotFound().finish())),
+    };
+    let msg = scraper::GetReleaseFeed {
+        stream: stream.clone(),
+    };
+    let resp = scraper::request(&req.state().scraper_addr, msg).map(move |entries| {
+        let entries = match entries {
+            Some(e) => e,
+            None => return HttpResponse::NotFound().finish(),
+        };
+        HttpResponse::Ok()
+            .content_type("application/atom+xml")
+            .body(render(&stream, &entries))
+    });
+    Box::new(resp)
+}
+
+/// Render an Atom document, newest entries first.
+fn render(stream: &str, entries: &[FeedEntry]) -> String {
+    let updated = entries
+        .iter()
+        .map(|e| e.timestamp)
+        .max()
+        .unwrap_or_else(crate::clock::now);
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    let _ = writeln!(xml, "  <id>urn:fakeup:stream:{}</id>", escape(stream));
+    let _ = writeln!(
+        xml,
+        "  <title>fakeup: new releases on stream {}</title>",
+        escape(stream)
+    );
+    let _ = writeln!(xml, "  <updated>{}</updated>", rfc3339(updated));
+    xml.push_str("  <author><name>fakeup</name></author>\n");
+
+    for entry in entries.iter().rev() {
+        xml.push_str("  <entry>\n");
+        let _ = writeln!(
+            xml,
+            "    <id>urn:fakeup:stream:{}:release:{}</id>",
+            escape(stream),
+            escape(&entry.version)
+        );
+        let _ = writeln!(xml, "    <title>{}</title>", escape(&entry.version));
+        let _ = writeln!(xml, "    <updated>{}</updated>", rfc3339(entry.timestamp));
+        let mut content = String::new();
+        for commit in &entry.commits {
+            let _ = writeln!(content, "{}: {}", commit.architecture, commit.checksum);
+        }
+        let _ = writeln!(
+            xml,
+            "    <content type=\"text\">{}</content>",
+            escape(&content)
+        );
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn rfc3339(ts: DateTime<Utc>) -> String {
+    ts.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Escape text for XML content and attributes.
+fn escape(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn entries_are_rendered_newest_first() {
+        let entry = |version: &str, secs| FeedEntry {
+            version: version.to_string(),
+            timestamp: Utc.timestamp_opt(secs, 0).unwrap(),
+            commits: vec![metadata::ReleaseCommit {
+                architecture: "x86_64".to_string(),
+                checksum: format!("sum-{}", version),
+                size: None,
+            }],
+        };
+        let xml = render("stable", &[entry("1", 1000), entry("2<", 2000)]);
+
+        assert!(xml.contains("<updated>1970-01-01T00:33:20Z</updated>\n  <author>"));
+        assert!(xml.contains("<title>2&lt;</title>"));
+        assert!(xml.contains("x86_64: sum-1"));
+        let newer = xml.find("release:2&lt;").unwrap();
+        let older = xml.find("release:1<").unwrap();
+        assert!(newer < older);
+    }
+
+    #[test]
+    fn markup_is_escaped() {
+        assert_eq!(
+            escape(r#"<a href="x">&'"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&apos;"
+        );
+    }
+}
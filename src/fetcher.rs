@@ -0,0 +1,231 @@
+//! Upstream HTTP clients, behind the `HttpFetcher` trait.
+//!
+//! The client is reqwest by default (`http-reqwest` feature), or ureq on
+//! blocking worker threads (`http-ureq` feature), which trims the
+//! dependency tree for constrained CI runners.
+
+use crate::tls;
+use failure::{Error, Fallible};
+use futures::prelude::*;
+use std::sync::Arc;
+
+#[cfg(not(any(feature = "http-reqwest", feature = "http-ureq")))]
+compile_error!("an upstream HTTP client is required, enable `http-reqwest` or `http-ureq`");
+
+/// Pending fetch of a response body.
+pub(crate) type FetchFuture = Box<dyn Future<Item = Vec<u8>, Error = Error> + Send>;
+
+/// HTTP client for upstream metadata.
+pub(crate) trait HttpFetcher: std::fmt::Debug + Send + Sync {
+    /// Fetch the body at `url`, with additional request headers.
+    ///
+    /// HTTP error statuses fail with a `retry::UpstreamError`.
+    fn get(&self, url: &url::Url, headers: Vec<(String, String)>) -> FetchFuture;
+}
+
+/// Build the upstream HTTP client, with custom trust settings.
+pub(crate) fn new_fetcher(tls: &tls::UpstreamTls) -> Fallible<Arc<dyn HttpFetcher>> {
+    #[cfg(feature = "http-ureq")]
+    let fetcher = ureq_backend::UreqFetcher::new(tls)?;
+    #[cfg(all(feature = "http-reqwest", not(feature = "http-ureq")))]
+    let fetcher = reqwest_backend::ReqwestFetcher::new(tls)?;
+    Ok(Arc::new(fetcher))
+}
+
+/// Fetch a body outside of a running actix system, with default trust.
+pub(crate) fn get_blocking(url: &url::Url) -> Fallible<Vec<u8>> {
+    let fetcher = new_fetcher(&Default::default())?;
+    actix::System::new("fakeup-fetch").block_on(fetcher.get(url, vec![]))
+}
+
+#[cfg(all(feature = "http-reqwest", not(feature = "http-ureq")))]
+mod reqwest_backend {
+    use super::{FetchFuture, HttpFetcher};
+    use crate::{retry, tls};
+    use failure::{format_err, Error, Fallible};
+    use futures::prelude::*;
+    use reqwest::r#async::{Client, ClientBuilder};
+
+    /// Asynchronous reqwest client.
+    #[derive(Debug)]
+    pub(crate) struct ReqwestFetcher {
+        client: Client,
+    }
+
+    impl ReqwestFetcher {
+        pub(crate) fn new(tls: &tls::UpstreamTls) -> Fallible<Self> {
+            let mut builder = ClientBuilder::new();
+            for pem in tls.ca_certs()? {
+                let cert = reqwest::Certificate::from_pem(pem.as_bytes())
+                    .map_err(|e| format_err!("invalid upstream CA certificate: {}", e))?;
+                builder = builder.add_root_certificate(cert);
+            }
+            if tls.insecure {
+                builder = builder.danger_accept_invalid_certs(true);
+            }
+            let client = builder.build()?;
+            Ok(Self { client })
+        }
+    }
+
+    impl HttpFetcher for ReqwestFetcher {
+        fn get(&self, url: &url::Url, headers: Vec<(String, String)>) -> FetchFuture {
+            let mut req = self.client.get(url.as_str());
+            for (name, value) in headers {
+                req = req.header(name.as_str(), value.as_str());
+            }
+            let fut = req
+                .send()
+                .from_err()
+                .and_then(|resp| {
+                    let status = resp.status();
+                    if status.is_client_error() || status.is_server_error() {
+                        let retry_after = resp
+                            .headers()
+                            .get(reqwest::header::RETRY_AFTER)
+                            .and_then(|v| v.to_str().ok());
+                        let err = retry::UpstreamError::from_response(status.as_u16(), retry_after);
+                        return Err(Error::from(err));
+                    }
+                    Ok(resp)
+                })
+                .and_then(|resp| resp.into_body().concat2().from_err())
+                .map(|body| body.to_vec());
+            Box::new(fut)
+        }
+    }
+}
+
+#[cfg(feature = "http-ureq")]
+mod ureq_backend {
+    use super::{FetchFuture, HttpFetcher};
+    use crate::{retry, tls};
+    use failure::{format_err, Fallible};
+    use futures::future;
+    use futures::prelude::*;
+    use futures::sync::oneshot;
+    use std::io::Read;
+    use std::sync::Arc;
+
+    /// Blocking ureq client, running each fetch on a worker thread.
+    #[derive(Debug)]
+    pub(crate) struct UreqFetcher {
+        agent: ureq::Agent,
+    }
+
+    impl UreqFetcher {
+        pub(crate) fn new(tls: &tls::UpstreamTls) -> Fallible<Self> {
+            let mut builder = native_tls::TlsConnector::builder();
+            for pem in tls.ca_certs()? {
+                let cert = native_tls::Certificate::from_pem(pem.as_bytes())
+                    .map_err(|e| format_err!("invalid upstream CA certificate: {}", e))?;
+                builder.add_root_certificate(cert);
+            }
+            if tls.insecure {
+                builder.danger_accept_invalid_certs(true);
+            }
+            let connector = builder.build()?;
+            let agent = ureq::AgentBuilder::new()
+                .tls_connector(Arc::new(connector))
+                .build();
+            Ok(Self { agent })
+        }
+    }
+
+    impl HttpFetcher for UreqFetcher {
+        fn get(&self, url: &url::Url, headers: Vec<(String, String)>) -> FetchFuture {
+            let agent = self.agent.clone();
+            let url = url.to_string();
+            let (tx, rx) = oneshot::channel();
+            let worker = std::thread::Builder::new()
+                .name("fakeup-fetch".to_string())
+                .spawn(move || {
+                    let _ = tx.send(fetch(&agent, &url, &headers));
+                });
+            if let Err(e) = worker {
+                let err = format_err!("failed to start fetch worker: {}", e);
+                return Box::new(future::err(err));
+            }
+            let fut = rx
+                .map_err(|_| format_err!("fetch worker exited early"))
+                .and_then(future::result);
+            Box::new(fut)
+        }
+    }
+
+    /// Fetch a body, blocking.
+    fn fetch(agent: &ureq::Agent, url: &str, headers: &[(String, String)]) -> Fallible<Vec<u8>> {
+        let mut req = agent.get(url);
+        for (name, value) in headers {
+            req = req.set(name, value);
+        }
+        let resp = match req.call() {
+            Ok(resp) => resp,
+            Err(ureq::Error::Status(status, resp)) => {
+                let err = retry::UpstreamError::from_response(status, resp.header("Retry-After"));
+                return Err(err.into());
+            }
+            Err(e) => return Err(format_err!("{}", e)),
+        };
+        let mut body = Vec::new();
+        resp.into_reader().read_to_end(&mut body)?;
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    /// Answer a single request with a canned response, reporting its headers.
+    fn serve_once(response: &'static str) -> (url::Url, mpsc::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/index.json", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let lines: Vec<String> = BufReader::new(conn.try_clone().unwrap())
+                .lines()
+                .map(Result::unwrap)
+                .take_while(|line| !line.is_empty())
+                .collect();
+            conn.write_all(response.as_bytes()).unwrap();
+            tx.send(lines).unwrap();
+        });
+        (url::Url::parse(&url).unwrap(), rx)
+    }
+
+    #[test]
+    fn bodies_are_fetched_with_headers() {
+        let (url, rx) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\n{\"a\":1}",
+        );
+        let fetcher = new_fetcher(&Default::default()).unwrap();
+        let headers = vec![("X-Test".to_string(), "value".to_string())];
+        let body = actix::System::new("fetch-body")
+            .block_on(fetcher.get(&url, headers))
+            .unwrap();
+        assert_eq!(body, b"{\"a\":1}");
+        let request = rx.recv().unwrap();
+        assert!(request[0].starts_with("GET /index.json "));
+        assert!(request
+            .iter()
+            .any(|line| line.eq_ignore_ascii_case("x-test: value")));
+    }
+
+    #[test]
+    fn error_statuses_are_upstream_errors() {
+        let (url, _rx) = serve_once(
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 120\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        let err = get_blocking(&url).unwrap_err();
+        let upstream = err.downcast_ref::<retry::UpstreamError>().unwrap();
+        assert_eq!(upstream.status, 503);
+        assert_eq!(upstream.retry_after, Some(Duration::from_secs(120)));
+    }
+}
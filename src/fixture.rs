@@ -0,0 +1,160 @@
+//! Fixture bundles, for reproducing server state elsewhere.
+//!
+//! A bundle is a gzip-compressed tarball containing the server
+//! configuration (`fixture/config.json`) and the cached release index
+//! of each stream (`fixture/releases/<stream>.json`).
+
+use crate::fetcher;
+use crate::metadata;
+use crate::ClientVersion;
+use failure::{format_err, Fallible};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::Path;
+
+/// Top-level directory in a bundle.
+static BUNDLE_DIR: &str = "fixture";
+
+/// Snapshot of server state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Fixture {
+    pub(crate) config: FixtureConfig,
+    /// Cached release index per stream, in upstream order (oldest first).
+    pub(crate) releases: BTreeMap<String, Vec<metadata::Release>>,
+}
+
+/// Server configuration captured in a fixture.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct FixtureConfig {
+    pub(crate) streams: BTreeSet<String>,
+    pub(crate) priority_streams: BTreeSet<String>,
+    pub(crate) client_version: ClientVersion,
+    pub(crate) frozen: bool,
+}
+
+impl Fixture {
+    /// Fetch a fixture from the admin API of a running instance.
+    pub(crate) fn fetch(base: &str) -> Fallible<Self> {
+        let path = format!("{}/admin/fixture", crate::router::FAKEUP_PREFIX);
+        let url = url::Url::parse(base)?.join(&path)?;
+        let body = fetcher::get_blocking(&url)?;
+        let fixture = serde_json::from_slice(&body)?;
+        Ok(fixture)
+    }
+
+    /// Write this fixture as a bundle.
+    pub(crate) fn write_bundle(&self, path: &Path) -> Fallible<()> {
+        let file = std::fs::File::create(path)?;
+        let gz = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        let mut bundle = tar::Builder::new(gz);
+
+        let config = serde_json::to_vec_pretty(&self.config)?;
+        append_file(&mut bundle, "config.json", &config)?;
+        for (stream, releases) in &self.releases {
+            let index = metadata::ReleasesJSON {
+                releases: releases.clone(),
+            };
+            let json = serde_json::to_vec_pretty(&index)?;
+            append_file(&mut bundle, &format!("releases/{}.json", stream), &json)?;
+        }
+
+        bundle.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    /// Read a fixture from a bundle.
+    pub(crate) fn read_bundle(path: &Path) -> Fallible<Self> {
+        let file = std::fs::File::open(path)?;
+        let mut bundle = tar::Archive::new(flate2::read::GzDecoder::new(file));
+
+        let mut config = None;
+        let mut releases = BTreeMap::new();
+        for entry in bundle.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let name = match name.strip_prefix(BUNDLE_DIR) {
+                Some(name) => name.trim_start_matches('/').to_string(),
+                None => continue,
+            };
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+
+            if name == "config.json" {
+                config = Some(serde_json::from_slice::<FixtureConfig>(&content)?);
+            } else if let Some(stream) = name
+                .strip_prefix("releases/")
+                .and_then(|n| n.strip_suffix(".json"))
+            {
+                let index: metadata::ReleasesJSON = serde_json::from_slice(&content)?;
+                releases.insert(stream.to_string(), index.releases);
+            }
+        }
+
+        let config =
+            config.ok_or_else(|| format_err!("missing '{}/config.json' in bundle", BUNDLE_DIR))?;
+        Ok(Self { config, releases })
+    }
+}
+
+/// Append a regular file to the bundle directory.
+fn append_file<W: std::io::Write>(
+    bundle: &mut tar::Builder<W>,
+    name: &str,
+    content: &[u8],
+) -> Fallible<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_cksum();
+    let path = format!("{}/{}", BUNDLE_DIR, name);
+    bundle.append_data(&mut header, path, content)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_round_trip() {
+        let release = metadata::Release {
+            commits: vec![metadata::ReleaseCommit {
+                architecture: "x86_64".to_string(),
+                checksum: "x1".to_string(),
+                size: Some(1024),
+            }],
+            version: "30.1".to_string(),
+            metadata: String::new(),
+        };
+        let fixture = Fixture {
+            config: FixtureConfig {
+                streams: maplit::btreeset!["stable".to_string(), "testing".to_string()],
+                priority_streams: maplit::btreeset!["stable".to_string()],
+                client_version: ClientVersion::Derived,
+                frozen: false,
+            },
+            releases: maplit::btreemap! {
+                "stable".to_string() => vec![release],
+                "testing".to_string() => vec![],
+            },
+        };
+
+        let path =
+            std::env::temp_dir().join(format!("fakeup-fixture-{}.tar.gz", std::process::id()));
+        fixture.write_bundle(&path).unwrap();
+        let read = Fixture::read_bundle(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.config.streams, fixture.config.streams);
+        assert_eq!(
+            read.config.priority_streams,
+            fixture.config.priority_streams
+        );
+        assert_eq!(read.config.client_version, ClientVersion::Derived);
+        assert_eq!(read.releases.len(), 2);
+        assert_eq!(read.releases["stable"][0].commits[0].size, Some(1024));
+        assert!(read.releases["testing"].is_empty());
+    }
+}
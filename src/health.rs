@@ -0,0 +1,108 @@
+//! Health probes, for container orchestrators.
+
+use crate::scraper;
+use crate::AppState;
+use actix_web::{HttpRequest, HttpResponse};
+use failure::Error;
+use futures::prelude::*;
+use std::time::Duration;
+
+/// Maximum time for the scraper to answer a liveness or readiness probe.
+static PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Liveness: the server answers, and the scraper actor processes messages.
+pub(crate) fn serve_healthz(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let ping = scraper::request(&req.state().scraper_addr, scraper::Ping);
+    let probe = tokio_timer::Timeout::new(ping, PROBE_TIMEOUT).then(|res| {
+        let resp = match res {
+            Ok(()) => HttpResponse::Ok().body("ok\n"),
+            Err(e) => {
+                log::warn!("liveness probe failed: {}", e);
+                HttpResponse::ServiceUnavailable().body("scraper unresponsive\n")
+            }
+        };
+        Ok(resp)
+    });
+    Box::new(probe)
+}
+
+/// Readiness: every configured stream has been populated once, whether
+/// scraped or imported, even while the cache is frozen.
+pub(crate) fn serve_readyz(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let pending = scraper::request(&req.state().scraper_addr, scraper::PendingStreams);
+    let probe = tokio_timer::Timeout::new(pending, PROBE_TIMEOUT).then(|res| {
+        let resp = match res {
+            Ok(ref pending) if pending.is_empty() => HttpResponse::Ok().body("ok\n"),
+            Ok(pending) => HttpResponse::ServiceUnavailable()
+                .body(format!("waiting for streams: {}\n", pending.join(", "))),
+            Err(e) => {
+                log::warn!("readiness probe failed: {}", e);
+                HttpResponse::ServiceUnavailable().body("scraper unresponsive\n")
+            }
+        };
+        Ok(resp)
+    });
+    Box::new(probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_state;
+    use actix::prelude::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn liveness_follows_scraper() {
+        // An actor whose system already stopped never answers.
+        let dead = std::thread::spawn(|| {
+            let sys = System::new("healthz-dead");
+            let scraper =
+                scraper::Scraper::new(btreeset![], Duration::from_secs(30), Default::default())
+                    .unwrap();
+            let addr = scraper.start();
+            System::current().stop();
+            sys.run();
+            addr
+        })
+        .join()
+        .unwrap();
+
+        let mut sys = System::new("healthz");
+        let state = test_state();
+        let req = TestRequest::with_state(state.clone()).finish();
+        let resp = sys.block_on(serve_healthz(req)).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let mut state = state;
+        state.scraper_addr = dead;
+        let req = TestRequest::with_state(state).finish();
+        let resp = sys.block_on(serve_healthz(req)).unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn readiness_waits_for_all_streams() {
+        let mut sys = System::new("readyz");
+        let req = TestRequest::with_state(test_state()).finish();
+        let resp = sys.block_on(serve_readyz(req)).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let scraper = scraper::Scraper::new(
+            btreeset!["unscraped".to_string()],
+            Duration::from_secs(30),
+            Default::default(),
+        )
+        .unwrap();
+        let mut state = test_state();
+        state.scraper_addr = scraper.start();
+        let req = TestRequest::with_state(state).finish();
+        let resp = sys.block_on(serve_readyz(req)).unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
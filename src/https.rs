@@ -0,0 +1,164 @@
+//! HTTPS serving, from a PEM certificate chain and private key.
+
+use actix_web::server::{HttpServer, IntoHttpHandler};
+use failure::Fallible;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+
+/// TLS settings for serving.
+#[derive(Clone)]
+pub(crate) struct ServerTls {
+    #[cfg(feature = "https")]
+    config: rustls::ServerConfig,
+    #[cfg(not(feature = "https"))]
+    never: std::convert::Infallible,
+}
+
+impl ServerTls {
+    /// Load a certificate chain and its private key (PKCS#8 or RSA).
+    ///
+    /// With `client_ca`, clients must present a certificate issued by one
+    /// of the CAs in this PEM bundle.
+    #[cfg(feature = "https")]
+    pub(crate) fn load(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Fallible<Self> {
+        use failure::{bail, format_err};
+        use rustls::internal::pemfile;
+        use std::io::BufReader;
+
+        let open = |path: &Path| {
+            std::fs::File::open(path)
+                .map(BufReader::new)
+                .map_err(|e| format_err!("failed to open '{}': {}", path.display(), e))
+        };
+        let chain = pemfile::certs(&mut open(cert)?)
+            .map_err(|_| format_err!("invalid certificate chain in '{}'", cert.display()))?;
+        if chain.is_empty() {
+            bail!("no certificates found in '{}'", cert.display());
+        }
+        let mut keys = pemfile::pkcs8_private_keys(&mut open(key)?).unwrap_or_default();
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut open(key)?).unwrap_or_default();
+        }
+        let key_der = match keys.into_iter().next() {
+            Some(k) => k,
+            None => bail!("no PKCS#8 or RSA private key found in '{}'", key.display()),
+        };
+
+        let verifier = match client_ca {
+            Some(path) => {
+                let mut roots = rustls::RootCertStore::empty();
+                let (valid, invalid) = roots
+                    .add_pem_file(&mut open(path)?)
+                    .map_err(|_| format_err!("invalid client CA bundle '{}'", path.display()))?;
+                if valid == 0 {
+                    bail!("no valid certificates found in '{}'", path.display());
+                }
+                if invalid > 0 {
+                    log::warn!(
+                        "skipped {} invalid certificates in '{}'",
+                        invalid,
+                        path.display()
+                    );
+                }
+                rustls::AllowAnyAuthenticatedClient::new(roots)
+            }
+            None => rustls::NoClientAuth::new(),
+        };
+        let mut config = rustls::ServerConfig::new(verifier);
+        config
+            .set_single_cert(chain, key_der)
+            .map_err(|e| format_err!("invalid certificate or key: {}", e))?;
+        Ok(Self { config })
+    }
+
+    #[cfg(not(feature = "https"))]
+    pub(crate) fn load(_cert: &Path, _key: &Path, _client_ca: Option<&Path>) -> Fallible<Self> {
+        Err(failure::format_err!(
+            "HTTPS serving requires the 'https' feature"
+        ))
+    }
+
+    /// Bind an address, serving HTTPS on it.
+    #[cfg_attr(not(feature = "https"), allow(unused_variables))]
+    pub(crate) fn bind<H, F>(
+        &self,
+        server: HttpServer<H, F>,
+        addr: SocketAddr,
+    ) -> std::io::Result<HttpServer<H, F>>
+    where
+        H: IntoHttpHandler + 'static,
+        F: Fn() -> H + Send + Clone + 'static,
+    {
+        #[cfg(feature = "https")]
+        return server.bind_rustls(addr, self.config.clone());
+        #[cfg(not(feature = "https"))]
+        match self.never {}
+    }
+
+    /// Serve HTTPS on an already bound listener.
+    #[cfg_attr(not(feature = "https"), allow(unused_variables))]
+    pub(crate) fn listen<H, F>(
+        &self,
+        server: HttpServer<H, F>,
+        listener: TcpListener,
+    ) -> HttpServer<H, F>
+    where
+        H: IntoHttpHandler + 'static,
+        F: Fn() -> H + Send + Clone + 'static,
+    {
+        #[cfg(feature = "https")]
+        return server.listen_rustls(listener, self.config.clone());
+        #[cfg(not(feature = "https"))]
+        match self.never {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_material_is_rejected() {
+        let dir = std::env::temp_dir().join(format!("fakeup-https-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let missing = dir.join("missing.pem");
+
+        assert!(ServerTls::load(&missing, &missing, None).is_err());
+        assert!(ServerTls::load(&empty, &empty, None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Self-signed `localhost` certificate, also used by actix-web test clients.
+    #[cfg(feature = "https")]
+    #[test]
+    fn certificate_and_key_are_loaded() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        assert!(ServerTls::load(&cert, &key, None).is_ok());
+        // A certificate is not a key.
+        assert!(ServerTls::load(&cert, &cert, None).is_err());
+    }
+
+    #[cfg(feature = "https")]
+    #[test]
+    fn client_cas_are_loaded() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        assert!(ServerTls::load(&cert, &key, Some(&cert)).is_ok());
+        // Bundles without any CA certificate are rejected.
+        assert!(ServerTls::load(&cert, &key, Some(&key)).is_err());
+        assert!(ServerTls::load(&cert, &key, Some(&dir.join("missing.pem"))).is_err());
+    }
+
+    #[cfg(not(feature = "https"))]
+    #[test]
+    fn https_requires_feature() {
+        let res = ServerTls::load(Path::new("cert.pem"), Path::new("key.pem"), None);
+        let err = res.err().unwrap();
+        assert!(err.to_string().contains("'https' feature"));
+    }
+}
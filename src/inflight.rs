@@ -0,0 +1,83 @@
+//! Limit on concurrent in-flight requests.
+
+use prometheus::IntCounter;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+lazy_static::lazy_static! {
+    static ref SHED_REQUESTS: IntCounter = register_int_counter!(opts!(
+        "fakeup_graph_shed_requests_total",
+        "Total number of graph requests rejected due to the in-flight limit"
+    ))
+    .unwrap();
+}
+
+/// Seconds clients are asked to wait after being shed.
+pub static RETRY_AFTER_SECS: u64 = 1;
+
+/// Concurrency limit, shared across server workers.
+#[derive(Clone, Debug)]
+pub struct InflightLimit {
+    max: usize,
+    current: Arc<AtomicUsize>,
+}
+
+/// Slot for an in-flight request, released on drop.
+#[derive(Debug)]
+pub struct InflightGuard {
+    current: Arc<AtomicUsize>,
+}
+
+impl InflightLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            current: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Try to take a slot, returning `None` (and counting it) if at capacity.
+    pub fn try_acquire(&self) -> Option<InflightGuard> {
+        let acquired = self
+            .current
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < self.max {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+        if !acquired {
+            SHED_REQUESTS.inc();
+            return None;
+        }
+        Some(InflightGuard {
+            current: Arc::clone(&self.current),
+        })
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.current.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_released_on_drop() {
+        let limit = InflightLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        let shed = SHED_REQUESTS.get();
+        assert!(limit.try_acquire().is_none());
+        assert!(SHED_REQUESTS.get() > shed);
+
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+    }
+}
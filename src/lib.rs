@@ -0,0 +1,10 @@
+//! Library side of fakeup, for use by downstream test suites and fuzzers.
+//!
+//! The server itself is the `fakeup` binary; this crate carries helpers to
+//! drive it, its runtime-agnostic scraping core, and the upstream metadata
+//! parsers exposed to fuzz targets.
+
+pub mod engine;
+pub mod metadata;
+#[cfg(feature = "testkit")]
+pub mod testkit;
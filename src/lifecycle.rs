@@ -0,0 +1,171 @@
+//! Automatic dead-ending of old releases, emulating lifecycle policies.
+
+use chrono::NaiveDate;
+use failure::{format_err, Fallible};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Limits past which a release is dead-ended.
+#[derive(Clone, Copy, Debug, Default)]
+struct DeadendRule {
+    /// Maximum release age.
+    max_age: Option<Duration>,
+    /// Maximum number of releases behind the latest one.
+    max_behind: Option<usize>,
+}
+
+/// Dead-end policy, with per-stream overrides of the default limits.
+#[derive(Clone, Debug, Default)]
+pub struct DeadendPolicy {
+    default: DeadendRule,
+    streams: HashMap<String, DeadendRule>,
+}
+
+impl DeadendPolicy {
+    /// Build a policy from `[<stream>=]<limit>` entries.
+    ///
+    /// Entries without a stream set the default for all streams.
+    pub fn new(ages: &[(Option<String>, Duration)], behinds: &[(Option<String>, usize)]) -> Self {
+        let mut policy = Self::default();
+        for (stream, age) in ages {
+            policy.rule_mut(stream.as_deref()).max_age = Some(*age);
+        }
+        for (stream, behind) in behinds {
+            policy.rule_mut(stream.as_deref()).max_behind = Some(*behind);
+        }
+        policy
+    }
+
+    fn rule_mut(&mut self, stream: Option<&str>) -> &mut DeadendRule {
+        match stream {
+            None => &mut self.default,
+            Some(s) => self.streams.entry(s.to_string()).or_default(),
+        }
+    }
+
+    /// Reason for dead-ending a release, if it is past a limit.
+    ///
+    /// `behind` is the number of newer releases in the stream, and `age`
+    /// the release age, if known.
+    pub fn reason(&self, stream: &str, behind: usize, age: Option<Duration>) -> Option<String> {
+        let rule = self.streams.get(stream);
+        let max_behind = rule.and_then(|r| r.max_behind).or(self.default.max_behind);
+        let max_age = rule.and_then(|r| r.max_age).or(self.default.max_age);
+
+        if let Some(max) = max_behind {
+            if behind > max {
+                return Some(format!(
+                    "release is {} releases behind latest on stream '{}' (limit: {})",
+                    behind, stream, max
+                ));
+            }
+        }
+        if let (Some(max), Some(age)) = (max_age, age) {
+            if age > max {
+                return Some(format!(
+                    "release is older than {} on stream '{}'",
+                    format_age(max),
+                    stream
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Format an age limit, in whole days where possible.
+fn format_age(age: Duration) -> String {
+    const DAY_SECS: u64 = 24 * 60 * 60;
+    if age.as_secs() > 0 && age.as_secs().is_multiple_of(DAY_SECS) && age.subsec_nanos() == 0 {
+        format!("{} days", age.as_secs() / DAY_SECS)
+    } else {
+        humantime::format_duration(age).to_string()
+    }
+}
+
+/// Build date of a release, from Fedora CoreOS versioning (`XX.YYYYMMDD.Z.N`).
+pub fn build_date(version: &str) -> Option<NaiveDate> {
+    let date = version.split('.').nth(1)?;
+    if date.len() != 8 {
+        return None;
+    }
+    NaiveDate::parse_from_str(date, "%Y%m%d").ok()
+}
+
+/// Parse a per-stream age limit, as `[<stream>=]<duration>` (e.g. `stable=90d`).
+pub fn parse_stream_age(input: &str) -> Fallible<(Option<String>, Duration)> {
+    let (stream, value) = split_stream(input)?;
+    Ok((stream, crate::config::parse_duration(value)?))
+}
+
+/// Parse a per-stream release count limit, as `[<stream>=]<count>` (e.g. `next=3`).
+pub fn parse_stream_behind(input: &str) -> Fallible<(Option<String>, usize)> {
+    let (stream, value) = split_stream(input)?;
+    let count = value
+        .trim()
+        .parse()
+        .map_err(|e| format_err!("invalid release count '{}': {}", value, e))?;
+    Ok((stream, count))
+}
+
+fn split_stream(input: &str) -> Fallible<(Option<String>, &str)> {
+    match input.split_once('=') {
+        None => Ok((None, input)),
+        Some(("", _)) => Err(format_err!("invalid limit '{}', empty stream", input)),
+        Some((stream, value)) => Ok((Some(stream.to_string()), value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn limits_parse() {
+        assert_eq!(parse_stream_age("90d").unwrap(), (None, 90 * DAY));
+        let (stream, age) = parse_stream_age("stable=2h").unwrap();
+        assert_eq!(
+            (stream.as_deref(), age),
+            (Some("stable"), Duration::from_secs(7200))
+        );
+        assert_eq!(
+            parse_stream_behind("next=3").unwrap(),
+            (Some("next".to_string()), 3)
+        );
+        assert!(parse_stream_behind("=3").is_err());
+        assert!(parse_stream_behind("next=many").is_err());
+        assert!(parse_stream_age("stable=soon").is_err());
+    }
+
+    #[test]
+    fn stream_limits_override_defaults() {
+        let policy = DeadendPolicy::new(
+            &[(None, 30 * DAY)],
+            &[(None, 5), (Some("next".to_string()), 1)],
+        );
+        assert_eq!(policy.reason("stable", 5, Some(DAY)), None);
+        assert_eq!(
+            policy.reason("stable", 6, None).unwrap(),
+            "release is 6 releases behind latest on stream 'stable' (limit: 5)"
+        );
+        assert!(policy.reason("next", 2, None).is_some());
+        assert_eq!(
+            policy.reason("next", 0, Some(31 * DAY)).unwrap(),
+            "release is older than 30 days on stream 'next'"
+        );
+        assert_eq!(
+            DeadendPolicy::default().reason("next", 100, Some(100 * DAY)),
+            None
+        );
+    }
+
+    #[test]
+    fn build_dates_come_from_versions() {
+        let date = build_date("39.20240101.3.0").unwrap();
+        assert_eq!(date, NaiveDate::from_ymd_opt(2024, 1, 1).unwrap());
+        assert_eq!(build_date("1.0"), None);
+        assert_eq!(build_date("39.20241301.3.0"), None);
+    }
+}
@@ -0,0 +1,157 @@
+//! OS resource limits checks.
+
+use failure::{bail, format_err, Fallible};
+
+/// File descriptors reserved for non-connection usage (logs, upstream, etc).
+static RESERVED_FDS: u64 = 64;
+
+/// Default maximum of concurrent connections per worker, in actix-web.
+static DEFAULT_MAXCONN: usize = 25_600;
+
+/// Resource limits requirements.
+#[derive(Clone, Debug)]
+pub struct LimitsCheck {
+    /// Minimum number of open file descriptors required.
+    pub min_nofile: u64,
+    /// Whether to raise the soft nofile limit up to the hard one.
+    pub raise_nofile: bool,
+    /// Whether to refuse to start if limits are too low.
+    pub strict: bool,
+}
+
+impl LimitsCheck {
+    /// Compute file descriptor requirements for the given server settings.
+    ///
+    /// Without `max_connections`, each worker accepts up to the actix-web
+    /// default.
+    pub fn required_nofile(workers: usize, max_connections: Option<usize>) -> u64 {
+        let maxconn = max_connections.unwrap_or(DEFAULT_MAXCONN);
+        (workers as u64) * (maxconn as u64) + RESERVED_FDS
+    }
+
+    /// Log current limits, raise them if requested, and check requirements.
+    pub fn run(&self) -> Fallible<()> {
+        let (mut soft, hard) = get_nofile()?;
+        info!("open files limit: soft={}, hard={}", soft, fmt_limit(hard));
+        if self.raise_nofile && soft < hard {
+            set_nofile(hard, hard)?;
+            soft = hard;
+            info!("raised open files soft limit to {}", fmt_limit(soft));
+        }
+
+        match memory_limit() {
+            Some(limit) => info!("memory limit: {} bytes", limit),
+            None => info!("memory limit: unlimited"),
+        }
+
+        if soft < self.min_nofile {
+            let msg = format!(
+                "open files limit too low: {} (required: {})",
+                soft, self.min_nofile
+            );
+            if self.strict {
+                bail!("{}", msg);
+            }
+            warn!("{}", msg);
+        }
+
+        Ok(())
+    }
+}
+
+fn fmt_limit(limit: u64) -> String {
+    if limit == libc::RLIM_INFINITY {
+        "unlimited".to_string()
+    } else {
+        limit.to_string()
+    }
+}
+
+/// Return soft and hard limits for open file descriptors.
+fn get_nofile() -> Fallible<(u64, u64)> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let ret = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) };
+    if ret != 0 {
+        return Err(format_err!(
+            "getrlimit failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok((rlim.rlim_cur, rlim.rlim_max))
+}
+
+/// Set soft and hard limits for open file descriptors.
+fn set_nofile(soft: u64, hard: u64) -> Fallible<()> {
+    let rlim = libc::rlimit {
+        rlim_cur: soft,
+        rlim_max: hard,
+    };
+    let ret = unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) };
+    if ret != 0 {
+        return Err(format_err!(
+            "setrlimit failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Return the effective memory limit, from cgroups or address space limits.
+fn memory_limit() -> Option<u64> {
+    // cgroups v2, then v1.
+    let cgroup_paths = [
+        "/sys/fs/cgroup/memory.max",
+        "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+    ];
+    for path in &cgroup_paths {
+        if let Ok(content) = std::fs::read_to_string(path) {
+            if let Ok(limit) = content.trim().parse::<u64>() {
+                return Some(limit);
+            }
+        }
+    }
+
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let ret = unsafe { libc::getrlimit(libc::RLIMIT_AS, &mut rlim) };
+    if ret != 0 || rlim.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    Some(rlim.rlim_cur)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_nofile_scales_with_workers() {
+        assert_eq!(LimitsCheck::required_nofile(4, Some(1000)), 4064);
+        assert_eq!(LimitsCheck::required_nofile(1, Some(0)), RESERVED_FDS);
+    }
+
+    #[test]
+    fn strict_check_fails_on_low_limits() {
+        if get_nofile().unwrap().0 == libc::RLIM_INFINITY {
+            return;
+        }
+        let mut check = LimitsCheck {
+            min_nofile: u64::MAX,
+            raise_nofile: false,
+            strict: false,
+        };
+        check.run().unwrap();
+        check.strict = true;
+        assert!(check.run().is_err());
+    }
+
+    #[test]
+    fn required_nofile_defaults_to_actix_maxconn() {
+        assert_eq!(LimitsCheck::required_nofile(2, None), 51_264);
+    }
+}
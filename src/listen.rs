@@ -0,0 +1,158 @@
+//! Server listeners.
+
+use failure::{bail, format_err, Fallible};
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+
+/// First file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Bind a Unix domain socket, replacing a stale socket file at `path`.
+pub(crate) fn bind_unix(path: &Path) -> Fallible<tokio_uds::UnixListener> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            bail!("'{}' exists and is not a socket", path.display());
+        }
+        std::fs::remove_file(path).map_err(|e| {
+            format_err!("failed to remove stale socket '{}': {}", path.display(), e)
+        })?;
+    }
+    let listener = tokio_uds::UnixListener::bind(path)
+        .map_err(|e| format_err!("failed to bind socket '{}': {}", path.display(), e))?;
+    Ok(listener)
+}
+
+/// Take TCP listeners passed by systemd socket activation (`sd_listen_fds`).
+///
+/// Activation variables are cleared, so that they do not leak to children.
+/// It returns no listeners if the process was not socket-activated.
+pub(crate) fn activated_tcp() -> Fallible<Vec<TcpListener>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(vec![]),
+    };
+    if pid.trim().parse::<u32>().ok() != Some(std::process::id()) {
+        log::warn!("ignoring LISTEN_FDS, passed to process {}", pid);
+        return Ok(vec![]);
+    }
+    let count: RawFd = fds
+        .trim()
+        .parse()
+        .map_err(|e| format_err!("invalid LISTEN_FDS '{}': {}", fds, e))?;
+
+    let mut listeners = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(count) {
+        check_tcp_listener(fd)?;
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            bail!(
+                "failed to set close-on-exec on fd {}: {}",
+                fd,
+                std::io::Error::last_os_error()
+            );
+        }
+        listeners.push(unsafe { TcpListener::from_raw_fd(fd) });
+    }
+    Ok(listeners)
+}
+
+/// Check that an inherited file descriptor is a TCP stream socket.
+fn check_tcp_listener(fd: RawFd) -> Fallible<()> {
+    let mut sock_type: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut sock_type as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res == -1 {
+        bail!(
+            "activated fd {} is not a socket: {}",
+            fd,
+            std::io::Error::last_os_error()
+        );
+    }
+    if sock_type != libc::SOCK_STREAM {
+        bail!("activated fd {} is not a stream socket", fd);
+    }
+
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if res == -1 {
+        bail!(
+            "failed to get address of activated fd {}: {}",
+            fd,
+            std::io::Error::last_os_error()
+        );
+    }
+    match libc::c_int::from(addr.ss_family) {
+        libc::AF_INET | libc::AF_INET6 => Ok(()),
+        _ => bail!("activated fd {} is not a TCP socket", fd),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let dir = std::env::temp_dir().join(format!("fakeup-listen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("fakeup.sock");
+
+        drop(bind_unix(&path).unwrap());
+        assert!(path.exists());
+        let listener = bind_unix(&path).unwrap();
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+        drop(listener);
+
+        let file = dir.join("regular");
+        std::fs::write(&file, "").unwrap();
+        assert!(bind_unix(&file).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn activated_sockets_are_checked() {
+        use std::os::unix::io::AsRawFd;
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(check_tcp_listener(tcp.as_raw_fd()).is_ok());
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(check_tcp_listener(udp.as_raw_fd()).is_err());
+        let dir = std::env::temp_dir().join(format!("fakeup-activated-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let unix = std::os::unix::net::UnixListener::bind(dir.join("unix.sock")).unwrap();
+        assert!(check_tcp_listener(unix.as_raw_fd()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn foreign_activation_is_ignored() {
+        assert!(activated_tcp().unwrap().is_empty());
+        let foreign = (std::process::id() + 1).to_string();
+        std::env::set_var("LISTEN_PID", &foreign);
+        std::env::set_var("LISTEN_FDS", "1");
+        assert!(activated_tcp().unwrap().is_empty());
+        assert!(std::env::var("LISTEN_PID").is_err());
+        assert!(std::env::var("LISTEN_FDS").is_err());
+    }
+}
@@ -5,134 +5,2046 @@ extern crate maplit;
 #[macro_use]
 extern crate prometheus;
 
-mod metadata;
+#[cfg(feature = "admin-api")]
+mod admin;
+mod auth;
+mod check;
+mod clients;
+mod clock;
+mod conditional;
+mod config;
+mod cors;
+mod daemon;
+mod debug;
+mod decode;
+mod demo;
+mod diff;
+mod dump;
+mod edges;
+mod encode;
+mod errors;
+mod feed;
+mod fetcher;
+mod fixture;
+mod health;
+mod https;
+mod inflight;
+mod lifecycle;
+mod limits;
+mod listen;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod namespace;
+mod payloads;
+mod pin;
+mod query;
+mod quiet;
+mod quota;
+mod raw;
+mod redact;
+mod reload;
+mod report;
+mod retry;
+mod router;
 mod scraper;
+mod sdnotify;
+mod shutdown;
+mod snapshot;
+#[cfg(feature = "metrics")]
+mod statsd;
+mod synthetic;
+mod template;
+mod tls;
+mod trace;
+mod version;
 
 use actix::prelude::*;
-use actix_web::{http::Method, middleware::Logger, server, App};
+use actix_web::{http::header, server, App};
 use actix_web::{HttpRequest, HttpResponse};
-use failure::{format_err, Error, Fallible};
+use errors::GraphError;
+use failure::{bail, format_err, Error, Fallible};
+use fakeup::engine::{self, CincinnatiPayload};
+use fakeup::metadata;
 use futures::future;
 use futures::prelude::*;
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
+/// Default port to which the server binds.
+static DEFAULT_PORT: u16 = 9876;
+
+/// Default pause between upstream refreshes.
+static DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
 fn main() -> Fallible<()> {
-    env_logger::Builder::from_default_env().try_init()?;
+    let mut cli = CliOptions::from_args();
+    let cmd = cli.command()?;
+    let verbosity = match cmd {
+        // The demo explains itself through logs.
+        Command::Demo { .. } => cli.verbose.max(1),
+        _ => cli.verbose,
+    };
+    init_logging(verbosity)?;
+    trace!("starting with config: {:#?}", cli);
+    let file_config = match cli.config {
+        Some(ref path) => config::ConfigFile::from_path(path)?,
+        None => config::ConfigFile::default(),
+    };
+    trace!("config file settings: {:#?}", file_config);
+    let releases_template = cli
+        .releases_url_template
+        .clone()
+        .or_else(|| file_config.releases_url_template.clone());
+    if let Some(ref template) = releases_template {
+        // Catch malformed templates early, rather than on each scrape.
+        scraper::releases_url(Some(template), "stable".to_string())
+            .map_err(|e| format_err!("invalid releases URL template '{}': {}", template, e))?;
+    }
+
+    match cmd {
+        Command::Serve(opts) => serve(opts, cli.config, file_config, releases_template),
+        Command::Fetch { streams } => {
+            let streams = scraped_streams(&streams, &file_config);
+            let latest = dump::fetch_latest(releases_template.as_deref(), &streams)?;
+            println!("{}", serde_json::to_string_pretty(&latest)?);
+            Ok(())
+        }
+        Command::Render { stream, basearch } => {
+            let graph = dump::dump_graph(releases_template.as_deref(), &stream, &basearch)?;
+            println!("{}", serde_json::to_string_pretty(&graph)?);
+            Ok(())
+        }
+        Command::CheckConfig(opts) => check::check_config(&opts, &file_config, releases_template),
+        Command::Config(ConfigCommand::Lint { path }) => {
+            let issues = config::ConfigFile::lint_path(&path)?;
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            if !issues.is_empty() {
+                bail!(
+                    "{} issue(s) in config file '{}'",
+                    issues.len(),
+                    path.display()
+                );
+            }
+            Ok(())
+        }
+        Command::ExportFixture { from, output } => {
+            let fixture = fixture::Fixture::fetch(&from)?;
+            fixture.write_bundle(&output)?;
+            info!("fixture bundle written to '{}'", output.display());
+            Ok(())
+        }
+        Command::Demo { port } => {
+            let demo = demo::Demo::new(port)?;
+            let res = demo.serve_options().and_then(|opts| {
+                demo.start_scenario()?;
+                serve(opts, None, config::ConfigFile::default(), None)
+            });
+            demo.cleanup();
+            res
+        }
+    }
+}
+
+/// Set up logging for a verbosity level, with `RUST_LOG` taking precedence.
+fn init_logging(verbosity: u8) -> Fallible<()> {
+    use log::LevelFilter;
+
+    let (own, others) = match verbosity {
+        0 => (LevelFilter::Error, LevelFilter::Error),
+        1 => (LevelFilter::Info, LevelFilter::Warn),
+        2 => (LevelFilter::Debug, LevelFilter::Info),
+        _ => (LevelFilter::Trace, LevelFilter::Debug),
+    };
+    let mut logger = env_logger::Builder::new();
+    logger
+        .filter_level(others)
+        .filter_module(module_path!(), own);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    if let Ok(style) = std::env::var("RUST_LOG_STYLE") {
+        logger.parse_write_style(&style);
+    }
+    logger.try_init()?;
+    Ok(())
+}
+
+/// Streams to scrape: from flags, else from the config file, else built-in ones.
+pub(crate) fn scraped_streams(
+    flags: &[String],
+    file_config: &config::ConfigFile,
+) -> BTreeSet<String> {
+    match file_config.streams {
+        _ if !flags.is_empty() => flags.iter().cloned().collect(),
+        Some(ref streams) => streams.clone(),
+        None => metadata::PRODUCTION_STREAMS
+            .iter()
+            .chain(metadata::DEVELOPMENT_STREAMS.iter())
+            .map(|s| s.to_string())
+            .collect(),
+    }
+}
+
+/// Refresh interval: from flags, else from the config file, else the default one.
+pub(crate) fn refresh_interval(
+    flag: Option<Duration>,
+    file_config: &config::ConfigFile,
+) -> Fallible<Duration> {
+    match flag {
+        Some(interval) => Ok(interval),
+        None => Ok(file_config
+            .refresh_interval()?
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL)),
+    }
+}
 
-    let opts = CliOptions::from_args();
-    trace!("starting with config: {:#?}", opts);
+/// Per-stream refresh intervals: from the config file, overridden by flags.
+pub(crate) fn stream_refresh_intervals(
+    flags: &[(String, Duration)],
+    file_config: &config::ConfigFile,
+) -> Fallible<BTreeMap<String, Duration>> {
+    let mut intervals = file_config.stream_refresh_intervals()?;
+    intervals.extend(flags.iter().cloned());
+    Ok(intervals)
+}
+
+/// Run the server, until the actix system stops.
+fn serve(
+    opts: ServeOptions,
+    config_path: Option<PathBuf>,
+    file_config: config::ConfigFile,
+    releases_template: Option<String>,
+) -> Fallible<()> {
+    info!("fakeup {} starting", version::VERSION);
+    redact::set(redact::Redactor::new(
+        opts.redact_params.clone(),
+        opts.redact_mode,
+    ));
+    // Before daemonizing, as activation is bound to the original PID.
+    let activated = listen::activated_tcp()?;
+    daemon::redirect_output(opts.stdout.as_deref(), opts.stderr.as_deref())?;
+    if opts.daemonize {
+        daemon::daemonize(opts.stdout.is_some(), opts.stderr.is_some())?;
+    }
+    let _pidfile = match opts.pidfile {
+        Some(ref path) => Some(daemon::Pidfile::create(path)?),
+        None => None,
+    };
+
+    let imported = match opts.import_fixture {
+        Some(ref path) => Some(fixture::Fixture::read_bundle(path)?),
+        None => None,
+    };
+
+    if let Some(frozen) = opts.frozen_time {
+        clock::set(clock::Clock::Frozen(frozen));
+    } else if let Some(offset) = opts.time_offset {
+        clock::set(clock::Clock::Offset(offset));
+    }
+
+    let workers = opts.workers.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    let required_nofile = limits::LimitsCheck::required_nofile(workers, opts.max_connections);
+    let limits_check = limits::LimitsCheck {
+        min_nofile: required_nofile,
+        raise_nofile: opts.raise_nofile,
+        strict: opts.strict_limits,
+    };
+    limits_check.run()?;
+    let server_tls = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls = https::ServerTls::load(cert, key, opts.tls_client_ca.as_deref())?;
+            info!("serving HTTPS with certificate '{}'", cert.display());
+            if let Some(ref ca) = opts.tls_client_ca {
+                info!("requiring client certificates issued by '{}'", ca.display());
+            }
+            Some(tls)
+        }
+        _ => None,
+    };
+    let started = clock::now();
 
     let sys = actix::System::new("fakeup");
-    let streams = btreeset!(
-        "bodhi-updates".to_string(),
-        "testing".to_string(),
-        "testing-devel".to_string(),
-    );
-    let refresh_pause = std::time::Duration::from_secs(30);
-    let scraper_addr = scraper::Scraper::new(streams, refresh_pause)?.start();
-    let app_state = AppState { scraper_addr };
-
-    server::new(move || {
-        App::with_state(app_state.clone())
-            .middleware(Logger::default())
-            .route("/v1/graph", Method::GET, serve_graph)
+    let mut streams = scraped_streams(&opts.streams, &file_config);
+    let refresh_pause = refresh_interval(opts.refresh_interval, &file_config)?;
+    let port = opts.port.or(file_config.port).unwrap_or(DEFAULT_PORT);
+    let retry_policy = retry::RetryPolicy::new(opts.upstream_retry.clone());
+    let mut priority_streams: BTreeSet<String> = opts.priority_streams.iter().cloned().collect();
+    let mut client_version = opts.client_version;
+    if let Some(ref fixture) = imported {
+        streams = fixture.config.streams.clone();
+        priority_streams.extend(fixture.config.priority_streams.iter().cloned());
+        client_version = fixture.config.client_version;
+    }
+    if !opts.pins.is_empty() {
+        // Pinned streams are served as-is, nothing is scraped.
+        streams = BTreeSet::new();
+        priority_streams = BTreeSet::new();
+    }
+    let arches: BTreeSet<String> = if opts.arches.is_empty() {
+        btreeset![query::DEFAULT_BASEARCH.to_string()]
+    } else {
+        opts.arches.iter().cloned().collect()
+    };
+    let mut scraper = scraper::Scraper::new(streams.clone(), refresh_pause, retry_policy)?
+        .with_arches(arches.clone())
+        .with_priority_streams(priority_streams)
+        .with_lazy_streams(opts.lazy_streams)
+        .with_releases_template(releases_template)
+        .with_rollouts(opts.rollout_metadata)
+        .with_deadend_policy(lifecycle::DeadendPolicy::new(
+            &opts.auto_deadend_age,
+            &opts.auto_deadend_behind,
+        ))
+        .with_fetch_concurrency(opts.upstream_concurrency)
+        .with_fixtures_dir(opts.fixtures_dir.clone())
+        .with_snapshots(opts.snapshot_dir.clone().map(snapshot::SnapshotDir::new))
+        .with_missing_stream(opts.missing_stream)
+        .with_notifier(sdnotify::Notifier::from_env()?)
+        .with_hop_policies(opts.hop_policies.iter().cloned().collect())
+        .with_stream_refresh_intervals(stream_refresh_intervals(
+            &opts.stream_refresh_intervals,
+            &file_config,
+        )?);
+    if opts.upstream_ca.is_some() || opts.upstream_insecure {
+        let upstream_tls = tls::UpstreamTls {
+            ca_bundle: opts.upstream_ca.clone(),
+            insecure: opts.upstream_insecure,
+        };
+        scraper = scraper.with_upstream_tls(&upstream_tls)?;
+    }
+    if opts.gate_releases {
+        let gate = engine::ReleaseGate::new(opts.auto_approve_after);
+        scraper = scraper.with_release_gate(gate);
+    }
+    if let Some(fixture) = imported {
+        info!(
+            "imported fixture with {} cached streams, cache frozen",
+            fixture.releases.len()
+        );
+        scraper = scraper.with_imported(fixture.releases);
+    }
+    if let Some(count) = opts.synthetic_releases {
+        let arches = if opts.synthetic_arches.is_empty() {
+            arches.iter().cloned().collect()
+        } else {
+            opts.synthetic_arches.clone()
+        };
+        let releases = synthetic::generate(&streams, count, &arches);
+        info!(
+            "generated {} synthetic releases per stream for {}, cache frozen",
+            count,
+            arches.join(", ")
+        );
+        scraper = scraper.with_imported(releases);
+    }
+    if !opts.pins.is_empty() {
+        info!(
+            "serving {} pinned streams, scraping disabled",
+            opts.pins.len()
+        );
+        scraper = scraper.with_imported(Default::default());
+    }
+    if let Some(ref url) = opts.discovery_url {
+        let discovery = scraper::StreamDiscovery {
+            url: url::Url::parse(url)?,
+            allow: opts.discovery_allow.clone(),
+            deny: opts.discovery_deny.clone(),
+        };
+        scraper = scraper.with_discovery(discovery);
+    }
+    let scraper = scraper.with_watchdog(opts.watchdog_intervals);
+    let scraper_addr = actix::Supervisor::start(move |_| scraper);
+    // Pinned streams are fixed, there is nothing to reload.
+    if let Some(path) = config_path.filter(|_| opts.pins.is_empty()) {
+        let reloader = reload::ConfigReloader {
+            path,
+            flag_streams: opts.streams.clone(),
+            flag_refresh_interval: opts.refresh_interval,
+            flag_stream_refresh: opts.stream_refresh_intervals.clone(),
+            scraper_addr: scraper_addr.clone(),
+        };
+        reloader.start();
+    }
+    if let Some(ref addr) = opts.statsd_addr {
+        start_statsd(addr, &opts)?;
+    }
+    let response_template = match opts.response_template {
+        Some(ref path) => Some(template::ResponseTemplate::from_path(path)?),
+        None => None,
+    };
+    let payload_blobs = match (&opts.payload_blob, opts.payload_size) {
+        (Some(path), _) => Some(payloads::PayloadBlobs::from_path(path)?),
+        (None, Some(size)) => Some(payloads::PayloadBlobs::Generated(size)),
+        (None, None) => None,
+    };
+    let clients = clients::ClientsTable::new(opts.track_node_bandwidth, opts.duplicates_threshold)
+        .with_sticky_targets(opts.sticky_targets)
+        .with_adoption(opts.track_adoption)
+        .with_max_nodes(opts.max_tracked_nodes);
+    let report_clients = clients.clone();
+    let allowed_platforms = match file_config.allowed_platforms {
+        _ if !opts.allowed_platforms.is_empty() => {
+            Some(opts.allowed_platforms.iter().cloned().collect())
+        }
+        Some(ref platforms) => Some(platforms.iter().cloned().collect()),
+        None => None,
+    };
+    let graph_token = match (&opts.graph_token, &opts.graph_token_file) {
+        (Some(token), _) => Some(token.clone()),
+        (None, Some(path)) => Some(auth::BearerToken::from_file(path)?),
+        (None, None) => None,
+    };
+    // Flags take precedence over the config file.
+    let cache_policy = conditional::CachePolicy::new(
+        opts.cache_control
+            .as_deref()
+            .or(file_config.cache_control.as_deref()),
+        match opts.cache_expires {
+            Some(lifetime) => Some(lifetime),
+            None => file_config.cache_expires()?,
+        },
+    )?;
+    let app_state = AppState {
+        inflight_limit: opts.max_inflight.map(inflight::InflightLimit::new),
+        node_quota: opts.node_quota.map(quota::NodeQuota::new),
+        client_version,
+        scraper_addr: scraper_addr.clone(),
+        pretty_json: opts.pretty_json,
+        graph_compression: opts.graph_compression,
+        cache_policy,
+        stream_override: opts.stream_override,
+        graph_token,
+        pins: opts
+            .pins
+            .iter()
+            .map(|pin| (pin.stream.clone(), pin.node()))
+            .collect(),
+        clients,
+        response_template,
+        payload_blobs,
+        stream_pattern: opts.stream_pattern.clone(),
+        checksum_validation: opts.checksum_validation,
+        arches: arches.clone(),
+        platform_delays: opts.platform_delays.iter().cloned().collect(),
+        quiet_hours: opts.quiet_hours.iter().cloned().collect(),
+        allowed_platforms,
+        unserved_platform: opts.unserved_platform,
+        alt_namespace: opts
+            .alt_namespace
+            .as_ref()
+            .map(|ns| namespace::AltNamespace::new(ns, opts.alt_keys.clone())),
+        edge_validator: if opts.validate_edges {
+            Some(edges::EdgeValidator::new(opts.rollback_edges.clone()))
+        } else {
+            None
+        },
+    };
+
+    let shutdown_timeout = opts
+        .shutdown_timeout
+        .map_or(30, |d| d.as_secs().min(u64::from(u16::MAX)) as u16);
+    let cors_origins = opts.cors_origins.clone();
+    let mut server = server::new(move || {
+        let app = App::with_state(app_state.clone()).middleware(redact::AccessLog);
+        let app = match cors_origins.as_slice() {
+            [] => app,
+            origins => app.middleware(cors::middleware(origins)),
+        };
+        app.configure(router::register)
     })
-    .bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), opts.port))?
-    .start();
+    .workers(workers)
+    .shutdown_timeout(shutdown_timeout)
+    .disable_signals();
+    if let Some(maxconn) = opts.max_connections {
+        server = server.maxconn(maxconn);
+    }
+
+    let mut servers = vec![];
 
+    let mut bound = match opts.listen_socket {
+        Some(ref path) => {
+            if !activated.is_empty() {
+                warn!("ignoring socket-activated listeners, serving on a Unix socket");
+            }
+            let listener = listen::bind_unix(path)?;
+            info!("listening on: {}", path.display());
+            // The only actix-web 0.7 entrypoint for non-TCP streams; it
+            // serves all connections from the main thread.
+            #[allow(deprecated)]
+            server.start_incoming(listener.incoming(), false);
+            serde_json::json!({ "listen_socket": path })
+        }
+        None => {
+            // Socket-activated listeners replace the default one.
+            let listen_addrs = if !opts.listen.is_empty() {
+                opts.listen.clone()
+            } else if activated.is_empty() {
+                vec![(IpAddr::from(Ipv4Addr::UNSPECIFIED), port).into()]
+            } else {
+                vec![]
+            };
+            // All listeners share the same `App` factory and workers.
+            for listener in activated {
+                server = match server_tls {
+                    Some(ref tls) => tls.listen(server, listener),
+                    None => server.listen(listener),
+                };
+            }
+            for addr in listen_addrs {
+                let bound = match server_tls {
+                    Some(ref tls) => tls.bind(server, addr),
+                    None => server.bind(addr),
+                };
+                server = bound.map_err(|e| format_err!("failed to bind '{}': {}", addr, e))?;
+            }
+            // Report bound addresses, as the port may have been picked by the OS.
+            let listen_addrs = server.addrs();
+            info!("listening on: {:?}", listen_addrs);
+            servers.push(server.start().recipient());
+            listen_report(&listen_addrs)
+        }
+    };
+    if let Some(ref dir) = opts.raw_fixtures {
+        let addr = (IpAddr::from(Ipv4Addr::UNSPECIFIED), opts.raw_fixtures_port).into();
+        let raw_fixtures = raw::RawFixtures::bind(dir, addr)?;
+        let raw_addr = raw_fixtures.local_addr()?.to_string();
+        info!("serving raw fixtures on: {}", raw_addr);
+        bound["raw_fixtures_addr"] = raw_addr.into();
+        raw_fixtures.spawn()?;
+    }
+    // Standard output may be gone (e.g. a closed pipe), which is not fatal.
+    let _ = writeln!(std::io::stdout(), "{}", bound);
+
+    shutdown::ShutdownHandler {
+        servers,
+        scraper_addr,
+        stopping: false,
+    }
+    .start();
     sys.run();
+
+    if let Some(ref path) = opts.report {
+        let report = report::ShutdownReport::collect(started, &report_clients);
+        report.write(path)?;
+        info!("shutdown report written to '{}'", path.display());
+    }
+    Ok(())
+}
+
+/// Render bound listen addresses as a JSON line.
+fn listen_report(addrs: &[SocketAddr]) -> serde_json::Value {
+    let listen_addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+    serde_json::json!({ "listen_addrs": listen_addrs })
+}
+
+#[cfg(feature = "metrics")]
+fn start_statsd(addr: &str, opts: &ServeOptions) -> Fallible<()> {
+    let prefix = opts.statsd_prefix.clone();
+    statsd::StatsdSink::new(addr, prefix, opts.statsd_interval)?.start();
     Ok(())
 }
 
+#[cfg(not(feature = "metrics"))]
+fn start_statsd(_addr: &str, _opts: &ServeOptions) -> Fallible<()> {
+    Err(format_err!("StatsD support requires the 'metrics' feature"))
+}
+
+/// Shared state for request handlers, built once from serve options.
 #[derive(Clone, Debug)]
 pub(crate) struct AppState {
+    pub(crate) inflight_limit: Option<inflight::InflightLimit>,
+    pub(crate) node_quota: Option<quota::NodeQuota>,
+    pub(crate) client_version: ClientVersion,
     pub(crate) scraper_addr: Addr<scraper::Scraper>,
+    pub(crate) clients: clients::ClientsTable,
+    pub(crate) response_template: Option<template::ResponseTemplate>,
+    pub(crate) payload_blobs: Option<payloads::PayloadBlobs>,
+    pub(crate) edge_validator: Option<edges::EdgeValidator>,
+    pub(crate) stream_pattern: regex::Regex,
+    pub(crate) checksum_validation: query::ChecksumValidation,
+    /// Architectures served to clients.
+    pub(crate) arches: BTreeSet<String>,
+    pub(crate) alt_namespace: Option<namespace::AltNamespace>,
+    /// Client platforms to serve (all if unset).
+    pub(crate) allowed_platforms: Option<HashSet<String>>,
+    pub(crate) unserved_platform: UnservedPlatform,
+    /// Additional rollout delay, per client platform.
+    pub(crate) platform_delays: HashMap<String, std::time::Duration>,
+    /// Hours without update offers, per stream.
+    pub(crate) quiet_hours: HashMap<String, quiet::QuietHours>,
+    /// Pretty-print graphs, unless overridden by the `pretty` query parameter.
+    pub(crate) pretty_json: bool,
+    /// Content encoding of graph responses.
+    pub(crate) graph_compression: encode::GraphCompression,
+    /// Caching headers on graph responses.
+    pub(crate) cache_policy: conditional::CachePolicy,
+    /// Honor the stream override request header.
+    pub(crate) stream_override: bool,
+    /// Token required from graph clients, if any.
+    pub(crate) graph_token: Option<auth::BearerToken>,
+    /// Fixed target node per stream, served without the scraper (if any).
+    pub(crate) pins: HashMap<String, CincinnatiPayload>,
 }
 
 pub(crate) fn serve_graph(
     req: HttpRequest<AppState>,
-) -> Box<Future<Item = HttpResponse, Error = Error>> {
-    // Get client OS version.
-    let current_os = req.query().get("current_os").cloned().unwrap_or_default();
-    let os_checksum = req.query().get("os_checksum").cloned().unwrap_or_default();
-    let mut os = current_os;
-    if os.is_empty() {
-        os = os_checksum;
-        if os.is_empty() {
-            return Box::new(future::ok(HttpResponse::BadRequest().finish()));
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let clients = req.state().clients.clone();
+    let resp = graph_response(req).map(move |resp| {
+        clients.record_status(resp.status().as_u16());
+        resp
+    });
+    Box::new(resp)
+}
+
+fn graph_response(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    if let Some(ref token) = req.state().graph_token {
+        if let Err(e) = token.check(req.headers()) {
+            return Box::new(future::ok(e.response()));
         }
     }
-    trace!("client OS checksum: {}", os);
+    // Shed load beyond the in-flight limit, instead of queueing.
+    let inflight_guard = match req.state().inflight_limit {
+        Some(ref limit) => match limit.try_acquire() {
+            Some(guard) => Some(guard),
+            None => return Box::new(future::ok(GraphError::Overloaded.response())),
+        },
+        None => None,
+    };
 
-    // Get client OS stream.
-    let stream = req.query().get("stream").cloned().unwrap_or_default();
-    if stream.is_empty() {
-        return Box::new(future::ok(HttpResponse::BadRequest().finish()));
+    // Get client OS checksum and stream.
+    let gq = match query::GraphQuery::from_request(&req) {
+        Ok(gq) => gq,
+        Err(e) => return Box::new(future::ok(e.response())),
+    };
+    if let Some(ref allowed) = req.state().allowed_platforms {
+        if !gq.platform.as_ref().is_some_and(|p| allowed.contains(p)) {
+            trace!("platform not served: {:?}", gq.platform);
+            let resp = match req.state().unserved_platform {
+                UnservedPlatform::Empty => HttpResponse::Ok().json(Graph {
+                    nodes: vec![],
+                    edges: vec![],
+                }),
+                UnservedPlatform::Reject => {
+                    GraphError::UnservedPlatform(gq.platform.clone()).response()
+                }
+            };
+            return Box::new(future::ok(resp));
+        }
+    }
+    trace!("client OS checksum: {}", gq.checksum);
+    trace!("client stream: {}", gq.stream);
+
+    if let (Some(quota), Some(uuid)) = (&req.state().node_quota, &gq.node_uuid) {
+        if let Err(retry_after) = quota.check(uuid) {
+            trace!("node '{}' over quota", redact::param("node_uuid", uuid));
+            let resp = GraphError::OverQuota(retry_after).response();
+            return Box::new(future::ok(resp));
+        }
+    }
+
+    if let (Some(uuid), Some(addr)) = (&gq.node_uuid, req.peer_addr()) {
+        req.state().clients.record_node_address(uuid, addr.ip());
     }
-    trace!("client stream: {}", os);
 
     // Synthesize source node.
-    let current = CincinnatiPayload {
-        version: "client-os-version".to_string(),
-        payload: os,
+    let mut current = CincinnatiPayload {
+        version: req.state().client_version.placeholder().to_string(),
+        payload: gq.checksum.clone(),
         metadata: hashmap! {
             "org.fedoraproject.coreos.scheme".to_string() => "checksum".to_string(),
             "org.fedoraproject.coreos.releases.age_index".to_string() => "0".to_string(),
         },
     };
 
-    let cached_latest = req
-        .state()
-        .scraper_addr
-        .send(scraper::GetLatest::new("x86_64".to_string(), stream))
-        .flatten();
+    let clients = req.state().clients.clone();
+    let stream = gq.stream.clone();
+    let node_uuid = gq.node_uuid.clone();
+    let scraper_addr = req.state().scraper_addr.clone();
+
+    // Long-poll: hold the request until the stream graph changes. Idle
+    // waiters do not count as in flight, or they would starve other clients.
+    let (wait_change, inflight_guard) = match gq.wait {
+        Some(wait) => {
+            drop(inflight_guard);
+            let waiting = scraper::wait_for_change(&scraper_addr, gq.stream.clone(), wait);
+            (future::Either::A(waiting), None)
+        }
+        None => (future::Either::B(future::ok(true)), inflight_guard),
+    };
+
+    // Pinned streams never change: resolve nodes locally, without waiting.
+    let pinned = if req.state().pins.is_empty() {
+        None
+    } else {
+        let found = match req.state().pins.get(&gq.stream) {
+            Some(pin) => {
+                let known = Some(pin.clone()).filter(|node| node.payload == gq.checksum);
+                Ok(Some((known, Some(pin.clone()), None)))
+            }
+            None => Err(GraphError::UnknownStream(gq.stream.clone()).into()),
+        };
+        Some(found)
+    };
+
+    let lookup = scraper::LookupNode {
+        basearch: gq.basearch.clone(),
+        stream: gq.stream.clone(),
+        checksum: gq.checksum,
+    };
+    let platform_delay = gq
+        .platform
+        .as_ref()
+        .and_then(|p| req.state().platform_delays.get(p))
+        .cloned();
+    let trace_ctx = trace::TraceContext::from_headers(req.headers());
+    if let Some(ref ctx) = trace_ctx {
+        trace!("client trace id: {}", ctx.trace_id());
+    }
+    let refresh_time = scraper::GetRefreshTime {
+        stream: gq.stream.clone(),
+    };
+    let get_latest = scraper::GetLatest::new(gq.basearch, gq.stream)
+        .with_current(current.payload.clone())
+        .with_delay(platform_delay)
+        .with_trace(trace_ctx);
+    let lookups = match pinned {
+        Some(found) => future::Either::A(future::result(found)),
+        None => future::Either::B(wait_change.and_then(move |changed| {
+            if !changed {
+                return future::Either::A(future::ok(None));
+            }
+            let cached_current = scraper::request(&scraper_addr, lookup);
+            let cached_latest = scraper::request(&scraper_addr, get_latest);
+            let refreshed = scraper::request(&scraper_addr, refresh_time);
+            future::Either::B(cached_current.join3(cached_latest, refreshed).map(Some))
+        })),
+    };
+
+    let response_template = req.state().response_template.clone();
+    let client_version = req.state().client_version;
+    let edge_validator = req.state().edge_validator.clone();
+    let alt_namespace = req.state().alt_namespace.clone();
+    let pretty = gq.pretty.unwrap_or(req.state().pretty_json);
+    let compression = req.state().graph_compression;
+    let cache_policy = req.state().cache_policy.clone();
+    let quiet_hours = req.state().quiet_hours.get(&stream).cloned();
+    let utc_offset = gq.utc_offset;
+    let headers = req.headers().clone();
 
     // Assemble graph and return it as JSON.
-    let resp = cached_latest
-        .and_then(|latest| {
-            let graph = if current.payload != latest.payload {
-                Graph {
+    let resp = lookups
+        .and_then(move |found| {
+            let (known, latest, refreshed) = match found {
+                Some(found) => found,
+                None => return Ok(HttpResponse::NotModified().finish()),
+            };
+
+            clients.record_release(
+                &stream,
+                node_uuid.as_deref(),
+                &current.payload,
+                known.as_ref().map(|n| n.version.as_str()),
+            );
+
+            // Keep upstream age index for releases present in the index.
+            if let Some(known) = known {
+                for key in &[
+                    metadata::AGE_INDEX,
+                    metadata::DEADEND,
+                    metadata::DEADEND_REASON,
+                ] {
+                    if let Some(value) = known.metadata.get(*key) {
+                        current.metadata.insert(key.to_string(), value.clone());
+                    }
+                }
+                if client_version == ClientVersion::Derived {
+                    current.version = known.version;
+                }
+            }
+
+            let latest = clients.sticky_target(node_uuid.as_deref(), &stream, &current, latest);
+            let mut graph = match latest {
+                Some(latest) if current.payload != latest.payload => Graph {
                     nodes: vec![current, latest],
                     edges: vec![(0, 1)],
-                }
-            } else {
-                Graph {
+                },
+                Some(latest) => Graph {
                     nodes: vec![latest],
                     edges: vec![],
+                },
+                // Stream is known but has no releases, only serve the client.
+                None => Graph {
+                    nodes: vec![current],
+                    edges: vec![],
+                },
+            };
+
+            // Checked on response, as long-polls may end past quiet hours.
+            let quiet = quiet_hours.is_some_and(|q| q.is_quiet(clock::now(), utc_offset));
+            if quiet {
+                trace!("quiet hours on stream '{}', no update offered", stream);
+                graph.edges.clear();
+            }
+
+            let stale = graph
+                .nodes
+                .iter()
+                .any(|node| node.metadata.contains_key(metadata::STALE));
+            if let Some(ns) = alt_namespace {
+                graph.nodes.iter_mut().for_each(|node| ns.apply(node));
+            }
+            if let Some(validator) = edge_validator {
+                validator
+                    .check(&graph)
+                    .map_err(|e| GraphError::InvalidGraph(e.to_string()))?;
+            }
+            let json = encode::to_json(&graph, pretty)?;
+            let json = match response_template {
+                Some(tmpl) => {
+                    let raw = std::str::from_utf8(&json).map_err(|e| format_err!("{}", e))?;
+                    tmpl.render(&graph, raw)
+                        .map_err(|e| GraphError::FailedTemplate(e.to_string()))?
+                        .into()
                 }
+                None => json,
             };
-            Ok(graph)
+
+            drop(inflight_guard);
+            let etag = conditional::etag(&stream, refreshed, &graph)?;
+            if conditional::is_fresh(&headers, &etag, refreshed) {
+                let mut resp = HttpResponse::NotModified();
+                resp.header(header::ETAG, etag);
+                cache_policy.apply(&mut resp);
+                if let Some(refreshed) = refreshed {
+                    resp.header(header::LAST_MODIFIED, conditional::last_modified(refreshed));
+                }
+                return Ok(resp.finish());
+            }
+            clients.record_response(&stream, node_uuid.as_deref(), json.len() as u64);
+            let mut resp = HttpResponse::Ok();
+            resp.content_type("application/json");
+            resp.header(header::ETAG, etag);
+            if let Some(refreshed) = refreshed {
+                resp.header(header::LAST_MODIFIED, conditional::last_modified(refreshed));
+            }
+            compression.apply(&mut resp);
+            cache_policy.apply(&mut resp);
+            if stale {
+                resp.header("X-Fakeup-Stale", "true");
+            }
+            if quiet {
+                resp.header("X-Fakeup-Quiet-Hours", "true");
+            }
+            Ok(resp.body(json))
         })
-        .from_err()
-        .and_then(|graph| serde_json::to_string_pretty(&graph).map_err(|e| format_err!("{}", e)))
-        .map(|json| {
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .body(json)
-        });
+        .or_else(|e| Ok(errors::graph_error(e)));
 
     Box::new(resp)
 }
 
+/// Reject non-GET requests to the graph endpoint, as the production backend does.
+pub(crate) fn graph_method_not_allowed(_req: &HttpRequest<AppState>) -> HttpResponse {
+    GraphError::MethodNotAllowed.response()
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct Graph {
     pub(crate) nodes: Vec<CincinnatiPayload>,
     pub(crate) edges: Vec<(u64, u64)>,
 }
 
+/// Version reported for the synthesized client node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ClientVersion {
+    /// Fixed `client-os-version` placeholder.
+    Placeholder,
+    /// Empty string.
+    Empty,
+    /// Version of the matching upstream release, or empty if unknown.
+    Derived,
+}
+
+impl ClientVersion {
+    /// Version to use before (or without) looking up the client release.
+    fn placeholder(self) -> &'static str {
+        match self {
+            ClientVersion::Placeholder => "client-os-version",
+            ClientVersion::Empty | ClientVersion::Derived => "",
+        }
+    }
+}
+
+impl std::str::FromStr for ClientVersion {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        match input {
+            "placeholder" => Ok(ClientVersion::Placeholder),
+            "empty" => Ok(ClientVersion::Empty),
+            "derived" => Ok(ClientVersion::Derived),
+            _ => Err(format_err!("unknown client version mode '{}'", input)),
+        }
+    }
+}
+
+/// Response to graph requests from platforms which are not served.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UnservedPlatform {
+    /// Graph without any node.
+    Empty,
+    /// Structured JSON error.
+    Reject,
+}
+
+impl std::str::FromStr for UnservedPlatform {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        match input {
+            "empty" => Ok(UnservedPlatform::Empty),
+            "reject" => Ok(UnservedPlatform::Reject),
+            _ => Err(format_err!("unknown unserved platform mode '{}'", input)),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
+#[structopt(raw(version = "version::VERSION"))]
 pub(crate) struct CliOptions {
-    /// Port to which the server will bind.
-    #[structopt(short = "p", long = "port", default_value = "9876")]
-    port: u16,
+    /// TOML configuration file; command-line flags take precedence over its values.
+    #[structopt(long = "config", parse(from_os_str), raw(env = "\"FAKEUP_CONFIG\""))]
+    config: Option<PathBuf>,
+
+    /// Templated URL for stream release indexes (e.g. `http://localhost:8000/${stream}.json`).
+    #[structopt(
+        long = "releases-url-template",
+        raw(env = "\"FAKEUP_RELEASES_URL_TEMPLATE\"")
+    )]
+    releases_url_template: Option<String>,
+
+    /// Increase log verbosity (`-v`, `-vv`, `-vvv`); `RUST_LOG` takes precedence.
+    #[structopt(
+        short = "v",
+        long = "verbose",
+        parse(from_occurrences),
+        raw(global = "true")
+    )]
+    verbose: u8,
+
+    /// Subcommand to run, `serve` by default.
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub(crate) struct CincinnatiPayload {
-    pub(crate) version: String,
-    pub(crate) metadata: HashMap<String, String>,
-    pub(crate) payload: String,
+impl CliOptions {
+    /// Take the subcommand to run, serving with default options if none was given.
+    fn command(&mut self) -> Fallible<Command> {
+        match self.cmd.take() {
+            Some(cmd) => Ok(cmd),
+            None => Ok(Command::Serve(ServeOptions::from_iter_safe(&["serve"])?)),
+        }
+    }
+}
+
+// Parsed once at startup, size does not matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+pub(crate) enum Command {
+    /// Run the server.
+    #[structopt(name = "serve")]
+    Serve(ServeOptions),
+
+    /// Scrape streams once and print their latest release.
+    #[structopt(name = "fetch")]
+    Fetch {
+        /// Stream to scrape, replacing the configured set (repeatable).
+        #[structopt(long = "stream", number_of_values = 1)]
+        streams: Vec<String>,
+    },
+
+    /// Scrape a stream once and print the graph for its latest payload.
+    #[structopt(name = "render", raw(alias = "\"dump\""))]
+    Render {
+        /// Stream to scrape.
+        #[structopt(long = "stream")]
+        stream: String,
+
+        /// Architecture of the payload.
+        #[structopt(long = "basearch", default_value = "x86_64")]
+        basearch: String,
+    },
+
+    /// Validate the serve configuration and scrape each stream once, without serving.
+    #[structopt(name = "check-config")]
+    CheckConfig(ServeOptions),
+
+    /// Configuration file tools.
+    #[structopt(name = "config")]
+    Config(ConfigCommand),
+
+    /// Export the state of a running instance as a fixture bundle.
+    #[structopt(name = "export-fixture")]
+    ExportFixture {
+        /// Base URL of the running instance.
+        #[structopt(long = "from", default_value = "http://localhost:9876")]
+        from: String,
+
+        /// Path of the bundle to write (`.tar.gz`).
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    /// Serve bundled demo data along a scripted scenario, without configuration or network.
+    #[structopt(name = "demo")]
+    Demo {
+        /// Port to which the server will bind.
+        #[structopt(short = "p", long = "port", raw(default_value = "\"9876\""))]
+        port: u16,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) enum ConfigCommand {
+    /// Check a configuration file, reporting unknown keys and bad values.
+    #[structopt(name = "lint")]
+    Lint {
+        /// Path of the TOML configuration file.
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ServeOptions {
+    /// Port to which the server will bind (0 for an ephemeral port) [default: 9876].
+    #[structopt(short = "p", long = "port", raw(env = "\"FAKEUP_PORT\""))]
+    port: Option<u16>,
+
+    /// PEM certificate chain, to serve HTTPS instead of plain HTTP.
+    #[structopt(
+        long = "tls-cert",
+        parse(from_os_str),
+        raw(requires = "\"tls_key\"", conflicts_with = "\"listen_socket\"")
+    )]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key (PKCS#8 or RSA) for `--tls-cert`.
+    #[structopt(long = "tls-key", parse(from_os_str), raw(requires = "\"tls_cert\""))]
+    tls_key: Option<PathBuf>,
+
+    /// PEM bundle of CAs for client certificates, which are then required on
+    /// all HTTPS endpoints (mutual TLS).
+    #[structopt(
+        long = "tls-client-ca",
+        parse(from_os_str),
+        raw(requires = "\"tls_cert\"")
+    )]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Listen on this Unix domain socket instead of TCP.
+    #[structopt(
+        long = "listen-socket",
+        parse(from_os_str),
+        raw(env = "\"FAKEUP_LISTEN_SOCKET\""),
+        raw(conflicts_with = "\"port\"")
+    )]
+    listen_socket: Option<PathBuf>,
+
+    /// Address and port to bind, e.g. `127.0.0.1:9876` or `[::1]:9876` (repeatable).
+    #[structopt(
+        long = "listen",
+        number_of_values = 1,
+        raw(env = "\"FAKEUP_LISTEN\"", use_delimiter = "true"),
+        raw(conflicts_with_all = r#"&["port", "listen_socket"]"#)
+    )]
+    listen: Vec<SocketAddr>,
+
+    /// Pause between upstream refreshes (e.g. `30s`, `5m`) [default: 30s].
+    #[structopt(
+        long = "refresh-interval",
+        parse(try_from_str = "config::parse_duration"),
+        raw(env = "\"FAKEUP_REFRESH_SECS\"")
+    )]
+    refresh_interval: Option<Duration>,
+
+    /// Refresh a stream on its own schedule, as `<stream>=<duration>` (repeatable).
+    #[structopt(
+        long = "stream-refresh-interval",
+        number_of_values = 1,
+        parse(try_from_str = "config::parse_stream_interval")
+    )]
+    stream_refresh_intervals: Vec<(String, Duration)>,
+
+    /// Template (minijinja syntax) to post-process graph responses.
+    #[structopt(long = "response-template", parse(from_os_str))]
+    response_template: Option<PathBuf>,
+
+    /// Freeze all emitted timestamps at this RFC 3339 time.
+    #[structopt(long = "frozen-time")]
+    frozen_time: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Offset applied to all emitted timestamps (e.g. `-2h`).
+    #[structopt(
+        long = "time-offset",
+        parse(try_from_str = "config::parse_signed_duration"),
+        raw(conflicts_with = "\"frozen_time\"", allow_hyphen_values = "true")
+    )]
+    time_offset: Option<chrono::Duration>,
+
+    /// Serve this file as payload blob at `/fakeup/v1/payloads/<checksum>`.
+    #[structopt(long = "payload-blob", parse(from_os_str))]
+    payload_blob: Option<PathBuf>,
+
+    /// Serve generated payload blobs of this size (in bytes) at `/fakeup/v1/payloads/<checksum>`.
+    #[structopt(long = "payload-size", raw(conflicts_with = "\"payload_blob\""))]
+    payload_size: Option<u64>,
+
+    /// Account served bytes per node UUID (reported in admin status).
+    #[structopt(long = "track-node-bandwidth")]
+    track_node_bandwidth: bool,
+
+    /// Track which releases nodes report running (served at `/fakeup/v1/admin/adoption`).
+    #[structopt(long = "track-adoption")]
+    track_adoption: bool,
+
+    /// Retry policy for upstream HTTP failures, as `<status>=<action>` (repeatable).
+    ///
+    /// Status is a code (`403`) or a class (`5xx`); action is one of
+    /// `give-up:<duration>`, `retry-after`, or `backoff`.
+    #[structopt(long = "upstream-retry", number_of_values = 1)]
+    upstream_retry: Vec<retry::RetryRule>,
+
+    /// Maximum number of concurrent upstream fetches, queueing the others (0 for unlimited).
+    #[structopt(long = "upstream-concurrency", default_value = "0")]
+    upstream_concurrency: usize,
+
+    /// PEM bundle of additional CA certificates to trust for upstream requests.
+    #[structopt(long = "upstream-ca", parse(from_os_str))]
+    upstream_ca: Option<PathBuf>,
+
+    /// INSECURE: do not verify upstream TLS certificates.
+    #[structopt(long = "upstream-insecure-skip-verify")]
+    upstream_insecure: bool,
+
+    /// Restart the scraper after this many refresh intervals without a full refresh (0 disables).
+    #[structopt(long = "watchdog-intervals", default_value = "10")]
+    watchdog_intervals: u32,
+
+    /// Number of HTTP worker threads (defaults to the number of CPUs).
+    #[structopt(long = "workers", raw(env = "\"FAKEUP_WORKERS\""))]
+    workers: Option<usize>,
+
+    /// Maximum number of concurrent connections per worker [default: 25600].
+    #[structopt(long = "max-connections")]
+    max_connections: Option<usize>,
+
+    /// Grace period for in-flight requests on SIGTERM/SIGINT (e.g. `5s`) [default: 30s].
+    #[structopt(
+        long = "shutdown-timeout",
+        parse(try_from_str = "config::parse_duration")
+    )]
+    shutdown_timeout: Option<Duration>,
+
+    /// Raise the open files soft limit up to the hard limit.
+    #[structopt(long = "raise-nofile")]
+    raise_nofile: bool,
+
+    /// Refuse to start if resource limits are too low for the configured settings.
+    #[structopt(long = "strict-limits")]
+    strict_limits: bool,
+
+    /// Version for the client node: `placeholder`, `empty`, or `derived` (from upstream).
+    #[structopt(
+        long = "client-version",
+        default_value = "placeholder",
+        raw(env = "\"FAKEUP_CLIENT_VERSION\"")
+    )]
+    client_version: ClientVersion,
+
+    /// Pretty-print graphs by default; clients can override it with `?pretty=true|false`.
+    #[structopt(long = "pretty-json")]
+    pretty_json: bool,
+
+    /// Graph response compression: `auto` (negotiated with `Accept-Encoding`),
+    /// `off`, or always `gzip` or `br` regardless of what the client accepts.
+    #[structopt(long = "graph-compression", default_value = "auto")]
+    graph_compression: encode::GraphCompression,
+
+    /// `Cache-Control` value for graph responses (e.g. `public, max-age=300`
+    /// or `no-store`).
+    #[structopt(long = "cache-control")]
+    cache_control: Option<String>,
+
+    /// Advertise this graph response lifetime with `Expires` (e.g. `5m`).
+    #[structopt(long = "cache-expires", parse(try_from_str = "config::parse_duration"))]
+    cache_expires: Option<Duration>,
+
+    /// Let proxies redirect graph lookups to another stream, with an
+    /// `X-Fakeup-Stream-Override: <stream>` request header.
+    #[structopt(long = "allow-stream-override")]
+    stream_override: bool,
+
+    /// Allow cross-origin requests from this origin, as `<scheme>://<host>[:<port>]`
+    /// or `*` for any (repeatable).
+    #[structopt(
+        long = "cors-origin",
+        number_of_values = 1,
+        parse(try_from_str = "cors::parse_origin")
+    )]
+    cors_origins: Vec<String>,
+
+    /// Require this bearer token in the `Authorization` header of graph requests.
+    #[structopt(
+        long = "graph-token",
+        parse(try_from_str = "auth::BearerToken::new"),
+        raw(env = "\"FAKEUP_GRAPH_TOKEN\"", hide_env_values = "true")
+    )]
+    graph_token: Option<auth::BearerToken>,
+
+    /// Read the graph bearer token from this file.
+    #[structopt(
+        long = "graph-token-file",
+        parse(from_os_str),
+        raw(conflicts_with = "\"graph_token\"")
+    )]
+    graph_token_file: Option<PathBuf>,
+
+    /// Stream to scrape, replacing the built-in set (repeatable, or comma-separated in env).
+    #[structopt(
+        long = "stream",
+        number_of_values = 1,
+        raw(env = "\"FAKEUP_STREAMS\"", use_delimiter = "true")
+    )]
+    streams: Vec<String>,
+
+    /// Stream to scrape first on startup, and retry more aggressively (repeatable).
+    #[structopt(long = "priority-stream", number_of_values = 1)]
+    priority_streams: Vec<String>,
+
+    /// Maximum number of concurrent graph requests; beyond it, reply 503.
+    #[structopt(long = "max-inflight")]
+    max_inflight: Option<usize>,
+
+    /// Per-node graph request quota, as `<requests>/<window>` (e.g. `10/1h`); beyond it, reply 429.
+    #[structopt(long = "node-quota")]
+    node_quota: Option<quota::QuotaConfig>,
+
+    /// Offer new releases to a platform only after a delay, as `<platform>=<duration>` (repeatable).
+    #[structopt(
+        long = "platform-delay",
+        number_of_values = 1,
+        parse(try_from_str = "config::parse_platform_delay")
+    )]
+    platform_delays: Vec<(String, std::time::Duration)>,
+
+    /// Only serve graphs to this client platform (repeatable).
+    #[structopt(long = "allow-platform", number_of_values = 1)]
+    allowed_platforms: Vec<String>,
+
+    /// Response for other platforms when restricted: `empty` (graph) or `reject` (400).
+    #[structopt(long = "unserved-platform", default_value = "empty")]
+    unserved_platform: UnservedPlatform,
+
+    /// Scrape rollouts from stream updates metadata, and annotate target nodes with them.
+    #[structopt(long = "rollout-metadata")]
+    rollout_metadata: bool,
+
+    /// Mark releases older than this as dead-ends, as `[<stream>=]<duration>` (repeatable).
+    #[structopt(
+        long = "auto-deadend-age",
+        number_of_values = 1,
+        parse(try_from_str = "lifecycle::parse_stream_age")
+    )]
+    auto_deadend_age: Vec<(Option<String>, std::time::Duration)>,
+
+    /// Mark releases more than this many releases behind latest as dead-ends,
+    /// as `[<stream>=]<count>` (repeatable).
+    #[structopt(
+        long = "auto-deadend-behind",
+        number_of_values = 1,
+        parse(try_from_str = "lifecycle::parse_stream_behind")
+    )]
+    auto_deadend_behind: Vec<(Option<String>, usize)>,
+
+    /// Update target policy for clients far behind, as `<stream>=latest` or
+    /// `<stream>=stepwise:<count>` (repeatable) [default: latest].
+    #[structopt(
+        long = "hop-policy",
+        number_of_values = 1,
+        parse(try_from_str = "config::parse_stream_hop_policy")
+    )]
+    hop_policies: Vec<(String, engine::HopPolicy)>,
+
+    /// Never offer updates during these hours, as `<stream>=<HH:MM>-<HH:MM>`
+    /// (repeatable), in server local time or the client `tz_offset` hint.
+    #[structopt(
+        long = "quiet-hours",
+        number_of_values = 1,
+        parse(try_from_str = "quiet::parse_stream_quiet_hours")
+    )]
+    quiet_hours: Vec<(String, quiet::QuietHours)>,
+
+    /// Keep offering the same target to a node until it reports running it.
+    #[structopt(long = "sticky-targets")]
+    sticky_targets: bool,
+
+    /// Reject graph requests for streams not matching this regex.
+    #[structopt(
+        long = "stream-pattern",
+        raw(default_value = "query::DEFAULT_STREAM_PATTERN")
+    )]
+    stream_pattern: regex::Regex,
+
+    /// Architecture to cache and serve (repeatable, or comma-separated in env) [default: x86_64].
+    #[structopt(
+        long = "arch",
+        number_of_values = 1,
+        raw(env = "\"FAKEUP_ARCHES\"", use_delimiter = "true")
+    )]
+    arches: Vec<String>,
+
+    /// Client OS checksum validation: `off`, `lenient` (log and count
+    /// malformed ones) or `strict` (reject them).
+    #[structopt(long = "checksum-validation", default_value = "lenient")]
+    checksum_validation: query::ChecksumValidation,
+
+    /// URL of a streams index document (`{"streams": [...]}`) for auto-discovery.
+    #[structopt(long = "discovery-url")]
+    discovery_url: Option<String>,
+
+    /// Only auto-discover streams matching this regex.
+    #[structopt(long = "discovery-allow")]
+    discovery_allow: Option<regex::Regex>,
+
+    /// Never auto-discover streams matching this regex.
+    #[structopt(long = "discovery-deny")]
+    discovery_deny: Option<regex::Regex>,
+
+    /// Write a JSON summary of the run to this path on exit.
+    #[structopt(long = "report", parse(from_os_str))]
+    report: Option<PathBuf>,
+
+    /// Flag node UUIDs seen from this many addresses (and vice versa) as duplicates.
+    #[structopt(long = "duplicates-threshold", default_value = "2")]
+    duplicates_threshold: usize,
+
+    /// Only keep per-node state (addresses, bandwidth, targets, adoption) for this many recent nodes.
+    #[structopt(long = "max-tracked-nodes", default_value = "100000")]
+    max_tracked_nodes: usize,
+
+    /// Only start scraping a stream once it is first requested by a client.
+    #[structopt(long = "lazy-streams")]
+    lazy_streams: bool,
+
+    /// Also emit node metadata keys under this namespace (e.g. `org.example.os`).
+    #[structopt(long = "alt-namespace")]
+    alt_namespace: Option<String>,
+
+    /// Explicit alternate name for a metadata key, as `<fcos-key>=<alternate-key>` (repeatable).
+    #[structopt(
+        long = "alt-key",
+        number_of_values = 1,
+        raw(requires = "\"alt_namespace\"")
+    )]
+    alt_keys: Vec<namespace::KeyMapping>,
+
+    /// Fail graph requests whose edges do not point from older to newer releases.
+    #[structopt(long = "validate-edges")]
+    validate_edges: bool,
+
+    /// Backward edge accepted by `--validate-edges`, as `<from-version>=<to-version>` (repeatable).
+    #[structopt(long = "rollback-edge", number_of_values = 1)]
+    rollback_edges: Vec<edges::RollbackEdge>,
+
+    /// Withhold newly scraped releases until approved via `/fakeup/v1/admin/approve/<version>`.
+    #[structopt(long = "gate-releases")]
+    gate_releases: bool,
+
+    /// Automatically approve gated releases after this delay.
+    #[structopt(
+        long = "auto-approve-after",
+        parse(try_from_str = "config::parse_duration"),
+        raw(requires = "\"gate_releases\"")
+    )]
+    auto_approve_after: Option<std::time::Duration>,
+
+    /// Serve canned raw responses from `<dir>/<name>.http` on a separate port.
+    #[structopt(long = "raw-fixtures", parse(from_os_str))]
+    raw_fixtures: Option<PathBuf>,
+
+    /// Port for the raw fixtures listener (0 for an ephemeral port).
+    #[structopt(long = "raw-fixtures-port", default_value = "9878")]
+    raw_fixtures_port: u16,
+
+    /// Mirror metrics to this StatsD address (`host:port`, UDP).
+    #[structopt(long = "statsd-addr")]
+    statsd_addr: Option<String>,
+
+    /// Prefix for StatsD metric names.
+    #[structopt(long = "statsd-prefix", default_value = "fakeup")]
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    statsd_prefix: String,
+
+    /// Interval between StatsD flushes.
+    #[structopt(
+        long = "statsd-interval",
+        default_value = "10s",
+        parse(try_from_str = "config::parse_duration")
+    )]
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    statsd_interval: std::time::Duration,
+
+    /// Serve this many generated releases per stream, instead of scraping upstream.
+    #[structopt(
+        long = "synthetic-releases",
+        raw(conflicts_with = "\"import_fixture\"")
+    )]
+    synthetic_releases: Option<usize>,
+
+    /// Serve a fixed target for a stream, as `<stream>=<version>:<checksum>`,
+    /// without scraping (repeatable).
+    #[structopt(
+        long = "pin",
+        number_of_values = 1,
+        parse(try_from_str = "pin::parse_pin"),
+        raw(conflicts_with_all = r#"&["import_fixture", "synthetic_releases", "discovery_url"]"#)
+    )]
+    pins: Vec<pin::Pin>,
+
+    /// Read `releases-<stream>.json` files (or `.json.gz`, `.json.zst`) from
+    /// this directory instead of upstream, re-reading them on every refresh.
+    #[structopt(
+        long = "fixtures-dir",
+        parse(from_os_str),
+        raw(env = "\"FAKEUP_FIXTURES_DIR\""),
+        raw(conflicts_with_all = r#"&["import_fixture", "synthetic_releases", "pins"]"#)
+    )]
+    fixtures_dir: Option<PathBuf>,
+
+    /// Persist scraped release indexes to this directory, to fall back on them
+    /// if upstream stops serving a stream.
+    #[structopt(
+        long = "snapshot-dir",
+        parse(from_os_str),
+        raw(env = "\"FAKEUP_SNAPSHOT_DIR\"")
+    )]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Streams not found upstream (404): `lenient` (serve last known releases,
+    /// flagged stale) or `strict` (drop them).
+    #[structopt(long = "missing-stream", default_value = "lenient")]
+    missing_stream: snapshot::MissingStream,
+
+    /// Architecture of generated payloads (repeatable) [default: served architectures].
+    #[structopt(
+        long = "synthetic-arch",
+        number_of_values = 1,
+        raw(requires = "\"synthetic_releases\"")
+    )]
+    synthetic_arches: Vec<String>,
+
+    /// Redact this query parameter in logs (repeatable, e.g. `node_uuid`).
+    #[structopt(long = "redact-param", number_of_values = 1)]
+    redact_params: Vec<String>,
+
+    /// How to redact logged parameters: `hash` (stable, for correlation) or `mask`.
+    #[structopt(long = "redact-mode", default_value = "hash")]
+    redact_mode: redact::RedactMode,
+
+    /// Detach from the terminal and run in the background, discarding output not redirected with `--stdout`/`--stderr`.
+    #[structopt(long = "daemonize")]
+    daemonize: bool,
+
+    /// Write the server PID to this file, removing it on exit.
+    #[structopt(long = "pidfile", parse(from_os_str))]
+    pidfile: Option<PathBuf>,
+
+    /// Append standard output to this file.
+    #[structopt(long = "stdout", parse(from_os_str))]
+    stdout: Option<PathBuf>,
+
+    /// Append standard error (logs) to this file.
+    #[structopt(long = "stderr", parse(from_os_str))]
+    stderr: Option<PathBuf>,
+
+    /// Load cached releases and config from a fixture bundle, freezing the cache.
+    #[structopt(
+        long = "import-fixture",
+        parse(from_os_str),
+        raw(env = "\"FAKEUP_IMPORT_FIXTURE\"")
+    )]
+    import_fixture: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::test::TestRequest;
+    use actix_web::HttpMessage;
+
+    /// Server state without any stream, nor optional feature.
+    pub(crate) fn test_state() -> AppState {
+        let scraper = scraper::Scraper::new(
+            Default::default(),
+            std::time::Duration::from_secs(30),
+            Default::default(),
+        )
+        .unwrap();
+        AppState {
+            inflight_limit: None,
+            node_quota: None,
+            client_version: ClientVersion::Placeholder,
+            scraper_addr: scraper.start(),
+            clients: clients::ClientsTable::new(false, 2),
+            response_template: None,
+            payload_blobs: None,
+            edge_validator: None,
+            stream_pattern: regex::Regex::new(query::DEFAULT_STREAM_PATTERN).unwrap(),
+            checksum_validation: query::ChecksumValidation::Off,
+            arches: maplit::btreeset![query::DEFAULT_BASEARCH.to_string()],
+            alt_namespace: None,
+            platform_delays: HashMap::new(),
+            quiet_hours: HashMap::new(),
+            allowed_platforms: None,
+            unserved_platform: UnservedPlatform::Empty,
+            pretty_json: false,
+            graph_compression: encode::GraphCompression::Auto,
+            cache_policy: Default::default(),
+            stream_override: false,
+            graph_token: None,
+            pins: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn graph_requests_are_shed_at_the_limit() {
+        let _sys = actix::System::new("graph-shedding");
+        let limit = inflight::InflightLimit::new(1);
+        let _busy = limit.try_acquire().unwrap();
+        let mut state = test_state();
+        state.inflight_limit = Some(limit);
+
+        let req = TestRequest::with_state(state)
+            .uri("/v1/graph?stream=stable&os_checksum=abc")
+            .finish();
+        let resp = serve_graph(req).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let retry_after = inflight::RETRY_AFTER_SECS.to_string();
+        assert_eq!(resp.headers()["Retry-After"], retry_after.as_str());
+    }
+
+    #[test]
+    fn long_polls_release_their_inflight_slot() {
+        let _sys = actix::System::new("graph-long-poll-slot");
+        let limit = inflight::InflightLimit::new(1);
+        let mut state = test_state();
+        state.inflight_limit = Some(limit.clone());
+
+        let req = TestRequest::with_state(state)
+            .uri("/v1/graph?stream=stable&os_checksum=abc&wait=30")
+            .finish();
+        let _waiting = serve_graph(req);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn invalid_streams_are_rejected() {
+        let _sys = actix::System::new("invalid-stream");
+        let req = TestRequest::with_state(test_state())
+            .uri("/v1/graph?stream=Not_A_Stream&os_checksum=abc")
+            .finish();
+        let resp = serve_graph(req).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn releases_url_template_flag_parses() {
+        let template = "http://localhost:8000/${stream}.json";
+        let args = &["fakeup", "--releases-url-template", template, "fetch"];
+        let opts = CliOptions::from_iter_safe(args).unwrap();
+        assert_eq!(opts.releases_url_template.as_deref(), Some(template));
+        assert!(scraper::releases_url(Some("localhost/${stream}"), "stable".to_string()).is_err());
+    }
+
+    #[test]
+    fn streams_are_repeatable() {
+        let args = &["fakeup", "serve", "--stream", "next", "--stream", "stable"];
+        match CliOptions::from_iter_safe(args).unwrap().command().unwrap() {
+            Command::Serve(opts) => assert_eq!(opts.streams, vec!["next", "stable"]),
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn streams_flags_take_precedence() {
+        let mut file_config = config::ConfigFile::default();
+        let builtin = scraped_streams(&[], &file_config);
+        assert!(builtin.contains("stable"));
+        file_config.streams = Some(maplit::btreeset!["from-file".to_string()]);
+        let streams = scraped_streams(&[], &file_config);
+        assert_eq!(streams, maplit::btreeset!["from-file".to_string()]);
+        let streams = scraped_streams(&["from-flag".to_string()], &file_config);
+        assert_eq!(streams, maplit::btreeset!["from-flag".to_string()]);
+    }
+
+    #[test]
+    fn refresh_interval_flag_takes_precedence() {
+        let mut file_config = config::ConfigFile::default();
+        let interval = refresh_interval(None, &file_config).unwrap();
+        assert_eq!(interval, DEFAULT_REFRESH_INTERVAL);
+        file_config.refresh_interval = Some("5m".to_string());
+        let interval = refresh_interval(None, &file_config).unwrap();
+        assert_eq!(interval, Duration::from_secs(300));
+        let flag = Duration::from_secs(10);
+        assert_eq!(refresh_interval(Some(flag), &file_config).unwrap(), flag);
+        file_config.refresh_interval = Some("often".to_string());
+        assert!(refresh_interval(None, &file_config).is_err());
+    }
+
+    #[test]
+    fn subcommands_parse() {
+        for name in &["render", "dump"] {
+            let args = &["fakeup", name, "--stream", "next"];
+            match CliOptions::from_iter_safe(args).unwrap().command().unwrap() {
+                Command::Render { stream, basearch } => {
+                    assert_eq!(stream, "next");
+                    assert_eq!(basearch, "x86_64");
+                }
+                cmd => panic!("unexpected command: {:?}", cmd),
+            }
+        }
+        assert!(CliOptions::from_iter_safe(&["fakeup", "render"]).is_err());
+
+        let args = &["fakeup", "fetch", "--stream", "next"];
+        match CliOptions::from_iter_safe(args).unwrap().command().unwrap() {
+            Command::Fetch { streams } => assert_eq!(streams, vec!["next"]),
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+        // Server options belong to `serve`.
+        assert!(CliOptions::from_iter_safe(&["fakeup", "fetch", "--port", "1"]).is_err());
+
+        match CliOptions::from_iter_safe(&["fakeup", "demo"])
+            .unwrap()
+            .command()
+            .unwrap()
+        {
+            Command::Demo { port } => assert_eq!(port, 9876),
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+
+        // Without a subcommand, fakeup serves.
+        let mut cli = CliOptions::from_iter_safe(&["fakeup", "-v"]).unwrap();
+        assert!(cli.cmd.is_none());
+        match cli.command().unwrap() {
+            Command::Serve(opts) => assert!(opts.streams.is_empty()),
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn tls_flags_go_together() {
+        let args = &[
+            "fakeup",
+            "serve",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+        ];
+        match CliOptions::from_iter_safe(args).unwrap().command().unwrap() {
+            Command::Serve(opts) => {
+                assert_eq!(opts.tls_cert, Some(PathBuf::from("cert.pem")));
+                assert_eq!(opts.tls_key, Some(PathBuf::from("key.pem")));
+            }
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+        let args = &["fakeup", "serve", "--tls-cert", "cert.pem"];
+        assert!(CliOptions::from_iter_safe(args).is_err());
+        let args = &["fakeup", "serve", "--tls-key", "key.pem"];
+        assert!(CliOptions::from_iter_safe(args).is_err());
+        let args = &[
+            "fakeup",
+            "serve",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "--listen-socket",
+            "/run/fakeup.sock",
+        ];
+        assert!(CliOptions::from_iter_safe(args).is_err());
+        let args = &["fakeup", "serve", "--tls-client-ca", "ca.pem"];
+        assert!(CliOptions::from_iter_safe(args).is_err());
+    }
+
+    #[test]
+    fn listen_addresses_are_repeatable() {
+        let args = &[
+            "fakeup",
+            "serve",
+            "--listen",
+            "127.0.0.1:1",
+            "--listen",
+            "[::1]:2",
+        ];
+        match CliOptions::from_iter_safe(args).unwrap().command().unwrap() {
+            Command::Serve(opts) => {
+                let addrs: Vec<_> = opts.listen.iter().map(|a| a.to_string()).collect();
+                assert_eq!(addrs, vec!["127.0.0.1:1", "[::1]:2"]);
+            }
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+        let args = &["fakeup", "serve", "--listen", "127.0.0.1:1", "--port", "2"];
+        assert!(CliOptions::from_iter_safe(args).is_err());
+        assert!(CliOptions::from_iter_safe(&["fakeup", "serve", "--listen", "localhost"]).is_err());
+    }
+
+    #[test]
+    fn verbosity_flags_are_global() {
+        let verbose = |args: &[&str]| CliOptions::from_iter_safe(args).unwrap().verbose;
+        assert_eq!(verbose(&["fakeup", "serve"]), 0);
+        assert_eq!(verbose(&["fakeup", "-vv", "serve"]), 2);
+        assert_eq!(verbose(&["fakeup", "fetch", "--verbose", "-v"]), 2);
+    }
+
+    #[test]
+    fn env_settings_yield_to_flags() {
+        let workers =
+            |args: &[&str]| match CliOptions::from_iter_safe(args).unwrap().command().unwrap() {
+                Command::Serve(opts) => opts.workers,
+                cmd => panic!("unexpected command: {:?}", cmd),
+            };
+        std::env::set_var("FAKEUP_WORKERS", "3");
+        let from_env = workers(&["fakeup", "serve"]);
+        let from_flag = workers(&["fakeup", "serve", "--workers", "5"]);
+        std::env::remove_var("FAKEUP_WORKERS");
+        assert_eq!(from_env, Some(3));
+        assert_eq!(from_flag, Some(5));
+    }
+
+    #[test]
+    fn unsupported_arches_are_rejected() {
+        let _sys = actix::System::new("unsupported-arches");
+        let req = TestRequest::with_state(test_state())
+            .uri("/v1/graph?stream=stable&os_checksum=abc&basearch=s390x")
+            .finish();
+        let resp = serve_graph(req).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn unserved_platforms_are_handled() {
+        let mut sys = actix::System::new("unserved-platforms");
+        let mut state = test_state();
+        state.allowed_platforms = Some(maplit::hashset!["metal".to_string()]);
+        let graph_req = |state: &AppState, params: &str| {
+            let uri = format!("/v1/graph?stream=stable&os_checksum=abc{}", params);
+            TestRequest::with_state(state.clone()).uri(&uri).finish()
+        };
+
+        let resp = serve_graph(graph_req(&state, "&platform=aws"))
+            .wait()
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        state.unserved_platform = "reject".parse().unwrap();
+        let resp = serve_graph(graph_req(&state, "")).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let served = serve_graph(graph_req(&state, "&platform=metal"));
+        let resp = sys.block_on(served).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!("drop".parse::<UnservedPlatform>().is_err());
+    }
+
+    #[test]
+    fn client_version_modes_parse() {
+        let mode: ClientVersion = "placeholder".parse().unwrap();
+        assert_eq!(mode.placeholder(), "client-os-version");
+        let mode: ClientVersion = "empty".parse().unwrap();
+        assert_eq!(mode.placeholder(), "");
+        let mode: ClientVersion = "derived".parse().unwrap();
+        assert_eq!(mode, ClientVersion::Derived);
+        assert_eq!(mode.placeholder(), "");
+        assert!("latest".parse::<ClientVersion>().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "admin-api")]
+    fn node_quotas_are_enforced_until_reset() {
+        let mut sys = actix::System::new("node-quotas");
+        let mut state = test_state();
+        state.node_quota = Some(quota::NodeQuota::new("1/1h".parse().unwrap()));
+        let graph_req = |state: &AppState| {
+            TestRequest::with_state(state.clone())
+                .uri("/v1/graph?stream=stable&os_checksum=abc&node_uuid=n1")
+                .finish()
+        };
+
+        // Within quota, the request goes through to the (stream-less) scraper.
+        let resp = sys.block_on(serve_graph(graph_req(&state))).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = sys.block_on(serve_graph(graph_req(&state))).unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["Retry-After"], "3600");
+
+        let reset_req = TestRequest::with_state(state.clone())
+            .method(Method::POST)
+            .uri("/admin/v1/quotas/reset?node_uuid=n1")
+            .finish();
+        let resp = admin::reset_quotas(reset_req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = sys.block_on(serve_graph(graph_req(&state))).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn non_get_graph_requests_are_not_allowed() {
+        let _sys = actix::System::new("graph-method");
+        let req = TestRequest::with_state(test_state())
+            .method(Method::POST)
+            .uri("/v1/graph?stream=stable&os_checksum=abc")
+            .finish();
+        let resp = graph_method_not_allowed(&req);
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[header::ALLOW], "GET");
+    }
+
+    #[test]
+    fn admin_routes_follow_features() {
+        let mut srv = actix_web::test::TestServer::with_factory(|| {
+            router::register(App::with_state(test_state()))
+        });
+        let req = srv
+            .client(Method::POST, "/fakeup/v1/admin/freeze")
+            .finish()
+            .unwrap();
+        let resp = srv.execute(req.send()).unwrap();
+        let registered = resp.status() != StatusCode::NOT_FOUND;
+        assert_eq!(registered, cfg!(feature = "admin-api"));
+
+        let req = srv
+            .get()
+            .uri(srv.url("/fakeup/v1/debug/echo"))
+            .finish()
+            .unwrap();
+        let resp = srv.execute(req.send()).unwrap();
+        assert_ne!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn pinned_streams_are_served_without_scraping() {
+        let mut srv = actix_web::test::TestServer::with_factory(|| {
+            let pin = pin::parse_pin("stable=1.2.3:c123").unwrap();
+            let mut state = test_state();
+            state.pins.insert(pin.stream.clone(), pin.node());
+            router::register(App::with_state(state))
+        });
+        let mut get_graph = |params: &str| {
+            let uri = srv.url(&format!("/v1/graph?{}", params));
+            let req = srv.get().uri(uri).finish().unwrap();
+            let resp = srv.execute(req.send()).unwrap();
+            let body = srv.execute(resp.body()).unwrap();
+            (resp.status(), body)
+        };
+
+        let (status, body) = get_graph("stream=stable&os_checksum=abc");
+        assert_eq!(status, StatusCode::OK);
+        let graph: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let payloads: Vec<_> = graph["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| node["payload"].as_str().unwrap())
+            .collect();
+        assert_eq!(payloads, vec!["abc", "c123"]);
+        assert_eq!(graph["edges"], serde_json::json!([[0, 1]]));
+
+        let (status, _) = get_graph("stream=next&os_checksum=abc");
+        assert_ne!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn stream_override_header_is_opt_in() {
+        let server = |allowed: bool| {
+            actix_web::test::TestServer::with_factory(move || {
+                let mut state = test_state();
+                for pin in &["stable=1.2.3:c123", "next=2.0.0:c200"] {
+                    let pin = pin::parse_pin(pin).unwrap();
+                    state.pins.insert(pin.stream.clone(), pin.node());
+                }
+                state.stream_override = allowed;
+                router::register(App::with_state(state))
+            })
+        };
+        let target = |srv: &mut actix_web::test::TestServer| {
+            let uri = srv.url("/v1/graph?stream=stable&os_checksum=abc");
+            let req = srv
+                .get()
+                .uri(uri)
+                .header(query::STREAM_OVERRIDE_HEADER, "next")
+                .finish()
+                .unwrap();
+            let resp = srv.execute(req.send()).unwrap();
+            let body = srv.execute(resp.body()).unwrap();
+            let graph: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            graph["nodes"][1]["payload"].as_str().unwrap().to_string()
+        };
+        assert_eq!(target(&mut server(true)), "c200");
+        assert_eq!(target(&mut server(false)), "c123");
+    }
+
+    #[test]
+    fn quiet_hours_drop_update_edges() {
+        let mut srv = actix_web::test::TestServer::with_factory(|| {
+            let pin = pin::parse_pin("stable=1.2.3:c123").unwrap();
+            let mut state = test_state();
+            state.pins.insert(pin.stream.clone(), pin.node());
+            let now = clock::now().time();
+            let hour = chrono::Duration::hours(1);
+            let hours = format!(
+                "{}-{}",
+                (now - hour).format("%H:%M"),
+                (now + hour).format("%H:%M")
+            );
+            state
+                .quiet_hours
+                .insert("stable".to_string(), hours.parse().unwrap());
+            router::register(App::with_state(state))
+        });
+        let mut get_graph = |offset: &str| {
+            let uri = srv.url(&format!(
+                "/v1/graph?stream=stable&os_checksum=abc&tz_offset={}",
+                offset
+            ));
+            let req = srv.get().uri(uri).finish().unwrap();
+            let resp = srv.execute(req.send()).unwrap();
+            let quiet = resp.headers().contains_key("X-Fakeup-Quiet-Hours");
+            let body = srv.execute(resp.body()).unwrap();
+            let graph: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            (quiet, graph["edges"].as_array().unwrap().len())
+        };
+        assert_eq!(get_graph("00:00"), (true, 0));
+        assert_eq!(get_graph("12:00"), (false, 1));
+    }
+
+    #[test]
+    fn graph_token_is_required_if_set() {
+        let mut srv = actix_web::test::TestServer::with_factory(|| {
+            let pin = pin::parse_pin("stable=1.2.3:c123").unwrap();
+            let mut state = test_state();
+            state.pins.insert(pin.stream.clone(), pin.node());
+            state.graph_token = Some(auth::BearerToken::new("s3cret").unwrap());
+            router::register(App::with_state(state))
+        });
+        let mut get_graph = |token: Option<&str>| {
+            let uri = srv.url("/v1/graph?stream=stable&os_checksum=abc");
+            let mut req = srv.get();
+            req.uri(uri);
+            if let Some(token) = token {
+                req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let resp = srv.execute(req.finish().unwrap().send()).unwrap();
+            resp.status()
+        };
+        assert_eq!(get_graph(Some("s3cret")), StatusCode::OK);
+        assert_eq!(get_graph(Some("guess")), StatusCode::UNAUTHORIZED);
+        assert_eq!(get_graph(None), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn unchanged_graphs_are_not_resent() {
+        let mut srv = actix_web::test::TestServer::with_factory(|| {
+            let pin = pin::parse_pin("stable=1.2.3:c123").unwrap();
+            let mut state = test_state();
+            state.pins.insert(pin.stream.clone(), pin.node());
+            router::register(App::with_state(state))
+        });
+        let mut get_graph = |etag: Option<&str>| {
+            let uri = srv.url("/v1/graph?stream=stable&os_checksum=abc");
+            let mut req = srv.get();
+            req.uri(uri);
+            if let Some(etag) = etag {
+                req.header(header::IF_NONE_MATCH, etag);
+            }
+            let resp = srv.execute(req.finish().unwrap().send()).unwrap();
+            let etag = resp.headers()[header::ETAG].to_str().unwrap().to_string();
+            (resp.status(), etag)
+        };
+        let (status, etag) = get_graph(None);
+        assert_eq!(status, StatusCode::OK);
+        let (status, revalidated) = get_graph(Some(&etag));
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(revalidated, etag);
+        let (status, _) = get_graph(Some("\"stale\""));
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn pretty_json_default_is_overridable() {
+        let mut srv = actix_web::test::TestServer::with_factory(|| {
+            let pin = pin::parse_pin("stable=1.2.3:c123").unwrap();
+            let mut state = test_state();
+            state.pins.insert(pin.stream.clone(), pin.node());
+            state.pretty_json = true;
+            router::register(App::with_state(state))
+        });
+        let mut get_body = |params: &str| {
+            let uri = srv.url(&format!(
+                "/v1/graph?stream=stable&os_checksum=abc{}",
+                params
+            ));
+            let req = srv.get().uri(uri).finish().unwrap();
+            let resp = srv.execute(req.send()).unwrap();
+            srv.execute(resp.body()).unwrap()
+        };
+        assert!(get_body("").contains(&b'\n'));
+        assert!(get_body("&pretty=1").contains(&b'\n'));
+        assert!(!get_body("&pretty=false").contains(&b'\n'));
+    }
+
+    #[test]
+    fn build_version_is_served() {
+        let mut srv = actix_web::test::TestServer::with_factory(|| {
+            router::register(App::with_state(test_state()))
+        });
+        let req = srv
+            .get()
+            .uri(srv.url("/fakeup/v1/version"))
+            .finish()
+            .unwrap();
+        let resp = srv.execute(req.send()).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = srv.execute(resp.body()).unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["version"], version::CRATE_VERSION);
+        assert_eq!(info["git_sha"], version::GIT_SHA);
+    }
+
+    #[test]
+    fn ephemeral_listen_addrs_are_reported() {
+        let server = server::new(App::new)
+            .bind((IpAddr::from(Ipv4Addr::LOCALHOST), 0))
+            .unwrap();
+        let addrs = server.addrs();
+        assert_eq!(addrs.len(), 1);
+        assert_ne!(addrs[0].port(), 0);
+
+        let report = listen_report(&addrs);
+        assert_eq!(report["listen_addrs"][0], addrs[0].to_string());
+    }
 }
@@ -2,18 +2,55 @@
 
 #![allow(dead_code)]
 
-use serde_derive::Deserialize;
+use failure::{bail, format_err, Fallible};
+use serde_derive::{Deserialize, Serialize};
+
+/// Production streams.
+pub static PRODUCTION_STREAMS: [&str; 3] = ["stable", "testing", "next"];
+
+/// Development streams.
+pub static DEVELOPMENT_STREAMS: [&str; 3] = ["bodhi-updates", "next-devel", "testing-devel"];
 
 /// Templated URL for release index.
 pub static RELEASES_JSON: &str =
     "https://builds.coreos.fedoraproject.org/prod/streams/${stream}/releases.json";
 
+/// Per-stream templated URLs for release index, overriding `RELEASES_JSON`.
+pub static STREAM_RELEASES_JSON: [(&str, &str); 3] = [
+    (
+        "stable",
+        "https://builds.coreos.fedoraproject.org/prod/streams/stable/releases.json",
+    ),
+    (
+        "testing",
+        "https://builds.coreos.fedoraproject.org/prod/streams/testing/releases.json",
+    ),
+    (
+        "next",
+        "https://builds.coreos.fedoraproject.org/prod/streams/next/releases.json",
+    ),
+];
+
 /// Templated URL for stream metadata.
 pub static STREAM_JSON: &str = "https://builds.coreos.fedoraproject.org/updates/${stream}.json";
 
+/// Return the templated URL for the release index of a stream.
+pub fn releases_json(stream: &str) -> &'static str {
+    STREAM_RELEASES_JSON
+        .iter()
+        .find(|(name, _)| *name == stream)
+        .map(|(_, url)| *url)
+        .unwrap_or(RELEASES_JSON)
+}
+
 pub static SCHEME: &str = "org.fedoraproject.coreos.scheme";
 
 pub static AGE_INDEX: &str = "org.fedoraproject.coreos.releases.age_index";
+pub static DOWNLOAD_SIZE: &str = "org.fedoraproject.coreos.releases.download_size";
+
+/// Fakeup-specific: node served from the last known releases of a stream
+/// which upstream stopped serving.
+pub static STALE: &str = "fakeup.stale";
 
 pub static DEADEND: &str = "org.fedoraproject.coreos.updates.deadend";
 pub static DEADEND_REASON: &str = "org.fedoraproject.coreos.updates.deadend_reason";
@@ -21,23 +58,76 @@ pub static DURATION: &str = "org.fedoraproject.coreos.updates.duration_minutes";
 pub static START_EPOCH: &str = "org.fedoraproject.coreos.updates.start_epoch";
 pub static START_VALUE: &str = "org.fedoraproject.coreos.updates.start_value";
 
-/// Fedora CoreOS release index.
+/// Stream discovery index.
 #[derive(Clone, Debug, Deserialize)]
+pub struct StreamsJSON {
+    pub streams: Vec<String>,
+}
+
+/// Fedora CoreOS release index.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct ReleasesJSON {
     pub releases: Vec<Release>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+impl ReleasesJSON {
+    /// Parse a release index, rejecting structurally invalid documents.
+    ///
+    /// This never panics on corrupted input, it returns an error instead.
+    pub fn from_slice(body: &[u8]) -> Fallible<Self> {
+        let index: Self = serde_json::from_slice(body)
+            .map_err(|e| format_err!("invalid release index: {}", e))?;
+        for (pos, rel) in index.releases.iter().enumerate() {
+            if rel.version.trim().is_empty() {
+                bail!(
+                    "invalid release index: release #{} has an empty version",
+                    pos
+                );
+            }
+        }
+        Ok(index)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct Release {
+    /// Payloads per architecture; malformed entries are skipped.
+    #[serde(deserialize_with = "lenient_commits")]
     pub commits: Vec<ReleaseCommit>,
     pub version: String,
     pub metadata: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct ReleaseCommit {
     pub architecture: String,
     pub checksum: String,
+    /// Payload size in bytes, if advertised upstream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+/// Parse release payloads, skipping malformed entries instead of
+/// rejecting the whole release index.
+fn lenient_commits<'de, D>(deserializer: D) -> Result<Vec<ReleaseCommit>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let entries: Vec<serde_json::Value> = serde::Deserialize::deserialize(deserializer)?;
+    let commits = entries
+        .into_iter()
+        .filter_map(|entry| match serde_json::from_value(entry) {
+            Ok(commit) => Some(commit),
+            Err(e) => {
+                log::warn!("skipping malformed release payload: {}", e);
+                None
+            }
+        })
+        .collect();
+    Ok(commits)
 }
 
 /// Fedora CoreOS updates metadata
@@ -72,3 +162,82 @@ pub struct UpdateRollout {
     pub start_value: String,
     pub duration_minutes: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn releases_json_overrides() {
+        assert_eq!(
+            releases_json("stable"),
+            "https://builds.coreos.fedoraproject.org/prod/streams/stable/releases.json"
+        );
+        assert_eq!(releases_json("testing-devel"), RELEASES_JSON);
+        for (stream, _) in STREAM_RELEASES_JSON.iter() {
+            assert!(PRODUCTION_STREAMS.contains(stream));
+        }
+    }
+
+    #[test]
+    fn commit_size_is_optional() {
+        let commits: Vec<ReleaseCommit> = serde_json::from_str(
+            r#"[
+                {"architecture": "x86_64", "checksum": "x1", "size": 1024},
+                {"architecture": "aarch64", "checksum": "a1"}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(commits[0].size, Some(1024));
+        assert_eq!(commits[1].size, None);
+    }
+
+    #[test]
+    fn malformed_commits_are_skipped() {
+        let release: Release = serde_json::from_str(
+            r#"{
+                "version": "30.1",
+                "metadata": "",
+                "commits": [
+                    {"architecture": "x86_64", "checksum": "x1"},
+                    {"architecture": "aarch64"},
+                    "garbage"
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(release.commits.len(), 1);
+        assert_eq!(release.commits[0].architecture, "x86_64");
+    }
+
+    #[test]
+    fn fuzz_corpus_is_handled() {
+        let corpus =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/releases_json");
+        let parse = |name: &str| {
+            let body = std::fs::read(corpus.join(name)).unwrap();
+            ReleasesJSON::from_slice(&body)
+        };
+
+        let index = parse("valid-index.json").unwrap();
+        let commits: Vec<_> = index.releases.iter().map(|r| r.commits.len()).collect();
+        assert_eq!(commits, vec![2, 1, 1]);
+        assert!(parse("empty-index.json").unwrap().releases.is_empty());
+        assert!(parse("malformed-commit.json").unwrap().releases[0]
+            .commits
+            .is_empty());
+        for invalid in &[
+            "blank-version.json",
+            "deep-nesting.json",
+            "truncated.json",
+            "wrong-types.json",
+        ] {
+            let err = parse(invalid).unwrap_err();
+            assert!(
+                err.to_string().starts_with("invalid release index"),
+                "{}",
+                invalid
+            );
+        }
+    }
+}
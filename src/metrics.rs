@@ -0,0 +1,52 @@
+//! Prometheus metrics endpoint.
+
+use crate::AppState;
+use actix_web::{HttpRequest, HttpResponse};
+use prometheus::{Encoder, TextEncoder};
+
+/// Serve all registered metrics, in Prometheus text format.
+pub(crate) fn serve_metrics(_req: HttpRequest<AppState>) -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut body = vec![];
+    match encoder.encode(&prometheus::gather(), &mut body) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(body),
+        Err(e) => {
+            log::error!("failed to encode metrics: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_state;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn metrics_are_served_as_text() {
+        let counter = prometheus::register_int_counter!(
+            "fakeup_test_metrics_served_total",
+            "Test counter for the metrics endpoint."
+        )
+        .unwrap();
+        counter.inc();
+
+        let req = TestRequest::with_state(test_state()).finish();
+        let resp = serve_metrics(req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            TextEncoder::new().format_type()
+        );
+        let body = match resp.body() {
+            actix_web::Body::Binary(body) => body.as_ref().to_vec(),
+            _ => panic!("unexpected streaming body"),
+        };
+        let text = String::from_utf8(body).unwrap();
+        assert!(text.contains("fakeup_test_metrics_served_total 1"));
+    }
+}
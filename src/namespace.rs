@@ -0,0 +1,120 @@
+//! Alternate namespace for node metadata keys.
+//!
+//! Derivative distributions may reuse Zincati with their own metadata key
+//! namespace. Nodes then carry each `org.fedoraproject.coreos.*` key twice,
+//! under both the original and the alternate namespace.
+
+use crate::CincinnatiPayload;
+use failure::{bail, format_err, Error, Fallible};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Namespace of upstream metadata keys.
+pub static FCOS_NAMESPACE: &str = "org.fedoraproject.coreos";
+
+/// Explicit mapping for a single key, as `<fcos-key>=<alternate-key>`.
+#[derive(Clone, Debug)]
+pub struct KeyMapping {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for KeyMapping {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        let (from, to) = input
+            .split_once('=')
+            .ok_or_else(|| format_err!("invalid key mapping '{}', missing '='", input))?;
+        if from.is_empty() || to.is_empty() {
+            bail!("invalid key mapping '{}', empty key", input);
+        }
+        let mapping = Self {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        Ok(mapping)
+    }
+}
+
+/// Alternate metadata namespace, with per-key overrides.
+#[derive(Clone, Debug)]
+pub struct AltNamespace {
+    prefix: String,
+    mappings: BTreeMap<String, String>,
+}
+
+impl AltNamespace {
+    pub fn new(prefix: &str, mappings: impl IntoIterator<Item = KeyMapping>) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('.').to_string(),
+            mappings: mappings.into_iter().map(|m| (m.from, m.to)).collect(),
+        }
+    }
+
+    /// Alternate name for a metadata key, if it belongs to the FCOS namespace.
+    fn map_key(&self, key: &str) -> Option<String> {
+        if let Some(mapped) = self.mappings.get(key) {
+            return Some(mapped.clone());
+        }
+        let suffix = key.strip_prefix(FCOS_NAMESPACE)?.strip_prefix('.')?;
+        Some(format!("{}.{}", self.prefix, suffix))
+    }
+
+    /// Add alternate keys to node metadata, keeping the original ones.
+    pub(crate) fn apply(&self, node: &mut CincinnatiPayload) {
+        let extra: Vec<(String, String)> = node
+            .metadata
+            .iter()
+            .filter_map(|(k, v)| self.map_key(k).map(|alt| (alt, v.clone())))
+            .collect();
+        for (key, value) in extra {
+            node.metadata.entry(key).or_insert(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn key_mappings_parse() {
+        let mapping: KeyMapping = "a.b=c.d".parse().unwrap();
+        assert_eq!(mapping.from, "a.b");
+        assert_eq!(mapping.to, "c.d");
+        assert!("a.b".parse::<KeyMapping>().is_err());
+        assert!("=c.d".parse::<KeyMapping>().is_err());
+        assert!("a.b=".parse::<KeyMapping>().is_err());
+    }
+
+    #[test]
+    fn fcos_keys_are_duplicated() {
+        let mapping: KeyMapping = "org.fedoraproject.coreos.scheme=org.example.update-scheme"
+            .parse()
+            .unwrap();
+        let ns = AltNamespace::new("org.example.os.", vec![mapping]);
+        let mut metadata = HashMap::new();
+        metadata.insert(
+            "org.fedoraproject.coreos.releases.age_index".to_string(),
+            "3".to_string(),
+        );
+        metadata.insert(
+            "org.fedoraproject.coreos.scheme".to_string(),
+            "checksum".to_string(),
+        );
+        metadata.insert("other.key".to_string(), "x".to_string());
+        let mut node = CincinnatiPayload {
+            version: "30.1".to_string(),
+            metadata,
+            payload: "abc".to_string(),
+        };
+
+        ns.apply(&mut node);
+        assert_eq!(node.metadata.len(), 5);
+        assert_eq!(node.metadata["org.example.os.releases.age_index"], "3");
+        assert_eq!(node.metadata["org.example.update-scheme"], "checksum");
+        assert_eq!(node.metadata["org.fedoraproject.coreos.scheme"], "checksum");
+    }
+}
@@ -0,0 +1,86 @@
+//! Dummy payload blobs, addressed by checksum.
+
+use crate::AppState;
+use crate::{errors, scraper};
+use actix_web::{HttpRequest, HttpResponse};
+use failure::{Error, Fallible};
+use futures::future;
+use futures::prelude::*;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Source of payload blob content.
+#[derive(Clone, Debug)]
+pub enum PayloadBlobs {
+    /// Same fixed content for all payloads.
+    Fixed(Arc<Vec<u8>>),
+    /// Content generated from the checksum, of the given size.
+    Generated(u64),
+}
+
+impl PayloadBlobs {
+    /// Load fixed blob content from file.
+    pub fn from_path(path: &Path) -> Fallible<Self> {
+        let content = std::fs::read(path)?;
+        Ok(PayloadBlobs::Fixed(Arc::new(content)))
+    }
+
+    /// Return blob content for a checksum.
+    fn content(&self, checksum: &str) -> Vec<u8> {
+        match self {
+            PayloadBlobs::Fixed(content) => content.as_ref().clone(),
+            PayloadBlobs::Generated(size) => {
+                checksum.bytes().cycle().take(*size as usize).collect()
+            }
+        }
+    }
+}
+
+/// Serve the payload blob for a known checksum.
+pub(crate) fn serve_payload(
+    req: HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let blobs = match req.state().payload_blobs.clone() {
+        Some(blobs) => blobs,
+        None => return Box::new(future::ok(HttpResponse::NotFound().finish())),
+    };
+    let checksum = match req.match_info().get("checksum") {
+        Some(c) if !c.is_empty() => c.to_string(),
+        _ => {
+            let body = errors::ErrorBody::new("missing_checksum", "missing payload checksum");
+            return Box::new(future::ok(HttpResponse::BadRequest().json(body)));
+        }
+    };
+
+    let msg = scraper::HasPayload {
+        checksum: checksum.clone(),
+    };
+    let resp = scraper::request(&req.state().scraper_addr, msg).map(move |known| {
+        if !known {
+            return HttpResponse::NotFound().finish();
+        }
+        HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(blobs.content(&checksum))
+    });
+    Box::new(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_content_cycles_checksum() {
+        let blobs = PayloadBlobs::Generated(7);
+        assert_eq!(blobs.content("abc"), b"abcabca".to_vec());
+        assert!(PayloadBlobs::Generated(0).content("abc").is_empty());
+    }
+
+    #[test]
+    fn fixed_content_ignores_checksum() {
+        let blobs = PayloadBlobs::Fixed(Arc::new(b"blob".to_vec()));
+        assert_eq!(blobs.content("abc"), blobs.content("def"));
+        assert_eq!(blobs.content("abc"), b"blob".to_vec());
+    }
+}
@@ -0,0 +1,77 @@
+//! Statically pinned releases, served without scraping.
+
+use crate::metadata;
+use crate::CincinnatiPayload;
+use failure::{format_err, Fallible};
+use maplit::hashmap;
+
+/// Fixed target release for a stream.
+#[derive(Clone, Debug)]
+pub(crate) struct Pin {
+    pub(crate) stream: String,
+    pub(crate) version: String,
+    pub(crate) checksum: String,
+}
+
+impl Pin {
+    /// Node served as the target of the pinned stream.
+    pub(crate) fn node(&self) -> CincinnatiPayload {
+        CincinnatiPayload {
+            version: self.version.clone(),
+            payload: self.checksum.clone(),
+            metadata: hashmap! {
+                metadata::SCHEME.to_string() => "checksum".to_string(),
+                metadata::AGE_INDEX.to_string() => "0".to_string(),
+            },
+        }
+    }
+}
+
+/// Parse a pinned release, as `<stream>=<version>:<checksum>`.
+pub(crate) fn parse_pin(input: &str) -> Fallible<Pin> {
+    let (stream, release) = input
+        .split_once('=')
+        .ok_or_else(|| format_err!("invalid pin '{}', missing '='", input))?;
+    let (version, checksum) = release
+        .split_once(':')
+        .ok_or_else(|| format_err!("invalid pin '{}', missing ':'", input))?;
+    if stream.is_empty() || version.is_empty() || checksum.is_empty() {
+        return Err(format_err!(
+            "invalid pin '{}', expected '<stream>=<version>:<checksum>'",
+            input
+        ));
+    }
+    let pin = Pin {
+        stream: stream.to_string(),
+        version: version.to_string(),
+        checksum: checksum.to_string(),
+    };
+    Ok(pin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_parse() {
+        let pin = parse_pin("stable=1.2.3:c123").unwrap();
+        assert_eq!(pin.stream, "stable");
+        let node = pin.node();
+        assert_eq!(
+            (node.version.as_str(), node.payload.as_str()),
+            ("1.2.3", "c123")
+        );
+        assert_eq!(node.metadata[metadata::AGE_INDEX], "0");
+
+        for invalid in &[
+            "stable",
+            "stable=1.2.3",
+            "=1.2.3:c123",
+            "stable=:c123",
+            "stable=1.2.3:",
+        ] {
+            assert!(parse_pin(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Client query parameters for graph requests.
+
+use crate::errors::GraphError;
+use crate::AppState;
+use actix_web::HttpRequest;
+use failure::{format_err, Error, Fallible};
+use prometheus::{IntCounter, IntCounterVec};
+use serde_derive::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+lazy_static::lazy_static! {
+    static ref INVALID_STREAMS: IntCounter = register_int_counter!(opts!(
+        "fakeup_graph_invalid_stream_requests_total",
+        "Total number of graph requests rejected due to an invalid stream name"
+    ))
+    .unwrap();
+    static ref MALFORMED_CHECKSUMS: IntCounterVec = register_int_counter_vec!(
+        "fakeup_graph_malformed_checksum_requests_total",
+        "Total number of graph requests with a malformed client OS checksum",
+        &["action"]
+    )
+    .unwrap();
+}
+
+/// Architecture served by default, and assumed for clients which do not send one.
+pub static DEFAULT_BASEARCH: &str = "x86_64";
+
+/// Request header redirecting the lookup to another stream, if allowed.
+pub static STREAM_OVERRIDE_HEADER: &str = "X-Fakeup-Stream-Override";
+
+/// Upper bound for long-poll waits.
+pub static MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// Default pattern for valid stream names (e.g. `stable`, `testing-devel`).
+pub static DEFAULT_STREAM_PATTERN: &str = "^[a-z0-9]+(-[a-z0-9]+)*$";
+
+/// Strictness of client OS checksum validation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumValidation {
+    /// No validation.
+    Off,
+    /// Accept malformed checksums, but log and count them.
+    Lenient,
+    /// Reject malformed checksums.
+    Strict,
+}
+
+impl std::str::FromStr for ChecksumValidation {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        match input {
+            "off" => Ok(ChecksumValidation::Off),
+            "lenient" => Ok(ChecksumValidation::Lenient),
+            "strict" => Ok(ChecksumValidation::Strict),
+            _ => Err(format_err!("unknown checksum validation mode '{}'", input)),
+        }
+    }
+}
+
+/// Graph request, as interpreted by the server.
+#[derive(Clone, Debug, Serialize)]
+pub struct GraphQuery {
+    /// Client OS stream.
+    pub stream: String,
+    /// Client OS checksum.
+    pub checksum: String,
+    /// Client architecture, of the served payloads.
+    pub basearch: String,
+    /// Client node identifier.
+    pub node_uuid: Option<String>,
+    /// Client platform.
+    pub platform: Option<String>,
+    /// Client updates group.
+    pub group: Option<String>,
+    /// Client rollout wariness, in the `[0.0, 1.0]` range.
+    pub rollout_wariness: Option<f64>,
+    /// Client timezone hint, for quiet hours.
+    #[serde(skip)]
+    pub utc_offset: Option<chrono::FixedOffset>,
+    /// Long-poll: wait up to this long for the stream graph to change.
+    #[serde(skip)]
+    pub wait: Option<Duration>,
+    /// Pretty-print the JSON response, overriding the server default.
+    #[serde(skip)]
+    pub pretty: Option<bool>,
+}
+
+impl GraphQuery {
+    /// Interpret a graph request, as the graph endpoint does: parse its
+    /// query, apply the stream override (if allowed) and validate it.
+    pub(crate) fn from_request(req: &HttpRequest<AppState>) -> Result<Self, GraphError> {
+        let state = req.state();
+        let mut gq = Self::parse(&req.query())?;
+        // Proxies may steer the lookup to another stream, unbeknownst to the client.
+        if state.stream_override {
+            if let Some(value) = req.headers().get(STREAM_OVERRIDE_HEADER) {
+                let stream = value.to_str().unwrap_or_default().trim();
+                log::debug!("stream override: '{}' -> '{}'", gq.stream, stream);
+                gq.stream = stream.to_string();
+            }
+        }
+        gq.check_stream(&state.stream_pattern)?;
+        gq.check_basearch(&state.arches)?;
+        gq.check_checksum(state.checksum_validation)?;
+        Ok(gq)
+    }
+
+    /// Parse and normalize query parameters.
+    pub fn parse(query: &HashMap<String, String>) -> Result<Self, GraphError> {
+        let non_empty = |key: &str| {
+            query
+                .get(key)
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+
+        // Zincati sends `os_checksum`, older clients `current_os`.
+        let checksum = match non_empty("current_os").or_else(|| non_empty("os_checksum")) {
+            Some(c) => c,
+            None => return Err(GraphError::MissingChecksum),
+        };
+        let stream = match non_empty("stream") {
+            Some(s) => s,
+            None => return Err(GraphError::MissingStream),
+        };
+        // Malformed optional parameters are ignored, as on the real service.
+        let rollout_wariness = match non_empty("rollout_wariness") {
+            Some(w) => match w.parse::<f64>() {
+                Ok(w) if w.is_finite() => Some(w.clamp(0.0, 1.0)),
+                _ => ignored("rollout_wariness", &w, "not a number"),
+            },
+            None => None,
+        };
+        let pretty = match non_empty("pretty").as_deref() {
+            Some("1") | Some("true") => Some(true),
+            Some("0") | Some("false") => Some(false),
+            // Unknown values fall back to the server default.
+            _ => None,
+        };
+        let wait = match non_empty("wait") {
+            Some(w) => match w.parse::<u64>() {
+                Ok(secs) => Some(Duration::from_secs(secs).min(MAX_WAIT)),
+                Err(e) => ignored("wait", &w, e),
+            },
+            None => None,
+        };
+        let utc_offset = match non_empty("tz_offset") {
+            Some(tz) => match crate::quiet::parse_utc_offset(&tz) {
+                Ok(offset) => Some(offset),
+                Err(e) => ignored("tz_offset", &tz, e),
+            },
+            None => None,
+        };
+
+        let gq = Self {
+            stream,
+            checksum,
+            basearch: non_empty("basearch").unwrap_or_else(|| DEFAULT_BASEARCH.to_string()),
+            node_uuid: non_empty("node_uuid"),
+            platform: non_empty("platform"),
+            group: non_empty("group"),
+            rollout_wariness,
+            utc_offset,
+            wait,
+            pretty,
+        };
+        Ok(gq)
+    }
+
+    /// Check that the client OS checksum is a SHA-256 digest (lowercase hex).
+    ///
+    /// It only fails in strict mode; in lenient mode, malformed checksums
+    /// are logged and counted.
+    pub fn check_checksum(&self, mode: ChecksumValidation) -> Result<(), GraphError> {
+        let well_formed = self.checksum.len() == 64
+            && self
+                .checksum
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        match mode {
+            _ if well_formed => Ok(()),
+            ChecksumValidation::Off => Ok(()),
+            ChecksumValidation::Lenient => {
+                MALFORMED_CHECKSUMS.with_label_values(&["accepted"]).inc();
+                log::warn!(
+                    "accepting malformed OS checksum '{}' (stream '{}', node {})",
+                    self.checksum,
+                    self.stream,
+                    self.node_uuid
+                        .as_deref()
+                        .map(|uuid| crate::redact::param("node_uuid", uuid))
+                        .unwrap_or_else(|| "-".to_string())
+                );
+                Ok(())
+            }
+            ChecksumValidation::Strict => {
+                MALFORMED_CHECKSUMS.with_label_values(&["rejected"]).inc();
+                Err(GraphError::InvalidChecksum(format!(
+                    "malformed OS checksum '{}', expected SHA-256",
+                    self.checksum
+                )))
+            }
+        }
+    }
+
+    /// Check the client architecture against the served ones.
+    pub fn check_basearch(&self, arches: &BTreeSet<String>) -> Result<(), GraphError> {
+        if !arches.contains(&self.basearch) {
+            let reason = format!("unsupported basearch '{}'", self.basearch);
+            return Err(GraphError::InvalidBasearch(reason));
+        }
+        Ok(())
+    }
+
+    /// Check the stream name against a validation pattern.
+    pub fn check_stream(&self, pattern: &regex::Regex) -> Result<(), GraphError> {
+        if !pattern.is_match(&self.stream) {
+            INVALID_STREAMS.inc();
+            let reason = format!("invalid stream name '{}'", self.stream);
+            return Err(GraphError::InvalidStream(reason));
+        }
+        Ok(())
+    }
+}
+
+/// Log and drop a malformed optional parameter.
+fn ignored<T>(key: &str, value: &str, reason: impl std::fmt::Display) -> Option<T> {
+    log::debug!(
+        "ignoring invalid '{}' parameter '{}': {}",
+        key,
+        value,
+        reason
+    );
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(params: &[(&str, &str)]) -> HashMap<String, String> {
+        params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_normalizes_parameters() {
+        let gq = GraphQuery::parse(&query(&[
+            ("stream", " testing "),
+            ("os_checksum", "abc"),
+            ("node_uuid", ""),
+            ("group", "workers"),
+            ("rollout_wariness", "1.5"),
+        ]))
+        .unwrap();
+        assert_eq!(gq.stream, "testing");
+        assert_eq!(gq.checksum, "abc");
+        assert_eq!(gq.basearch, DEFAULT_BASEARCH);
+        assert_eq!(gq.node_uuid, None);
+        assert_eq!(gq.group.as_deref(), Some("workers"));
+        assert_eq!(gq.rollout_wariness, Some(1.0));
+    }
+
+    #[test]
+    fn current_os_takes_precedence() {
+        let gq = GraphQuery::parse(&query(&[
+            ("stream", "stable"),
+            ("current_os", "old"),
+            ("os_checksum", "new"),
+        ]))
+        .unwrap();
+        assert_eq!(gq.checksum, "old");
+    }
+
+    #[test]
+    fn parse_requires_stream_and_checksum() {
+        assert!(GraphQuery::parse(&query(&[("stream", "stable")])).is_err());
+        assert!(GraphQuery::parse(&query(&[("os_checksum", "abc")])).is_err());
+        let bad_wariness = query(&[
+            ("stream", "stable"),
+            ("os_checksum", "abc"),
+            ("rollout_wariness", "NaN"),
+        ]);
+        let gq = GraphQuery::parse(&bad_wariness).unwrap();
+        assert_eq!(gq.rollout_wariness, None);
+    }
+
+    #[test]
+    fn long_poll_wait_is_capped() {
+        let params = |wait| query(&[("stream", "stable"), ("os_checksum", "abc"), ("wait", wait)]);
+        let gq = GraphQuery::parse(&params("30")).unwrap();
+        assert_eq!(gq.wait, Some(Duration::from_secs(30)));
+        let gq = GraphQuery::parse(&params("3600")).unwrap();
+        assert_eq!(gq.wait, Some(MAX_WAIT));
+        assert_eq!(GraphQuery::parse(&params("soon")).unwrap().wait, None);
+    }
+
+    #[test]
+    fn pretty_output_overrides_default() {
+        let params = |pretty| {
+            query(&[
+                ("stream", "stable"),
+                ("os_checksum", "abc"),
+                ("pretty", pretty),
+            ])
+        };
+        let pretty = |value| GraphQuery::parse(&params(value)).unwrap().pretty;
+        assert_eq!(pretty("1"), Some(true));
+        assert_eq!(pretty("true"), Some(true));
+        assert_eq!(pretty("0"), Some(false));
+        assert_eq!(pretty("false"), Some(false));
+        assert_eq!(pretty(""), None);
+        assert_eq!(pretty("yes"), None);
+    }
+
+    #[test]
+    fn malformed_checksums_follow_validation_mode() {
+        let gq = |checksum: &str| {
+            GraphQuery::parse(&query(&[("stream", "stable"), ("os_checksum", checksum)])).unwrap()
+        };
+        let digest = "0123456789abcdef".repeat(4);
+        for mode in &["off", "lenient", "strict"] {
+            let mode: ChecksumValidation = mode.parse().unwrap();
+            assert!(gq(&digest).check_checksum(mode).is_ok());
+        }
+        for malformed in &["abc", &digest.to_uppercase(), &format!("{}0", digest)] {
+            let gq = gq(malformed);
+            assert!(gq.check_checksum(ChecksumValidation::Off).is_ok());
+            assert!(gq.check_checksum(ChecksumValidation::Lenient).is_ok());
+            assert!(gq.check_checksum(ChecksumValidation::Strict).is_err());
+        }
+        assert!("paranoid".parse::<ChecksumValidation>().is_err());
+    }
+
+    #[test]
+    fn basearch_defaults_to_x86_64() {
+        let arches = maplit::btreeset!["x86_64".to_string(), "aarch64".to_string()];
+        let gq = GraphQuery::parse(&query(&[("stream", "stable"), ("os_checksum", "abc")]));
+        let gq = gq.unwrap();
+        assert_eq!(gq.basearch, DEFAULT_BASEARCH);
+        assert!(gq.check_basearch(&arches).is_ok());
+
+        let params = [
+            ("stream", "stable"),
+            ("os_checksum", "abc"),
+            ("basearch", "aarch64"),
+        ];
+        assert!(GraphQuery::parse(&query(&params))
+            .unwrap()
+            .check_basearch(&arches)
+            .is_ok());
+        let params = [
+            ("stream", "stable"),
+            ("os_checksum", "abc"),
+            ("basearch", "s390x"),
+        ];
+        let err = GraphQuery::parse(&query(&params))
+            .unwrap()
+            .check_basearch(&arches);
+        assert_eq!(err.unwrap_err().to_string(), "unsupported basearch 's390x'");
+    }
+
+    #[test]
+    fn default_stream_pattern() {
+        let pattern = regex::Regex::new(DEFAULT_STREAM_PATTERN).unwrap();
+        for stream in &["stable", "testing-devel", "rhcos-4"] {
+            let gq = GraphQuery::parse(&query(&[("stream", stream), ("os_checksum", "abc")]));
+            assert!(gq.unwrap().check_stream(&pattern).is_ok(), "{}", stream);
+        }
+        for stream in &["Stable", "testing--devel", "-next", "../etc"] {
+            let gq = GraphQuery::parse(&query(&[("stream", stream), ("os_checksum", "abc")]));
+            assert!(gq.unwrap().check_stream(&pattern).is_err(), "{}", stream);
+        }
+    }
+}
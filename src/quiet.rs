@@ -0,0 +1,158 @@
+//! Quiet hours, during which no update is offered.
+//!
+//! This emulates server-side maintenance windows: during quiet hours, graph
+//! responses keep their nodes but drop all edges. Hours are in server local
+//! time, or in the client timezone if it sends a `tz_offset` hint.
+
+use chrono::{DateTime, FixedOffset, Local, NaiveTime, Utc};
+use failure::{bail, format_err, Error, Fallible};
+use std::str::FromStr;
+
+/// Daily time range, possibly wrapping past midnight (e.g. `22:00-06:00`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Check whether a time of day falls within quiet hours (end excluded).
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Check whether `now` falls within quiet hours, for a client at
+    /// `utc_offset` (server local time if unknown).
+    pub(crate) fn is_quiet(&self, now: DateTime<Utc>, utc_offset: Option<FixedOffset>) -> bool {
+        let time = match utc_offset {
+            Some(offset) => now.with_timezone(&offset).time(),
+            None => now.with_timezone(&Local).time(),
+        };
+        self.contains(time)
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = Error;
+
+    /// Parse `<HH:MM>-<HH:MM>`.
+    fn from_str(input: &str) -> Fallible<Self> {
+        let (start, end) = input.split_once('-').ok_or_else(|| {
+            format_err!("invalid quiet hours '{}', expected 'HH:MM-HH:MM'", input)
+        })?;
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .map_err(|e| format_err!("invalid time '{}' in quiet hours: {}", t, e))
+        };
+        let hours = Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        if hours.start == hours.end {
+            bail!("invalid quiet hours '{}', empty range", input);
+        }
+        Ok(hours)
+    }
+}
+
+/// Parse per-stream quiet hours, as `<stream>=<HH:MM>-<HH:MM>` (e.g. `stable=22:00-06:00`).
+pub(crate) fn parse_stream_quiet_hours(input: &str) -> Fallible<(String, QuietHours)> {
+    let (stream, hours) = input
+        .split_once('=')
+        .ok_or_else(|| format_err!("invalid stream quiet hours '{}', missing '='", input))?;
+    if stream.is_empty() {
+        bail!("invalid stream quiet hours '{}', empty stream", input);
+    }
+    Ok((stream.to_string(), hours.parse()?))
+}
+
+/// Parse a client UTC offset hint, as `[+|-]HH:MM` (e.g. `-05:00`).
+///
+/// An unescaped `+` in a query string decodes to a space, so unsigned
+/// offsets are positive.
+pub(crate) fn parse_utc_offset(input: &str) -> Fallible<FixedOffset> {
+    let input = input.trim();
+    let (sign, abs) = match input.chars().next() {
+        Some('-') => (-1, &input[1..]),
+        Some('+') => (1, &input[1..]),
+        _ => (1, input),
+    };
+    let (hours, minutes) = abs
+        .split_once(':')
+        .and_then(|(h, m)| Some((h.parse::<i32>().ok()?, m.parse::<i32>().ok()?)))
+        .filter(|(h, m)| (0..24).contains(h) && (0..60).contains(m))
+        .ok_or_else(|| format_err!("invalid UTC offset '{}', expected '[+|-]HH:MM'", input))?;
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+        .ok_or_else(|| format_err!("UTC offset out of range '{}'", input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn ranges_may_wrap_past_midnight() {
+        let day: QuietHours = "09:00-17:30".parse().unwrap();
+        assert!(day.contains(time(9, 0)));
+        assert!(day.contains(time(17, 29)));
+        assert!(!day.contains(time(17, 30)));
+        assert!(!day.contains(time(8, 59)));
+
+        let night: QuietHours = "22:00-06:00".parse().unwrap();
+        assert!(night.contains(time(23, 0)));
+        assert!(night.contains(time(0, 0)));
+        assert!(night.contains(time(5, 59)));
+        assert!(!night.contains(time(6, 0)));
+        assert!(!night.contains(time(12, 0)));
+    }
+
+    #[test]
+    fn client_offsets_shift_quiet_hours() {
+        let night: QuietHours = "22:00-06:00".parse().unwrap();
+        let noon_utc = Utc.with_ymd_and_hms(2020, 1, 1, 12, 0, 0).unwrap();
+        let utc = parse_utc_offset("00:00").unwrap();
+        assert!(!night.is_quiet(noon_utc, Some(utc)));
+        let tokyo = parse_utc_offset("+11:00").unwrap();
+        assert!(night.is_quiet(noon_utc, Some(tokyo)));
+        let hawaii = parse_utc_offset("-10:00").unwrap();
+        assert!(night.is_quiet(noon_utc, Some(hawaii)));
+    }
+
+    #[test]
+    fn quiet_hours_parse() {
+        assert_eq!(
+            parse_stream_quiet_hours("stable=22:00-06:00").unwrap(),
+            ("stable".to_string(), "22:00-06:00".parse().unwrap())
+        );
+        for invalid in &[
+            "stable",
+            "=22:00-06:00",
+            "stable=22:00",
+            "stable=22:00-22:00",
+            "stable=25:00-06:00",
+        ] {
+            assert!(parse_stream_quiet_hours(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn utc_offsets_parse() {
+        let offset = |input| parse_utc_offset(input).unwrap().local_minus_utc();
+        assert_eq!(offset("-05:00"), -5 * 3600);
+        assert_eq!(offset("+05:30"), 5 * 3600 + 30 * 60);
+        // Unescaped `+` decodes to a space.
+        assert_eq!(offset(" 02:00"), 2 * 3600);
+        for invalid in &["", "5", "+24:00", "-01:60", "ab:cd"] {
+            assert!(parse_utc_offset(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
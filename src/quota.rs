@@ -0,0 +1,205 @@
+//! Per-node request quotas, over fixed time windows.
+
+use crate::config;
+use failure::{format_err, Error, Fallible};
+use prometheus::IntCounter;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref QUOTA_EXCEEDED: IntCounter = register_int_counter!(opts!(
+        "fakeup_graph_quota_exceeded_total",
+        "Total number of graph requests rejected due to node quotas"
+    ))
+    .unwrap();
+    static ref QUOTA_RESETS: IntCounter = register_int_counter!(opts!(
+        "fakeup_graph_quota_resets_total",
+        "Total number of node quota resets via the admin API"
+    ))
+    .unwrap();
+}
+
+/// Quota configuration, as `<requests>/<window>` (e.g. `10/1h`).
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaConfig {
+    pub max_requests: u64,
+    pub window: Duration,
+}
+
+impl FromStr for QuotaConfig {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        let (max, window) = input.split_once('/').ok_or_else(|| {
+            format_err!("invalid quota '{}', expected <requests>/<window>", input)
+        })?;
+        let max_requests = max
+            .parse()
+            .map_err(|e| format_err!("invalid quota requests '{}': {}", max, e))?;
+        let window = config::parse_duration(window)?;
+        if window.is_zero() {
+            return Err(format_err!("invalid quota '{}', empty window", input));
+        }
+        let quota = Self {
+            max_requests,
+            window,
+        };
+        Ok(quota)
+    }
+}
+
+/// Request accounting for a single node.
+#[derive(Clone, Copy, Debug)]
+struct NodeWindow {
+    started: Instant,
+    requests: u64,
+}
+
+/// Request windows of all nodes.
+#[derive(Debug)]
+struct NodeWindows {
+    windows: HashMap<String, NodeWindow>,
+    /// Last time expired windows were dropped.
+    pruned: Instant,
+}
+
+/// Per-node quota tracker, shared across server workers.
+#[derive(Clone, Debug)]
+pub struct NodeQuota {
+    config: QuotaConfig,
+    nodes: Arc<Mutex<NodeWindows>>,
+}
+
+impl NodeQuota {
+    pub fn new(config: QuotaConfig) -> Self {
+        let nodes = NodeWindows {
+            windows: HashMap::new(),
+            pruned: Instant::now(),
+        };
+        Self {
+            config,
+            nodes: Arc::new(Mutex::new(nodes)),
+        }
+    }
+
+    /// Account a request for a node.
+    ///
+    /// Beyond the quota, it returns the delay until the current window ends.
+    pub fn check(&self, node_uuid: &str) -> Result<(), Duration> {
+        self.check_at(node_uuid, Instant::now())
+    }
+
+    fn check_at(&self, node_uuid: &str, now: Instant) -> Result<(), Duration> {
+        let window = self.config.window;
+        let mut nodes = self.nodes.lock().unwrap_or_else(|e| e.into_inner());
+        // Expired windows would be restarted anyway, drop them once per
+        // window so that state only covers recently seen nodes.
+        if now.saturating_duration_since(nodes.pruned) >= window {
+            nodes
+                .windows
+                .retain(|_, node| now.saturating_duration_since(node.started) < window);
+            nodes.pruned = now;
+        }
+        let entry = nodes
+            .windows
+            .entry(node_uuid.to_string())
+            .or_insert(NodeWindow {
+                started: now,
+                requests: 0,
+            });
+        let elapsed = now.saturating_duration_since(entry.started);
+        if elapsed >= window {
+            entry.started = now;
+            entry.requests = 0;
+        }
+        if entry.requests >= self.config.max_requests {
+            QUOTA_EXCEEDED.inc();
+            let remaining = window - now.saturating_duration_since(entry.started);
+            return Err(remaining);
+        }
+        entry.requests += 1;
+        Ok(())
+    }
+
+    /// Reset quotas for a single node, or for all nodes.
+    ///
+    /// It returns the number of nodes which were reset.
+    #[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+    pub fn reset(&self, node_uuid: Option<&str>) -> usize {
+        let mut nodes = self.nodes.lock().unwrap_or_else(|e| e.into_inner());
+        QUOTA_RESETS.inc();
+        match node_uuid {
+            Some(uuid) => nodes.windows.remove(uuid).map_or(0, |_| 1),
+            None => {
+                let count = nodes.windows.len();
+                nodes.windows.clear();
+                count
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_parses() {
+        let quota: QuotaConfig = "10/1h".parse().unwrap();
+        assert_eq!(quota.max_requests, 10);
+        assert_eq!(quota.window, Duration::from_secs(3600));
+
+        for invalid in &["10", "ten/1h", "10/0s", "10/soon"] {
+            assert!(invalid.parse::<QuotaConfig>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn nodes_are_limited_independently() {
+        let quota = NodeQuota::new("2/1h".parse().unwrap());
+        assert!(quota.check("a").is_ok());
+        assert!(quota.check("a").is_ok());
+        let retry_after = quota.check("a").unwrap_err();
+        assert!(retry_after > Duration::from_secs(3500));
+        assert!(retry_after <= Duration::from_secs(3600));
+        assert!(quota.check("b").is_ok());
+    }
+
+    #[test]
+    fn reset_clears_quotas() {
+        let quota = NodeQuota::new("1/1h".parse().unwrap());
+        for node in &["a", "b", "c"] {
+            assert!(quota.check(node).is_ok());
+        }
+        assert_eq!(quota.reset(Some("a")), 1);
+        assert_eq!(quota.reset(Some("a")), 0);
+        assert!(quota.check("a").is_ok());
+        assert!(quota.check("b").is_err());
+        assert_eq!(quota.reset(None), 3);
+        assert!(quota.check("b").is_ok());
+    }
+
+    #[test]
+    fn expired_windows_are_pruned() {
+        let quota = NodeQuota::new("1/1h".parse().unwrap());
+        let start = Instant::now();
+        let tracked = || quota.nodes.lock().unwrap().windows.len();
+        assert!(quota.check_at("a", start).is_ok());
+        assert!(quota.check_at("b", start).is_ok());
+        assert_eq!(tracked(), 2);
+
+        let later = start + Duration::from_secs(1800);
+        assert!(quota.check_at("b", later).is_err());
+        assert!(quota.check_at("c", later).is_ok());
+        assert_eq!(tracked(), 3);
+
+        // Past a window, only nodes seen within it are kept.
+        let next = start + Duration::from_secs(3600);
+        assert!(quota.check_at("c", next).is_err());
+        assert_eq!(tracked(), 1);
+        assert!(quota.check_at("a", next).is_ok());
+        assert_eq!(tracked(), 2);
+    }
+}
@@ -0,0 +1,171 @@
+//! Raw-socket listener, replying with canned bytes.
+//!
+//! This is meant to exercise client HTTP stacks against malformed
+//! responses (bad chunking, header folding, truncation, ...), which the
+//! regular server cannot produce. Requests are only minimally parsed:
+//! the first path segment of the request target selects the fixture
+//! file `<dir>/<name>.http`, which is written verbatim before closing
+//! the connection.
+
+use failure::{format_err, Fallible};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Upper bound on the size of a request head.
+static MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Socket timeout for reading requests and writing fixtures.
+static IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reply sent when no fixture matches.
+static NOT_FOUND: &[u8] =
+    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Listener serving canned raw responses.
+#[derive(Debug)]
+pub struct RawFixtures {
+    dir: PathBuf,
+    listener: TcpListener,
+}
+
+impl RawFixtures {
+    pub fn bind(dir: &Path, addr: SocketAddr) -> Fallible<Self> {
+        if !dir.is_dir() {
+            return Err(format_err!(
+                "raw fixtures path '{}' is not a directory",
+                dir.display()
+            ));
+        }
+        let listener = TcpListener::bind(addr)?;
+        let fixtures = Self {
+            dir: dir.to_path_buf(),
+            listener,
+        };
+        Ok(fixtures)
+    }
+
+    pub fn local_addr(&self) -> Fallible<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serve connections on a background thread.
+    pub fn spawn(self) -> Fallible<()> {
+        std::thread::Builder::new()
+            .name("raw-fixtures".to_string())
+            .spawn(move || self.run())?;
+        Ok(())
+    }
+
+    fn run(self) {
+        for conn in self.listener.incoming() {
+            let conn = match conn {
+                Ok(c) => c,
+                Err(e) => {
+                    log::warn!("raw fixtures: failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let dir = self.dir.clone();
+            let _ = std::thread::Builder::new()
+                .name("raw-fixtures-conn".to_string())
+                .spawn(move || {
+                    if let Err(e) = serve(&dir, conn) {
+                        log::debug!("raw fixtures: connection error: {}", e);
+                    }
+                });
+        }
+    }
+}
+
+/// Read a request head and reply with the matching fixture.
+fn serve(dir: &Path, mut conn: TcpStream) -> Fallible<()> {
+    conn.set_read_timeout(Some(IO_TIMEOUT))?;
+    conn.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = conn.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(format_err!("request head too large"));
+        }
+    }
+
+    let reply = match fixture_name(&head) {
+        Some(name) => {
+            let path = dir.join(format!("{}.http", name));
+            match std::fs::read(&path) {
+                Ok(bytes) => {
+                    log::trace!("raw fixtures: serving '{}'", path.display());
+                    bytes
+                }
+                Err(_) => NOT_FOUND.to_vec(),
+            }
+        }
+        None => NOT_FOUND.to_vec(),
+    };
+    conn.write_all(&reply)?;
+    conn.flush()?;
+    Ok(())
+}
+
+/// Extract the fixture name from the request line, if well-formed enough.
+fn fixture_name(head: &[u8]) -> Option<String> {
+    let line = head.split(|b| *b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let target = line.split_whitespace().nth(1)?;
+    let name = target.trim_start_matches('/').split(['/', '?']).next()?;
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixture_names_are_parsed() {
+        let name = |head: &str| fixture_name(head.as_bytes());
+        assert_eq!(name("GET /chunked HTTP/1.1\r\n\r\n").unwrap(), "chunked");
+        assert_eq!(name("GET /bad_fold/x?y HTTP/1.1\r\n").unwrap(), "bad_fold");
+        assert_eq!(name("GET /../etc HTTP/1.1\r\n"), None);
+        assert_eq!(name("GET / HTTP/1.1\r\n"), None);
+        assert_eq!(name("garbage"), None);
+    }
+
+    #[test]
+    fn fixtures_are_served_verbatim() {
+        let dir = std::env::temp_dir().join(format!("fakeup-raw-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let canned = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        std::fs::write(dir.join("broken.http"), &canned[..]).unwrap();
+
+        let fixtures = RawFixtures::bind(&dir, ([127, 0, 0, 1], 0).into()).unwrap();
+        let addr = fixtures.local_addr().unwrap();
+        fixtures.spawn().unwrap();
+
+        let fetch = |path: &str| {
+            let mut conn = TcpStream::connect(addr).unwrap();
+            write!(conn, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+            let mut reply = Vec::new();
+            conn.read_to_end(&mut reply).unwrap();
+            reply
+        };
+        assert_eq!(fetch("/broken"), canned.to_vec());
+        assert_eq!(fetch("/missing"), NOT_FOUND.to_vec());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! Redaction of sensitive query parameters in logs.
+//!
+//! Redacted values are either masked, or replaced by a stable hash so
+//! that log lines for the same value can still be correlated.
+
+use actix_web::middleware::{Finished, Middleware, Started};
+use actix_web::{HttpRequest, HttpResponse};
+use failure::{format_err, Error, Fallible};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::sync::RwLock;
+use std::time::Instant;
+
+lazy_static::lazy_static! {
+    static ref REDACTOR: RwLock<Redactor> = RwLock::new(Redactor::default());
+}
+
+/// Replacement for masked values.
+static MASK: &str = "***";
+
+/// How redacted values are logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RedactMode {
+    /// Stable hash of the value, prefixed by `h:`.
+    #[default]
+    Hash,
+    /// Fixed mask.
+    Mask,
+}
+
+impl std::str::FromStr for RedactMode {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        match input {
+            "hash" => Ok(RedactMode::Hash),
+            "mask" => Ok(RedactMode::Mask),
+            _ => Err(format_err!("unknown redaction mode '{}'", input)),
+        }
+    }
+}
+
+/// Query parameters to redact in logs.
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+    params: BTreeSet<String>,
+    mode: RedactMode,
+}
+
+impl Redactor {
+    pub fn new(params: impl IntoIterator<Item = String>, mode: RedactMode) -> Self {
+        Self {
+            params: params.into_iter().collect(),
+            mode,
+        }
+    }
+
+    fn redact_value(&self, value: &str) -> String {
+        match self.mode {
+            RedactMode::Mask => MASK.to_string(),
+            RedactMode::Hash => {
+                // SipHash with fixed keys: stable across runs and hosts.
+                let mut hasher = DefaultHasher::new();
+                value.hash(&mut hasher);
+                format!("h:{:016x}", hasher.finish())
+            }
+        }
+    }
+
+    /// Value of a query parameter, as it should be logged.
+    fn param(&self, name: &str, value: &str) -> String {
+        if self.params.contains(name) {
+            self.redact_value(value)
+        } else {
+            value.to_string()
+        }
+    }
+
+    /// Query string, with redacted parameter values.
+    fn query(&self, query: &str) -> String {
+        if self.params.is_empty() {
+            return query.to_string();
+        }
+        query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) if self.params.contains(name) => {
+                    format!("{}={}", name, self.redact_value(value))
+                }
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+/// Set the global redactor.
+pub fn set(redactor: Redactor) {
+    let mut global = REDACTOR.write().unwrap_or_else(|e| e.into_inner());
+    *global = redactor;
+}
+
+/// Value of a query parameter, as it should be logged.
+pub fn param(name: &str, value: &str) -> String {
+    let redactor = REDACTOR.read().unwrap_or_else(|e| e.into_inner());
+    redactor.param(name, value)
+}
+
+/// Access log, redacting query parameters.
+///
+/// This follows the default actix-web access log format, plus the
+/// client trace context.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AccessLog;
+
+/// Start time of a request, for the access log.
+struct RequestStart(Instant);
+
+impl<S> Middleware<S> for AccessLog {
+    fn start(&self, req: &HttpRequest<S>) -> actix_web::Result<Started> {
+        req.extensions_mut().insert(RequestStart(Instant::now()));
+        Ok(Started::Done)
+    }
+
+    fn finish(&self, req: &HttpRequest<S>, resp: &HttpResponse) -> Finished {
+        if !log::log_enabled!(log::Level::Info) {
+            return Finished::Done;
+        }
+        let elapsed = req
+            .extensions()
+            .get::<RequestStart>()
+            .map(|start| start.0.elapsed().as_secs_f64())
+            .unwrap_or_default();
+        let target = match req.query_string() {
+            "" => req.path().to_string(),
+            query => {
+                let redactor = REDACTOR.read().unwrap_or_else(|e| e.into_inner());
+                format!("{}?{}", req.path(), redactor.query(query))
+            }
+        };
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("-")
+                .to_string()
+        };
+        log::info!(
+            "{} \"{} {} {:?}\" {} {} \"{}\" \"{}\" {:.6} traceparent={}",
+            req.connection_info().remote().unwrap_or("-"),
+            req.method(),
+            target,
+            req.version(),
+            resp.status().as_u16(),
+            resp.response_size(),
+            header("referer"),
+            header("user-agent"),
+            elapsed,
+            header(crate::trace::TRACEPARENT),
+        );
+        Finished::Done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(mode: RedactMode) -> Redactor {
+        Redactor::new(vec!["node_uuid".to_string()], mode)
+    }
+
+    #[test]
+    fn modes_parse() {
+        assert_eq!("hash".parse::<RedactMode>().unwrap(), RedactMode::Hash);
+        assert_eq!("mask".parse::<RedactMode>().unwrap(), RedactMode::Mask);
+        assert!("Hash".parse::<RedactMode>().is_err());
+    }
+
+    #[test]
+    fn params_are_redacted() {
+        let masked = redactor(RedactMode::Mask);
+        assert_eq!(masked.param("node_uuid", "abc"), "***");
+        assert_eq!(masked.param("stream", "stable"), "stable");
+
+        let hashed = redactor(RedactMode::Hash);
+        let abc = hashed.param("node_uuid", "abc");
+        assert!(abc.starts_with("h:") && abc.len() == 18, "{}", abc);
+        assert_eq!(hashed.param("node_uuid", "abc"), abc);
+        assert_ne!(hashed.param("node_uuid", "abd"), abc);
+        assert_eq!(hashed.param("stream", "stable"), "stable");
+    }
+
+    #[test]
+    fn queries_are_redacted() {
+        let masked = redactor(RedactMode::Mask);
+        assert_eq!(
+            masked.query("stream=stable&node_uuid=abc&basearch=x86_64"),
+            "stream=stable&node_uuid=***&basearch=x86_64"
+        );
+        assert_eq!(masked.query("node_uuid"), "node_uuid");
+        assert_eq!(
+            masked.query("node_uuid=a&node_uuid=b"),
+            "node_uuid=***&node_uuid=***"
+        );
+
+        let none = Redactor::default();
+        assert_eq!(none.query("node_uuid=abc"), "node_uuid=abc");
+    }
+}
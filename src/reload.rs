@@ -0,0 +1,96 @@
+//! Live configuration reload, on SIGHUP.
+
+use crate::config;
+use crate::scraper;
+use actix::actors::signal;
+use actix::prelude::*;
+use failure::Fallible;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Re-read the config file on SIGHUP, and reconfigure the scraper.
+#[derive(Debug)]
+pub(crate) struct ConfigReloader {
+    pub(crate) path: PathBuf,
+    /// Streams from command-line flags, which take precedence over the file.
+    pub(crate) flag_streams: Vec<String>,
+    /// Refresh interval from command-line flags, which takes precedence over the file.
+    pub(crate) flag_refresh_interval: Option<Duration>,
+    /// Per-stream refresh intervals from command-line flags, overriding the file ones.
+    pub(crate) flag_stream_refresh: Vec<(String, Duration)>,
+    pub(crate) scraper_addr: Addr<scraper::Scraper>,
+}
+
+impl ConfigReloader {
+    fn reload(&self) -> Fallible<()> {
+        let file_config = config::ConfigFile::from_path(&self.path)?;
+        let msg = scraper::Reconfigure {
+            streams: crate::scraped_streams(&self.flag_streams, &file_config),
+            refresh_pause: crate::refresh_interval(self.flag_refresh_interval, &file_config)?,
+            stream_refresh: crate::stream_refresh_intervals(
+                &self.flag_stream_refresh,
+                &file_config,
+            )?,
+        };
+        self.scraper_addr.do_send(msg);
+        Ok(())
+    }
+}
+
+impl Actor for ConfigReloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let signals = System::current().registry().get::<signal::ProcessSignals>();
+        signals.do_send(signal::Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<signal::Signal> for ConfigReloader {
+    type Result = ();
+
+    fn handle(&mut self, msg: signal::Signal, _ctx: &mut Self::Context) {
+        if msg.0 != signal::SignalType::Hup {
+            return;
+        }
+        log::info!("SIGHUP received, reloading '{}'", self.path.display());
+        if let Err(e) = self.reload() {
+            log::error!("configuration reload failed, keeping current one: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::btreeset;
+
+    #[test]
+    fn reloads_reconfigure_the_scraper() {
+        let path = std::env::temp_dir().join(format!("fakeup-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, "streams = [\"reloaded\"]\n").unwrap();
+
+        let mut sys = actix::System::new("config-reload");
+        let streams = btreeset!["initial".to_string()];
+        let scraper = scraper::Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_lazy_streams(true);
+        let reloader = ConfigReloader {
+            path: path.clone(),
+            flag_streams: vec![],
+            flag_refresh_interval: None,
+            flag_stream_refresh: vec![],
+            scraper_addr: scraper.start(),
+        };
+        reloader.reload().unwrap();
+        let status = scraper::request(&reloader.scraper_addr, scraper::GetStatus {});
+        let status = sys.block_on(status).unwrap();
+        let streams: Vec<_> = status.streams.keys().map(String::as_str).collect();
+        assert_eq!(streams, vec!["reloaded"]);
+
+        // Invalid files leave the scraper alone.
+        std::fs::write(&path, "streams = 42\n").unwrap();
+        assert!(reloader.reload().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+//! Structured report of backend activity, written on shutdown.
+
+use crate::clients::ClientsTable;
+use chrono::{DateTime, Utc};
+use failure::{format_err, Fallible};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Summary of a whole server run.
+#[derive(Clone, Debug, Serialize)]
+pub struct ShutdownReport {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    /// Graph responses, per HTTP status (including not-modified and errors).
+    pub responses: BTreeMap<u16, u64>,
+    /// Graph response bytes served, per stream.
+    pub response_bytes: BTreeMap<String, u64>,
+    /// Successful refreshes, per stream.
+    pub scrapes: BTreeMap<String, u64>,
+    /// Failed refreshes, per stream.
+    pub scrape_failures: BTreeMap<String, u64>,
+}
+
+impl ShutdownReport {
+    /// Assemble a report from client accounting and metrics.
+    pub fn collect(started: DateTime<Utc>, clients: &ClientsTable) -> Self {
+        Self {
+            started,
+            finished: crate::clock::now(),
+            responses: clients.responses(),
+            response_bytes: clients.bandwidth().streams,
+            scrapes: counter_values("fakeup_scraper_stream_scrapes_total", Some("stream")),
+            scrape_failures: counter_values("fakeup_scraper_stream_errors_total", Some("stream")),
+        }
+    }
+
+    /// Write the report as JSON.
+    pub fn write(&self, path: &Path) -> Fallible<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)
+            .map_err(|e| format_err!("failed to write report '{}': {}", path.display(), e))?;
+        Ok(())
+    }
+}
+
+/// Collect values of a counter from the default registry, keyed by a label.
+fn counter_values(name: &str, label: Option<&str>) -> BTreeMap<String, u64> {
+    let mut values = BTreeMap::new();
+    for family in prometheus::gather() {
+        if family.get_name() != name {
+            continue;
+        }
+        for metric in family.get_metric() {
+            let key = label
+                .and_then(|l| metric.get_label().iter().find(|lp| lp.get_name() == l))
+                .map(|lp| lp.get_value().to_string())
+                .unwrap_or_default();
+            *values.entry(key).or_default() += metric.get_counter().get_value() as u64;
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_covers_served_streams() {
+        let clients = ClientsTable::new(false, 2);
+        clients.record_response("report-stable", None, 10);
+        clients.record_response("report-stable", None, 20);
+        clients.record_status(200);
+        clients.record_status(200);
+        clients.record_status(304);
+        let started = crate::clock::now();
+        let report = ShutdownReport::collect(started, &clients);
+        assert_eq!(report.started, started);
+        assert_eq!(report.responses[&200], 2);
+        assert_eq!(report.responses[&304], 1);
+        assert_eq!(report.response_bytes["report-stable"], 30);
+
+        let path = std::env::temp_dir().join(format!("fakeup-report-{}", std::process::id()));
+        report.write(&path).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written["responses"]["200"], 2);
+    }
+}
@@ -0,0 +1,283 @@
+//! Retry policy for failed upstream requests.
+
+use crate::engine;
+use failure::{bail, format_err, Fallible};
+use prometheus::IntCounterVec;
+use std::str::FromStr;
+use std::time::Duration;
+
+lazy_static::lazy_static! {
+    static ref UPSTREAM_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "fakeup_scraper_upstream_failures_total",
+        "Total number of failed upstream requests, by status class",
+        &["class"]
+    )
+    .unwrap();
+}
+
+/// Upper bound for exponential backoff.
+static MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Upstream request failure with a non-success HTTP status.
+#[derive(Debug)]
+pub struct UpstreamError {
+    pub status: u16,
+    /// Delay requested by upstream via `Retry-After`.
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upstream returned HTTP status {}", self.status)
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+impl UpstreamError {
+    /// Build an error from an upstream response.
+    pub fn from_response(status: u16, retry_after: Option<&str>) -> Self {
+        let retry_after = retry_after.and_then(parse_retry_after);
+        Self {
+            status,
+            retry_after,
+        }
+    }
+}
+
+/// Parse a `Retry-After` value, either in seconds or as an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    let delta = date.signed_duration_since(crate::clock::now());
+    delta.to_std().ok()
+}
+
+/// Set of HTTP statuses a rule applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusMatcher {
+    /// A single status code (e.g. `429`).
+    Exact(u16),
+    /// A whole status class (e.g. `5xx`).
+    Class(u16),
+}
+
+impl StatusMatcher {
+    fn matches(self, status: u16) -> bool {
+        match self {
+            StatusMatcher::Exact(code) => code == status,
+            StatusMatcher::Class(class) => status / 100 == class,
+        }
+    }
+
+    /// Label for metrics.
+    fn label(self) -> String {
+        match self {
+            StatusMatcher::Exact(code) => code.to_string(),
+            StatusMatcher::Class(class) => format!("{}xx", class),
+        }
+    }
+}
+
+/// How to react to a failed upstream request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryAction {
+    /// Stop trying for the given duration.
+    GiveUp(Duration),
+    /// Wait as long as requested by upstream via `Retry-After`.
+    RetryAfter,
+    /// Back off exponentially on consecutive failures.
+    Backoff,
+}
+
+/// Single policy rule, in `<status>=<action>` form.
+///
+/// Status is either a code (`403`) or a class (`5xx`), action is one of
+/// `give-up:<duration>`, `retry-after`, or `backoff`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryRule {
+    pub matcher: StatusMatcher,
+    pub action: RetryAction,
+}
+
+impl FromStr for RetryRule {
+    type Err = failure::Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        let mut parts = input.splitn(2, '=');
+        let status = parts.next().unwrap_or_default().trim().to_lowercase();
+        let action = match parts.next() {
+            Some(a) => a.trim(),
+            None => bail!("missing action in retry rule '{}'", input),
+        };
+
+        let matcher = if status.len() == 3 && status.ends_with("xx") {
+            let class = status[..1]
+                .parse()
+                .map_err(|_| format_err!("invalid status class '{}'", status))?;
+            StatusMatcher::Class(class)
+        } else {
+            let code = status
+                .parse()
+                .map_err(|_| format_err!("invalid status code '{}'", status))?;
+            StatusMatcher::Exact(code)
+        };
+
+        let action = match action {
+            "retry-after" => RetryAction::RetryAfter,
+            "backoff" => RetryAction::Backoff,
+            a if a.starts_with("give-up:") => {
+                let delay = crate::config::parse_duration(&a["give-up:".len()..])?;
+                RetryAction::GiveUp(delay)
+            }
+            a => bail!("unknown retry action '{}'", a),
+        };
+
+        Ok(Self { matcher, action })
+    }
+}
+
+/// Policy for failed upstream requests.
+///
+/// Exact status rules take precedence over class rules. Failures not
+/// covered by any rule are retried at the next refresh.
+#[derive(Clone, Debug, Default)]
+pub struct RetryPolicy {
+    rules: Vec<RetryRule>,
+}
+
+impl RetryPolicy {
+    pub fn new(rules: Vec<RetryRule>) -> Self {
+        Self { rules }
+    }
+}
+
+impl engine::Backoff for RetryPolicy {
+    fn retry_delay(
+        &self,
+        err: &failure::Error,
+        failures: u32,
+        refresh_pause: Duration,
+    ) -> Option<Duration> {
+        let upstream = match err.downcast_ref::<UpstreamError>() {
+            Some(e) => e,
+            None => {
+                UPSTREAM_FAILURES.with_label_values(&["other"]).inc();
+                return None;
+            }
+        };
+
+        let exact = self.rules.iter().find(|r| match r.matcher {
+            StatusMatcher::Exact(_) => r.matcher.matches(upstream.status),
+            StatusMatcher::Class(_) => false,
+        });
+        let rule = exact.or_else(|| {
+            self.rules
+                .iter()
+                .find(|r| r.matcher.matches(upstream.status))
+        });
+        let rule = match rule {
+            Some(r) => r,
+            None => {
+                let class = StatusMatcher::Class(upstream.status / 100);
+                UPSTREAM_FAILURES.with_label_values(&[&class.label()]).inc();
+                return None;
+            }
+        };
+        UPSTREAM_FAILURES
+            .with_label_values(&[&rule.matcher.label()])
+            .inc();
+
+        let delay = match rule.action {
+            RetryAction::GiveUp(delay) => delay,
+            RetryAction::RetryAfter => upstream.retry_after.unwrap_or(refresh_pause),
+            RetryAction::Backoff => {
+                let exp = failures.saturating_sub(1).min(16);
+                refresh_pause
+                    .checked_mul(1 << exp)
+                    .unwrap_or(MAX_BACKOFF)
+                    .min(MAX_BACKOFF)
+            }
+        };
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Backoff;
+
+    fn upstream(status: u16, retry_after: Option<&str>) -> failure::Error {
+        UpstreamError::from_response(status, retry_after).into()
+    }
+
+    fn policy(rules: &[&str]) -> RetryPolicy {
+        RetryPolicy::new(rules.iter().map(|r| r.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn rules_parse() {
+        let rule: RetryRule = "5XX = backoff".parse().unwrap();
+        assert_eq!(rule.matcher, StatusMatcher::Class(5));
+        assert_eq!(rule.action, RetryAction::Backoff);
+
+        let rule: RetryRule = "403=give-up:1h".parse().unwrap();
+        assert_eq!(rule.matcher, StatusMatcher::Exact(403));
+        assert_eq!(rule.action, RetryAction::GiveUp(Duration::from_secs(3600)));
+
+        let rule: RetryRule = "429=retry-after".parse().unwrap();
+        assert_eq!(rule.action, RetryAction::RetryAfter);
+
+        for invalid in &[
+            "429",
+            "xxx=backoff",
+            "4x=backoff",
+            "500=sleep",
+            "500=give-up:soon",
+        ] {
+            assert!(invalid.parse::<RetryRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn exact_rules_take_precedence() {
+        let policy = policy(&["4xx=give-up:1h", "429=retry-after"]);
+        let pause = Duration::from_secs(30);
+        assert_eq!(
+            policy.retry_delay(&upstream(429, Some("5")), 1, pause),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            policy.retry_delay(&upstream(429, None), 1, pause),
+            Some(pause)
+        );
+        assert_eq!(
+            policy.retry_delay(&upstream(404, None), 1, pause),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(policy.retry_delay(&upstream(503, None), 1, pause), None);
+        let other = format_err!("connection refused");
+        assert_eq!(policy.retry_delay(&other, 1, pause), None);
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_capped() {
+        let policy = policy(&["5xx=backoff"]);
+        let pause = Duration::from_secs(30);
+        let delays: Vec<_> = (1..=4)
+            .map(|failures| policy.retry_delay(&upstream(500, None), failures, pause))
+            .collect();
+        let expected: Vec<_> = [30, 60, 120, 240]
+            .iter()
+            .map(|secs| Some(Duration::from_secs(*secs)))
+            .collect();
+        assert_eq!(delays, expected);
+        assert_eq!(
+            policy.retry_delay(&upstream(500, None), 40, pause),
+            Some(MAX_BACKOFF)
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! HTTP routing, keeping fakeup extensions apart from the emulated protocol.
+//!
+//! Only the Cincinnati graph endpoint and operational endpoints at their
+//! conventional paths live at the top level; everything fakeup-specific is
+//! registered under `FAKEUP_PREFIX`, so that new endpoints cannot
+//! accidentally shadow protocol paths.
+
+use crate::{debug, feed, health, payloads, version, AppState};
+use actix_web::{http::Method, App, Scope};
+
+/// Prefix for all fakeup-specific endpoints.
+pub(crate) static FAKEUP_PREFIX: &str = "/fakeup/v1";
+
+/// Register all routes, for enabled subsystems.
+pub(crate) fn register(app: App<AppState>) -> App<AppState> {
+    operational(cincinnati(app)).scope(FAKEUP_PREFIX, extensions)
+}
+
+/// Cincinnati-compatible endpoints, as served by the real update service.
+fn cincinnati(app: App<AppState>) -> App<AppState> {
+    app.resource("/v1/graph", |r| {
+        r.method(Method::GET).with(crate::serve_graph);
+        r.f(crate::graph_method_not_allowed)
+    })
+}
+
+/// Operational endpoints, at the paths monitoring tools expect.
+fn operational(app: App<AppState>) -> App<AppState> {
+    let app = app
+        .route("/healthz", Method::GET, health::serve_healthz)
+        .route("/readyz", Method::GET, health::serve_readyz);
+    #[cfg(feature = "metrics")]
+    let app = app.route("/metrics", Method::GET, crate::metrics::serve_metrics);
+    app
+}
+
+/// Fakeup-specific endpoints, relative to `FAKEUP_PREFIX`.
+fn extensions(scope: Scope<AppState>) -> Scope<AppState> {
+    let scope = scope
+        .nested("/debug", debug::register)
+        .route("/payloads/{checksum}", Method::GET, payloads::serve_payload)
+        .route("/feeds/{stream}.atom", Method::GET, feed::serve_feed)
+        .route("/version", Method::GET, version::serve_version);
+    #[cfg(feature = "admin-api")]
+    let scope = scope.nested("/admin", crate::admin::register);
+    scope
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_state;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestServer;
+
+    fn status(srv: &mut TestServer, path: &str) -> StatusCode {
+        let req = srv.get().uri(srv.url(path)).finish().unwrap();
+        srv.execute(req.send()).unwrap().status()
+    }
+
+    #[test]
+    fn extensions_are_namespaced() {
+        let mut srv = TestServer::with_factory(|| register(App::with_state(test_state())));
+        assert_eq!(status(&mut srv, "/fakeup/v1/version"), StatusCode::OK);
+        assert_ne!(
+            status(&mut srv, "/fakeup/v1/debug/echo"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(&mut srv, "/fakeup/v1/v1/graph"),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn metrics_follow_features() {
+        let mut srv = TestServer::with_factory(|| register(App::with_state(test_state())));
+        let registered = status(&mut srv, "/metrics") != StatusCode::NOT_FOUND;
+        assert_eq!(registered, cfg!(feature = "metrics"));
+    }
+
+    #[test]
+    fn legacy_paths_are_gone() {
+        let mut srv = TestServer::with_factory(|| register(App::with_state(test_state())));
+        assert_eq!(status(&mut srv, "/version"), StatusCode::NOT_FOUND);
+        assert_eq!(status(&mut srv, "/debug/v1/echo"), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,13 +1,30 @@
+use crate::clock;
+use crate::decode;
+use crate::diff;
+use crate::engine::{self, RefreshScope, Scheduler, StreamUpdate};
+use crate::errors;
+use crate::feed;
+use crate::fetcher;
+use crate::lifecycle;
 use crate::metadata;
+use crate::retry;
+use crate::sdnotify;
+use crate::snapshot;
+use crate::tls;
+use crate::trace;
 use crate::CincinnatiPayload;
 use actix::prelude::*;
 use failure::{Error, Fallible};
 use futures::future;
 use futures::prelude::*;
-use prometheus::{IntCounter, IntGauge};
-use reqwest::Method;
-use std::collections::{BTreeSet, HashMap};
-use std::time::Duration;
+use futures::sync::oneshot;
+use prometheus::{HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 lazy_static::lazy_static! {
     static ref LAST_REFRESH: IntGauge = register_int_gauge!(opts!(
@@ -19,75 +36,698 @@ lazy_static::lazy_static! {
         "Total number of upstream scrapes"
     ))
     .unwrap();
+    static ref FROZEN: IntGauge = register_int_gauge!(opts!(
+        "fakeup_scraper_frozen",
+        "Whether the cache is frozen, ignoring upstream scrapes"
+    )).unwrap();
+    static ref STALE_STREAMS: IntGauge = register_int_gauge!(opts!(
+        "fakeup_scraper_stale_streams",
+        "Number of streams not found upstream, served from their last known releases"
+    )).unwrap();
+    static ref STREAM_EMPTY: IntGaugeVec = register_int_gauge_vec!(
+        "fakeup_scraper_stream_empty",
+        "Whether the upstream release index for a stream is empty",
+        &["stream"]
+    )
+    .unwrap();
+    static ref STREAM_ARCH_REFRESH: IntGaugeVec = register_int_gauge_vec!(
+        "fakeup_scraper_stream_arch_last_refresh_timestamp",
+        "UTC timestamp of last refresh with payloads for an architecture",
+        &["stream", "arch"]
+    )
+    .unwrap();
+    static ref STREAM_ARCH_LATEST: IntGaugeVec = register_int_gauge_vec!(
+        "fakeup_scraper_stream_arch_latest_age_index",
+        "Age index of the latest release with a payload for an architecture",
+        &["stream", "arch"]
+    )
+    .unwrap();
+    static ref STREAM_SCRAPES: IntCounterVec = register_int_counter_vec!(
+        "fakeup_scraper_stream_scrapes_total",
+        "Total number of successful stream refreshes",
+        &["stream"]
+    )
+    .unwrap();
+    static ref STREAM_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "fakeup_scraper_stream_errors_total",
+        "Total number of failed stream refreshes",
+        &["stream"]
+    )
+    .unwrap();
+    static ref RELEASE_CHANGES: IntCounterVec = register_int_counter_vec!(
+        "fakeup_scraper_release_changes_total",
+        "Total number of changes detected between consecutive stream scrapes",
+        &["stream"]
+    )
+    .unwrap();
+    static ref MAILBOX_PENDING: IntGauge = register_int_gauge!(opts!(
+        "fakeup_scraper_mailbox_pending_messages",
+        "Number of requests to the scraper actor waiting for a reply"
+    ))
+    .unwrap();
+    static ref MESSAGE_LATENCY: HistogramVec = register_histogram_vec!(
+        "fakeup_scraper_message_latency_seconds",
+        "Time from sending a request to the scraper actor to its reply",
+        &["message"]
+    )
+    .unwrap();
+    static ref HANDLER_DURATION: HistogramVec = register_histogram_vec!(
+        "fakeup_scraper_handler_duration_seconds",
+        "Time spent in scraper message handlers (sampled)",
+        &["message"]
+    )
+    .unwrap();
+    static ref WATCHDOG_RESTARTS: IntCounter = register_int_counter!(opts!(
+        "fakeup_scraper_watchdog_restarts_total",
+        "Total number of scraper restarts forced by the watchdog"
+    ))
+    .unwrap();
+    static ref STREAM_REFRESH_DURATION: HistogramVec = register_histogram_vec!(
+        "fakeup_scraper_stream_refresh_duration_seconds",
+        "Time spent refreshing a single stream",
+        &["stream"]
+    )
+    .unwrap();
+    static ref STREAM_GRAPH_BUILD_DURATION: HistogramVec = register_histogram_vec!(
+        "fakeup_scraper_stream_graph_build_duration_seconds",
+        "Time spent precomputing the graph of a refreshed stream",
+        &["stream"]
+    )
+    .unwrap();
 }
 
+/// Fixture file extensions, by preference (compression is detected on read).
+static FIXTURE_EXTENSIONS: [&str; 3] = ["json", "json.gz", "json.zst"];
+
+/// One in this many handler executions is timed.
+static HANDLER_SAMPLE_RATE: usize = 10;
+
+/// Counter of handler executions, for sampling.
+static HANDLER_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximum number of entries kept in the scrape changelog.
+static CHANGELOG_CAPACITY: usize = 1000;
+
 /// Release scraper.
-#[derive(Clone, Debug)]
+///
+/// This is a thin actix adapter around `engine::Core`, which owns the cache
+/// and its refresh state machine: the actor fetches from upstream, and
+/// handles side effects (metrics, snapshots, notifications).
+#[derive(Debug)]
 pub struct Scraper {
-    hclient: reqwest::r#async::Client,
-    latest: HashMap<String, metadata::Release>,
-    refresh_pause: Duration,
-    streams: BTreeSet<String>,
+    fetcher: Arc<dyn fetcher::HttpFetcher>,
+    /// Release cache, and refresh state machine.
+    core: engine::Core<SpawnHandle>,
+    /// Templated URL for release indexes, overriding the built-in ones.
+    releases_template: Option<String>,
+    /// Automatic discovery of additional streams.
+    discovery: Option<StreamDiscovery>,
+    /// Recent changes between scrapes, oldest first.
+    changelog: VecDeque<diff::ChangelogEntry>,
+    /// Restart after this many refresh intervals without a full refresh (0 disables).
+    watchdog_intervals: u32,
+    /// Completion of the last full refresh cycle (or of the last restart).
+    last_cycle: Instant,
+    /// Maximum number of concurrent upstream fetches (0 for unlimited).
+    fetch_concurrency: usize,
+    /// Whether to scrape rollouts from the updates metadata of each stream.
+    scrape_rollouts: bool,
+    /// Rollouts in progress per stream, by release version.
+    rollouts: HashMap<String, HashMap<String, metadata::UpdateRollout>>,
+    /// Refresh generation of the rollouts of each stream.
+    rollouts_generation: HashMap<String, u64>,
+    /// Long-poll requests waiting for a stream to change.
+    watchers: HashMap<String, Vec<oneshot::Sender<()>>>,
+    /// Automatic dead-ending of old releases.
+    deadend_policy: lifecycle::DeadendPolicy,
+    /// Directory of local fixtures, read instead of upstream.
+    fixtures_dir: Option<PathBuf>,
+    /// Snapshots of scraped release indexes, to fall back on.
+    snapshots: Option<snapshot::SnapshotDir>,
+    /// Behavior when upstream stops serving a stream.
+    missing_stream: snapshot::MissingStream,
+    /// Architectures to cache payloads for (all if empty).
+    arches: BTreeSet<String>,
+    /// Readiness and status notifications to systemd.
+    notifier: Option<sdnotify::Notifier>,
+}
+
+/// Stream auto-discovery from an upstream index document.
+#[derive(Clone, Debug)]
+pub struct StreamDiscovery {
+    /// URL of the streams index document.
+    pub url: url::Url,
+    /// Only discover streams matching this pattern.
+    pub allow: Option<regex::Regex>,
+    /// Never discover streams matching this pattern.
+    pub deny: Option<regex::Regex>,
+}
+
+impl StreamDiscovery {
+    /// Check whether a discovered stream passes allow/deny patterns.
+    fn is_allowed(&self, stream: &str) -> bool {
+        let allowed = self.allow.as_ref().is_none_or(|re| re.is_match(stream));
+        let denied = self.deny.as_ref().is_some_and(|re| re.is_match(stream));
+        allowed && !denied
+    }
 }
 
 impl Scraper {
-    pub fn new(streams: BTreeSet<String>, refresh_pause: Duration) -> Fallible<Self> {
+    pub fn new(
+        streams: BTreeSet<String>,
+        refresh_pause: Duration,
+        retry_policy: retry::RetryPolicy,
+    ) -> Fallible<Self> {
         let scraper = Self {
-            hclient: reqwest::r#async::ClientBuilder::new().build()?,
-            latest: HashMap::new(),
-            refresh_pause,
-            streams,
+            fetcher: fetcher::new_fetcher(&Default::default())?,
+            core: engine::Core::new(streams, refresh_pause).with_backoff(Box::new(retry_policy)),
+            releases_template: None,
+            discovery: None,
+            changelog: VecDeque::new(),
+            watchers: HashMap::new(),
+            watchdog_intervals: 0,
+            last_cycle: Instant::now(),
+            fetch_concurrency: 0,
+            scrape_rollouts: false,
+            rollouts: HashMap::new(),
+            rollouts_generation: HashMap::new(),
+            deadend_policy: lifecycle::DeadendPolicy::default(),
+            fixtures_dir: None,
+            snapshots: None,
+            missing_stream: snapshot::MissingStream::Lenient,
+            arches: BTreeSet::new(),
+            notifier: None,
         };
         Ok(scraper)
     }
 
-    /// Return a request builder with base URL and parameters set.
-    fn new_request(
-        &self,
-        method: reqwest::Method,
-        stream: String,
-    ) -> Fallible<reqwest::r#async::RequestBuilder> {
-        let vars = hashmap!("stream".to_string() => stream);
-        let full = envsubst::substitute(metadata::RELEASES_JSON, &vars)?;
-        let url = reqwest::Url::parse(&full)?;
-        let builder = self.hclient.request(method, url);
-        Ok(builder)
-    }
-
-    /// Fetch last release from release-index.
-    fn fetch_releases(
+    /// Mark streams as high priority.
+    ///
+    /// Priority streams are also added to the set of scraped streams.
+    pub fn with_priority_streams(mut self, priority_streams: BTreeSet<String>) -> Self {
+        self.core = self.core.with_priority_streams(priority_streams);
+        self
+    }
+
+    /// Automatically discover additional streams from an index document.
+    pub fn with_discovery(mut self, discovery: StreamDiscovery) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    /// Fetch stream names from the discovery index, if configured.
+    fn discover_streams(&self) -> impl Future<Item = Option<Vec<String>>, Error = Error> {
+        let url = match self.discovery {
+            Some(ref d) => d.url.clone(),
+            None => return future::Either::A(future::ok(None)),
+        };
+        let fut = self
+            .fetcher
+            .get(&url, vec![])
+            .and_then(|body| decode::json::<metadata::StreamsJSON>(&body))
+            .map(|json| Some(json.streams));
+        future::Either::B(fut)
+    }
+
+    /// Start scraping newly discovered streams.
+    fn add_discovered(&mut self, found: Vec<String>) {
+        let discovery = match self.discovery {
+            Some(ref d) => d,
+            None => return,
+        };
+        for stream in found {
+            if !discovery.is_allowed(&stream) {
+                continue;
+            }
+            if self.core.add_stream(stream.clone()) {
+                log::info!("discovered new stream '{}'", stream);
+            }
+        }
+    }
+
+    /// Only scrape streams once they are requested by a client.
+    ///
+    /// Priority streams are always scraped.
+    pub fn with_lazy_streams(mut self, lazy: bool) -> Self {
+        self.core = self.core.with_lazy_streams(lazy);
+        self
+    }
+
+    /// Restart the scraper if no full refresh completes for this many intervals.
+    pub fn with_watchdog(mut self, intervals: u32) -> Self {
+        self.watchdog_intervals = intervals;
+        self
+    }
+
+    /// Stop the actor (to be restarted by its supervisor) if refreshes are stuck.
+    fn check_watchdog(&mut self, ctx: &mut Context<Self>) {
+        let limit = self.core.refresh_pause() * self.watchdog_intervals;
+        let stalled = self.last_cycle.elapsed();
+        if stalled <= limit || self.core.is_stopping() {
+            return;
+        }
+        log::error!(
+            "watchdog: no full refresh in {}s, restarting scraper",
+            stalled.as_secs()
+        );
+        WATCHDOG_RESTARTS.inc();
+        ctx.stop();
+    }
+
+    /// Fetch release indexes from a templated URL (with `${stream}`).
+    pub fn with_releases_template(mut self, template: Option<String>) -> Self {
+        self.releases_template = template;
+        self
+    }
+
+    /// Read `releases-<stream>.json` (and `updates-<stream>.json`) from a
+    /// local directory instead of upstream, or their `.json.gz`/`.json.zst`
+    /// compressed variants.
+    ///
+    /// Files are re-read on every refresh, so they can be changed at runtime.
+    pub fn with_fixtures_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.fixtures_dir = dir;
+        self
+    }
+
+    /// Persist scraped release indexes, and fall back on them if upstream
+    /// stops serving a stream.
+    pub fn with_snapshots(mut self, snapshots: Option<snapshot::SnapshotDir>) -> Self {
+        self.snapshots = snapshots;
+        self
+    }
+
+    /// Drop streams not found upstream anymore, or keep serving them as stale.
+    pub fn with_missing_stream(mut self, mode: snapshot::MissingStream) -> Self {
+        self.missing_stream = mode;
+        self
+    }
+
+    /// Refresh some streams on their own schedule, with a distinct interval.
+    pub fn with_stream_refresh_intervals(mut self, intervals: BTreeMap<String, Duration>) -> Self {
+        self.core = self.core.with_stream_refresh_intervals(intervals);
+        self
+    }
+
+    /// Only cache payloads for these architectures.
+    pub fn with_arches(mut self, arches: BTreeSet<String>) -> Self {
+        self.arches = arches;
+        self
+    }
+
+    /// Limit concurrent upstream fetches, queueing the others (0 for unlimited).
+    pub fn with_fetch_concurrency(mut self, limit: usize) -> Self {
+        self.fetch_concurrency = limit;
+        self
+    }
+
+    /// Scrape rollouts from updates metadata, and annotate nodes with them.
+    pub fn with_rollouts(mut self, enabled: bool) -> Self {
+        self.scrape_rollouts = enabled;
+        self
+    }
+
+    /// Mark old releases as dead-ends in served nodes.
+    pub fn with_deadend_policy(mut self, policy: lifecycle::DeadendPolicy) -> Self {
+        self.deadend_policy = policy;
+        self
+    }
+
+    /// Customize TLS trust for upstream requests.
+    pub fn with_upstream_tls(mut self, tls: &tls::UpstreamTls) -> Fallible<Self> {
+        self.fetcher = fetcher::new_fetcher(tls)?;
+        Ok(self)
+    }
+
+    /// Notify systemd once the cache is usable, and of cached versions.
+    pub fn with_notifier(mut self, notifier: Option<sdnotify::Notifier>) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// Select update targets per stream, for clients far behind latest.
+    pub fn with_hop_policies(mut self, policies: HashMap<String, engine::HopPolicy>) -> Self {
+        self.core = self.core.with_hop_policies(policies);
+        self
+    }
+
+    /// Withhold newly scraped releases until they are approved.
+    pub fn with_release_gate(mut self, gate: engine::ReleaseGate) -> Self {
+        self.core = self.core.with_release_gate(gate);
+        self
+    }
+
+    /// Preload the cache with imported releases, and freeze it.
+    ///
+    /// Imported streams are also added to the set of scraped streams.
+    pub fn with_imported(mut self, releases: BTreeMap<String, Vec<metadata::Release>>) -> Self {
+        self.core = self.core.with_imported(releases);
+        FROZEN.set(1);
+        self
+    }
+
+    /// Fetch all releases from release-index.
+    pub(crate) fn fetch_releases(
         &self,
         stream: &str,
-    ) -> impl Future<Item = (String, Option<metadata::Release>), Error = Error> {
-        let out_stream = stream.to_string();
-        let req = self.new_request(Method::GET, stream.to_string());
-        future::result(req)
-            .and_then(|req| req.send().from_err())
-            .and_then(|resp| resp.error_for_status().map_err(Error::from))
-            .and_then(|mut resp| resp.json::<metadata::ReleasesJSON>().from_err())
-            .map(|mut json| (out_stream, json.releases.pop()))
+        trace: Option<&trace::TraceContext>,
+    ) -> impl Future<Item = Vec<metadata::Release>, Error = Error> {
+        let arches = self.arches.clone();
+        let timer = STREAM_REFRESH_DURATION.with_label_values(&[stream]);
+        let start = Instant::now();
+        let index = match self.fixtures_dir {
+            Some(ref dir) => {
+                let name = format!("releases-{}", stream);
+                let index = read_fixture(dir, &name).and_then(|body| parse_releases(&body));
+                future::Either::A(future::result(index))
+            }
+            None => {
+                let url = releases_url(self.releases_template.as_deref(), stream.to_string());
+                let headers = trace.map(|t| t.headers()).unwrap_or_default();
+                let fetcher = Arc::clone(&self.fetcher);
+                let fut = future::result(url)
+                    .and_then(move |url| fetcher.get(&url, headers))
+                    .and_then(|body| parse_releases(&body));
+                future::Either::B(fut)
+            }
+        };
+        index
+            .then(move |res| {
+                timer.observe(start.elapsed().as_secs_f64());
+                res
+            })
+            .map(move |json| {
+                let mut releases = json.releases;
+                engine::filter_arches(&mut releases, &arches);
+                releases
+            })
     }
 
     /// Refresh cache.
+    ///
+    /// This never fails as a whole; each stream carries its own result.
     fn refresh_cache(
         &self,
-    ) -> impl Future<Item = HashMap<String, metadata::Release>, Error = Error> {
-        let mut latest: Vec<_> = Vec::new();
-        for stream in &self.streams {
-            let fut = self.fetch_releases(stream);
-            latest.push(fut);
-        }
-
-        let cache = future::join_all(latest).map(|vec| {
-            let mut streams_latest = HashMap::new();
-            for (stream, latest) in vec {
-                if let Some(rel) = latest {
-                    streams_latest.insert(stream, rel);
+        streams: &[String],
+        trace: Option<&trace::TraceContext>,
+    ) -> impl Future<Item = Vec<(String, Fallible<Vec<metadata::Release>>)>, Error = Error> {
+        // Streams are fetched concurrently, so refresh latency is bound
+        // by the slowest stream rather than growing with stream count
+        // (unless limited, for rate-limited upstreams).
+        let fetches = streams
+            .iter()
+            .map(|stream| {
+                let fetch = self.fetch_releases(stream, trace);
+                (stream.clone(), fetch)
+            })
+            .collect();
+        engine::refresh_streams(fetches, self.fetch_concurrency)
+    }
+
+    /// Run fetches concurrently, up to the concurrency limit, in order.
+    fn buffered<F: Future>(
+        &self,
+        fetches: Vec<F>,
+    ) -> impl Future<Item = Vec<F::Item>, Error = F::Error> {
+        engine::buffered(fetches, self.fetch_concurrency)
+    }
+
+    /// Fetch rollouts from the updates metadata of a stream.
+    fn fetch_rollouts(
+        &self,
+        stream: &str,
+    ) -> impl Future<Item = Vec<metadata::UpdateRollout>, Error = Error> {
+        if let Some(ref dir) = self.fixtures_dir {
+            let name = format!("updates-{}", stream);
+            let rollouts = read_fixture(dir, &name)
+                .and_then(|body| decode::json::<metadata::UpdatesJSON>(&body))
+                .map(|json| json.updates.rollouts);
+            return future::Either::A(future::result(rollouts));
+        }
+        let vars = hashmap!("stream".to_string() => stream.to_string());
+        let url = envsubst::substitute(metadata::STREAM_JSON, &vars)
+            .map_err(Error::from)
+            .and_then(|full| url::Url::parse(&full).map_err(Error::from));
+        let fetcher = Arc::clone(&self.fetcher);
+        let fut = future::result(url)
+            .and_then(move |url| fetcher.get(&url, vec![]))
+            .and_then(|body| decode::json::<metadata::UpdatesJSON>(&body))
+            .map(|json| json.updates.rollouts);
+        future::Either::B(fut)
+    }
+
+    /// Refresh rollouts, if enabled.
+    fn refresh_rollouts(
+        &self,
+        scope: &RefreshScope,
+    ) -> impl Future<Item = Vec<(String, Fallible<Vec<metadata::UpdateRollout>>)>, Error = Error>
+    {
+        let streams = if self.scrape_rollouts && !self.core.is_frozen() {
+            self.core.scope_streams(scope, Instant::now())
+        } else {
+            vec![]
+        };
+        let rollouts: Vec<_> = streams
+            .into_iter()
+            .map(|stream| self.fetch_rollouts(&stream).then(|res| Ok((stream, res))))
+            .collect();
+        self.buffered(rollouts)
+    }
+
+    /// Merge refreshed rollouts; streams which failed keep previous ones.
+    ///
+    /// Rollouts already updated by a later refresh are kept.
+    fn update_rollouts(
+        &mut self,
+        refresh: &engine::Refresh,
+        refreshed: Vec<(String, Fallible<Vec<metadata::UpdateRollout>>)>,
+    ) {
+        for (stream, res) in refreshed {
+            let applied = self.rollouts_generation.entry(stream.clone()).or_default();
+            if *applied > refresh.generation {
+                continue;
+            }
+            *applied = refresh.generation;
+            match res {
+                Ok(rollouts) => {
+                    let by_version = rollouts
+                        .into_iter()
+                        .map(|r| (r.version.clone(), r))
+                        .collect();
+                    self.rollouts.insert(stream, by_version);
                 }
+                Err(e) => log::warn!("failed to refresh rollouts for stream '{}': {}", stream, e),
             }
-            streams_latest
+        }
+    }
+
+    /// Add rollout metadata to a node, if its release is being rolled out.
+    fn annotate_rollout(&self, stream: &str, node: &mut CincinnatiPayload) {
+        let rollout = match self.rollouts.get(stream).and_then(|r| r.get(&node.version)) {
+            Some(rollout) => rollout,
+            None => return,
+        };
+        node.metadata.insert(
+            metadata::START_EPOCH.to_string(),
+            rollout.start_epoch.clone(),
+        );
+        node.metadata.insert(
+            metadata::START_VALUE.to_string(),
+            rollout.start_value.clone(),
+        );
+        if let Some(ref duration) = rollout.duration_minutes {
+            node.metadata
+                .insert(metadata::DURATION.to_string(), duration.clone());
+        }
+    }
+
+    /// Add dead-end metadata to a node, if its release is past the policy limits.
+    ///
+    /// Release age comes from the build date in its version, or else from
+    /// when it was first scraped.
+    fn annotate_deadend(&self, stream: &str, node: &mut CincinnatiPayload) {
+        let releases = match self.core.releases(stream) {
+            Some(r) => r,
+            None => return,
+        };
+        let position = match releases.iter().rposition(|r| r.version == node.version) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let behind = releases.len() - 1 - position;
+        let age = match lifecycle::build_date(&node.version) {
+            Some(date) => (clock::now().date_naive() - date).to_std().ok(),
+            None => self
+                .core
+                .first_seen(&node.version)
+                .map(|seen| seen.elapsed()),
+        };
+        if let Some(reason) = self.deadend_policy.reason(stream, behind, age) {
+            node.metadata
+                .insert(metadata::DEADEND.to_string(), "true".to_string());
+            node.metadata
+                .insert(metadata::DEADEND_REASON.to_string(), reason);
+        }
+    }
+
+    /// Merge refreshed streams into the cache.
+    ///
+    /// Streams which failed to refresh keep their previous cache entry.
+    /// It returns whether all streams were successfully refreshed.
+    fn update_cache(
+        &mut self,
+        refresh: &engine::Refresh,
+        refreshed: Vec<(String, Fallible<Vec<metadata::Release>>)>,
+    ) -> bool {
+        if self.core.is_frozen() {
+            log::debug!("cache frozen, ignoring scraped releases");
+            return false;
+        }
+
+        let mut all_refreshed = true;
+        let updates = self
+            .core
+            .apply_refresh(refresh, refreshed, Instant::now(), clock::now());
+        for update in updates {
+            match update {
+                StreamUpdate::Refreshed {
+                    stream,
+                    previous,
+                    current,
+                    build_time,
+                    resumed,
+                } => {
+                    STREAM_SCRAPES.with_label_values(&[&stream]).inc();
+                    STREAM_GRAPH_BUILD_DURATION
+                        .with_label_values(&[&stream])
+                        .observe(build_time.as_secs_f64());
+                    STREAM_EMPTY.with_label_values(&[&stream]).set(0);
+                    record_arch_freshness(&stream, &current);
+                    let changed = match previous {
+                        Some(previous) => self.record_changes(&stream, &previous, &current),
+                        None => true,
+                    };
+                    if changed || resumed {
+                        self.notify_watchers(Some(&stream));
+                    }
+                    if let (true, Some(snapshots)) = (changed, &self.snapshots) {
+                        if let Err(e) = snapshots.save(&stream, &current) {
+                            log::warn!("{}", e);
+                        }
+                    }
+                }
+                StreamUpdate::Empty { stream, emptied } => {
+                    STREAM_SCRAPES.with_label_values(&[&stream]).inc();
+                    STREAM_EMPTY.with_label_values(&[&stream]).set(1);
+                    if emptied {
+                        self.notify_watchers(Some(&stream));
+                    }
+                }
+                StreamUpdate::Failed { stream, error } => {
+                    all_refreshed = false;
+                    STREAM_ERRORS.with_label_values(&[&stream]).inc();
+                    let not_found = error
+                        .downcast_ref::<retry::UpstreamError>()
+                        .is_some_and(|e| e.status == 404);
+                    if not_found {
+                        self.handle_missing_stream(&stream);
+                    }
+                }
+            }
+        }
+        STALE_STREAMS.set(self.core.stale_streams().len() as i64);
+        self.notify_status();
+        all_refreshed
+    }
+
+    /// Report the latest cached version of each stream to systemd.
+    ///
+    /// Readiness is only signalled once every stream has been populated.
+    fn notify_status(&mut self) {
+        let notifier = match self.notifier {
+            Some(ref mut notifier) => notifier,
+            None => return,
+        };
+        let latest: BTreeMap<&str, &str> = self
+            .core
+            .cached()
+            .filter_map(|(stream, releases)| {
+                Some((stream.as_str(), releases.last()?.version.as_str()))
+            })
+            .collect();
+        let pending = self.core.pending_streams();
+        let status = if !pending.is_empty() {
+            format!("waiting for streams: {}", pending.join(", "))
+        } else if latest.is_empty() {
+            "serving, no cached releases".to_string()
+        } else {
+            let versions: Vec<String> = latest
+                .iter()
+                .map(|(stream, version)| format!("{} {}", stream, version))
+                .collect();
+            format!("serving {}", versions.join(", "))
+        };
+        notifier.update(&status, pending.is_empty());
+    }
+
+    /// Handle upstream not serving (404) a stream anymore.
+    fn handle_missing_stream(&mut self, stream: &str) {
+        if self.missing_stream == snapshot::MissingStream::Strict {
+            if self.core.drop_stream(stream) {
+                self.notify_watchers(Some(stream));
+            }
+            return;
+        }
+
+        let snapshots = &self.snapshots;
+        self.core.mark_stale(stream, || {
+            let loaded = match snapshots {
+                Some(snapshots) => snapshots.load(stream),
+                None => Ok(None),
+            };
+            loaded.unwrap_or_else(|e| {
+                log::warn!("{}", e);
+                None
+            })
         });
-        cache
+    }
+
+    /// Wake up long-poll requests for a stream, or for all streams.
+    fn notify_watchers(&mut self, stream: Option<&str>) {
+        let watchers: Vec<_> = match stream {
+            Some(s) => self.watchers.remove(s).unwrap_or_default(),
+            None => self.watchers.drain().flat_map(|(_, w)| w).collect(),
+        };
+        for watcher in watchers {
+            let _ = watcher.send(());
+        }
+    }
+
+    /// Log and record changes between two scrapes of a stream.
+    ///
+    /// It returns whether there was any change.
+    fn record_changes(
+        &mut self,
+        stream: &str,
+        previous: &[metadata::Release],
+        current: &[metadata::Release],
+    ) -> bool {
+        let timestamp = clock::now();
+        let changes = diff::diff_releases(previous, current);
+        let changed = !changes.is_empty();
+        for change in changes {
+            log::info!("stream '{}': {}", stream, change);
+            RELEASE_CHANGES.with_label_values(&[stream]).inc();
+            if self.changelog.len() >= CHANGELOG_CAPACITY {
+                self.changelog.pop_front();
+            }
+            self.changelog.push_back(diff::ChangelogEntry {
+                timestamp,
+                stream: stream.to_string(),
+                change,
+            });
+        }
+        changed
     }
 }
 
@@ -95,12 +735,33 @@ impl Actor for Scraper {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        // Kick-start the state machine.
-        Self::tick_now(ctx);
+        self.last_cycle = Instant::now();
+        // A frozen cache is already as usable as it will get.
+        if self.core.is_frozen() {
+            self.notify_status();
+        }
+        if self.watchdog_intervals > 0 {
+            ctx.run_interval(self.core.refresh_pause(), |act, ctx| {
+                act.check_watchdog(ctx)
+            });
+        }
+        self.core.start(&mut Timers(ctx));
     }
 }
 
-pub(crate) struct RefreshTick {}
+impl actix::Supervised for Scraper {
+    fn restarting(&mut self, _ctx: &mut Self::Context) {
+        // Pending timers and refreshes were dropped with the old context.
+        log::warn!("scraper restarting");
+        self.core.restarted();
+    }
+}
+
+pub(crate) struct RefreshTick {
+    pub(crate) scope: RefreshScope,
+    /// Trace context of the client request which triggered this refresh.
+    pub(crate) trace: Option<trace::TraceContext>,
+}
 
 impl Message for RefreshTick {
     type Result = Result<(), Error>;
@@ -109,24 +770,66 @@ impl Message for RefreshTick {
 impl Handler<RefreshTick> for Scraper {
     type Result = ResponseActFuture<Self, (), Error>;
 
-    fn handle(&mut self, _msg: RefreshTick, ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: RefreshTick, ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<RefreshTick>();
+        if self.core.is_stopping() {
+            return Box::new(actix::fut::ok(()));
+        }
         UPSTREAM_SCRAPES.inc();
 
-        let updates = self.refresh_cache();
+        let scope = msg.scope;
+        // Discovery only runs on full refreshes.
+        let discovery = if scope == RefreshScope::All {
+            future::Either::A(self.discover_streams())
+        } else {
+            future::Either::B(future::ok(None))
+        };
+        let trace = msg.trace;
 
-        let update_graph = actix::fut::wrap_future::<_, Self>(updates)
-            .map_err(|err, _actor, _ctx| log::error!("{}", err))
-            .map(|cache, actor, _ctx| {
-                actor.latest = cache;
-                let refresh_timestamp = chrono::Utc::now();
-                LAST_REFRESH.set(refresh_timestamp.timestamp());
+        let update_graph = actix::fut::wrap_future::<_, Self>(discovery)
+            .then(move |res, actor, _ctx| {
+                match res {
+                    Ok(Some(found)) => actor.add_discovered(found),
+                    Ok(None) => {}
+                    Err(e) => log::error!("stream discovery failed: {}", e),
+                };
+                let refresh = actor.core.begin_refresh(scope, Instant::now());
+                let fetch = actor.refresh_cache(&refresh.streams, trace.as_ref());
+                actix::fut::wrap_future(fetch.then(move |res| Ok((refresh, res))))
             })
-            .then(|_r, actor, ctx| {
-                Self::tick_later(ctx, actor.refresh_pause);
-                actix::fut::ok(())
-            });
+            .map(|(refresh, res), actor, _ctx| {
+                let is_full = refresh.scope == RefreshScope::All;
+                match res {
+                    Ok(refreshed) => {
+                        if actor.update_cache(&refresh, refreshed) && is_full {
+                            let refresh_timestamp = clock::now();
+                            LAST_REFRESH.set(refresh_timestamp.timestamp());
+                        }
+                    }
+                    Err(e) => log::error!("{}", e),
+                }
+                refresh
+            })
+            .and_then(|refresh, actor, _ctx| {
+                let rollouts = actor.refresh_rollouts(&refresh.scope);
+                actix::fut::wrap_future(rollouts.then(move |res| Ok((refresh, res))))
+            })
+            .map(|(refresh, res), actor, ctx| {
+                match res {
+                    Ok(refreshed) => actor.update_rollouts(&refresh, refreshed),
+                    Err(e) => log::error!("{}", e),
+                }
+                if refresh.scope == RefreshScope::All {
+                    actor.last_cycle = Instant::now();
+                }
+                actor.core.finish_refresh(&refresh, &mut Timers(ctx));
+            })
+            .map_err(|_: Error, _actor, _ctx| ());
 
-        ctx.wait(update_graph);
+        // Run the refresh in the background, without blocking the mailbox:
+        // queries keep being served from the current cache until the
+        // refreshed one is swapped in.
+        ctx.spawn(update_graph);
 
         Box::new(actix::fut::ok(()))
     }
@@ -135,62 +838,1559 @@ impl Handler<RefreshTick> for Scraper {
 pub(crate) struct GetLatest {
     pub(crate) basearch: String,
     pub(crate) stream: String,
+    /// Skip releases first scraped less than this long ago.
+    pub(crate) delay: Option<Duration>,
+    /// Payload of the client, for stream hop policies.
+    pub(crate) current: Option<String>,
+    /// Trace context of the client request.
+    pub(crate) trace: Option<trace::TraceContext>,
 }
 
 impl GetLatest {
     pub fn new(basearch: String, stream: String) -> Self {
-        Self { basearch, stream }
+        Self {
+            basearch,
+            stream,
+            delay: None,
+            current: None,
+            trace: None,
+        }
+    }
+
+    /// Select the target for a client running this payload.
+    pub fn with_current(mut self, checksum: String) -> Self {
+        self.current = Some(checksum);
+        self
+    }
+
+    /// Only consider releases which have been available for some time.
+    pub fn with_delay(mut self, delay: Option<Duration>) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Propagate a client trace context to upstream requests.
+    pub fn with_trace(mut self, trace: Option<trace::TraceContext>) -> Self {
+        self.trace = trace;
+        self
     }
 }
 
 impl Message for GetLatest {
-    type Result = Result<CincinnatiPayload, Error>;
+    /// Latest payload, or `None` if the stream is known but has no releases.
+    type Result = Result<Option<CincinnatiPayload>, Error>;
 }
 
 impl Handler<GetLatest> for Scraper {
-    type Result = ResponseActFuture<Self, CincinnatiPayload, Error>;
-    fn handle(&mut self, msg: GetLatest, _ctx: &mut Self::Context) -> Self::Result {
-        let release = match self.latest.get(&msg.stream) {
-            None => return Box::new(actix::fut::err(failure::format_err!("stream unavailable"))),
-            Some(latest) => latest,
-        };
-
-        let checksum = {
-            let mut matching = String::new();
-            for commit in &release.commits {
-                if commit.architecture == msg.basearch {
-                    matching = commit.checksum.to_string();
-                }
+    type Result = ResponseActFuture<Self, Option<CincinnatiPayload>, Error>;
+    fn handle(&mut self, msg: GetLatest, ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<GetLatest>();
+        if self.core.activate_stream(&msg.stream) {
+            if let Some(ref trace) = msg.trace {
+                log::debug!(
+                    "stream '{}' activated by trace {}",
+                    msg.stream,
+                    trace.trace_id()
+                );
             }
-            if matching.is_empty() {
-                return Box::new(actix::fut::err(failure::format_err!(
-                    "basearch unavailable"
-                )));
+            ctx.notify(RefreshTick {
+                scope: RefreshScope::Single(msg.stream.clone()),
+                trace: msg.trace.clone(),
+            });
+        }
+
+        let latest = self.core.latest(
+            &msg.stream,
+            &msg.basearch,
+            msg.current.as_deref(),
+            msg.delay,
+            Instant::now(),
+        );
+        let mut node = match latest {
+            Err(miss) => {
+                let err = errors::GraphError::from(miss);
+                return Box::new(actix::fut::err(err.into()));
             }
-            matching
+            Ok(None) => return Box::new(actix::fut::ok(None)),
+            Ok(Some(node)) => node,
+        };
+        self.annotate_rollout(&msg.stream, &mut node);
+        self.annotate_deadend(&msg.stream, &mut node);
+
+        Box::new(actix::fut::ok(Some(node)))
+    }
+}
+
+/// Look up the node for a specific payload in a stream release index.
+pub(crate) struct LookupNode {
+    pub(crate) basearch: String,
+    pub(crate) stream: String,
+    pub(crate) checksum: String,
+}
+
+impl Message for LookupNode {
+    /// Matching node, or `None` if the payload is not in the release index.
+    type Result = Result<Option<CincinnatiPayload>, Error>;
+}
+
+impl Handler<LookupNode> for Scraper {
+    type Result = Result<Option<CincinnatiPayload>, Error>;
+    fn handle(&mut self, msg: LookupNode, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<LookupNode>();
+        let mut node = self.core.lookup(&msg.stream, &msg.basearch, &msg.checksum);
+        if let Some(ref mut node) = node {
+            self.annotate_deadend(&msg.stream, node);
+        }
+        Ok(node)
+    }
+}
+
+/// Check whether a payload is present in any cached release index.
+pub(crate) struct HasPayload {
+    pub(crate) checksum: String,
+}
+
+impl Message for HasPayload {
+    type Result = Result<bool, Error>;
+}
+
+impl Handler<HasPayload> for Scraper {
+    type Result = Result<bool, Error>;
+    fn handle(&mut self, msg: HasPayload, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<HasPayload>();
+        Ok(self.core.has_payload(&msg.checksum))
+    }
+}
+
+/// Get the time of the last successful refresh of a stream, if any.
+pub(crate) struct GetRefreshTime {
+    pub(crate) stream: String,
+}
+
+impl Message for GetRefreshTime {
+    type Result = Result<Option<chrono::DateTime<chrono::Utc>>, Error>;
+}
+
+impl Handler<GetRefreshTime> for Scraper {
+    type Result = Result<Option<chrono::DateTime<chrono::Utc>>, Error>;
+    fn handle(&mut self, msg: GetRefreshTime, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<GetRefreshTime>();
+        Ok(self.core.refreshed_at(&msg.stream))
+    }
+}
+
+/// Track freshness of each architecture in a refreshed stream.
+fn record_arch_freshness(stream: &str, releases: &[metadata::Release]) {
+    let mut latest: BTreeMap<&str, usize> = BTreeMap::new();
+    for (age_index, rel) in releases.iter().enumerate() {
+        for commit in &rel.commits {
+            latest.insert(&commit.architecture, age_index);
+        }
+    }
+    let now = clock::now().timestamp();
+    for (arch, age_index) in latest {
+        STREAM_ARCH_REFRESH
+            .with_label_values(&[stream, arch])
+            .set(now);
+        STREAM_ARCH_LATEST
+            .with_label_values(&[stream, arch])
+            .set(age_index as i64);
+    }
+}
+
+/// Return the release index URL for a stream, from a custom or built-in template.
+pub(crate) fn releases_url(template: Option<&str>, stream: String) -> Fallible<url::Url> {
+    let template = template.unwrap_or_else(|| metadata::releases_json(&stream));
+    let vars = hashmap!("stream".to_string() => stream);
+    let full = envsubst::substitute(template, &vars)?;
+    let url = url::Url::parse(&full)?;
+    Ok(url)
+}
+
+/// Parse a release index, decompressing it if needed.
+fn parse_releases(body: &[u8]) -> Fallible<metadata::ReleasesJSON> {
+    let body = decode::decompress(body)?;
+    metadata::ReleasesJSON::from_slice(&body)
+}
+
+/// Read a local JSON fixture file, possibly compressed.
+///
+/// Plain `<name>.json` is preferred over `<name>.json.gz` and `<name>.json.zst`.
+fn read_fixture(dir: &Path, name: &str) -> Fallible<Vec<u8>> {
+    let path = FIXTURE_EXTENSIONS
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", name, ext)))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| dir.join(format!("{}.json", name)));
+    std::fs::read(&path)
+        .map_err(|e| failure::format_err!("failed to read fixture '{}': {}", path.display(), e))
+}
+
+/// Replace the set of scraped streams and the refresh intervals.
+///
+/// Priority streams are always kept. A new global refresh interval applies
+/// from the next scheduled refresh, while streams whose own interval changed
+/// are refreshed right away.
+pub(crate) struct Reconfigure {
+    pub(crate) streams: BTreeSet<String>,
+    pub(crate) refresh_pause: Duration,
+    /// Streams refreshed on their own schedule, with their interval.
+    pub(crate) stream_refresh: BTreeMap<String, Duration>,
+}
+
+impl Message for Reconfigure {
+    type Result = ();
+}
+
+impl Handler<Reconfigure> for Scraper {
+    type Result = ();
+    fn handle(&mut self, msg: Reconfigure, ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<Reconfigure>();
+        self.core.reconfigure(
+            msg.streams,
+            msg.refresh_pause,
+            msg.stream_refresh,
+            &mut Timers(ctx),
+        );
+    }
+}
+
+pub(crate) struct SetFrozen {
+    pub(crate) frozen: bool,
+}
+
+impl Message for SetFrozen {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<SetFrozen> for Scraper {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, msg: SetFrozen, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<SetFrozen>();
+        if self.core.set_frozen(msg.frozen) {
+            log::info!("cache frozen: {}", msg.frozen);
+        }
+        FROZEN.set(if msg.frozen { 1 } else { 0 });
+        Ok(())
+    }
+}
+
+/// Check that the scraper is alive, and processing messages.
+pub(crate) struct Ping;
+
+impl Message for Ping {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<Ping> for Scraper {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, _msg: Ping, _ctx: &mut Self::Context) -> Self::Result {
+        Ok(())
+    }
+}
+
+/// List streams which still wait to be populated, see `Core::pending_streams`.
+pub(crate) struct PendingStreams;
+
+impl Message for PendingStreams {
+    type Result = Result<Vec<String>, Error>;
+}
+
+impl Handler<PendingStreams> for Scraper {
+    type Result = Result<Vec<String>, Error>;
+    fn handle(&mut self, _msg: PendingStreams, _ctx: &mut Self::Context) -> Self::Result {
+        Ok(self.core.pending_streams())
+    }
+}
+
+/// Stop refreshing, and wake up all long-poll requests, before shutdown.
+pub(crate) struct Shutdown;
+
+impl Message for Shutdown {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<Shutdown> for Scraper {
+    type Result = Result<(), Error>;
+    fn handle(&mut self, _msg: Shutdown, ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<Shutdown>();
+        self.core.shutdown(&mut Timers(ctx));
+        self.notify_watchers(None);
+        log::info!("scraper stopped");
+        Ok(())
+    }
+}
+
+/// Approve a pending release, so that it can be served.
+pub(crate) struct ApproveRelease {
+    pub(crate) version: String,
+}
+
+impl Message for ApproveRelease {
+    /// Whether the version was pending approval.
+    type Result = Result<bool, Error>;
+}
+
+impl Handler<ApproveRelease> for Scraper {
+    type Result = Result<bool, Error>;
+    fn handle(&mut self, msg: ApproveRelease, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<ApproveRelease>();
+        let found = self.core.approve(&msg.version);
+        if found {
+            self.notify_watchers(None);
+        }
+        Ok(found)
+    }
+}
+
+/// Subscribe to the next change of a stream graph.
+pub(crate) struct WatchStream {
+    pub(crate) stream: String,
+}
+
+impl Message for WatchStream {
+    type Result = Result<oneshot::Receiver<()>, Error>;
+}
+
+impl Handler<WatchStream> for Scraper {
+    type Result = Result<oneshot::Receiver<()>, Error>;
+    fn handle(&mut self, msg: WatchStream, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<WatchStream>();
+        let (tx, rx) = oneshot::channel();
+        if self.core.is_stopping() {
+            // Dropping the sender wakes the request right away.
+            return Ok(rx);
+        }
+        let watchers = self.watchers.entry(msg.stream).or_default();
+        // Drop requests which already timed out.
+        watchers.retain(|w| !w.is_canceled());
+        watchers.push(tx);
+        Ok(rx)
+    }
+}
+
+/// Send a request to the scraper, tracking mailbox depth and latency.
+pub(crate) fn request<M, T>(addr: &Addr<Scraper>, msg: M) -> impl Future<Item = T, Error = Error>
+where
+    M: Message<Result = Result<T, Error>> + Send + 'static,
+    T: Send + 'static,
+    Scraper: Handler<M>,
+{
+    let name = message_name::<M>();
+    let timer = MESSAGE_LATENCY.with_label_values(&[name]).start_timer();
+    MAILBOX_PENDING.inc();
+    addr.send(msg).flatten().then(move |res| {
+        MAILBOX_PENDING.dec();
+        timer.observe_duration();
+        res
+    })
+}
+
+/// Start timing a message handler, for one in `HANDLER_SAMPLE_RATE` runs.
+fn sample_handler<M>() -> Option<HistogramTimer> {
+    let run = HANDLER_RUNS.fetch_add(1, Ordering::Relaxed);
+    if !run.is_multiple_of(HANDLER_SAMPLE_RATE) {
+        return None;
+    }
+    let name = message_name::<M>();
+    Some(HANDLER_DURATION.with_label_values(&[name]).start_timer())
+}
+
+/// Short type name of a message, for metric labels.
+fn message_name<M>() -> &'static str {
+    let full = std::any::type_name::<M>();
+    full.rsplit("::").next().unwrap_or(full)
+}
+
+/// Wait for a stream graph to change, up to a timeout.
+///
+/// It resolves to whether the stream changed.
+pub(crate) fn wait_for_change(
+    addr: &Addr<Scraper>,
+    stream: String,
+    timeout: Duration,
+) -> impl Future<Item = bool, Error = Error> {
+    let deadline = tokio_timer::Delay::new(Instant::now() + timeout);
+    request(addr, WatchStream { stream }).and_then(move |rx| {
+        rx.select2(deadline).then(|res| match res {
+            Ok(future::Either::A(_)) => Ok(true),
+            Ok(future::Either::B(_)) => Ok(false),
+            // Scraper went away, let the caller find out.
+            Err(future::Either::A(_)) => Ok(true),
+            Err(future::Either::B((e, _))) => Err(Error::from(e)),
+        })
+    })
+}
+
+/// Query releases added to a stream, for its feed.
+pub(crate) struct GetReleaseFeed {
+    pub(crate) stream: String,
+}
+
+impl Message for GetReleaseFeed {
+    /// Added releases (oldest first), or `None` if the stream is unknown.
+    type Result = Result<Option<Vec<feed::FeedEntry>>, Error>;
+}
+
+impl Handler<GetReleaseFeed> for Scraper {
+    type Result = Result<Option<Vec<feed::FeedEntry>>, Error>;
+    fn handle(&mut self, msg: GetReleaseFeed, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<GetReleaseFeed>();
+        if !self.core.streams().contains(&msg.stream) {
+            return Ok(None);
+        }
+        let releases = self.core.releases(&msg.stream);
+        let entries = self
+            .changelog
+            .iter()
+            .filter(|e| e.stream == msg.stream)
+            .filter_map(|e| match e.change {
+                diff::ReleaseChange::Added { ref version } => Some((e.timestamp, version)),
+                _ => None,
+            })
+            .map(|(timestamp, version)| {
+                let commits = releases
+                    .and_then(|r| r.iter().find(|rel| rel.version == *version))
+                    .map(|rel| rel.commits.clone())
+                    .unwrap_or_default();
+                feed::FeedEntry {
+                    version: version.clone(),
+                    timestamp,
+                    commits,
+                }
+            })
+            .collect();
+        Ok(Some(entries))
+    }
+}
+
+/// Query the scrape changelog, optionally for a single stream.
+pub(crate) struct GetChangelog {
+    pub(crate) stream: Option<String>,
+}
+
+impl Message for GetChangelog {
+    type Result = Result<Vec<diff::ChangelogEntry>, Error>;
+}
+
+impl Handler<GetChangelog> for Scraper {
+    type Result = Result<Vec<diff::ChangelogEntry>, Error>;
+    fn handle(&mut self, msg: GetChangelog, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<GetChangelog>();
+        let entries = self
+            .changelog
+            .iter()
+            .filter(|e| msg.stream.as_ref().is_none_or(|s| *s == e.stream))
+            .cloned()
+            .collect();
+        Ok(entries)
+    }
+}
+
+pub(crate) struct GetStatus {}
+
+impl Message for GetStatus {
+    type Result = Result<ScraperStatus, Error>;
+}
+
+/// Scraper status, for admin reporting.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ScraperStatus {
+    pub(crate) frozen: bool,
+    pub(crate) last_refresh: i64,
+    pub(crate) streams: BTreeMap<String, StreamStatus>,
+    /// Releases withheld pending approval.
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) pending_releases: BTreeSet<String>,
+}
+
+/// Cache status of a single stream.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct StreamStatus {
+    pub(crate) cached: bool,
+    pub(crate) latest_version: Option<String>,
+}
+
+impl Handler<GetStatus> for Scraper {
+    type Result = Result<ScraperStatus, Error>;
+    fn handle(&mut self, _msg: GetStatus, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<GetStatus>();
+        let mut streams = BTreeMap::new();
+        for stream in self.core.streams() {
+            let entry = self.core.releases(stream);
+            let status = StreamStatus {
+                cached: entry.is_some(),
+                latest_version: entry.and_then(|e| e.last().map(|r| r.version.clone())),
+            };
+            streams.insert(stream.clone(), status);
+        }
+        let status = ScraperStatus {
+            frozen: self.core.is_frozen(),
+            last_refresh: LAST_REFRESH.get(),
+            streams,
+            pending_releases: self.core.withheld(Instant::now()),
         };
+        Ok(status)
+    }
+}
 
-        let node = CincinnatiPayload {
-            version: release.version.clone(),
-            payload: checksum,
-            metadata: hashmap! {
-                "org.fedoraproject.coreos.scheme".to_string() => "checksum".to_string(),
-                "org.fedoraproject.coreos.releases.age_index".to_string() => "1".to_string(),
-            },
+pub(crate) struct GetSnapshot {}
+
+impl Message for GetSnapshot {
+    type Result = Result<ScraperSnapshot, Error>;
+}
+
+/// Scraper state, for fixture export.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "admin-api"), allow(dead_code))]
+pub(crate) struct ScraperSnapshot {
+    pub(crate) frozen: bool,
+    pub(crate) streams: BTreeSet<String>,
+    pub(crate) priority_streams: BTreeSet<String>,
+    pub(crate) releases: BTreeMap<String, Vec<metadata::Release>>,
+}
+
+impl Handler<GetSnapshot> for Scraper {
+    type Result = Result<ScraperSnapshot, Error>;
+    fn handle(&mut self, _msg: GetSnapshot, _ctx: &mut Self::Context) -> Self::Result {
+        let _timer = sample_handler::<GetSnapshot>();
+        let releases = self
+            .core
+            .cached()
+            .map(|(stream, index)| (stream.clone(), index.as_ref().clone()))
+            .collect();
+        let snapshot = ScraperSnapshot {
+            frozen: self.core.is_frozen(),
+            streams: self.core.streams().clone(),
+            priority_streams: self.core.priority_streams().clone(),
+            releases,
         };
+        Ok(snapshot)
+    }
+}
+
+/// Actor timers, driving the scraping core from the actix runtime.
+pub(crate) struct Timers<'a>(pub(crate) &'a mut Context<Scraper>);
 
-        return Box::new(actix::fut::ok(node));
+impl Scheduler for Timers<'_> {
+    type Handle = SpawnHandle;
+
+    fn schedule(&mut self, scope: RefreshScope, after: Duration) -> SpawnHandle {
+        self.0
+            .notify_later(RefreshTick { scope, trace: None }, after)
+    }
+
+    fn cancel(&mut self, handle: SpawnHandle) {
+        self.0.cancel_future(handle);
     }
 }
 
-impl Scraper {
-    /// Schedule an immediate refresh the state machine.
-    pub fn tick_now(ctx: &mut Context<Self>) {
-        ctx.notify(RefreshTick {})
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn release(version: &str, commits: &[(&str, &str)]) -> metadata::Release {
+        metadata::Release {
+            commits: commits
+                .iter()
+                .map(|(arch, checksum)| metadata::ReleaseCommit {
+                    architecture: arch.to_string(),
+                    checksum: checksum.to_string(),
+                    size: None,
+                })
+                .collect(),
+            version: version.to_string(),
+            metadata: String::new(),
+        }
+    }
+
+    /// Merge refresh results, as a new refresh of all streams.
+    fn update_cache(
+        scraper: &mut Scraper,
+        refreshed: Vec<(String, Fallible<Vec<metadata::Release>>)>,
+    ) -> bool {
+        let refresh = scraper
+            .core
+            .begin_refresh(RefreshScope::All, Instant::now());
+        scraper.update_cache(&refresh, refreshed)
+    }
+
+    /// Latest cached node of a stream, for x86_64.
+    fn latest(scraper: &Scraper, stream: &str) -> CincinnatiPayload {
+        let latest = scraper
+            .core
+            .latest(stream, "x86_64", None, None, Instant::now());
+        latest.unwrap().unwrap()
+    }
+
+    #[test]
+    fn refresh_results_are_merged_per_stream() {
+        let streams = btreeset!["merge-a".to_string(), "merge-b".to_string()];
+        let mut scraper = Scraper::new(
+            streams,
+            Duration::from_secs(30),
+            retry::RetryPolicy::default(),
+        )
+        .unwrap();
+        update_cache(
+            &mut scraper,
+            vec![
+                (
+                    "merge-a".to_string(),
+                    Ok(vec![release("1", &[("x86_64", "a1")])]),
+                ),
+                (
+                    "merge-b".to_string(),
+                    Ok(vec![release("1", &[("x86_64", "b1")])]),
+                ),
+            ],
+        );
+        update_cache(
+            &mut scraper,
+            vec![
+                ("merge-a".to_string(), Err(failure::format_err!("boom"))),
+                ("merge-b".to_string(), Ok(vec![])),
+            ],
+        );
+
+        // Failed streams keep serving their previous release.
+        let node = &latest(&scraper, "merge-a");
+        assert_eq!(node.payload, "a1");
+        assert_eq!(STREAM_ERRORS.with_label_values(&["merge-a"]).get(), 1);
+        assert_eq!(STREAM_EMPTY.with_label_values(&["merge-a"]).get(), 0);
+
+        // Empty streams are healthy and keep their releases, but only serve
+        // the client node.
+        assert_eq!(scraper.core.releases("merge-b").unwrap().len(), 1);
+        let latest = scraper
+            .core
+            .latest("merge-b", "x86_64", None, None, Instant::now());
+        assert_eq!(latest, Ok(None));
+        assert_eq!(STREAM_ERRORS.with_label_values(&["merge-b"]).get(), 0);
+        assert_eq!(STREAM_EMPTY.with_label_values(&["merge-b"]).get(), 1);
+    }
+
+    #[test]
+    fn frozen_cache_ignores_refreshes() {
+        let streams = btreeset!["frozen".to_string()];
+        let mut scraper = Scraper::new(
+            streams,
+            Duration::from_secs(30),
+            retry::RetryPolicy::default(),
+        )
+        .unwrap();
+        let first = vec![(
+            "frozen".to_string(),
+            Ok(vec![release("1", &[("x86_64", "f1")])]),
+        )];
+        assert!(update_cache(&mut scraper, first));
+
+        scraper.core.set_frozen(true);
+        let second = vec![(
+            "frozen".to_string(),
+            Ok(vec![release("2", &[("x86_64", "f2")])]),
+        )];
+        assert!(!update_cache(&mut scraper, second));
+        let node = &latest(&scraper, "frozen");
+        assert_eq!(node.payload, "f1");
     }
 
-    /// Schedule a delayed refresh of the state machine.
-    pub fn tick_later(ctx: &mut Context<Self>, after: std::time::Duration) -> actix::SpawnHandle {
-        ctx.notify_later(RefreshTick {}, after)
+    #[test]
+    fn readiness_waits_for_every_stream() {
+        let (notifier, manager, path) = sdnotify::tests::notifier_pair("scraper");
+        let streams = btreeset!["notify-a".to_string(), "notify-b".to_string()];
+        let mut scraper = Scraper::new(
+            streams,
+            Duration::from_secs(30),
+            retry::RetryPolicy::default(),
+        )
+        .unwrap()
+        .with_notifier(Some(notifier));
+        update_cache(
+            &mut scraper,
+            vec![("notify-a".to_string(), Err(failure::format_err!("boom")))],
+        );
+        assert_eq!(
+            sdnotify::tests::recv(&manager).unwrap(),
+            "STATUS=waiting for streams: notify-a, notify-b\n"
+        );
+
+        let populated = vec![(
+            "notify-a".to_string(),
+            Ok(vec![release("2", &[("x86_64", "a2")])]),
+        )];
+        update_cache(&mut scraper, populated);
+        assert_eq!(
+            sdnotify::tests::recv(&manager).unwrap(),
+            "STATUS=waiting for streams: notify-b\n"
+        );
+
+        update_cache(&mut scraper, vec![("notify-b".to_string(), Ok(vec![]))]);
+        assert_eq!(
+            sdnotify::tests::recv(&manager).unwrap(),
+            "READY=1\nSTATUS=serving notify-a 2\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn partial_refreshes_are_reported() {
+        let streams = btreeset!["partial-a".to_string(), "partial-b".to_string()];
+        let mut scraper = Scraper::new(
+            streams,
+            Duration::from_secs(30),
+            retry::RetryPolicy::default(),
+        )
+        .unwrap();
+        let refreshed = vec![
+            ("partial-a".to_string(), Ok(vec![])),
+            ("partial-b".to_string(), Err(failure::format_err!("boom"))),
+        ];
+        assert!(!update_cache(&mut scraper, refreshed));
+        assert!(scraper.core.releases("partial-a").is_some());
+        assert!(scraper.core.releases("partial-b").is_none());
+    }
+
+    #[test]
+    fn changes_between_scrapes_are_recorded() {
+        let streams = btreeset!["changes".to_string()];
+        let mut scraper =
+            Scraper::new(streams, Duration::from_secs(30), Default::default()).unwrap();
+        let r1 = release("30.1", &[("x86_64", "x1")]);
+        let r2 = release("30.2", &[("x86_64", "x2")]);
+        update_cache(
+            &mut scraper,
+            vec![("changes".to_string(), Ok(vec![r1.clone()]))],
+        );
+        assert!(scraper.changelog.is_empty());
+
+        update_cache(
+            &mut scraper,
+            vec![("changes".to_string(), Ok(vec![r2, r1]))],
+        );
+        assert_eq!(scraper.changelog.len(), 1);
+        let entry = &scraper.changelog[0];
+        assert_eq!(entry.stream, "changes");
+        assert_eq!(
+            entry.change,
+            diff::ReleaseChange::Added {
+                version: "30.2".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn failing_streams_wait_for_retry() {
+        let streams = btreeset!["retry-a".to_string(), "retry-b".to_string()];
+        let policy = retry::RetryPolicy::new(vec!["403=give-up:1h".parse().unwrap()]);
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), policy).unwrap();
+        let forbidden = retry::UpstreamError::from_response(403, None);
+        update_cache(
+            &mut scraper,
+            vec![
+                ("retry-a".to_string(), Err(forbidden.into())),
+                ("retry-b".to_string(), Err(failure::format_err!("timeout"))),
+            ],
+        );
+
+        let now = Instant::now();
+        let due = |after| scraper.core.scope_streams(&RefreshScope::All, now + after);
+        assert_eq!(due(Duration::from_secs(0)), vec!["retry-b"]);
+        assert_eq!(due(Duration::from_secs(3700)), vec!["retry-a", "retry-b"]);
+    }
+
+    #[test]
+    fn releases_urls_are_templated() {
+        let url = releases_url(None, "testing-devel".to_string()).unwrap();
+        let expected = metadata::RELEASES_JSON.replace("${stream}", "testing-devel");
+        assert_eq!(url.as_str(), expected);
+        let template = Some("http://mirror.example.com/${stream}/releases.json");
+        let url = releases_url(template, "next".to_string()).unwrap();
+        assert_eq!(url.as_str(), "http://mirror.example.com/next/releases.json");
+    }
+
+    #[test]
+    fn rollouts_annotate_target_nodes() {
+        let mut scraper = Scraper::new(btreeset![], Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_rollouts(true);
+        let rollout = metadata::UpdateRollout {
+            version: "2".to_string(),
+            start_epoch: "1600000000".to_string(),
+            start_value: "0.5".to_string(),
+            duration_minutes: None,
+        };
+        let stale = scraper
+            .core
+            .begin_refresh(RefreshScope::All, Instant::now());
+        let first = scraper
+            .core
+            .begin_refresh(RefreshScope::All, Instant::now());
+        let second = scraper
+            .core
+            .begin_refresh(RefreshScope::All, Instant::now());
+        let rollouts = vec![("rolling".to_string(), Ok(vec![rollout]))];
+        scraper.update_rollouts(&first, rollouts);
+        // Failures keep previous rollouts.
+        let failed = vec![("rolling".to_string(), Err(failure::format_err!("timeout")))];
+        scraper.update_rollouts(&second, failed);
+        // Refreshes overtaken by a later one are dropped.
+        scraper.update_rollouts(&stale, vec![("rolling".to_string(), Ok(vec![]))]);
+
+        let mut target =
+            engine::release_node(1, &release("2", &[("x86_64", "r2")]), "x86_64").unwrap();
+        scraper.annotate_rollout("rolling", &mut target);
+        assert_eq!(target.metadata[metadata::START_EPOCH], "1600000000");
+        assert_eq!(target.metadata[metadata::START_VALUE], "0.5");
+        assert!(!target.metadata.contains_key(metadata::DURATION));
+
+        let mut other =
+            engine::release_node(0, &release("1", &[("x86_64", "r1")]), "x86_64").unwrap();
+        scraper.annotate_rollout("rolling", &mut other);
+        assert!(!other.metadata.contains_key(metadata::START_EPOCH));
+    }
+
+    #[test]
+    fn scheduled_streams_are_refreshed_apart() {
+        let streams = btreeset!["regular".to_string(), "scheduled".to_string()];
+        let intervals = btreemap!["scheduled".to_string() => Duration::from_secs(600)];
+        let scraper = Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_stream_refresh_intervals(intervals);
+        assert_eq!(
+            scraper
+                .core
+                .scope_streams(&RefreshScope::All, Instant::now()),
+            vec!["regular"]
+        );
+        let scheduled = RefreshScope::Scheduled("scheduled".to_string());
+        assert_eq!(
+            scraper.core.scope_streams(&scheduled, Instant::now()),
+            vec!["scheduled"]
+        );
+        assert_eq!(
+            scraper.core.stream_refresh_pause("scheduled"),
+            Duration::from_secs(600)
+        );
+        assert_eq!(
+            scraper.core.stream_refresh_pause("regular"),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn streams_with_pending_retries_are_skipped() {
+        let streams = btreeset!["ready".to_string(), "backoff".to_string()];
+        let policy = retry::RetryPolicy::new(vec!["403=give-up:1h".parse().unwrap()]);
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), policy).unwrap();
+        let forbidden = retry::UpstreamError::from_response(403, None);
+        update_cache(
+            &mut scraper,
+            vec![("backoff".to_string(), Err(forbidden.into()))],
+        );
+        assert_eq!(
+            scraper
+                .core
+                .scope_streams(&RefreshScope::All, Instant::now()),
+            vec!["ready"]
+        );
+    }
+
+    #[test]
+    fn missing_streams_are_served_from_snapshots() {
+        let dir = std::env::temp_dir().join(format!("fakeup-stale-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let snapshots = snapshot::SnapshotDir::new(dir.clone());
+        snapshots
+            .save("vanished", &[release("1", &[("x86_64", "v1")])])
+            .unwrap();
+        let not_found = || retry::UpstreamError::from_response(404, None).into();
+
+        let streams = btreeset!["vanished".to_string()];
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_lazy_streams(true)
+            .with_snapshots(Some(snapshots));
+        update_cache(
+            &mut scraper,
+            vec![("vanished".to_string(), Err(not_found()))],
+        );
+        assert!(scraper.core.stale_streams().contains("vanished"));
+
+        let mut sys = actix::System::new("stale-streams");
+        let addr = scraper.start();
+        let latest = GetLatest::new("x86_64".to_string(), "vanished".to_string());
+        let node = sys.block_on(request(&addr, latest)).unwrap().unwrap();
+        assert_eq!(node.payload, "v1");
+        assert_eq!(node.metadata[metadata::STALE], "true");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_streams_are_dropped_if_strict() {
+        let streams = btreeset!["dropped".to_string()];
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_missing_stream(snapshot::MissingStream::Strict);
+        update_cache(
+            &mut scraper,
+            vec![(
+                "dropped".to_string(),
+                Ok(vec![release("1", &[("x86_64", "d1")])]),
+            )],
+        );
+        let not_found = retry::UpstreamError::from_response(404, None);
+        update_cache(
+            &mut scraper,
+            vec![("dropped".to_string(), Err(not_found.into()))],
+        );
+        assert!(scraper.core.releases("dropped").is_none());
+        assert!(scraper.core.lookup("dropped", "x86_64", "d1").is_none());
+        assert!(scraper.core.stale_streams().is_empty());
+    }
+
+    #[test]
+    fn architectures_are_served_independently() {
+        let mut scraper =
+            Scraper::new(btreeset![], Duration::from_secs(30), Default::default()).unwrap();
+        let releases = vec![
+            release("1", &[("x86_64", "x1"), ("aarch64", "a1")]),
+            release("2", &[("x86_64", "x2")]),
+        ];
+        update_cache(&mut scraper, vec![("arches".to_string(), Ok(releases))]);
+        let age_index = STREAM_ARCH_LATEST.with_label_values(&["arches", "aarch64"]);
+        assert_eq!(age_index.get(), 0);
+
+        let mut sys = actix::System::new("independent-arches");
+        let addr = scraper.start();
+        let latest = |basearch: &str| GetLatest::new(basearch.to_string(), "arches".to_string());
+        let node = sys.block_on(addr.send(latest("aarch64"))).unwrap().unwrap();
+        assert_eq!(node.unwrap().payload, "a1");
+        let node = sys.block_on(addr.send(latest("x86_64"))).unwrap().unwrap();
+        assert_eq!(node.unwrap().payload, "x2");
+        assert!(sys.block_on(addr.send(latest("s390x"))).unwrap().is_err());
+    }
+
+    #[test]
+    fn reconfigured_streams_replace_cached_ones() {
+        let streams = btreeset!["kept".to_string(), "dropped".to_string()];
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_lazy_streams(true);
+        update_cache(
+            &mut scraper,
+            vec![
+                (
+                    "kept".to_string(),
+                    Ok(vec![release("1", &[("x86_64", "k1")])]),
+                ),
+                (
+                    "dropped".to_string(),
+                    Ok(vec![release("1", &[("x86_64", "d1")])]),
+                ),
+            ],
+        );
+
+        let mut sys = actix::System::new("reconfigure");
+        let addr = scraper.start();
+        let msg = Reconfigure {
+            streams: btreeset!["kept".to_string(), "added".to_string()],
+            refresh_pause: Duration::from_secs(60),
+            stream_refresh: BTreeMap::new(),
+        };
+        sys.block_on(addr.send(msg)).unwrap();
+        let status = sys.block_on(request(&addr, GetStatus {})).unwrap();
+        let streams: Vec<_> = status.streams.keys().map(String::as_str).collect();
+        assert_eq!(streams, vec!["added", "kept"]);
+
+        let latest = |stream: &str| GetLatest::new("x86_64".to_string(), stream.to_string());
+        let node = sys.block_on(request(&addr, latest("kept"))).unwrap();
+        assert_eq!(node.unwrap().payload, "k1");
+        assert!(sys.block_on(request(&addr, latest("dropped"))).is_err());
+    }
+
+    #[test]
+    fn hop_policies_apply_per_stream() {
+        let streams = btreeset!["hop-a".to_string(), "hop-b".to_string()];
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_hop_policies(hashmap! {
+                "hop-a".to_string() => engine::HopPolicy::Stepwise(1),
+            });
+        let releases = |stream: &str| {
+            let releases = (1..=3)
+                .map(|i| release(&i.to_string(), &[("x86_64", &format!("c{}", i))]))
+                .collect();
+            (stream.to_string(), Ok(releases))
+        };
+        update_cache(&mut scraper, vec![releases("hop-a"), releases("hop-b")]);
+
+        let mut sys = actix::System::new("hop-policies");
+        let addr = scraper.start();
+        let target = |stream: &str, current: Option<&str>| {
+            let mut msg = GetLatest::new("x86_64".to_string(), stream.to_string());
+            if let Some(current) = current {
+                msg = msg.with_current(current.to_string());
+            }
+            msg
+        };
+        let payload = |node: Option<CincinnatiPayload>| node.unwrap().payload;
+        let node = sys.block_on(request(&addr, target("hop-a", Some("c1"))));
+        assert_eq!(payload(node.unwrap()), "c2");
+        let node = sys.block_on(request(&addr, target("hop-a", None)));
+        assert_eq!(payload(node.unwrap()), "c3");
+        let node = sys.block_on(request(&addr, target("hop-b", Some("c1"))));
+        assert_eq!(payload(node.unwrap()), "c3");
+    }
+
+    #[test]
+    fn pending_streams_wait_for_first_refresh() {
+        let streams = btreeset![
+            "ready-a".to_string(),
+            "ready-b".to_string(),
+            "ready-lazy".to_string()
+        ];
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_lazy_streams(true);
+        scraper.core.activate_stream("ready-a");
+        scraper.core.activate_stream("ready-b");
+        update_cache(
+            &mut scraper,
+            vec![
+                ("ready-a".to_string(), Ok(vec![])),
+                ("ready-b".to_string(), Err(failure::format_err!("boom"))),
+            ],
+        );
+
+        let mut sys = actix::System::new("pending-streams");
+        let addr = scraper.start();
+        let pending = sys.block_on(request(&addr, PendingStreams)).unwrap();
+        assert_eq!(pending, vec!["ready-b".to_string()]);
+
+        // Freezing does not make unpopulated streams ready.
+        sys.block_on(request(&addr, SetFrozen { frozen: true }))
+            .unwrap();
+        let pending = sys.block_on(request(&addr, PendingStreams)).unwrap();
+        assert_eq!(pending, vec!["ready-b".to_string()]);
+    }
+
+    #[test]
+    fn uncached_and_unknown_streams_are_told_apart() {
+        let streams = btreeset!["cold".to_string()];
+        let scraper = Scraper::new(streams, Duration::from_secs(30), Default::default()).unwrap();
+
+        let mut sys = actix::System::new("graph-errors");
+        let addr = scraper.start();
+        let latest = |stream: &str| GetLatest::new("x86_64".to_string(), stream.to_string());
+        let err = sys.block_on(request(&addr, latest("cold"))).unwrap_err();
+        match err.downcast_ref::<errors::GraphError>() {
+            Some(errors::GraphError::EmptyCache(stream)) => assert_eq!(stream, "cold"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+        let err = sys.block_on(request(&addr, latest("warm"))).unwrap_err();
+        match err.downcast_ref::<errors::GraphError>() {
+            Some(errors::GraphError::UnknownStream(stream)) => assert_eq!(stream, "warm"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refresh_times_follow_successful_refreshes() {
+        let streams = btreeset!["timed-a".to_string(), "timed-b".to_string()];
+        let mut scraper =
+            Scraper::new(streams, Duration::from_secs(30), Default::default()).unwrap();
+        let before = clock::now();
+        update_cache(
+            &mut scraper,
+            vec![
+                ("timed-a".to_string(), Ok(vec![])),
+                ("timed-b".to_string(), Err(failure::format_err!("boom"))),
+            ],
+        );
+
+        let mut sys = actix::System::new("refresh-times");
+        let addr = scraper.start();
+        let refreshed = |stream: &str| GetRefreshTime {
+            stream: stream.to_string(),
+        };
+        let time = sys.block_on(request(&addr, refreshed("timed-a"))).unwrap();
+        assert!(time.unwrap() >= before);
+        let time = sys.block_on(request(&addr, refreshed("timed-b"))).unwrap();
+        assert_eq!(time, None);
+    }
+
+    #[test]
+    fn shutdown_wakes_long_polls() {
+        let streams = btreeset!["stopping".to_string()];
+        let scraper = Scraper::new(streams, Duration::from_secs(30), Default::default()).unwrap();
+        let mut sys = actix::System::new("shutdown");
+        let addr = scraper.start();
+        let timeout = Duration::from_secs(60);
+        let started = Instant::now();
+        let waiting = wait_for_change(&addr, "stopping".to_string(), timeout);
+        let (changed, _) = sys
+            .block_on(waiting.join(request(&addr, Shutdown)))
+            .unwrap();
+        assert!(changed);
+
+        // Later requests do not wait at all.
+        let waiting = wait_for_change(&addr, "stopping".to_string(), timeout);
+        assert!(sys.block_on(waiting).unwrap());
+        assert!(started.elapsed() < timeout);
+    }
+
+    #[test]
+    fn fixtures_dir_is_reread_on_refresh() {
+        let dir = std::env::temp_dir().join(format!("fakeup-fixtures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("releases-stable.json");
+        let index = |version: &str| {
+            format!(
+                r#"{{"releases":[{{"version":"{}","metadata":"","commits":[{{"architecture":"x86_64","checksum":"c{}"}}]}}]}}"#,
+                version, version
+            )
+        };
+        let scraper = Scraper::new(
+            btreeset!["stable".to_string()],
+            Duration::from_secs(30),
+            Default::default(),
+        )
+        .unwrap()
+        .with_fixtures_dir(Some(dir.clone()));
+
+        std::fs::write(&path, index("1")).unwrap();
+        let releases = scraper.fetch_releases("stable", None).wait().unwrap();
+        assert_eq!(releases[0].version, "1");
+        std::fs::write(&path, index("2")).unwrap();
+        let releases = scraper.fetch_releases("stable", None).wait().unwrap();
+        assert_eq!(releases[0].version, "2");
+
+        let err = scraper.fetch_releases("next", None).wait().unwrap_err();
+        assert!(err.to_string().contains("releases-next.json"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn uncached_arches_are_dropped() {
+        let dir = std::env::temp_dir().join(format!("fakeup-arches-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let index = r#"{"releases":[{"version":"1","metadata":"","commits":[
+            {"architecture":"x86_64","checksum":"x1"},
+            {"architecture":"aarch64","checksum":"a1"},
+            {"architecture":"s390x","checksum":"s1"}
+        ]}]}"#;
+        std::fs::write(dir.join("releases-stable.json"), index).unwrap();
+        let scraper = |arches| {
+            Scraper::new(
+                btreeset!["stable".to_string()],
+                Duration::from_secs(30),
+                Default::default(),
+            )
+            .unwrap()
+            .with_fixtures_dir(Some(dir.clone()))
+            .with_arches(arches)
+        };
+        let cached_arches = |scraper: Scraper| -> Vec<String> {
+            let releases = scraper.fetch_releases("stable", None).wait().unwrap();
+            releases[0]
+                .commits
+                .iter()
+                .map(|c| c.architecture.clone())
+                .collect()
+        };
+
+        let arches = btreeset!["x86_64".to_string(), "s390x".to_string()];
+        assert_eq!(cached_arches(scraper(arches)), vec!["x86_64", "s390x"]);
+        assert_eq!(cached_arches(scraper(BTreeSet::new())).len(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn old_releases_are_dead_ended() {
+        let streams = btreeset!["stable".to_string()];
+        let policy = lifecycle::DeadendPolicy::new(&[], &[(None, 1)]);
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_deadend_policy(policy);
+        update_cache(
+            &mut scraper,
+            vec![(
+                "stable".to_string(),
+                Ok(vec![
+                    release("1", &[("x86_64", "c1")]),
+                    release("2", &[("x86_64", "c2")]),
+                    release("3", &[("x86_64", "c3")]),
+                ]),
+            )],
+        );
+
+        let mut sys = actix::System::new("deadend");
+        let addr = scraper.start();
+        let lookup = |checksum: &str| LookupNode {
+            basearch: "x86_64".to_string(),
+            stream: "stable".to_string(),
+            checksum: checksum.to_string(),
+        };
+        let oldest = sys.block_on(request(&addr, lookup("c1"))).unwrap().unwrap();
+        assert_eq!(oldest.metadata[metadata::DEADEND], "true");
+        assert!(oldest.metadata[metadata::DEADEND_REASON].contains("2 releases behind"));
+        let previous = sys.block_on(request(&addr, lookup("c2"))).unwrap().unwrap();
+        assert!(!previous.metadata.contains_key(metadata::DEADEND));
+
+        let latest = GetLatest::new("x86_64".to_string(), "stable".to_string());
+        let latest = sys.block_on(request(&addr, latest)).unwrap().unwrap();
+        assert!(!latest.metadata.contains_key(metadata::DEADEND));
+    }
+
+    #[test]
+    fn concurrent_fetches_are_limited() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        // Each fetch yields once, so that queued fetches overlap it.
+        let peak = |limit: usize| {
+            let inflight = Rc::new(Cell::new(0));
+            let peak = Rc::new(Cell::new(0));
+            let fetches: Vec<_> = (0..5)
+                .map(|n| {
+                    let (inflight, peak) = (inflight.clone(), peak.clone());
+                    let mut started = false;
+                    future::poll_fn(move || -> Poll<usize, ()> {
+                        if started {
+                            inflight.set(inflight.get() - 1);
+                            return Ok(Async::Ready(n));
+                        }
+                        started = true;
+                        inflight.set(inflight.get() + 1);
+                        peak.set(peak.get().max(inflight.get()));
+                        futures::task::current().notify();
+                        Ok(Async::NotReady)
+                    })
+                })
+                .collect();
+            let scraper =
+                Scraper::new(BTreeSet::new(), Duration::from_secs(30), Default::default())
+                    .unwrap()
+                    .with_fetch_concurrency(limit);
+            let done = scraper.buffered(fetches).wait().unwrap();
+            assert_eq!(done, vec![0, 1, 2, 3, 4]);
+            peak.get()
+        };
+        assert_eq!(peak(2), 2);
+        assert_eq!(peak(0), 5);
+    }
+
+    #[test]
+    fn discovered_streams_are_filtered() {
+        let streams = btreeset!["stable".to_string()];
+        let discovery = StreamDiscovery {
+            url: url::Url::parse("http://localhost/streams.json").unwrap(),
+            allow: Some(regex::Regex::new("^(stable|testing|next)").unwrap()),
+            deny: Some(regex::Regex::new("-devel$").unwrap()),
+        };
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_discovery(discovery);
+        let found = vec!["stable", "testing", "next-devel", "rawhide"];
+        scraper.add_discovered(found.into_iter().map(String::from).collect());
+
+        let expected = btreeset!["stable".to_string(), "testing".to_string()];
+        assert_eq!(scraper.core.streams(), &expected);
+    }
+
+    #[test]
+    fn lazy_streams_are_activated_once() {
+        let streams = btreeset!["lazy-a".to_string(), "lazy-b".to_string()];
+        let mut scraper = Scraper::new(streams, Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_lazy_streams(true);
+        assert!(scraper.core.activate_stream("lazy-a"));
+        assert!(!scraper.core.activate_stream("lazy-a"));
+        assert!(!scraper.core.activate_stream("unknown"));
+        assert_eq!(scraper.core.pending_streams(), vec!["lazy-a".to_string()]);
+
+        let mut eager = Scraper::new(
+            btreeset!["eager".to_string()],
+            Duration::from_secs(30),
+            Default::default(),
+        )
+        .unwrap();
+        assert!(!eager.core.activate_stream("eager"));
+    }
+
+    #[test]
+    fn imported_releases_are_served_frozen() {
+        let imported = btreemap! {
+            "imported".to_string() => vec![release("1", &[("x86_64", "i1")])],
+        };
+        let mut scraper = Scraper::new(btreeset![], Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_imported(imported);
+        assert!(scraper.core.is_frozen());
+        assert!(scraper.core.streams().contains("imported"));
+        let node = latest(&scraper, "imported");
+        assert_eq!(node.payload, "i1");
+
+        let refreshed = vec![("imported".to_string(), Ok(vec![]))];
+        assert!(!update_cache(&mut scraper, refreshed));
+        assert!(!scraper.core.releases("imported").unwrap().is_empty());
+    }
+
+    #[test]
+    fn gated_releases_are_withheld_until_approved() {
+        let gate = engine::ReleaseGate::new(None);
+        let mut scraper = Scraper::new(btreeset![], Duration::from_secs(30), Default::default())
+            .unwrap()
+            .with_release_gate(gate);
+        let r1 = release("1", &[("x86_64", "g1")]);
+        let r2 = release("2", &[("x86_64", "g2")]);
+        update_cache(
+            &mut scraper,
+            vec![("gated".to_string(), Ok(vec![r1.clone()]))],
+        );
+        update_cache(&mut scraper, vec![("gated".to_string(), Ok(vec![r1, r2]))]);
+        let withheld = scraper.core.withheld(Instant::now());
+        assert_eq!(withheld, btreeset!["2".to_string()]);
+
+        let mut sys = actix::System::new("gated-releases");
+        let addr = scraper.start();
+        let latest = || GetLatest::new("x86_64".to_string(), "gated".to_string());
+        let node = sys.block_on(addr.send(latest())).unwrap().unwrap();
+        assert_eq!(node.unwrap().payload, "g1");
+
+        let approve = ApproveRelease {
+            version: "2".to_string(),
+        };
+        assert!(sys.block_on(addr.send(approve)).unwrap().unwrap());
+        let node = sys.block_on(addr.send(latest())).unwrap().unwrap();
+        assert_eq!(node.unwrap().payload, "g2");
+    }
+
+    #[test]
+    fn watchers_are_notified_on_changes() {
+        let streams = btreeset!["watched".to_string()];
+        let mut scraper =
+            Scraper::new(streams, Duration::from_secs(30), Default::default()).unwrap();
+        let r1 = release("1", &[("x86_64", "w1")]);
+        let r2 = release("2", &[("x86_64", "w2")]);
+        update_cache(
+            &mut scraper,
+            vec![("watched".to_string(), Ok(vec![r1.clone()]))],
+        );
+
+        let (tx, mut rx) = oneshot::channel();
+        scraper
+            .watchers
+            .entry("watched".to_string())
+            .or_default()
+            .push(tx);
+        update_cache(
+            &mut scraper,
+            vec![("watched".to_string(), Ok(vec![r1.clone()]))],
+        );
+        assert_eq!(rx.try_recv(), Ok(None));
+        update_cache(
+            &mut scraper,
+            vec![("watched".to_string(), Ok(vec![r1, r2]))],
+        );
+        assert_eq!(rx.try_recv(), Ok(Some(())));
+        assert!(scraper.watchers.is_empty());
+    }
+
+    #[test]
+    fn waits_for_unchanged_streams_time_out() {
+        let mut sys = actix::System::new("wait-timeout");
+        let scraper =
+            Scraper::new(btreeset![], Duration::from_secs(30), Default::default()).unwrap();
+        let addr = scraper.start();
+        let waited = wait_for_change(&addr, "idle".to_string(), Duration::from_millis(10));
+        assert!(!sys.block_on(waited).unwrap());
+    }
+
+    #[test]
+    fn delayed_releases_are_held_back() {
+        let mut scraper =
+            Scraper::new(btreeset![], Duration::from_secs(30), Default::default()).unwrap();
+        let r1 = release("1", &[("x86_64", "d1")]);
+        let r2 = release("2", &[("x86_64", "d2")]);
+        update_cache(
+            &mut scraper,
+            vec![("delayed".to_string(), Ok(vec![r1.clone()]))],
+        );
+        update_cache(
+            &mut scraper,
+            vec![("delayed".to_string(), Ok(vec![r1, r2]))],
+        );
+        assert!(scraper.core.first_seen("1").is_none());
+        assert!(scraper.core.first_seen("2").is_some());
+
+        let mut sys = actix::System::new("delayed-releases");
+        let addr = scraper.start();
+        let latest =
+            |delay| GetLatest::new("x86_64".to_string(), "delayed".to_string()).with_delay(delay);
+        let node = sys.block_on(addr.send(latest(None))).unwrap().unwrap();
+        assert_eq!(node.unwrap().payload, "d2");
+        let delay = Some(Duration::from_secs(3600));
+        let node = sys.block_on(addr.send(latest(delay))).unwrap().unwrap();
+        assert_eq!(node.unwrap().payload, "d1");
+        let delay = Some(Duration::from_secs(0));
+        let node = sys.block_on(addr.send(latest(delay))).unwrap().unwrap();
+        assert_eq!(node.unwrap().payload, "d2");
+    }
+
+    #[test]
+    fn requests_are_instrumented() {
+        assert_eq!(message_name::<GetLatest>(), "GetLatest");
+
+        let mut sys = actix::System::new("instrumented-requests");
+        let scraper =
+            Scraper::new(btreeset![], Duration::from_secs(30), Default::default()).unwrap();
+        let addr = scraper.start();
+        use prometheus::core::Metric;
+        let samples = || {
+            let latency = MESSAGE_LATENCY.with_label_values(&["GetChangelog"]);
+            latency.metric().get_histogram().get_sample_count()
+        };
+        let before = samples();
+        let msg = GetChangelog { stream: None };
+        assert!(sys.block_on(request(&addr, msg)).unwrap().is_empty());
+        assert_eq!(samples(), before + 1);
+    }
+
+    #[test]
+    fn feeds_list_added_releases() {
+        let streams = btreeset!["fed".to_string()];
+        let scraper = Scraper::new(streams, Duration::from_secs(30), Default::default());
+        let mut scraper = scraper.unwrap();
+        let r1 = release("1", &[("x86_64", "f1")]);
+        let r2 = release("2", &[("x86_64", "f2")]);
+        update_cache(
+            &mut scraper,
+            vec![("fed".to_string(), Ok(vec![r1.clone()]))],
+        );
+        update_cache(&mut scraper, vec![("fed".to_string(), Ok(vec![r1, r2]))]);
+
+        let mut sys = actix::System::new("release-feeds");
+        let addr = scraper.start();
+        let feed = |stream: &str| GetReleaseFeed {
+            stream: stream.to_string(),
+        };
+        let entries = sys.block_on(addr.send(feed("fed"))).unwrap().unwrap();
+        let entries = entries.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].version, "2");
+        assert_eq!(entries[0].commits[0].checksum, "f2");
+        let unknown = sys.block_on(addr.send(feed("unknown"))).unwrap().unwrap();
+        assert!(unknown.is_none());
+    }
+
+    /// Upstream which never answers, so that refreshes never complete.
+    #[derive(Debug)]
+    struct StalledFetcher;
+
+    impl fetcher::HttpFetcher for StalledFetcher {
+        fn get(&self, _url: &url::Url, _headers: Vec<(String, String)>) -> fetcher::FetchFuture {
+            Box::new(future::empty())
+        }
+    }
+
+    #[test]
+    fn stuck_scrapers_are_restarted() {
+        let mut sys = actix::System::new("watchdog");
+        let streams = btreeset!["stuck".to_string()];
+        let mut scraper = Scraper::new(streams, Duration::from_millis(50), Default::default())
+            .unwrap()
+            .with_watchdog(1);
+        scraper.fetcher = Arc::new(StalledFetcher);
+        update_cache(
+            &mut scraper,
+            vec![(
+                "stuck".to_string(),
+                Ok(vec![release("1", &[("x86_64", "kept")])]),
+            )],
+        );
+
+        let restarts = WATCHDOG_RESTARTS.get();
+        let addr = actix::Supervisor::start(move |_| scraper);
+        let later = tokio_timer::Delay::new(Instant::now() + Duration::from_millis(300));
+        let query = later.from_err().and_then(move |_| {
+            let msg = GetLatest::new("x86_64".to_string(), "stuck".to_string());
+            request(&addr, msg)
+        });
+        let node = sys.block_on(query).unwrap().unwrap();
+        assert_eq!(node.payload, "kept");
+        assert!(WATCHDOG_RESTARTS.get() > restarts);
+    }
+
+    #[test]
+    fn queries_are_served_during_refresh() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let sys = actix::System::new("queries-during-refresh");
+            let streams = btreeset!["in-flight".to_string()];
+            let mut scraper = Scraper::new(
+                streams,
+                Duration::from_secs(30),
+                retry::RetryPolicy::default(),
+            )
+            .unwrap();
+            scraper.fetcher = Arc::new(StalledFetcher);
+            update_cache(
+                &mut scraper,
+                vec![(
+                    "in-flight".to_string(),
+                    Ok(vec![release("1", &[("x86_64", "cached")])]),
+                )],
+            );
+            let addr = scraper.start();
+            let query = addr
+                .send(GetLatest::new(
+                    "x86_64".to_string(),
+                    "in-flight".to_string(),
+                ))
+                .flatten()
+                .then(move |res| {
+                    tx.send(res.map_err(|e| e.to_string())).unwrap();
+                    actix::System::current().stop();
+                    Ok(())
+                });
+            actix::Arbiter::spawn(query);
+            sys.run();
+        });
+
+        let node = rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("query blocked by an in-flight refresh")
+            .unwrap()
+            .unwrap();
+        assert_eq!(node.payload, "cached");
+    }
+
+    #[test]
+    fn fixtures_can_be_compressed() {
+        let dir = std::env::temp_dir().join(format!("fakeup-fixtures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json = br#"{"releases": [{"commits": [], "version": "1", "metadata": ""}]}"#;
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(json).unwrap();
+        std::fs::write(dir.join("releases-gz.json.gz"), gz.finish().unwrap()).unwrap();
+        let zst = zstd::encode_all(&json[..], 0).unwrap();
+        std::fs::write(dir.join("releases-zst.json.zst"), zst).unwrap();
+        std::fs::write(dir.join("releases-zst.json"), b"{\"releases\": []}").unwrap();
+
+        let gz = read_fixture(&dir, "releases-gz").and_then(|b| parse_releases(&b));
+        assert_eq!(gz.unwrap().releases.len(), 1);
+        // Plain JSON takes precedence.
+        let plain = read_fixture(&dir, "releases-zst").and_then(|b| parse_releases(&b));
+        assert!(plain.unwrap().releases.is_empty());
+        std::fs::remove_file(dir.join("releases-zst.json")).unwrap();
+        let zst = read_fixture(&dir, "releases-zst").and_then(|b| parse_releases(&b));
+        assert_eq!(zst.unwrap().releases.len(), 1);
+        let err = read_fixture(&dir, "releases-missing").unwrap_err();
+        assert!(err.to_string().contains("releases-missing.json'"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
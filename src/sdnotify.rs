@@ -0,0 +1,144 @@
+//! Service status notifications to systemd (`sd_notify`).
+
+use failure::{format_err, Fallible};
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Notifier for the service manager socket in `NOTIFY_SOCKET`.
+#[derive(Debug)]
+pub(crate) struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// Whether readiness was already signalled.
+    ready: bool,
+    /// Last status sent, to skip repeated updates.
+    status: String,
+}
+
+impl Notifier {
+    /// Set up notifications, if running under a service manager that wants them.
+    pub(crate) fn from_env() -> Fallible<Option<Self>> {
+        let path = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(path) if !path.is_empty() => path,
+            _ => return Ok(None),
+        };
+        let invalid = |e| format_err!("invalid NOTIFY_SOCKET {:?}: {}", path, e);
+        let addr = match path.to_str().and_then(|p| p.strip_prefix('@')) {
+            Some(name) => match abstract_addr(name).map_err(invalid)? {
+                Some(addr) => addr,
+                None => {
+                    log::warn!("abstract NOTIFY_SOCKET {:?} not supported, ignoring", path);
+                    return Ok(None);
+                }
+            },
+            None => SocketAddr::from_pathname(&path).map_err(invalid)?,
+        };
+        let socket = UnixDatagram::unbound()
+            .map_err(|e| format_err!("failed to create notification socket: {}", e))?;
+        let notifier = Self {
+            socket,
+            addr,
+            ready: false,
+            status: String::new(),
+        };
+        Ok(Some(notifier))
+    }
+
+    /// Update the service status, signalling readiness once `ready`.
+    pub(crate) fn update(&mut self, status: &str, ready: bool) {
+        let signal_ready = ready && !self.ready;
+        if !signal_ready && self.status == status {
+            return;
+        }
+        let mut msg = format!("STATUS={}\n", status);
+        if signal_ready {
+            msg.insert_str(0, "READY=1\n");
+        }
+        match self.socket.send_to_addr(msg.as_bytes(), &self.addr) {
+            Ok(_) => {
+                self.ready |= signal_ready;
+                self.status = status.to_string();
+            }
+            Err(e) => log::warn!("failed to notify service manager: {}", e),
+        }
+    }
+}
+
+/// Socket address in the Linux abstract namespace.
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> std::io::Result<Option<SocketAddr>> {
+    SocketAddr::from_abstract_name(name).map(Some)
+}
+
+/// Socket address in the Linux abstract namespace, unsupported here.
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> std::io::Result<Option<SocketAddr>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A notifier paired with the service manager end of its socket.
+    pub(crate) fn notifier_pair(name: &str) -> (Notifier, UnixDatagram, PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("fakeup-notify-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        manager.set_nonblocking(true).unwrap();
+        let notifier = Notifier {
+            socket: UnixDatagram::unbound().unwrap(),
+            addr: SocketAddr::from_pathname(&path).unwrap(),
+            ready: false,
+            status: String::new(),
+        };
+        (notifier, manager, path)
+    }
+
+    /// Receive the next pending message, if any.
+    pub(crate) fn recv(manager: &UnixDatagram) -> Option<String> {
+        let mut buf = [0u8; 512];
+        let len = manager.recv(&mut buf).ok()?;
+        Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+
+    #[test]
+    fn readiness_is_signalled_once() {
+        let (mut notifier, manager, path) = notifier_pair("ready");
+        notifier.update("waiting for streams: stable", false);
+        assert_eq!(
+            recv(&manager).unwrap(),
+            "STATUS=waiting for streams: stable\n"
+        );
+        notifier.update("serving stable 1.0", true);
+        assert_eq!(
+            recv(&manager).unwrap(),
+            "READY=1\nSTATUS=serving stable 1.0\n"
+        );
+
+        notifier.update("serving stable 1.0", true);
+        assert_eq!(recv(&manager), None);
+
+        notifier.update("serving stable 2.0", true);
+        assert_eq!(recv(&manager).unwrap(), "STATUS=serving stable 2.0\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn failed_sends_are_retried() {
+        let (mut notifier, manager, path) = notifier_pair("retry");
+        drop(manager);
+        std::fs::remove_file(&path).unwrap();
+        notifier.update("serving", true);
+        assert!(!notifier.ready);
+
+        let manager = UnixDatagram::bind(&path).unwrap();
+        manager.set_nonblocking(true).unwrap();
+        notifier.update("serving", true);
+        assert_eq!(recv(&manager).unwrap(), "READY=1\nSTATUS=serving\n");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+//! Graceful shutdown, on SIGTERM and SIGINT.
+
+use crate::scraper;
+use actix::actors::signal;
+use actix::prelude::*;
+use actix_web::server::StopServer;
+use futures::future;
+use futures::prelude::*;
+
+/// Drain servers and stop the scraper on termination signals, then exit.
+///
+/// A second signal stops right away, without waiting for in-flight requests.
+pub(crate) struct ShutdownHandler {
+    /// TCP servers to drain (none when serving on a Unix socket).
+    pub(crate) servers: Vec<Recipient<StopServer>>,
+    pub(crate) scraper_addr: Addr<scraper::Scraper>,
+    /// Whether a shutdown is already in progress.
+    pub(crate) stopping: bool,
+}
+
+impl ShutdownHandler {
+    fn shutdown(&mut self, ctx: &mut Context<Self>) {
+        let stop_scraper = scraper::request(&self.scraper_addr, scraper::Shutdown)
+            .map_err(|e| log::warn!("failed to stop scraper: {}", e));
+        // Servers stop accepting connections, and wait for in-flight
+        // requests up to their shutdown timeout.
+        let drains: Vec<_> = self
+            .servers
+            .iter()
+            .map(|server| {
+                server
+                    .send(StopServer { graceful: true })
+                    .then(|_| Ok::<_, ()>(()))
+            })
+            .collect();
+        let shutdown = stop_scraper
+            .then(move |_| future::join_all(drains))
+            .then(|_| {
+                log::info!("shutdown complete");
+                System::current().stop();
+                Ok(())
+            });
+        ctx.spawn(shutdown.into_actor(self));
+    }
+}
+
+impl Actor for ShutdownHandler {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let signals = System::current().registry().get::<signal::ProcessSignals>();
+        signals.do_send(signal::Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<signal::Signal> for ShutdownHandler {
+    type Result = ();
+
+    fn handle(&mut self, msg: signal::Signal, ctx: &mut Self::Context) {
+        let name = match msg.0 {
+            signal::SignalType::Term => "SIGTERM",
+            signal::SignalType::Int => "SIGINT",
+            signal::SignalType::Quit => "SIGQUIT",
+            _ => return,
+        };
+        if self.stopping || msg.0 == signal::SignalType::Quit {
+            log::warn!("{} received, exiting immediately", name);
+            System::current().stop();
+            return;
+        }
+        log::info!("{} received, shutting down", name);
+        self.stopping = true;
+        self.shutdown(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn termination_stops_the_system() {
+        let sys = System::new("shutdown-signal");
+        let scraper = scraper::Scraper::new(
+            btreeset!["stable".to_string()],
+            Duration::from_secs(30),
+            Default::default(),
+        )
+        .unwrap();
+        let handler = ShutdownHandler {
+            servers: vec![],
+            scraper_addr: scraper.start(),
+            stopping: false,
+        }
+        .start();
+        handler.do_send(signal::Signal(signal::SignalType::Term));
+        assert_eq!(sys.run(), 0);
+    }
+}
@@ -0,0 +1,114 @@
+//! On-disk snapshots of release indexes, to survive upstream outages.
+
+use crate::metadata;
+use failure::{format_err, Error, Fallible};
+use std::path::PathBuf;
+
+/// Behavior when upstream stops serving (404) a known stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingStream {
+    /// Drop the stream, as upstream did.
+    Strict,
+    /// Keep serving the last known releases, flagged as stale.
+    Lenient,
+}
+
+impl std::str::FromStr for MissingStream {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Fallible<Self> {
+        match input {
+            "strict" => Ok(MissingStream::Strict),
+            "lenient" => Ok(MissingStream::Lenient),
+            _ => Err(format_err!("unknown missing stream mode '{}'", input)),
+        }
+    }
+}
+
+/// Directory of `releases-<stream>.json` snapshots, in the fixtures layout.
+#[derive(Clone, Debug)]
+pub struct SnapshotDir {
+    dir: PathBuf,
+}
+
+impl SnapshotDir {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, stream: &str) -> PathBuf {
+        self.dir.join(format!("releases-{}.json", stream))
+    }
+
+    /// Persist the release index of a stream, replacing it atomically.
+    pub fn save(&self, stream: &str, releases: &[metadata::Release]) -> Fallible<()> {
+        let index = metadata::ReleasesJSON {
+            releases: releases.to_vec(),
+        };
+        let path = self.path(stream);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&index)?)
+            .and_then(|_| std::fs::rename(&tmp, &path))
+            .map_err(|e| format_err!("failed to write snapshot '{}': {}", path.display(), e))
+    }
+
+    /// Load the persisted release index of a stream, if any.
+    pub fn load(&self, stream: &str) -> Fallible<Option<Vec<metadata::Release>>> {
+        let path = self.path(stream);
+        let body = match std::fs::read(&path) {
+            Ok(body) => body,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(format_err!(
+                    "failed to read snapshot '{}': {}",
+                    path.display(),
+                    e
+                ))
+            }
+        };
+        let index = metadata::ReleasesJSON::from_slice(&body)?;
+        Ok(Some(index.releases))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("fakeup-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let snapshots = SnapshotDir::new(dir.clone());
+        assert!(snapshots.load("stable").unwrap().is_none());
+
+        let index: metadata::ReleasesJSON = serde_json::from_str(
+            r#"{"releases": [{"version": "1", "metadata": "", "commits": [
+                {"architecture": "x86_64", "checksum": "c1"}
+            ]}]}"#,
+        )
+        .unwrap();
+        snapshots.save("stable", &index.releases).unwrap();
+        let loaded = snapshots.load("stable").unwrap().unwrap();
+        assert_eq!(loaded[0].version, "1");
+        assert_eq!(loaded[0].commits[0].checksum, "c1");
+        assert!(!dir.join("releases-stable.json.tmp").exists());
+
+        std::fs::write(dir.join("releases-next.json"), "{").unwrap();
+        assert!(snapshots.load("next").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_stream_modes_parse() {
+        assert_eq!(
+            "strict".parse::<MissingStream>().unwrap(),
+            MissingStream::Strict
+        );
+        assert_eq!(
+            "lenient".parse::<MissingStream>().unwrap(),
+            MissingStream::Lenient
+        );
+        assert!("drop".parse::<MissingStream>().is_err());
+    }
+}
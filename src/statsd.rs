@@ -0,0 +1,199 @@
+//! StatsD sink, mirroring Prometheus metrics over UDP.
+//!
+//! Counters are sent as deltas since the previous flush, gauges as their
+//! current value. Histograms are flattened into `count` (counter) and
+//! `sum` (gauge). Labels are appended to the metric name as
+//! `.<label>.<value>` pairs.
+
+use actix::prelude::*;
+use failure::{format_err, Fallible};
+use prometheus::proto::{Metric, MetricType};
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+/// Maximum payload size per datagram, to avoid fragmentation.
+static MAX_PACKET_SIZE: usize = 1432;
+
+/// Periodic StatsD emitter.
+#[derive(Debug)]
+pub struct StatsdSink {
+    socket: UdpSocket,
+    target: SocketAddr,
+    prefix: String,
+    flush_interval: Duration,
+    /// Counter values at the previous flush.
+    last_counters: HashMap<String, f64>,
+}
+
+impl StatsdSink {
+    pub fn new(target: &str, prefix: String, flush_interval: Duration) -> Fallible<Self> {
+        let target = target
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format_err!("statsd address '{}' did not resolve", target))?;
+        let local: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let sink = Self {
+            socket: UdpSocket::bind(local)?,
+            target,
+            prefix,
+            flush_interval,
+            last_counters: HashMap::new(),
+        };
+        Ok(sink)
+    }
+
+    /// Send all current metric values.
+    fn flush(&mut self) {
+        let mut lines = Vec::new();
+        for family in prometheus::gather() {
+            let name = family.get_name();
+            for metric in family.get_metric() {
+                let key = self.metric_key(name, metric);
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let value = metric.get_counter().get_value();
+                        lines.push(self.counter_line(key, value));
+                    }
+                    MetricType::GAUGE => {
+                        let value = metric.get_gauge().get_value();
+                        lines.push(format!("{}:{}|g", key, value));
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        let count = histogram.get_sample_count() as f64;
+                        lines.push(self.counter_line(format!("{}.count", key), count));
+                        lines.push(format!("{}.sum:{}|g", key, histogram.get_sample_sum()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        lines.retain(|l| !l.is_empty());
+
+        let mut packet = String::new();
+        for line in lines {
+            if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET_SIZE {
+                self.send(&packet);
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            self.send(&packet);
+        }
+    }
+
+    /// Format a counter delta, skipping unchanged counters.
+    fn counter_line(&mut self, key: String, value: f64) -> String {
+        let last = self.last_counters.insert(key.clone(), value).unwrap_or(0.0);
+        // Counters never decrease, unless the process restarted.
+        let delta = if value >= last { value - last } else { value };
+        if delta == 0.0 {
+            return String::new();
+        }
+        format!("{}:{}|c", key, delta)
+    }
+
+    /// Build the StatsD key for a metric, including its labels.
+    fn metric_key(&self, name: &str, metric: &Metric) -> String {
+        let name = name.strip_prefix("fakeup_").unwrap_or(name);
+        let mut key = if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.prefix, name)
+        };
+        for label in metric.get_label() {
+            key.push('.');
+            key.push_str(&sanitize(label.get_name()));
+            key.push('.');
+            key.push_str(&sanitize(label.get_value()));
+        }
+        key
+    }
+
+    fn send(&self, packet: &str) {
+        if let Err(e) = self.socket.send_to(packet.as_bytes(), self.target) {
+            log::warn!("failed to send statsd metrics: {}", e);
+        }
+    }
+}
+
+/// Replace characters with special meaning in StatsD keys.
+fn sanitize(input: &str) -> String {
+    input
+        .chars()
+        .map(|c| match c {
+            '.' | ':' | '|' | '@' | '#' | ' ' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+impl Actor for StatsdSink {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.flush_interval, |act, _ctx| act.flush());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_sanitized() {
+        assert_eq!(sanitize("a.b:c|d@e#f g"), "a_b_c_d_e_f_g");
+        assert_eq!(sanitize("x86_64"), "x86_64");
+    }
+
+    #[test]
+    fn counters_are_sent_as_deltas() {
+        let mut sink =
+            StatsdSink::new("127.0.0.1:8125", "p".to_string(), Duration::from_secs(10)).unwrap();
+        assert_eq!(sink.counter_line("c".to_string(), 3.0), "c:3|c");
+        assert_eq!(sink.counter_line("c".to_string(), 3.0), "");
+        assert_eq!(sink.counter_line("c".to_string(), 5.0), "c:2|c");
+        // Restarted counter.
+        assert_eq!(sink.counter_line("c".to_string(), 1.0), "c:1|c");
+    }
+
+    #[test]
+    fn flush_sends_registered_metrics() {
+        let counter = register_int_counter!(opts!(
+            "fakeup_statsd_flush_test_total",
+            "Counter for the StatsD flush test"
+        ))
+        .unwrap();
+        counter.inc_by(2);
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let target = server.local_addr().unwrap().to_string();
+        let mut sink =
+            StatsdSink::new(&target, "test".to_string(), Duration::from_secs(10)).unwrap();
+        sink.flush();
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let len = server.recv(&mut buf).expect("missing statsd line");
+            let packet = String::from_utf8_lossy(&buf[..len]);
+            if packet
+                .lines()
+                .any(|l| l == "test.statsd_flush_test_total:2|c")
+            {
+                break;
+            }
+        }
+    }
+}
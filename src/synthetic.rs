@@ -0,0 +1,99 @@
+//! Synthetic release indexes, generated without any upstream.
+
+use crate::metadata;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+
+/// Generate `count` releases for each stream, oldest first.
+///
+/// Every release carries a payload for each architecture, with a
+/// checksum distinct per stream, release and architecture, and stable
+/// across runs of the same build.
+pub(crate) fn generate(
+    streams: &BTreeSet<String>,
+    count: usize,
+    arches: &[String],
+) -> BTreeMap<String, Vec<metadata::Release>> {
+    streams
+        .iter()
+        .map(|stream| {
+            let releases = (1..=count)
+                .map(|n| {
+                    let version = format!("40.{}.0", n);
+                    let commits = arches
+                        .iter()
+                        .map(|arch| metadata::ReleaseCommit {
+                            architecture: arch.clone(),
+                            checksum: checksum(stream, &version, arch),
+                            size: None,
+                        })
+                        .collect();
+                    metadata::Release {
+                        commits,
+                        version,
+                        metadata: String::new(),
+                    }
+                })
+                .collect();
+            (stream.clone(), releases)
+        })
+        .collect()
+}
+
+/// Deterministic 64 hex digits checksum, shaped like an OSTree commit.
+fn checksum(stream: &str, version: &str, arch: &str) -> String {
+    (0u8..4)
+        .map(|round| {
+            let mut hasher = DefaultHasher::new();
+            (round, stream, version, arch).hash(&mut hasher);
+            format!("{:016x}", hasher.finish())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::btreeset;
+
+    #[test]
+    fn releases_cover_every_stream_and_arch() {
+        let streams = btreeset!["next".to_string(), "stable".to_string()];
+        let arches = vec!["x86_64".to_string(), "aarch64".to_string()];
+        let generated = generate(&streams, 3, &arches);
+        assert_eq!(generated.keys().collect::<Vec<_>>(), vec!["next", "stable"]);
+
+        let mut checksums = BTreeSet::new();
+        for releases in generated.values() {
+            let versions: Vec<_> = releases.iter().map(|r| r.version.as_str()).collect();
+            assert_eq!(versions, vec!["40.1.0", "40.2.0", "40.3.0"]);
+            for release in releases {
+                let arches: Vec<_> = release
+                    .commits
+                    .iter()
+                    .map(|c| c.architecture.as_str())
+                    .collect();
+                assert_eq!(arches, vec!["x86_64", "aarch64"]);
+                for commit in &release.commits {
+                    assert_eq!(commit.checksum.len(), 64);
+                    assert!(checksums.insert(commit.checksum.clone()));
+                }
+            }
+        }
+        assert_eq!(checksums.len(), 12);
+    }
+
+    #[test]
+    fn checksums_are_stable() {
+        let streams = btreeset!["stable".to_string()];
+        let arches = vec!["x86_64".to_string()];
+        let checksums = || -> Vec<String> {
+            generate(&streams, 2, &arches)["stable"]
+                .iter()
+                .map(|r| r.commits[0].checksum.clone())
+                .collect()
+        };
+        assert_eq!(checksums(), checksums());
+    }
+}
@@ -0,0 +1,69 @@
+//! Response post-processing via user-provided templates.
+
+use failure::{format_err, Fallible};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Name under which the user template is registered.
+static TEMPLATE_NAME: &str = "response";
+
+/// Template for rendering graph responses into custom shapes.
+///
+/// The template is rendered with the graph as `graph`, and with its
+/// pre-serialized JSON form as `json`.
+#[derive(Clone, Debug)]
+pub struct ResponseTemplate {
+    env: Arc<minijinja::Environment<'static>>,
+}
+
+impl ResponseTemplate {
+    /// Load and compile a template from file.
+    pub fn from_path(path: &Path) -> Fallible<Self> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("failed to read template '{}': {}", path.display(), e))?;
+        let mut env = minijinja::Environment::new();
+        env.add_template_owned(TEMPLATE_NAME, source)
+            .map_err(|e| format_err!("invalid template '{}': {}", path.display(), e))?;
+        let tmpl = Self { env: Arc::new(env) };
+        Ok(tmpl)
+    }
+
+    /// Render a serialized graph through the template.
+    pub fn render<T: serde::Serialize>(&self, graph: &T, json: &str) -> Fallible<String> {
+        let tmpl = self.env.get_template(TEMPLATE_NAME)?;
+        let ctx = minijinja::context! {
+            graph => minijinja::Value::from_serialize(graph),
+            json => json,
+        };
+        let out = tmpl.render(ctx)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(name: &str, source: &str) -> Fallible<ResponseTemplate> {
+        let path =
+            std::env::temp_dir().join(format!("fakeup-template-{}-{}", name, std::process::id()));
+        std::fs::write(&path, source)?;
+        let tmpl = ResponseTemplate::from_path(&path);
+        std::fs::remove_file(&path)?;
+        tmpl
+    }
+
+    #[test]
+    fn render_graph_and_json() {
+        let tmpl = template("render", "{{ graph.nodes | length }}:{{ json }}").unwrap();
+        let graph = serde_json::json!({ "nodes": [1, 2] });
+        let out = tmpl.render(&graph, "raw").unwrap();
+        assert_eq!(out, "2:raw");
+    }
+
+    #[test]
+    fn invalid_template_is_rejected() {
+        assert!(template("invalid", "{{ graph").is_err());
+        assert!(ResponseTemplate::from_path(Path::new("/nonexistent/template")).is_err());
+    }
+}
@@ -0,0 +1,372 @@
+//! Test harness for clients of the graph endpoint.
+//!
+//! It runs the `fakeup` binary on an ephemeral port, optionally preloaded
+//! with a fixture of releases, and checks served graphs for invariants.
+//!
+//! ```no_run
+//! use fakeup::testkit::{assert_graph_valid, Fakeup};
+//!
+//! let server = Fakeup::builder()
+//!     .release("stable", "30.1", &[("x86_64", "aaa")])
+//!     .release("stable", "30.2", &[("x86_64", "bbb")])
+//!     .spawn()
+//!     .unwrap();
+//! let graph = server.graph("stable", "aaa").unwrap();
+//! assert_graph_valid(&graph);
+//! ```
+//!
+//! Graphs are requested with the same HTTP client as upstream scrapes
+//! (`http-reqwest` or `http-ureq` feature).
+
+use failure::{bail, format_err, Fallible};
+use serde_derive::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(any(feature = "http-reqwest", feature = "http-ureq")))]
+compile_error!("the testkit needs an HTTP client, enable `http-reqwest` or `http-ureq`");
+
+/// Environment variable overriding the path of the `fakeup` binary.
+pub static FAKEUP_BIN_ENV: &str = "FAKEUP_BIN";
+
+static AGE_INDEX: &str = "org.fedoraproject.coreos.releases.age_index";
+
+/// Unique suffix for fixture directories of this process.
+static FIXTURE_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Preloaded release, as `(version, [(arch, checksum)])`.
+type FixtureRelease = (String, Vec<(String, String)>);
+
+/// Graph, as served by fakeup.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<(u64, u64)>,
+}
+
+/// Graph node.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Node {
+    pub version: String,
+    pub payload: String,
+    pub metadata: HashMap<String, String>,
+}
+
+/// Builder for a fakeup server.
+#[derive(Clone, Debug)]
+pub struct FakeupBuilder {
+    binary: PathBuf,
+    args: Vec<String>,
+    /// Releases per stream, oldest first.
+    releases: BTreeMap<String, Vec<FixtureRelease>>,
+}
+
+impl FakeupBuilder {
+    /// Path of the `fakeup` binary (defaults to `$FAKEUP_BIN`, or `fakeup` in `$PATH`).
+    pub fn binary(mut self, path: impl Into<PathBuf>) -> Self {
+        self.binary = path.into();
+        self
+    }
+
+    /// Additional command-line argument for the server.
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Preload a release, newer than the ones already added to its stream.
+    ///
+    /// Preloading releases freezes the server cache, so nothing is scraped.
+    pub fn release(mut self, stream: &str, version: &str, payloads: &[(&str, &str)]) -> Self {
+        let payloads = payloads
+            .iter()
+            .map(|(arch, checksum)| (arch.to_string(), checksum.to_string()))
+            .collect();
+        self.releases
+            .entry(stream.to_string())
+            .or_default()
+            .push((version.to_string(), payloads));
+        self
+    }
+
+    /// Start the server, waiting until it is listening.
+    pub fn spawn(self) -> Fallible<Fakeup> {
+        let fixture_dir = if self.releases.is_empty() {
+            None
+        } else {
+            Some(write_fixture(&self.releases)?)
+        };
+
+        let mut cmd = Command::new(&self.binary);
+        cmd.args(["serve", "--port", "0"]);
+        if let Some(ref dir) = fixture_dir {
+            cmd.arg("--import-fixture").arg(dir.join("fixture.tar.gz"));
+        }
+        cmd.args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| format_err!("failed to run '{}': {}", self.binary.display(), e))?;
+
+        // The server reports its bound addresses as a JSON line on stdout.
+        let mut line = String::new();
+        if let Some(stdout) = child.stdout.take() {
+            std::io::BufReader::new(stdout).read_line(&mut line)?;
+        }
+        let mut server = Fakeup {
+            child,
+            base_url: String::new(),
+            fixture_dir,
+        };
+        let bound: serde_json::Value = serde_json::from_str(&line)
+            .map_err(|e| format_err!("server failed to start (output '{}'): {}", line.trim(), e))?;
+        let port = bound["listen_addrs"][0]
+            .as_str()
+            .and_then(|addr| addr.rsplit(':').next())
+            .ok_or_else(|| format_err!("no listen address in '{}'", line.trim()))?;
+        server.base_url = format!("http://127.0.0.1:{}", port);
+        Ok(server)
+    }
+}
+
+/// Running fakeup server, stopped on drop.
+#[derive(Debug)]
+pub struct Fakeup {
+    child: Child,
+    base_url: String,
+    fixture_dir: Option<PathBuf>,
+}
+
+impl Fakeup {
+    pub fn builder() -> FakeupBuilder {
+        let binary = std::env::var_os(FAKEUP_BIN_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("fakeup"));
+        FakeupBuilder {
+            binary,
+            args: vec![],
+            releases: BTreeMap::new(),
+        }
+    }
+
+    /// Base URL of the server (e.g. `http://127.0.0.1:34567`).
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Request the graph for a client on `stream`, running payload `checksum`.
+    pub fn graph(&self, stream: &str, checksum: &str) -> Fallible<Graph> {
+        let url = url::Url::parse_with_params(
+            &format!("{}/v1/graph", self.base_url),
+            &[("stream", stream), ("os_checksum", checksum)],
+        )?;
+        let body = get(&url)?;
+        Ok(serde_json::from_str(&body)?)
+    }
+}
+
+impl Drop for Fakeup {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if let Some(ref dir) = self.fixture_dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Fetch a body, failing on HTTP error statuses.
+#[cfg(feature = "http-ureq")]
+fn get(url: &url::Url) -> Fallible<String> {
+    let body = ureq::get(url.as_str())
+        .call()
+        .map_err(|e| format_err!("{}", e))?
+        .into_string()?;
+    Ok(body)
+}
+
+/// Fetch a body, failing on HTTP error statuses.
+#[cfg(all(feature = "http-reqwest", not(feature = "http-ureq")))]
+fn get(url: &url::Url) -> Fallible<String> {
+    let body = reqwest::Client::new()
+        .get(url.as_str())
+        .send()?
+        .error_for_status()?
+        .text()?;
+    Ok(body)
+}
+
+/// Check graph invariants.
+///
+/// Edges must reference existing nodes, and point from a release to a
+/// strictly newer one (by age index); payloads must be unique.
+pub fn check_graph(graph: &Graph) -> Fallible<()> {
+    let mut payloads = HashMap::new();
+    for (index, node) in graph.nodes.iter().enumerate() {
+        if let Some(other) = payloads.insert(node.payload.as_str(), index) {
+            bail!(
+                "nodes {} and {} share payload '{}'",
+                other,
+                index,
+                node.payload
+            );
+        }
+    }
+
+    let age_index = |index: u64| -> Fallible<u64> {
+        let node = graph
+            .nodes
+            .get(index as usize)
+            .ok_or_else(|| format_err!("edge references missing node {}", index))?;
+        let age = node
+            .metadata
+            .get(AGE_INDEX)
+            .ok_or_else(|| format_err!("node {} has no age index", index))?;
+        age.parse()
+            .map_err(|e| format_err!("node {} has invalid age index '{}': {}", index, age, e))
+    };
+    for &(from, to) in &graph.edges {
+        if age_index(from)? >= age_index(to)? {
+            bail!("edge {} -> {} does not point to a newer release", from, to);
+        }
+    }
+    Ok(())
+}
+
+/// Assert graph invariants (see `check_graph`), panicking on violations.
+pub fn assert_graph_valid(graph: &Graph) {
+    if let Err(e) = check_graph(graph) {
+        panic!("invalid graph: {}\n{:#?}", e, graph);
+    }
+}
+
+/// Write a fixture bundle with preloaded releases, returning its directory.
+fn write_fixture(releases: &BTreeMap<String, Vec<FixtureRelease>>) -> Fallible<PathBuf> {
+    let dir = std::env::temp_dir().join(format!(
+        "fakeup-testkit-{}-{}",
+        std::process::id(),
+        FIXTURE_SEQ.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::create_dir_all(&dir)?;
+
+    let file = std::fs::File::create(dir.join("fixture.tar.gz"))?;
+    let gz = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    let mut bundle = tar::Builder::new(gz);
+
+    let config = serde_json::json!({
+        "streams": releases.keys().collect::<Vec<_>>(),
+        "priority_streams": [],
+        "client_version": "placeholder",
+        "frozen": true,
+    });
+    append_file(&mut bundle, Path::new("config.json"), &config)?;
+    for (stream, index) in releases {
+        let index: Vec<_> = index
+            .iter()
+            .map(|(version, payloads)| {
+                let commits: Vec<_> = payloads
+                    .iter()
+                    .map(|(arch, checksum)| {
+                        serde_json::json!({ "architecture": arch, "checksum": checksum })
+                    })
+                    .collect();
+                serde_json::json!({ "version": version, "metadata": "", "commits": commits })
+            })
+            .collect();
+        let name = format!("releases/{}.json", stream);
+        append_file(
+            &mut bundle,
+            Path::new(&name),
+            &serde_json::json!({ "releases": index }),
+        )?;
+    }
+    bundle.into_inner()?.finish()?;
+    Ok(dir)
+}
+
+/// Append a JSON file to the bundle `fixture/` directory.
+fn append_file<W: std::io::Write>(
+    bundle: &mut tar::Builder<W>,
+    name: &Path,
+    content: &serde_json::Value,
+) -> Fallible<()> {
+    let content = serde_json::to_vec_pretty(content)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_cksum();
+    bundle.append_data(
+        &mut header,
+        Path::new("fixture").join(name),
+        content.as_slice(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(payload: &str, age_index: u64) -> Node {
+        let mut metadata = HashMap::new();
+        metadata.insert(AGE_INDEX.to_string(), age_index.to_string());
+        Node {
+            version: format!("v{}", age_index),
+            payload: payload.to_string(),
+            metadata,
+        }
+    }
+
+    #[test]
+    fn valid_graphs_pass() {
+        let graph = Graph {
+            nodes: vec![node("a", 0), node("b", 1)],
+            edges: vec![(0, 1)],
+        };
+        assert_graph_valid(&graph);
+    }
+
+    #[test]
+    fn graph_invariants_are_checked() {
+        let backwards = Graph {
+            nodes: vec![node("a", 1), node("b", 0)],
+            edges: vec![(0, 1)],
+        };
+        assert!(check_graph(&backwards).is_err());
+        let dangling = Graph {
+            nodes: vec![node("a", 0)],
+            edges: vec![(0, 1)],
+        };
+        assert!(check_graph(&dangling).is_err());
+        let duplicated = Graph {
+            nodes: vec![node("a", 0), node("a", 1)],
+            edges: vec![],
+        };
+        assert!(check_graph(&duplicated).is_err());
+    }
+
+    #[test]
+    fn fixtures_carry_preloaded_releases() {
+        let builder = Fakeup::builder()
+            .release("stable", "30.1", &[("x86_64", "aaa")])
+            .release("stable", "30.2", &[("x86_64", "bbb")]);
+        let dir = write_fixture(&builder.releases).unwrap();
+        let file = std::fs::File::open(dir.join("fixture.tar.gz")).unwrap();
+        let mut bundle = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let names: Vec<String> = bundle
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["fixture/config.json", "fixture/releases/stable.json"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
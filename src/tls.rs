@@ -0,0 +1,94 @@
+//! TLS trust settings for upstream requests.
+
+use failure::{bail, format_err, Fallible};
+use std::path::PathBuf;
+
+static PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+static PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// Trust customization for the upstream HTTP client.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamTls {
+    /// PEM bundle of additional trusted CA certificates.
+    pub ca_bundle: Option<PathBuf>,
+    /// Skip certificate verification altogether (insecure).
+    pub insecure: bool,
+}
+
+impl UpstreamTls {
+    /// Load additional trusted CA certificates, as single PEM blocks.
+    ///
+    /// This also warns if certificate verification is disabled, as
+    /// backends are expected to apply both settings together.
+    pub fn ca_certs(&self) -> Fallible<Vec<String>> {
+        if self.insecure {
+            log::warn!("INSECURE: upstream TLS certificate verification is disabled");
+        }
+        let path = match self.ca_bundle {
+            Some(ref path) => path,
+            None => return Ok(vec![]),
+        };
+        let bundle = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("failed to read CA bundle '{}': {}", path.display(), e))?;
+        let certs = split_pem(&bundle);
+        if certs.is_empty() {
+            bail!("no certificates found in CA bundle '{}'", path.display());
+        }
+        log::info!(
+            "trusting {} upstream CA certificates from '{}'",
+            certs.len(),
+            path.display()
+        );
+        Ok(certs)
+    }
+}
+
+/// Split a PEM bundle into single certificates.
+fn split_pem(bundle: &str) -> Vec<String> {
+    let mut certs = Vec::new();
+    let mut rest = bundle;
+    while let Some(start) = rest.find(PEM_BEGIN) {
+        let end = match rest[start..].find(PEM_END) {
+            Some(end) => start + end + PEM_END.len(),
+            None => break,
+        };
+        certs.push(rest[start..end].to_string());
+        rest = &rest[end..];
+    }
+    certs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pem_bundles_are_split() {
+        let bundle = format!(
+            "# first\n{}\nAAAA\n{}\n# second\n{}\nBBBB\n{}\n{}\ntruncated\n",
+            PEM_BEGIN, PEM_END, PEM_BEGIN, PEM_END, PEM_BEGIN
+        );
+        let certs = split_pem(&bundle);
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs[0], format!("{}\nAAAA\n{}", PEM_BEGIN, PEM_END));
+        assert!(certs[1].contains("BBBB"));
+    }
+
+    #[test]
+    fn bundles_without_certificates_are_rejected() {
+        let path = std::env::temp_dir().join(format!("fakeup-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, "not a certificate\n").unwrap();
+        let tls = UpstreamTls {
+            ca_bundle: Some(path.clone()),
+            insecure: false,
+        };
+        assert!(tls.ca_certs().is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let insecure = UpstreamTls {
+            ca_bundle: None,
+            insecure: true,
+        };
+        assert!(insecure.ca_certs().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+//! W3C Trace Context propagation.
+//!
+//! Incoming `traceparent`/`tracestate` headers are forwarded on upstream
+//! requests made on behalf of a client, with a fresh parent ID as each
+//! outgoing request is a new span of the same trace.
+
+use actix_web::http::HeaderMap;
+
+pub(crate) static TRACEPARENT: &str = "traceparent";
+pub(crate) static TRACESTATE: &str = "tracestate";
+
+/// Trace context of an incoming request.
+#[derive(Clone, Debug)]
+pub(crate) struct TraceContext {
+    trace_id: String,
+    flags: String,
+    tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Extract a trace context from request headers, ignoring invalid ones.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT)?.to_str().ok()?;
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        let (version, trace_id, parent_id, flags) = match parts.as_slice() {
+            [version, trace_id, parent_id, flags] => (*version, *trace_id, *parent_id, *flags),
+            // Later versions may append fields.
+            [version, trace_id, parent_id, flags, ..] if *version != "00" => {
+                (*version, *trace_id, *parent_id, *flags)
+            }
+            _ => return None,
+        };
+        if !is_hex(version, 2) || version == "ff" || !is_hex(flags, 2) {
+            return None;
+        }
+        if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) {
+            return None;
+        }
+        if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+
+        let tracestate = headers
+            .get(TRACESTATE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let ctx = Self {
+            trace_id: trace_id.to_string(),
+            flags: flags.to_string(),
+            tracestate,
+        };
+        Some(ctx)
+    }
+
+    pub(crate) fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    /// Trace headers for an outgoing request, as a new child span.
+    pub(crate) fn headers(&self) -> Vec<(String, String)> {
+        let span_id = loop {
+            let id: u64 = rand::random();
+            if id != 0 {
+                break id;
+            }
+        };
+        let traceparent = format!("00-{}-{:016x}-{}", self.trace_id, span_id, self.flags);
+        let mut headers = vec![(TRACEPARENT.to_string(), traceparent)];
+        if let Some(ref state) = self.tracestate {
+            headers.push((TRACESTATE.to_string(), state.clone()));
+        }
+        headers
+    }
+}
+
+/// Check for a lowercase hex string of the given length.
+fn is_hex(input: &str, len: usize) -> bool {
+    input.len() == len
+        && input
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+
+    static TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    fn headers(traceparent: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_str(traceparent).unwrap());
+        headers
+    }
+
+    #[test]
+    fn valid_traceparents_are_parsed() {
+        let traceparent = format!("00-{}-00f067aa0ba902b7-01", TRACE_ID);
+        let ctx = TraceContext::from_headers(&headers(&traceparent)).unwrap();
+        assert_eq!(ctx.trace_id(), TRACE_ID);
+        let future = format!("01-{}-00f067aa0ba902b7-01-extra", TRACE_ID);
+        assert!(TraceContext::from_headers(&headers(&future)).is_some());
+        assert!(TraceContext::from_headers(&HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn invalid_traceparents_are_ignored() {
+        let invalid = [
+            format!("00-{}-00f067aa0ba902b7-01-extra", TRACE_ID),
+            format!("ff-{}-00f067aa0ba902b7-01", TRACE_ID),
+            format!("00-{}-00f067aa0ba902b7-01", TRACE_ID.to_uppercase()),
+            format!("00-{}-0000000000000000-01", TRACE_ID),
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01".to_string(),
+            "00-abc-00f067aa0ba902b7-01".to_string(),
+        ];
+        for traceparent in &invalid {
+            assert!(
+                TraceContext::from_headers(&headers(traceparent)).is_none(),
+                "{}",
+                traceparent
+            );
+        }
+    }
+
+    #[test]
+    fn outgoing_requests_get_a_child_span() {
+        let incoming = format!("00-{}-00f067aa0ba902b7-01", TRACE_ID);
+        let mut incoming_headers = headers(&incoming);
+        incoming_headers.insert(TRACESTATE, HeaderValue::from_static("vendor=value"));
+        let ctx = TraceContext::from_headers(&incoming_headers).unwrap();
+
+        let outgoing = ctx.headers();
+        assert_eq!(outgoing.len(), 2);
+        let (ref name, ref traceparent) = outgoing[0];
+        assert_eq!(name, TRACEPARENT);
+        assert!(traceparent.starts_with(&format!("00-{}-", TRACE_ID)));
+        assert!(traceparent.ends_with("-01"));
+        assert_ne!(traceparent, &incoming);
+        assert_eq!(
+            outgoing[1],
+            (TRACESTATE.to_string(), "vendor=value".to_string())
+        );
+    }
+}
@@ -0,0 +1,39 @@
+//! Build information.
+
+use crate::AppState;
+use actix_web::{HttpRequest, HttpResponse};
+use serde_derive::Serialize;
+
+/// Crate version.
+pub static CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Git commit of the build (`unknown` outside of a git checkout).
+pub static GIT_SHA: &str = env!("FAKEUP_GIT_SHA");
+
+/// Version string for `--version`.
+pub static VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("FAKEUP_GIT_SHA"), ")");
+
+#[derive(Debug, Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    git_sha: &'static str,
+}
+
+/// Serve build information as JSON.
+pub(crate) fn serve_version(_req: HttpRequest<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(VersionInfo {
+        version: CRATE_VERSION,
+        git_sha: GIT_SHA,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_includes_git_sha() {
+        assert!(!GIT_SHA.is_empty());
+        assert_eq!(VERSION, format!("{} ({})", CRATE_VERSION, GIT_SHA));
+    }
+}